@@ -1,25 +1,96 @@
 use crate::consts::*;
-use std::io;
+use crate::error::{Error, Result};
+use core::time::Duration;
 
-// todo: we should use overlapped api (async await)
+// todo: async variants on `read_pipe_async` and `write_pipe_async`, so that
+// several dongles can be driven from one thread
 
-pub(crate) fn command(handle: &nihao_usb::Handle, cmd0: u8, cmd1: u8, resp_len: usize) -> io::Result<Vec<u8>> {
+/// Endpoint taking the commands of a dongle with this product ID.
+pub(crate) fn tx_endpoint(pid: u16) -> u8 {
+    if pid == STLINK_V2_PID {
+        STLINK_TX_EP
+    } else {
+        STLINK_V2_1_TX_EP
+    }
+}
+
+pub(crate) fn command(handle: &nihao_usb::Handle, tx_ep: u8, cmd0: u8, cmd1: u8, resp_len: usize) -> Result<Vec<u8>> {
     let mut s = vec![0u8; STLINK_CMD_SIZE_V2];
     s[0] = cmd0;
     s[1] = cmd1;
-    let mut r = vec![0u8; resp_len];
-    handle.write_pipe(STLINK_TX_EP, &s)?;
-    handle.read_pipe(STLINK_RX_EP, &mut r)?;
-    Ok(r)
+    transfer(handle, tx_ep, &s, resp_len)
 }
 
-pub(crate) fn debug_command(handle: &nihao_usb::Handle, cmd0: u8, cmd1: u8, resp_len: usize) -> io::Result<Vec<u8>> {
+pub(crate) fn debug_command(handle: &nihao_usb::Handle, tx_ep: u8, cmd0: u8, cmd1: u8, resp_len: usize) -> Result<Vec<u8>> {
     let mut s = vec![0u8; STLINK_CMD_SIZE_V2];
     s[0] = STLINK_DEBUG_COMMAND;
     s[1] = cmd0;
     s[2] = cmd1;
+    transfer(handle, tx_ep, &s, resp_len)
+}
+
+/// A debug command followed by argument bytes, as in `cmd`.
+pub(crate) fn debug_command_bytes(handle: &nihao_usb::Handle, tx_ep: u8, cmd: &[u8], resp_len: usize) -> Result<Vec<u8>> {
+    let mut s = vec![0u8; STLINK_CMD_SIZE_V2];
+    s[0] = STLINK_DEBUG_COMMAND;
+    s[1..1 + cmd.len()].copy_from_slice(cmd);
+    transfer(handle, tx_ep, &s, resp_len)
+}
+
+pub(crate) fn read_mem(handle: &nihao_usb::Handle, tx_ep: u8, cmd: u8, address: u32, len: usize) -> Result<Vec<u8>> {
+    // a single byte read answers two bytes
+    let resp_len = if len == 1 { 2 } else { len };
+    let mut r = transfer(handle, tx_ep, &mem_command(cmd, address, len), resp_len)?;
+    r.truncate(len);
+    Ok(r)
+}
+
+pub(crate) fn write_mem(handle: &nihao_usb::Handle, tx_ep: u8, cmd: u8, address: u32, data: &[u8]) -> Result<()> {
+    transfer(handle, tx_ep, &mem_command(cmd, address, data.len()), 0)?;
+    handle.write_pipe(tx_ep, data)?;
+    Ok(())
+}
+
+fn mem_command(cmd: u8, address: u32, len: usize) -> Vec<u8> {
+    let mut s = vec![0u8; STLINK_CMD_SIZE_V2];
+    s[0] = STLINK_DEBUG_COMMAND;
+    s[1] = cmd;
+    s[2..6].copy_from_slice(&address.to_le_bytes());
+    s[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+    s
+}
+
+// commands answering nothing are not followed by a read
+fn transfer(handle: &nihao_usb::Handle, tx_ep: u8, s: &[u8], resp_len: usize) -> Result<Vec<u8>> {
     let mut r = vec![0u8; resp_len];
-    handle.write_pipe(STLINK_TX_EP, &s)?;
-    handle.read_pipe(STLINK_RX_EP, &mut r)?;
+    handle.write_pipe(tx_ep, s)?;
+    if resp_len > 0 {
+        handle.read_pipe(STLINK_RX_EP, &mut r)?;
+    }
     Ok(r)
 }
+
+/// Fail unless a debug command answered `STLINK_DEBUG_ERR_OK`.
+pub(crate) fn check_status(r: &[u8]) -> Result<()> {
+    match Error::from_status(r[0]) {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+// As OpenOCD does, retry after 1, 2, 4 ... milliseconds while the target 
+// asks to wait, and give up on the ninth WAIT in a row.
+const MAX_WAIT_RETRIES: u32 = 8;
+
+pub(crate) fn retry_on_wait<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(e) if e.is_wait() && retries < MAX_WAIT_RETRIES => {
+                std::thread::sleep(Duration::from_millis(1 << retries));
+                retries += 1;
+            },
+            ans => return ans,
+        }
+    }
+}
@@ -3,15 +3,91 @@ pub const ENDPOINT_OUT: u8 = 0x00;
 pub const STLINK_RX_EP: u8 = 1 | ENDPOINT_IN;
 pub const STLINK_TX_EP: u8 = 2 | ENDPOINT_OUT;
 pub const STLINK_TRACE_EP: u8 = 3 | ENDPOINT_IN;
+// ST-Link V2-1 and V3 moved commands and trace to other endpoints
+pub const STLINK_V2_1_TX_EP: u8 = 1 | ENDPOINT_OUT;
+pub const STLINK_V2_1_TRACE_EP: u8 = 2 | ENDPOINT_IN;
 
 pub const STLINK_CMD_SIZE_V2: usize = 16;
  
 pub const STLINK_GET_VERSION: u8 = 0xF1;
 pub const STLINK_DEBUG_COMMAND: u8 = 0xF2;
+pub const STLINK_DFU_COMMAND: u8 = 0xF3;
+pub const STLINK_SWIM_COMMAND: u8 = 0xF4;
 pub const STLINK_GET_CURRENT_MODE: u8 = 0xF5;
 pub const STLINK_GET_TARGET_VOLTAGE: u8 = 0xF7;
+pub const STLINK_APIV3_GET_VERSION_EX: u8 = 0xFB;
 
+// answers to `STLINK_GET_CURRENT_MODE`
+pub const STLINK_DEV_DFU_MODE: u8 = 0x00;
+pub const STLINK_DEV_MASS_MODE: u8 = 0x01;
+pub const STLINK_DEV_DEBUG_MODE: u8 = 0x02;
+pub const STLINK_DEV_SWIM_MODE: u8 = 0x03;
+pub const STLINK_DEV_BOOTLOADER_MODE: u8 = 0x04;
+
+pub const STLINK_DFU_EXIT: u8 = 0x07;
+pub const STLINK_SWIM_EXIT: u8 = 0x01;
+
+pub const STLINK_DEBUG_READMEM_32BIT: u8 = 0x07;
+pub const STLINK_DEBUG_WRITEMEM_32BIT: u8 = 0x08;
+pub const STLINK_DEBUG_READMEM_8BIT: u8 = 0x0C;
+pub const STLINK_DEBUG_WRITEMEM_8BIT: u8 = 0x0D;
+pub const STLINK_DEBUG_APIV1_ENTER: u8 = 0x20;
+pub const STLINK_DEBUG_EXIT: u8 = 0x21;
+pub const STLINK_DEBUG_APIV2_ENTER: u8 = 0x30;
 pub const STLINK_DEBUG_APIV2_RESETSYS: u8 = 0x32;
+pub const STLINK_DEBUG_APIV2_GETLASTRWSTATUS: u8 = 0x3B;
+pub const STLINK_DEBUG_APIV2_GETLASTRWSTATUS2: u8 = 0x3E;
+pub const STLINK_DEBUG_APIV2_SWD_SET_FREQ: u8 = 0x43;
+pub const STLINK_DEBUG_APIV2_JTAG_SET_FREQ: u8 = 0x44;
+pub const STLINK_DEBUG_APIV2_READMEM_16BIT: u8 = 0x47;
+pub const STLINK_DEBUG_APIV2_WRITEMEM_16BIT: u8 = 0x48;
+pub const STLINK_APIV3_SET_COM_FREQ: u8 = 0x61;
+pub const STLINK_APIV3_GET_COM_FREQ: u8 = 0x62;
+pub const STLINK_DEBUG_ENTER_SWD_NO_RESET: u8 = 0xA3;
+pub const STLINK_DEBUG_ENTER_JTAG_NO_RESET: u8 = 0xA4;
+
+// first byte of the status answered by debug commands
+pub const STLINK_DEBUG_ERR_OK: u8 = 0x80;
+pub const STLINK_DEBUG_ERR_FAULT: u8 = 0x81;
+pub const STLINK_JTAG_GET_IDCODE_ERROR: u8 = 0x09;
+pub const STLINK_JTAG_WRITE_ERROR: u8 = 0x0C;
+pub const STLINK_JTAG_WRITE_VERIF_ERROR: u8 = 0x0D;
+pub const STLINK_SWD_AP_WAIT: u8 = 0x10;
+pub const STLINK_SWD_AP_FAULT: u8 = 0x11;
+pub const STLINK_SWD_AP_ERROR: u8 = 0x12;
+pub const STLINK_SWD_AP_PARITY_ERROR: u8 = 0x13;
+pub const STLINK_SWD_DP_WAIT: u8 = 0x14;
+pub const STLINK_SWD_DP_FAULT: u8 = 0x15;
+pub const STLINK_SWD_DP_ERROR: u8 = 0x16;
+pub const STLINK_SWD_DP_PARITY_ERROR: u8 = 0x17;
+pub const STLINK_SWD_AP_WDATA_ERROR: u8 = 0x18;
+pub const STLINK_SWD_AP_STICKY_ERROR: u8 = 0x19;
+pub const STLINK_SWD_AP_STICKYORUN_ERROR: u8 = 0x1A;
+pub const STLINK_BAD_AP_ERROR: u8 = 0x1D;
+pub const STLINK_TOO_MANY_AP_ERROR: u8 = 0x29;
+pub const STLINK_JTAG_UNKNOWN_CMD: u8 = 0x42;
+
+// clock speeds in kHz with the dividers selecting them on ST-Link V2
+pub const STLINK_SWD_SPEED_MAP: &[(u32, u16)] = &[
+    (4000, 0), (1800, 1), (1200, 2), (950, 3), (480, 7), (240, 15), 
+    (125, 31), (100, 40), (50, 79), (25, 158), (15, 265), (5, 798),
+];
+pub const STLINK_JTAG_SPEED_MAP: &[(u32, u16)] = &[
+    (9000, 4), (4500, 8), (2250, 16), (1125, 32), (562, 64), (281, 128), (140, 256),
+];
+// clock speeds in kHz after plugging in
+pub const STLINK_SWD_DEFAULT_KHZ: u32 = 1800;
+pub const STLINK_JTAG_DEFAULT_KHZ: u32 = 1125;
+// communication modes of `STLINK_APIV3_GET_COM_FREQ` and `STLINK_APIV3_SET_COM_FREQ`
+pub const STLINK_V3_COM_SWD: u8 = 0;
+pub const STLINK_V3_COM_JTAG: u8 = 1;
+pub const STLINK_V3_MAX_FREQ_NB: usize = 10;
+
+// longest 8-bit memory access
+pub const STLINK_MAX_RW8: usize = 64;
+pub const STLINKV3_MAX_RW8: usize = 512;
+// wider accesses auto-increment the address within blocks of this size only
+pub const STLINK_TAR_AUTOINCR_BLOCK: usize = 1024;
 
 pub const STLINK_VID: u16 = 0x0483;
 pub const STLINK_V1_PID: u16 = 0x3744;
@@ -22,3 +98,16 @@ pub const STLINK_V3_USBLOADER_PID: u16 = 0x374D;
 pub const STLINK_V3E_PID: u16 = 0x374E;
 pub const STLINK_V3S_PID: u16 = 0x374F;
 pub const STLINK_V3_2VCP_PID: u16 = 0x3753;
+
+/// Product IDs of dongles this crate can drive.
+/// 
+/// ST-Link V1 is missing as it wraps commands in SCSI requests.
+pub const STLINK_SUPPORTED_PIDS: &[u16] = &[
+    STLINK_V2_PID,
+    STLINK_V2_1_PID,
+    STLINK_V2_1_NO_MSD_PID,
+    STLINK_V3_USBLOADER_PID,
+    STLINK_V3E_PID,
+    STLINK_V3S_PID,
+    STLINK_V3_2VCP_PID,
+];
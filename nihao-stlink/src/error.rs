@@ -0,0 +1,164 @@
+//! Errors of ST-Link commands.
+use crate::consts::*;
+use crate::handle::Mode;
+use core::fmt;
+use std::io;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Why an ST-Link command failed.
+///
+/// Commands talking to the target answer a status byte; every status other 
+/// than `STLINK_DEBUG_ERR_OK` has its own variant. Converts into `io::Error` 
+/// and back, so it mixes with other I/O code through the `?` operator.
+#[derive(Debug)]
+pub enum Error {
+    /// The dongle refused the command, usually for being in the wrong mode
+    Fault,
+    /// Reading the JTAG IDCODE failed
+    JtagGetIdcode,
+    /// A JTAG write failed
+    JtagWrite,
+    /// A JTAG write failed its verification
+    JtagWriteVerify,
+    /// The access port asked to retry later
+    ApWait,
+    /// The access port signalled a fault
+    ApFault,
+    /// The access port failed
+    ApError,
+    /// Parity error on the access port
+    ApParity,
+    /// The debug port asked to retry later
+    DpWait,
+    /// The debug port signalled a fault
+    DpFault,
+    /// The debug port failed
+    DpError,
+    /// Parity error on the debug port
+    DpParity,
+    /// Writing data through the access port failed
+    ApWriteData,
+    /// A sticky error flag is set in the access port
+    ApSticky,
+    /// The sticky overrun flag is set in the access port
+    ApStickyOverrun,
+    /// The access port does not exist
+    BadAp,
+    /// More access ports were opened than the firmware supports
+    TooManyAps,
+    /// The firmware does not know the command
+    UnknownCommand,
+    /// A status byte not known to this crate
+    UnknownStatus(u8),
+    /// The dongle stayed in this mode instead of entering debug mode
+    NotInDebugMode(Mode),
+    /// Talking to the dongle over USB failed
+    Usb(nihao_usb::Error),
+    /// Any other failure, like invalid arguments
+    Other(io::Error),
+}
+
+impl Error {
+    /// Whether the target asked to retry later; such commands are retried a 
+    /// few times before this error is returned.
+    pub fn is_wait(&self) -> bool {
+        matches!(self, Error::ApWait | Error::DpWait)
+    }
+
+    // `None` on `STLINK_DEBUG_ERR_OK`
+    pub(crate) fn from_status(status: u8) -> Option<Error> {
+        Some(match status {
+            STLINK_DEBUG_ERR_OK => return None,
+            STLINK_DEBUG_ERR_FAULT => Error::Fault,
+            STLINK_JTAG_GET_IDCODE_ERROR => Error::JtagGetIdcode,
+            STLINK_JTAG_WRITE_ERROR => Error::JtagWrite,
+            STLINK_JTAG_WRITE_VERIF_ERROR => Error::JtagWriteVerify,
+            STLINK_SWD_AP_WAIT => Error::ApWait,
+            STLINK_SWD_AP_FAULT => Error::ApFault,
+            STLINK_SWD_AP_ERROR => Error::ApError,
+            STLINK_SWD_AP_PARITY_ERROR => Error::ApParity,
+            STLINK_SWD_DP_WAIT => Error::DpWait,
+            STLINK_SWD_DP_FAULT => Error::DpFault,
+            STLINK_SWD_DP_ERROR => Error::DpError,
+            STLINK_SWD_DP_PARITY_ERROR => Error::DpParity,
+            STLINK_SWD_AP_WDATA_ERROR => Error::ApWriteData,
+            STLINK_SWD_AP_STICKY_ERROR => Error::ApSticky,
+            STLINK_SWD_AP_STICKYORUN_ERROR => Error::ApStickyOverrun,
+            STLINK_BAD_AP_ERROR => Error::BadAp,
+            STLINK_TOO_MANY_AP_ERROR => Error::TooManyAps,
+            STLINK_JTAG_UNKNOWN_CMD => Error::UnknownCommand,
+            other => Error::UnknownStatus(other),
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Fault => f.write_str("command refused by the dongle"),
+            Error::JtagGetIdcode => f.write_str("reading JTAG IDCODE failed"),
+            Error::JtagWrite => f.write_str("JTAG write failed"),
+            Error::JtagWriteVerify => f.write_str("JTAG write verification failed"),
+            Error::ApWait => f.write_str("access port busy"),
+            Error::ApFault => f.write_str("access port fault"),
+            Error::ApError => f.write_str("access port error"),
+            Error::ApParity => f.write_str("access port parity error"),
+            Error::DpWait => f.write_str("debug port busy"),
+            Error::DpFault => f.write_str("debug port fault"),
+            Error::DpError => f.write_str("debug port error"),
+            Error::DpParity => f.write_str("debug port parity error"),
+            Error::ApWriteData => f.write_str("access port write data error"),
+            Error::ApSticky => f.write_str("access port sticky error"),
+            Error::ApStickyOverrun => f.write_str("access port sticky overrun"),
+            Error::BadAp => f.write_str("no such access port"),
+            Error::TooManyAps => f.write_str("too many access ports open"),
+            Error::UnknownCommand => f.write_str("command unknown to the firmware"),
+            Error::UnknownStatus(status) => write!(f, "unknown status {:#04X}", status),
+            Error::NotInDebugMode(mode) => write!(f, "dongle stays in {:?} mode instead of debug mode", mode),
+            Error::Usb(e) => e.fmt(f),
+            Error::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Usb(e) => Some(e),
+            Error::Other(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<nihao_usb::Error> for Error {
+    fn from(src: nihao_usb::Error) -> Error {
+        Error::Usb(src)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(src: io::Error) -> Error {
+        // errors converted into `io::Error` before come back unchanged
+        if src.get_ref().is_some_and(|e| e.is::<Error>()) {
+            let inner = src.into_inner().expect("checked to wrap an error");
+            return *inner.downcast::<Error>().expect("checked to be an `Error`")
+        }
+        if src.get_ref().is_some_and(|e| e.is::<nihao_usb::Error>()) {
+            return Error::Usb(src.into())
+        }
+        Error::Other(src)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(src: Error) -> io::Error {
+        match src {
+            Error::Usb(e) => e.into(),
+            Error::Other(e) => e,
+            Error::ApWait | Error::DpWait => io::Error::new(io::ErrorKind::WouldBlock, src),
+            _ => io::Error::other(src),
+        }
+    }
+}
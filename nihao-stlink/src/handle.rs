@@ -3,7 +3,8 @@ use core::{
     fmt,
 };
 use std::io;
-use crate::version::Version;
+use crate::version::{JtagApi, Version};
+use crate::error::{Error, Result};
 use crate::consts::*;
 
 /// A handle of a device connection. 
@@ -15,6 +16,7 @@ use crate::consts::*;
 pub struct Handle<'h> {
     inner: nihao_usb::Handle<'h>,
     version: Version,
+    tx_ep: u8,
 }
 
 impl<'h> Handle<'h> {
@@ -22,11 +24,11 @@ impl<'h> Handle<'h> {
         self.version
     }
 
-    pub fn get_voltage(&self) -> io::Result<Option<f32>> {
+    pub fn get_voltage(&self) -> Result<Option<f32>> {
         if !self.version.has_trace {
             return Ok(None);
         }
-        let r = crate::command::command(&self.inner, STLINK_GET_TARGET_VOLTAGE, 0, 8)?;
+        let r = crate::command::command(&self.inner, self.tx_ep, STLINK_GET_TARGET_VOLTAGE, 0, 8)?;
         let adc_result = [
             u32::from_le_bytes([r[0], r[1], r[2], r[3]]),
             u32::from_le_bytes([r[4], r[5], r[6], r[7]]),
@@ -38,16 +40,282 @@ impl<'h> Handle<'h> {
         } ))
     }
 
-    pub fn get_mode(&self) -> io::Result<u8> {
-        let r = crate::command::command(&self.inner, STLINK_GET_CURRENT_MODE, 0, 2)?;
-        Ok(r[0])
+    pub fn get_mode(&self) -> Result<Mode> {
+        let r = crate::command::command(&self.inner, self.tx_ep, STLINK_GET_CURRENT_MODE, 0, 2)?;
+        Ok(Mode::from(r[0]))
+    }
+
+    /// Enter debug mode talking to the target over SWD.
+    pub fn enter_swd(&self) -> Result<()> {
+        self.enter_debug(STLINK_DEBUG_ENTER_SWD_NO_RESET)
+    }
+
+    /// Enter debug mode talking to the target over JTAG.
+    pub fn enter_jtag(&self) -> Result<()> {
+        self.enter_debug(STLINK_DEBUG_ENTER_JTAG_NO_RESET)
+    }
+
+    /// Leave debug mode; the dongle falls back to mass storage mode.
+    pub fn exit_debug(&self) -> Result<()> {
+        crate::command::debug_command(&self.inner, self.tx_ep, STLINK_DEBUG_EXIT, 0, 0)?;
+        Ok(())
+    }
+
+    /// Leave DFU mode, which some dongles are in right after plugging in.
+    pub fn exit_dfu(&self) -> Result<()> {
+        crate::command::command(&self.inner, self.tx_ep, STLINK_DFU_COMMAND, STLINK_DFU_EXIT, 0)?;
+        Ok(())
+    }
+
+    /// Set the SWD clock to the fastest speed not above `hz`, or the slowest 
+    /// one when all are faster; returns the frequency chosen in Hz.
+    /// 
+    /// Firmware without `has_swd_set_freq` keeps its default of 1.8MHz.
+    pub fn set_swd_frequency(&self, hz: u32) -> Result<u32> {
+        if self.version.jtag_api == JtagApi::V3 {
+            return self.set_com_frequency(STLINK_V3_COM_SWD, hz);
+        }
+        if !self.version.has_swd_set_freq {
+            return Ok(STLINK_SWD_DEFAULT_KHZ * 1000);
+        }
+        self.set_divider(STLINK_DEBUG_APIV2_SWD_SET_FREQ, STLINK_SWD_SPEED_MAP, hz)
+    }
+
+    /// Set the JTAG clock as `set_swd_frequency` does for SWD.
+    /// 
+    /// Firmware without `has_jtag_set_freq` keeps its default of 1.125MHz.
+    pub fn set_jtag_frequency(&self, hz: u32) -> Result<u32> {
+        if self.version.jtag_api == JtagApi::V3 {
+            return self.set_com_frequency(STLINK_V3_COM_JTAG, hz);
+        }
+        if !self.version.has_jtag_set_freq {
+            return Ok(STLINK_JTAG_DEFAULT_KHZ * 1000);
+        }
+        self.set_divider(STLINK_DEBUG_APIV2_JTAG_SET_FREQ, STLINK_JTAG_SPEED_MAP, hz)
+    }
+
+    // ST-Link V2 picks from a fixed table of dividers
+    fn set_divider(&self, cmd: u8, map: &[(u32, u16)], hz: u32) -> Result<u32> {
+        let speeds: Vec<u32> = map.iter().map(|&(khz, _)| khz).collect();
+        let (khz, divider) = map[pick_speed(&speeds, hz)];
+        let [lo, hi] = divider.to_le_bytes();
+        let r = crate::command::debug_command_bytes(&self.inner, self.tx_ep, &[cmd, lo, hi], 2)?;
+        crate::command::check_status(&r)?;
+        Ok(khz * 1000)
+    }
+
+    // ST-Link V3 lists the speeds it supports in each communication mode
+    fn set_com_frequency(&self, com_mode: u8, hz: u32) -> Result<u32> {
+        let r = crate::command::debug_command(&self.inner, self.tx_ep, STLINK_APIV3_GET_COM_FREQ, com_mode, 52)?;
+        crate::command::check_status(&r)?;
+        let count = (r[8] as usize).min(STLINK_V3_MAX_FREQ_NB);
+        let speeds: Vec<u32> = r[12..12 + 4 * count].chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        if speeds.is_empty() {
+            return Err(Error::Other(io::Error::new(io::ErrorKind::InvalidData, "dongle lists no clock speeds")));
+        }
+        let khz = speeds[pick_speed(&speeds, hz)];
+        let [f0, f1, f2, f3] = khz.to_le_bytes();
+        let cmd = [STLINK_APIV3_SET_COM_FREQ, com_mode, 0, f0, f1, f2, f3];
+        let r = crate::command::debug_command_bytes(&self.inner, self.tx_ep, &cmd, 8)?;
+        crate::command::check_status(&r)?;
+        Ok(khz * 1000)
+    }
+
+    /// Read target memory into `buf` one byte at a time.
+    pub fn read_mem8(&self, address: u32, buf: &mut [u8]) -> Result<()> {
+        self.read_mem(address, buf, 1)
+    }
+
+    /// Read target memory into `buf` with 16-bit accesses.
+    /// 
+    /// Unaligned ends are read one byte at a time; so is everything on 
+    /// firmware without `has_mem_16bit`.
+    pub fn read_mem16(&self, address: u32, buf: &mut [u8]) -> Result<()> {
+        self.read_mem(address, buf, 2)
+    }
+
+    /// Read target memory into `buf` with 32-bit accesses, except for 
+    /// unaligned ends which are read one byte at a time.
+    pub fn read_mem32(&self, address: u32, buf: &mut [u8]) -> Result<()> {
+        self.read_mem(address, buf, 4)
+    }
+
+    /// Write `data` to target memory one byte at a time.
+    pub fn write_mem8(&self, address: u32, data: &[u8]) -> Result<()> {
+        self.write_mem(address, data, 1)
+    }
+
+    /// Write `data` to target memory with 16-bit accesses, falling back to 
+    /// bytes as `read_mem16` does.
+    pub fn write_mem16(&self, address: u32, data: &[u8]) -> Result<()> {
+        self.write_mem(address, data, 2)
+    }
+
+    /// Write `data` to target memory with 32-bit accesses, except for 
+    /// unaligned ends which are written one byte at a time.
+    pub fn write_mem32(&self, address: u32, data: &[u8]) -> Result<()> {
+        self.write_mem(address, data, 4)
+    }
+
+    fn read_mem(&self, mut address: u32, mut buf: &mut [u8], width: usize) -> Result<()> {
+        check_range(address, buf.len())?;
+        while !buf.is_empty() {
+            let (len, width) = self.chunk(address, buf.len(), width);
+            let (chunk, rest) = core::mem::take(&mut buf).split_at_mut(len);
+            let cmd = match width {
+                1 => STLINK_DEBUG_READMEM_8BIT,
+                2 => STLINK_DEBUG_APIV2_READMEM_16BIT,
+                _ => STLINK_DEBUG_READMEM_32BIT,
+            };
+            let r = crate::command::retry_on_wait(|| {
+                let r = crate::command::read_mem(&self.inner, self.tx_ep, cmd, address, len)?;
+                self.check_rw_status()?;
+                Ok(r)
+            })?;
+            chunk.copy_from_slice(&r);
+            address = address.wrapping_add(len as u32);
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn write_mem(&self, mut address: u32, mut data: &[u8], width: usize) -> Result<()> {
+        check_range(address, data.len())?;
+        while !data.is_empty() {
+            let (len, width) = self.chunk(address, data.len(), width);
+            let (chunk, rest) = data.split_at(len);
+            let cmd = match width {
+                1 => STLINK_DEBUG_WRITEMEM_8BIT,
+                2 => STLINK_DEBUG_APIV2_WRITEMEM_16BIT,
+                _ => STLINK_DEBUG_WRITEMEM_32BIT,
+            };
+            crate::command::retry_on_wait(|| {
+                crate::command::write_mem(&self.inner, self.tx_ep, cmd, address, chunk)?;
+                self.check_rw_status()
+            })?;
+            address = address.wrapping_add(len as u32);
+            data = rest;
+        }
+        Ok(())
+    }
+
+    // Length and access width of the next chunk starting at `address`. Wide 
+    // accesses must be aligned and stay within one auto-increment block; 
+    // whatever does not fit goes byte by byte.
+    fn chunk(&self, address: u32, remaining: usize, width: usize) -> (usize, usize) {
+        let max_rw8 = if self.version.stlink_version >= 3 { STLINKV3_MAX_RW8 } else { STLINK_MAX_RW8 };
+        let width = if width == 2 && !self.version.has_mem_16bit { 1 } else { width };
+        let address = address as usize;
+        if width == 1 {
+            return (remaining.min(max_rw8), 1);
+        }
+        let misaligned = address % width;
+        if misaligned != 0 {
+            return (remaining.min(width - misaligned), 1);
+        }
+        let block = STLINK_TAR_AUTOINCR_BLOCK - address % STLINK_TAR_AUTOINCR_BLOCK;
+        let len = remaining.min(block);
+        if len < width {
+            return (len, 1);
+        }
+        (len - len % width, width)
+    }
+
+    // API v1 has no way to ask how the last memory access went
+    fn check_rw_status(&self) -> Result<()> {
+        let r = match self.version.jtag_api {
+            JtagApi::V1 => return Ok(()),
+            _ if self.version.has_get_last_rwstatus2 =>
+                crate::command::debug_command(&self.inner, self.tx_ep, STLINK_DEBUG_APIV2_GETLASTRWSTATUS2, 0, 12)?,
+            _ => crate::command::debug_command(&self.inner, self.tx_ep, STLINK_DEBUG_APIV2_GETLASTRWSTATUS, 0, 2)?,
+        };
+        crate::command::check_status(&r)
+    }
+
+    fn exit_swim(&self) -> Result<()> {
+        crate::command::command(&self.inner, self.tx_ep, STLINK_SWIM_COMMAND, STLINK_SWIM_EXIT, 0)?;
+        Ok(())
+    }
+
+    // The dongle ignores the enter command while in DFU, SWIM or another 
+    // debug mode, so leave the current mode first as OpenOCD does.
+    fn enter_debug(&self, transport: u8) -> Result<()> {
+        match self.get_mode()? {
+            Mode::Dfu => self.exit_dfu()?,
+            Mode::Debug => self.exit_debug()?,
+            Mode::Swim => self.exit_swim()?,
+            Mode::MassStorage | Mode::Bootloader | Mode::Unknown(_) => {},
+        }
+        if self.version.jtag_api == JtagApi::V1 {
+            // API v1 does not answer the enter command
+            crate::command::debug_command(&self.inner, self.tx_ep, STLINK_DEBUG_APIV1_ENTER, transport, 0)?;
+        } else {
+            crate::command::retry_on_wait(|| {
+                let r = crate::command::debug_command(&self.inner, self.tx_ep, STLINK_DEBUG_APIV2_ENTER, transport, 2)?;
+                crate::command::check_status(&r)
+            })?;
+        }
+        match self.get_mode()? {
+            Mode::Debug => Ok(()),
+            mode => Err(Error::NotInDebugMode(mode)),
+        }
+    }
+}
+
+// Index of the fastest speed in kHz not above `hz`, or of the slowest one 
+// when all are faster; `speeds` must not be empty.
+fn pick_speed(speeds: &[u32], hz: u32) -> usize {
+    let khz = hz / 1000;
+    let slowest = (0..speeds.len()).min_by_key(|&i| speeds[i]);
+    (0..speeds.len()).filter(|&i| speeds[i] <= khz).max_by_key(|&i| speeds[i])
+        .or(slowest)
+        .expect("speed list not empty")
+}
+
+fn check_range(address: u32, len: usize) -> Result<()> {
+    if address as u64 + len as u64 > 1 << 32 {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "memory range past the end of the address space");
+        return Err(Error::Other(e));
+    }
+    Ok(())
+}
+
+/// Current mode of a dongle, as answered by `Handle::get_mode`.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum Mode {
+    /// Waiting for a firmware update
+    Dfu,
+    /// Exposing the mass storage drive; the usual mode after plugging in
+    MassStorage,
+    /// Debugging a target over SWD or JTAG
+    Debug,
+    /// Debugging an STM8 target over SWIM
+    Swim,
+    /// Running the bootloader of an ST-Link V3
+    Bootloader,
+    /// A mode value not known to this crate
+    Unknown(u8),
+}
+
+impl From<u8> for Mode {
+    fn from(src: u8) -> Mode {
+        match src {
+            STLINK_DEV_DFU_MODE => Mode::Dfu,
+            STLINK_DEV_MASS_MODE => Mode::MassStorage,
+            STLINK_DEV_DEBUG_MODE => Mode::Debug,
+            STLINK_DEV_SWIM_MODE => Mode::Swim,
+            STLINK_DEV_BOOTLOADER_MODE => Mode::Bootloader,
+            other => Mode::Unknown(other),
+        }
     }
 }
 
 // //todo: bug?
 // impl Drop for Handle<'_> {
 //     fn drop(&mut self) {
-//         crate::command::command(&self.inner, STLINK_DEBUG_APIV2_RESETSYS, 0x80, 2).unwrap();
+//         crate::command::command(&self.inner, self.tx_ep, STLINK_DEBUG_APIV2_RESETSYS, 0x80, 2).unwrap();
 //     }
 // }
 
@@ -77,30 +345,43 @@ impl From<io::Error> for TryFromHandleError {
     }
 }
 
+impl From<nihao_usb::Error> for TryFromHandleError {
+    fn from(src: nihao_usb::Error) -> TryFromHandleError {
+        TryFromHandleError::IoError(src.into())
+    }
+}
+
+impl From<Error> for TryFromHandleError {
+    fn from(src: Error) -> TryFromHandleError {
+        TryFromHandleError::IoError(src.into())
+    }
+}
+
 impl From<TryFromHandleError> for io::Error {
     fn from(src: TryFromHandleError) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, src)
+        io::Error::other(src)
     }
 }
 
 impl<'h> TryFrom<nihao_usb::Handle<'h>> for Handle<'h> {
     type Error = (nihao_usb::Handle<'h>, TryFromHandleError);
 
-    fn try_from(src: nihao_usb::Handle<'h>) -> Result<Handle<'h>, Self::Error> {
+    fn try_from(src: nihao_usb::Handle<'h>) -> core::result::Result<Handle<'h>, Self::Error> {
         use TryFromHandleError::*;
         let desc = match src.device_descriptor() {
             Ok(desc) => desc,
             Err(err) => return Err((src, err.into())),
         };
-        if desc.id_vendor != STLINK_VID || desc.id_product != STLINK_V2_PID {
+        if desc.id_vendor != STLINK_VID || !STLINK_SUPPORTED_PIDS.contains(&desc.id_product) {
             return Err((src, InvalidVendorProductId(desc.id_vendor, desc.id_product)))
         }
+        let tx_ep = crate::command::tx_endpoint(desc.id_product);
         // get version
-        let version = match crate::version::read_handle(&src) {
+        let version = match crate::version::read_handle(&src, tx_ep) {
             Ok(ver) => ver,
             Err(err) => return Err((src, err.into())),
         };
-        let ans = Handle { inner: src, version };
+        let ans = Handle { inner: src, version, tx_ep };
         Ok(ans)
     }
 }
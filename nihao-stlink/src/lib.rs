@@ -2,14 +2,22 @@ pub mod handle;
 pub mod version;
 pub mod consts;
 pub mod command;
+pub mod error;
 
-pub use handle::Handle;
+pub use handle::{Handle, Mode};
+pub use error::{Error, Result};
 
 use core::iter::FusedIterator;
 use std::io;
 
+/// List ST-Link dongles; other USB devices are skipped without being opened.
 pub fn handles<'iter>() -> io::Result<HandleList<'iter>> {
-    nihao_usb::devices().map(|inner| HandleList { inner })
+    nihao_usb::DeviceFilter::new()
+        .vendor_id(consts::STLINK_VID)
+        .product_ids(consts::STLINK_SUPPORTED_PIDS)
+        .list()
+        .map(|inner| HandleList { inner })
+        .map_err(io::Error::from)
 }
 
 #[derive(Debug, Clone)]
@@ -25,6 +33,10 @@ impl<'list> HandleList<'list> {
     pub fn len(&self) -> usize {
         self.inner.len()
     } 
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
 }
 
 /// An owned iterator for USB devices.
@@ -46,18 +58,30 @@ impl<'iter> Iterator for HandleIntoIter<'iter> {
     type Item = io::Result<Handle<'iter>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        use handle::TryFromHandleError::*;
-        use core::convert::TryFrom;
-        
-        while let Some(Ok(usb_device)) = self.inner.next() {
-            let h = if let Ok(h) = usb_device.open() { h } else { continue };
-            match Handle::try_from(h) {
-                Ok(h) => return Some(Ok(h)),
-                Err((_h, IoError(e))) => return Some(Err(e)),
-                Err(_) => continue,
-            }
+        next_handle(&mut self.inner)
+    }
+}
+
+// Open the next ST-Link; dongles that cannot be listed or opened are reported
+// with the reason, so that callers can tell a missing udev rule from a dongle
+// in use by another program.
+fn next_handle<'a, I>(iter: &mut I) -> Option<io::Result<Handle<'a>>>
+where
+    I: Iterator<Item = nihao_usb::Result<nihao_usb::Device<'a>>>
+{
+    use handle::TryFromHandleError::*;
+    use core::convert::TryFrom;
+
+    loop {
+        let usb_handle = match iter.next()?.and_then(|usb_device| usb_device.open()) {
+            Ok(h) => h,
+            Err(e) => return Some(Err(e.into())),
+        };
+        match Handle::try_from(usb_handle) {
+            Ok(h) => return Some(Ok(h)),
+            Err((_h, IoError(e))) => return Some(Err(e)),
+            Err(_) => continue,
         }
-        None
     }
 }
 
@@ -70,19 +94,7 @@ impl<'iter> Iterator for Handles<'iter> {
     type Item = io::Result<Handle<'iter>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        use handle::TryFromHandleError::*;
-        use core::convert::TryFrom;
-        
-        while let Some(Ok(usb_device)) = self.inner.next() {
-            // unable to open, continue
-            let h = if let Ok(h) = usb_device.open() { h } else { continue };
-            match Handle::try_from(h) {
-                Ok(h) => return Some(Ok(h)),
-                Err((_h, IoError(e))) => return Some(Err(e)),
-                Err(_) => continue,
-            }
-        }
-        None
+        next_handle(&mut self.inner)
     }
 }
 
@@ -1,6 +1,7 @@
-use std::io;
 use crate::consts::*;
+use crate::error::Error;
 use core::fmt;
+use std::io;
 
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub struct Version {
@@ -61,18 +62,18 @@ impl fmt::Display for JtagApi {
     }
 }
 
-pub(crate) fn read_handle(handle: &nihao_usb::Handle<'_>) -> io::Result<Version> {
-    let buf_recv = crate::command::command(&handle, STLINK_GET_VERSION, 0x80, 6)?;
+pub(crate) fn read_handle(handle: &nihao_usb::Handle<'_>, tx_ep: u8) -> crate::error::Result<Version> {
+    let buf_recv = crate::command::command(handle, tx_ep, STLINK_GET_VERSION, 0x80, 6)?;
     let version = u16::from_be_bytes([buf_recv[0], buf_recv[1]]);
     let v = (version >> 12) & 0x0f;
     let x = (version >> 6) & 0x3f;
     let y = version & 0x3f;
     // println!("{:?} {:?} {:?}", v, x, y);
-    let vid = u16::from_le_bytes([buf_recv[2], buf_recv[3]]);
-    let pid = u16::from_le_bytes([buf_recv[4], buf_recv[5]]);
+    let mut vid = u16::from_le_bytes([buf_recv[2], buf_recv[3]]);
+    let mut pid = u16::from_le_bytes([buf_recv[4], buf_recv[5]]);
     // println!("{:?} {:?}", vid, pid);
-    let (msd, swim, jtag) = if pid == STLINK_V2_1_PID || pid == STLINK_V2_1_NO_MSD_PID {
-        if (x <= 22 && y == 7) || (x >= 25 && y >= 7 && y <= 12) {
+    let (mut msd, mut swim, mut jtag) = if pid == STLINK_V2_1_PID || pid == STLINK_V2_1_NO_MSD_PID {
+        if (x <= 22 && y == 7) || (x >= 25 && (7..=12).contains(&y)) {
             (x, y, 0)
         } else {
             (y, 0, x)
@@ -80,10 +81,16 @@ pub(crate) fn read_handle(handle: &nihao_usb::Handle<'_>) -> io::Result<Version>
     } else {
         (0, y, x)
     };
-    let bridge = 0;
+    let mut bridge = 0;
     if v == 3 && x == 0 && y == 0 {
-        // todo: write, verify and test STLink v3 special command
-        // here bridge may change
+        // ST-Link V3 has too many firmware parts for the fields above
+        let r = crate::command::command(handle, tx_ep, STLINK_APIV3_GET_VERSION_EX, 0, 12)?;
+        swim = r[1] as u16;
+        jtag = r[2] as u16;
+        msd = r[3] as u16;
+        bridge = r[4];
+        vid = u16::from_le_bytes([r[8], r[9]]);
+        pid = u16::from_le_bytes([r[10], r[11]]);
     }
     // println!("{:?}", (msd, swim, jtag));
     let jtag_api = match v /*STLink version*/ {
@@ -91,7 +98,10 @@ pub(crate) fn read_handle(handle: &nihao_usb::Handle<'_>) -> io::Result<Version>
         1 if jtag < 11 => JtagApi::V1,
         2 => JtagApi::V2,
         3 => JtagApi::V3,
-        _ => unimplemented!("version not in 1, 2 or 3")
+        _ => {
+            let message = format!("unsupported ST-Link version V{}", v);
+            return Err(Error::Other(io::Error::new(io::ErrorKind::InvalidData, message)))
+        },
     };
     // API for trace from J13, or from STLink v3
     let has_trace = (v == 2 && jtag >= 13) || v == 3;
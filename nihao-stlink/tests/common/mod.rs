@@ -0,0 +1,298 @@
+//! A simulated ST-Link dongle answering on the mock USB bus.
+#![allow(dead_code)]
+
+use nihao_usb::sys::mock::{self, Responder, VirtualDevice};
+use nihao_usb::DeviceDescriptor;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+};
+
+pub const STLINK_VID: u16 = 0x0483;
+pub const STLINK_V2_PID: u16 = 0x3748;
+pub const STLINK_V3E_PID: u16 = 0x374E;
+/// Clock speeds in kHz listed by the simulated ST-Link V3
+pub const V3_SWD_KHZ: [u32; 7] = [24000, 8000, 3300, 1000, 200, 50, 5];
+pub const V3_JTAG_KHZ: [u32; 6] = [21333, 16000, 12000, 8000, 1000, 250];
+/// Simulated target RAM starts here; other addresses fault.
+pub const RAM_BASE: u32 = 0x2000_0000;
+pub const RAM_SIZE: usize = 0x2000;
+
+#[derive(Debug, Clone)]
+pub struct StLinkSim {
+    /// Firmware version as `V`, `J`/`M` and `S` fields of `GET_VERSION`
+    pub version: (u16, u16, u16),
+    /// SWIM, JTAG, MSD and bridge versions of `GET_VERSION_EX` on V3
+    pub version_ex: Option<(u8, u8, u8, u8)>,
+    pub pid: u16,
+    pub mode: u8,
+    pub voltage_adc: [u32; 2],
+    pub serial: String,
+    /// Every command received, shared with the clone plugged into the bus
+    pub log: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// Target RAM at `RAM_BASE`
+    pub ram: Arc<Mutex<Vec<u8>>>,
+    /// Number of memory accesses to answer with `SWD_AP_WAIT` before doing any
+    pub waits: usize,
+    rw_status: u8,
+    // address and length of a memory write waiting for its data
+    pending_write: Option<(u32, usize)>,
+    response: VecDeque<u8>,
+}
+
+impl StLinkSim {
+    pub fn v3(jtag: u8) -> Self {
+        StLinkSim {
+            version: (3, 0, 0),
+            version_ex: Some((1, jtag, 1, 3)),
+            pid: STLINK_V3E_PID,
+            ..StLinkSim::v2(0)
+        }
+    }
+
+    pub fn v2(jtag: u16) -> Self {
+        StLinkSim {
+            version: (2, jtag, 0),
+            version_ex: None,
+            pid: STLINK_V2_PID,
+            mode: 0x01, // mass storage mode after plugging in
+            voltage_adc: [1600, 2167],
+            serial: String::from("0671FF485550755187121723"),
+            log: Arc::new(Mutex::new(Vec::new())),
+            ram: Arc::new(Mutex::new(vec![0; RAM_SIZE])),
+            waits: 0,
+            rw_status: 0x80,
+            pending_write: None,
+            response: VecDeque::new(),
+        }
+    }
+
+    pub fn device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor {
+            length: 18,
+            descriptor_type: 1,
+            bcd_usb: 0x0200,
+            device_class: 0,
+            device_sub_class: 0,
+            device_protocol: 0,
+            max_packet_size_0: 64,
+            id_vendor: STLINK_VID,
+            id_product: self.pid,
+            bcd_device: 0x0100,
+            manufacturer: 1,
+            product: 2,
+            serial_number: 3,
+            num_configurations: 1,
+        }
+    }
+
+    /// Plug this dongle into the mock bus of the current thread.
+    pub fn register(self) -> u64 {
+        let device = VirtualDevice::new(self.device_descriptor(), self.clone())
+            .string(1, "STMicroelectronics")
+            .string(2, "STM32 STLink")
+            .string(3, &self.serial);
+        mock::register(device)
+    }
+
+    /// Commands received so far, each cut down to its first `len` bytes.
+    pub fn commands(&self, len: usize) -> Vec<Vec<u8>> {
+        self.log.lock().unwrap().iter().map(|cmd| cmd[..len].to_vec()).collect()
+    }
+
+    fn command(&mut self, cmd: &[u8]) -> Vec<u8> {
+        self.log.lock().unwrap().push(cmd.to_vec());
+        match cmd[0] {
+            0xFB => {
+                let (swim, jtag, msd, bridge) = self.version_ex.expect("GET_VERSION_EX is for V3");
+                let mut r = vec![3, swim, jtag, msd, bridge, 0, 0, 0];
+                r.extend_from_slice(&STLINK_VID.to_le_bytes());
+                r.extend_from_slice(&self.pid.to_le_bytes());
+                r
+            },
+            0xF1 => {
+                let (v, x, y) = self.version;
+                let version = (v << 12) | (x << 6) | y;
+                let mut r = version.to_be_bytes().to_vec();
+                r.extend_from_slice(&STLINK_VID.to_le_bytes());
+                r.extend_from_slice(&self.pid.to_le_bytes());
+                r
+            },
+            0xF2 => match cmd[1] {
+                // exit debug mode
+                0x21 => {
+                    if self.mode == 0x02 {
+                        self.mode = 0x01;
+                    }
+                    Vec::new()
+                },
+                // enter SWD or JTAG; refused while in DFU mode
+                0x30 => {
+                    assert!(cmd[2] == 0xA3 || cmd[2] == 0xA4, "unknown transport");
+                    if self.mode == 0x00 {
+                        return vec![0x81, 0];
+                    }
+                    self.mode = 0x02;
+                    vec![0x80, 0]
+                },
+                // SWD and JTAG clock dividers
+                0x43 => {
+                    assert!(self.version.0 == 2 && self.version.1 >= 22, "setting the SWD clock needs V2J22");
+                    vec![0x80, 0]
+                },
+                0x44 => {
+                    assert!(self.version.0 == 2 && self.version.1 >= 24, "setting the JTAG clock needs V2J24");
+                    vec![0x80, 0]
+                },
+                // clock speeds of V3, by communication mode
+                0x62 => {
+                    let speeds = self.v3_speeds(cmd[2]);
+                    let mut r = vec![0; 52];
+                    r[0] = 0x80;
+                    r[8] = speeds.len() as u8;
+                    for (i, khz) in speeds.iter().enumerate() {
+                        r[12 + 4 * i..16 + 4 * i].copy_from_slice(&khz.to_le_bytes());
+                    }
+                    r
+                },
+                0x61 => {
+                    let khz = u32::from_le_bytes([cmd[4], cmd[5], cmd[6], cmd[7]]);
+                    assert!(self.v3_speeds(cmd[2]).contains(&khz), "unlisted speed {}kHz", khz);
+                    vec![0x80, 0, 0, 0, 0, 0, 0, 0]
+                },
+                // last R/W status, short and long forms
+                0x3B => vec![self.rw_status, 0],
+                0x3E => {
+                    assert!(self.since(15), "GETLASTRWSTATUS2 needs J15");
+                    let mut r = vec![0; 12];
+                    r[0] = self.rw_status;
+                    r
+                },
+                0x07 | 0x08 | 0x0C | 0x0D | 0x47 | 0x48 => {
+                    let address = u32::from_le_bytes([cmd[2], cmd[3], cmd[4], cmd[5]]);
+                    let len = u16::from_le_bytes([cmd[6], cmd[7]]) as usize;
+                    self.check_access(cmd[1], address, len);
+                    if let 0x08 | 0x0D | 0x48 = cmd[1] {
+                        self.pending_write = Some((address, len));
+                        return Vec::new();
+                    }
+                    let mut r = vec![0; len.max(2)];
+                    let ram = self.ram.lock().unwrap();
+                    self.rw_status = match ram_range(address, len) {
+                        _ if self.waits > 0 => {
+                            self.waits -= 1;
+                            0x10
+                        },
+                        Some(range) => {
+                            r[..len].copy_from_slice(&ram[range]);
+                            0x80
+                        },
+                        None => 0x11,
+                    };
+                    if len != 1 {
+                        r.truncate(len);
+                    }
+                    r
+                },
+                _ => Vec::new(),
+            },
+            // exit DFU mode
+            0xF3 if cmd[1] == 0x07 => {
+                self.mode = 0x01;
+                Vec::new()
+            },
+            // exit SWIM mode
+            0xF4 if cmd[1] == 0x01 => {
+                self.mode = 0x01;
+                Vec::new()
+            },
+            0xF5 => vec![self.mode, 0],
+            0xF7 => {
+                let mut r = self.voltage_adc[0].to_le_bytes().to_vec();
+                r.extend_from_slice(&self.voltage_adc[1].to_le_bytes());
+                r
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    // Whether the firmware is a V3 or a V2 of at least this JTAG version.
+    fn since(&self, jtag: u16) -> bool {
+        self.version.0 == 3 || self.version.1 >= jtag
+    }
+
+    fn v3_speeds(&self, com_mode: u8) -> &'static [u32] {
+        assert_eq!(self.version.0, 3, "communication frequencies are for V3");
+        match com_mode {
+            0 => &V3_SWD_KHZ,
+            1 => &V3_JTAG_KHZ,
+            _ => panic!("unknown communication mode {}", com_mode),
+        }
+    }
+
+    // Enforce the length and alignment rules of the firmware.
+    fn check_access(&self, cmd: u8, address: u32, len: usize) {
+        assert!(len > 0, "empty memory access");
+        match cmd {
+            0x0C | 0x0D => {
+                let max = if self.version.0 == 3 { 512 } else { 64 };
+                assert!(len <= max, "8-bit access of {} bytes", len);
+            },
+            0x47 | 0x48 => {
+                assert!(self.since(26), "16-bit access needs J26");
+                assert!(address.is_multiple_of(2) && len.is_multiple_of(2), "unaligned 16-bit access");
+            },
+            _ => assert!(address.is_multiple_of(4) && len.is_multiple_of(4), "unaligned 32-bit access"),
+        }
+        if cmd != 0x0C && cmd != 0x0D {
+            let last = address as usize + len - 1;
+            assert_eq!(address as usize / 1024, last / 1024, "access crosses a 1KB block");
+        }
+    }
+}
+
+fn ram_range(address: u32, len: usize) -> Option<core::ops::Range<usize>> {
+    let start = address.checked_sub(RAM_BASE)? as usize;
+    if start + len > RAM_SIZE {
+        return None;
+    }
+    Some(start..start + len)
+}
+
+impl Responder for StLinkSim {
+    fn write_pipe(&mut self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        // ST-Link V2-1 and V3 take commands on endpoint 1
+        let tx_ep = if self.pid == STLINK_V2_PID { 0x02 } else { 0x01 };
+        assert_eq!(pipe_index, tx_ep, "commands go to the TX endpoint");
+        if let Some((address, len)) = self.pending_write.take() {
+            assert_eq!(buf.len(), len, "data follows its write command");
+            let mut ram = self.ram.lock().unwrap();
+            self.rw_status = match ram_range(address, len) {
+                _ if self.waits > 0 => {
+                    self.waits -= 1;
+                    0x10
+                },
+                Some(range) => {
+                    ram[range].copy_from_slice(buf);
+                    0x80
+                },
+                None => 0x11,
+            };
+            return Ok(buf.len());
+        }
+        assert_eq!(buf.len(), 16, "commands are 16 bytes long");
+        let response = self.command(buf);
+        self.response.extend(response);
+        Ok(buf.len())
+    }
+
+    fn read_pipe(&mut self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        assert_eq!(pipe_index, 0x81, "responses come from the RX endpoint");
+        let len = buf.len().min(self.response.len());
+        for (dst, src) in buf.iter_mut().zip(self.response.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
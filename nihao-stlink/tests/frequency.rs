@@ -0,0 +1,65 @@
+mod common;
+
+use common::StLinkSim;
+use std::io;
+
+// divider sent by each clock command, by command
+fn dividers(sim: &StLinkSim) -> Vec<(u8, u16)> {
+    sim.commands(4).iter()
+        .filter(|cmd| cmd[0] == 0xF2 && (cmd[1] == 0x43 || cmd[1] == 0x44))
+        .map(|cmd| (cmd[1], u16::from_le_bytes([cmd[2], cmd[3]])))
+        .collect()
+}
+
+#[test]
+fn swd_frequency_from_table() -> io::Result<()> {
+    let sim = StLinkSim::v2(28);
+    sim.clone().register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.set_swd_frequency(1_000_000)?, 950_000);
+    assert_eq!(handle.set_swd_frequency(24_000_000)?, 4_000_000);
+    assert_eq!(handle.set_swd_frequency(1_800_000)?, 1_800_000);
+    // slower than the table goes as slow as possible
+    assert_eq!(handle.set_swd_frequency(1_000)?, 5_000);
+    assert_eq!(dividers(&sim), vec![(0x43, 3), (0x43, 0), (0x43, 1), (0x43, 798)]);
+    Ok(())
+}
+
+#[test]
+fn jtag_frequency_from_table() -> io::Result<()> {
+    let sim = StLinkSim::v2(28);
+    sim.clone().register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.set_jtag_frequency(2_000_000)?, 1_125_000);
+    assert_eq!(handle.set_jtag_frequency(100_000_000)?, 9_000_000);
+    assert_eq!(dividers(&sim), vec![(0x44, 32), (0x44, 4)]);
+    Ok(())
+}
+
+#[test]
+fn old_firmware_keeps_default_clock() -> io::Result<()> {
+    let sim = StLinkSim::v2(21);
+    sim.clone().register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.set_swd_frequency(4_000_000)?, 1_800_000);
+    assert_eq!(handle.set_jtag_frequency(4_000_000)?, 1_125_000);
+    assert!(dividers(&sim).is_empty());
+    Ok(())
+}
+
+#[test]
+fn v3_frequency_from_list() -> io::Result<()> {
+    let sim = StLinkSim::v3(7);
+    sim.clone().register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.set_swd_frequency(4_000_000)?, 3_300_000);
+    assert_eq!(handle.set_swd_frequency(100_000_000)?, 24_000_000);
+    assert_eq!(handle.set_jtag_frequency(100_000)?, 250_000);
+    let sets: Vec<_> = sim.commands(8).into_iter()
+        .filter(|cmd| cmd[..2] == [0xF2, 0x61])
+        .map(|cmd| (cmd[2], u32::from_le_bytes([cmd[4], cmd[5], cmd[6], cmd[7]])))
+        .collect();
+    assert_eq!(sets, vec![(0, 3300), (0, 24000), (1, 250)]);
+    assert!(dividers(&sim).is_empty());
+    Ok(())
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use common::StLinkSim;
+use core::convert::TryFrom;
+use nihao_stlink::{handle::TryFromHandleError, version::JtagApi, Handle, Mode};
+use std::io;
+
+#[test]
+fn read_version_and_voltage() -> io::Result<()> {
+    StLinkSim::v2(28).register();
+    let handles: Vec<_> = nihao_stlink::handles()?.into_iter().collect::<io::Result<_>>()?;
+    assert_eq!(handles.len(), 1);
+    let version = handles[0].version();
+    assert_eq!(version.stlink_version, 2);
+    assert_eq!(version.jtag, 28);
+    assert_eq!(version.jtag_api, JtagApi::V2);
+    assert!(version.has_trace && version.has_mem_16bit);
+    let voltage = handles[0].get_voltage()?.expect("J28 supports voltage");
+    assert!((voltage - 3.25).abs() < 0.01);
+    assert_eq!(handles[0].get_mode()?, Mode::MassStorage);
+    Ok(())
+}
+
+#[test]
+fn read_v3_version() -> io::Result<()> {
+    StLinkSim::v3(7).register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    let version = handle.version();
+    assert_eq!(version.jtag_api, JtagApi::V3);
+    assert_eq!((version.stlink_version, version.jtag, version.swim), (3, 7, 1));
+    assert_eq!((version.msd, version.bridge), (1, 3));
+    assert_eq!(version.pid, 0x374E);
+    assert!(version.has_get_last_rwstatus2 && version.has_mem_16bit);
+    assert_eq!(version.to_string(), "ST-Link V3J7M1B3S1 (API v3) VID:PID 0483:374E");
+    // commands go to the V3 endpoint once opened, too
+    assert_eq!(handle.get_mode()?, Mode::MassStorage);
+    Ok(())
+}
+
+#[test]
+fn accept_known_pids() -> io::Result<()> {
+    for &pid in &[0x374B, 0x3752] {
+        let mut sim = StLinkSim::v2(37);
+        sim.pid = pid;
+        sim.register();
+    }
+    for &pid in &[0x374D, 0x374E, 0x374F, 0x3753] {
+        let mut sim = StLinkSim::v3(7);
+        sim.pid = pid;
+        sim.register();
+    }
+    // ST-Link V1 speaks SCSI
+    let mut v1 = StLinkSim::v2(10);
+    v1.pid = 0x3744;
+    v1.register();
+    let pids = nihao_stlink::handles()?.into_iter()
+        .map(|handle| handle.map(|handle| handle.version().pid))
+        .collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(pids, vec![0x374B, 0x3752, 0x374D, 0x374E, 0x374F, 0x3753]);
+    Ok(())
+}
+
+#[test]
+fn tell_dongles_apart_by_serial() -> io::Result<()> {
+    let mut a = StLinkSim::v2(28);
+    a.serial = String::from("A");
+    let mut b = StLinkSim::v2(28);
+    b.serial = String::from("B");
+    a.register();
+    b.register();
+    let mut serials = Vec::new();
+    for handle in nihao_stlink::handles()? {
+        let usb = handle?;
+        let usb = usb.as_ref();
+        assert_eq!(usb.languages()?, vec![0x0409]);
+        assert_eq!(usb.manufacturer()?.as_deref(), Some("STMicroelectronics"));
+        serials.push(usb.serial_number()?.expect("has serial number"));
+    }
+    assert_eq!(serials, vec!["A", "B"]);
+    Ok(())
+}
+
+#[test]
+fn old_firmware_has_no_voltage() -> io::Result<()> {
+    StLinkSim::v2(12).register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.get_voltage()?, None);
+    Ok(())
+}
+
+#[test]
+fn skip_other_devices() -> io::Result<()> {
+    let mut other = StLinkSim::v2(28);
+    other.pid = 0x1234;
+    let desc = other.device_descriptor();
+    nihao_usb::sys::mock::register(nihao_usb::sys::mock::VirtualDevice::new(desc, other));
+    assert_eq!(nihao_stlink::handles()?.iter().count(), 0);
+    let usb_handle = nihao_usb::devices()?.iter().next().expect("one device")?.open()?;
+    assert!(Handle::try_from(usb_handle).is_err());
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_firmware_version() -> io::Result<()> {
+    let mut sim = StLinkSim::v2(28);
+    sim.version = (4, 28, 0);
+    sim.register();
+    let usb_handle = nihao_usb::devices()?.iter().next().expect("one device")?.open()?;
+    match Handle::try_from(usb_handle).unwrap_err().1 {
+        TryFromHandleError::IoError(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+        other => panic!("unexpected error {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn report_dongles_failing_to_open() -> io::Result<()> {
+    StLinkSim::v2(28).register();
+    let list = nihao_stlink::handles()?;
+    // unplugged between listing and opening
+    nihao_usb::sys::mock::clear();
+    let err = list.iter().next().expect("one dongle").unwrap_err();
+    assert!(matches!(nihao_usb::Error::from(err), nihao_usb::Error::Disconnected));
+    Ok(())
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::{StLinkSim, RAM_BASE};
+use nihao_stlink::{Error, Handle};
+use std::io;
+
+const MEM_COMMANDS: [u8; 6] = [0x07, 0x08, 0x0C, 0x0D, 0x47, 0x48];
+
+fn open(sim: StLinkSim) -> io::Result<Handle<'static>> {
+    sim.register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    handle.enter_swd()?;
+    Ok(handle)
+}
+
+// (command, address, length) of every memory access
+fn accesses(sim: &StLinkSim) -> Vec<(u8, u32, usize)> {
+    sim.commands(8).iter()
+        .filter(|cmd| cmd[0] == 0xF2 && MEM_COMMANDS.contains(&cmd[1]))
+        .map(|cmd| (
+            cmd[1],
+            u32::from_le_bytes([cmd[2], cmd[3], cmd[4], cmd[5]]),
+            u16::from_le_bytes([cmd[6], cmd[7]]) as usize,
+        ))
+        .collect()
+}
+
+#[test]
+fn round_trip_across_blocks() -> io::Result<()> {
+    let sim = StLinkSim::v2(28);
+    let handle = open(sim.clone())?;
+    let address = RAM_BASE + 0x3FE;
+    let data: Vec<u8> = (0..2000).map(|i| (i * 7 % 251) as u8).collect();
+    handle.write_mem32(address, &data)?;
+    assert_eq!(&sim.ram.lock().unwrap()[0x3FE..0x3FE + 2000], &data[..]);
+    let mut buf = vec![0; data.len()];
+    handle.read_mem32(address, &mut buf)?;
+    assert_eq!(buf, data);
+    // two unaligned bytes, the rest of the first block, then block by block
+    assert_eq!(&accesses(&sim)[..4], &[
+        (0x0D, address, 2),
+        (0x08, RAM_BASE + 0x400, 0x400),
+        (0x08, RAM_BASE + 0x800, 0x3CC),
+        (0x0D, RAM_BASE + 0xBCC, 2),
+    ]);
+    // every access is followed by a status check
+    let commands = sim.commands(2);
+    for (i, cmd) in commands.iter().enumerate() {
+        if cmd[0] == 0xF2 && MEM_COMMANDS.contains(&cmd[1]) {
+            assert_eq!(commands[i + 1], vec![0xF2, 0x3E]);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn split_byte_accesses() -> io::Result<()> {
+    let sim = StLinkSim::v2(28);
+    let handle = open(sim.clone())?;
+    let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    handle.write_mem8(RAM_BASE + 1, &data)?;
+    let mut buf = [0; 1];
+    handle.read_mem8(RAM_BASE + 200, &mut buf)?;
+    assert_eq!(buf, [199]);
+    assert_eq!(accesses(&sim), vec![
+        (0x0D, RAM_BASE + 1, 64),
+        (0x0D, RAM_BASE + 65, 64),
+        (0x0D, RAM_BASE + 129, 64),
+        (0x0D, RAM_BASE + 193, 8),
+        (0x0C, RAM_BASE + 200, 1),
+    ]);
+    Ok(())
+}
+
+#[test]
+fn sixteen_bit_needs_j26() -> io::Result<()> {
+    let old = StLinkSim::v2(24);
+    let handle = open(old.clone())?;
+    handle.write_mem16(RAM_BASE, &[1, 2, 3, 4])?;
+    let mut buf = [0; 4];
+    handle.read_mem16(RAM_BASE, &mut buf)?;
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(accesses(&old), vec![(0x0D, RAM_BASE, 4), (0x0C, RAM_BASE, 4)]);
+    Ok(())
+}
+
+#[test]
+fn sixteen_bit_unaligned_ends() -> io::Result<()> {
+    let new = StLinkSim::v2(28);
+    let handle = open(new.clone())?;
+    handle.write_mem16(RAM_BASE + 1, &[1, 2, 3, 4])?;
+    assert_eq!(accesses(&new), vec![(0x0D, RAM_BASE + 1, 1), (0x48, RAM_BASE + 2, 2), (0x0D, RAM_BASE + 4, 1)]);
+    Ok(())
+}
+
+#[test]
+fn old_firmware_checks_short_status() -> io::Result<()> {
+    let sim = StLinkSim::v2(13);
+    let handle = open(sim.clone())?;
+    let mut buf = [0; 8];
+    handle.read_mem32(RAM_BASE, &mut buf)?;
+    let commands = sim.commands(2);
+    assert!(commands.contains(&vec![0xF2, 0x3B]));
+    assert!(!commands.contains(&vec![0xF2, 0x3E]));
+    Ok(())
+}
+
+#[test]
+fn fault_outside_ram() -> io::Result<()> {
+    let handle = open(StLinkSim::v2(28))?;
+    let mut buf = [0; 4];
+    assert!(matches!(handle.read_mem32(0x1000_0000, &mut buf), Err(Error::ApFault)));
+    assert!(matches!(handle.write_mem8(0x1000_0000, &[0]), Err(Error::ApFault)));
+    // the variant survives a trip through `io::Error`
+    let err = io::Error::from(handle.write_mem8(0x1000_0000, &[0]).unwrap_err());
+    assert!(matches!(Error::from(err), Error::ApFault));
+    match handle.read_mem8(0xFFFF_FFFF, &mut buf) {
+        Err(Error::Other(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        other => panic!("expected invalid input, got {:?}", other),
+    }
+    // the dongle carries on after a fault
+    handle.read_mem32(RAM_BASE, &mut buf)?;
+    Ok(())
+}
+
+#[test]
+fn retry_on_wait() -> io::Result<()> {
+    let mut sim = StLinkSim::v2(28);
+    sim.waits = 3;
+    let handle = open(sim.clone())?;
+    handle.write_mem32(RAM_BASE, &[1, 2, 3, 4])?;
+    assert_eq!(&sim.ram.lock().unwrap()[..4], &[1, 2, 3, 4]);
+    assert_eq!(accesses(&sim), vec![(0x08, RAM_BASE, 4); 4]);
+    Ok(())
+}
+
+#[test]
+fn give_up_waiting() -> io::Result<()> {
+    let mut sim = StLinkSim::v2(28);
+    sim.waits = 100;
+    let handle = open(sim.clone())?;
+    let mut buf = [0; 4];
+    let err = handle.read_mem32(RAM_BASE, &mut buf).unwrap_err();
+    assert!(err.is_wait());
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::WouldBlock);
+    // the first try and eight retries
+    assert_eq!(accesses(&sim).len(), 9);
+    Ok(())
+}
+
+#[test]
+fn v3_byte_accesses() -> io::Result<()> {
+    let sim = StLinkSim::v3(7);
+    let handle = open(sim.clone())?;
+    let data = vec![0x5A; 600];
+    handle.write_mem8(RAM_BASE, &data)?;
+    let mut buf = vec![0; 600];
+    handle.read_mem16(RAM_BASE, &mut buf)?;
+    assert_eq!(buf, data);
+    assert_eq!(accesses(&sim), vec![
+        (0x0D, RAM_BASE, 512),
+        (0x0D, RAM_BASE + 512, 88),
+        (0x47, RAM_BASE, 600),
+    ]);
+    Ok(())
+}
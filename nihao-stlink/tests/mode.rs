@@ -0,0 +1,69 @@
+mod common;
+
+use common::StLinkSim;
+use nihao_stlink::Mode;
+use std::io;
+
+#[test]
+fn enter_swd_from_dfu() -> io::Result<()> {
+    let mut sim = StLinkSim::v2(28);
+    sim.mode = 0x00;
+    let log = sim.clone();
+    sim.register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.get_mode()?, Mode::Dfu);
+    handle.enter_swd()?;
+    assert_eq!(handle.get_mode()?, Mode::Debug);
+    // version, mode, leave DFU, enter SWD, check the mode, then our own query
+    assert_eq!(log.commands(3), vec![
+        vec![0xF1, 0x80, 0x00],
+        vec![0xF5, 0x00, 0x00],
+        vec![0xF5, 0x00, 0x00],
+        vec![0xF3, 0x07, 0x00],
+        vec![0xF2, 0x30, 0xA3],
+        vec![0xF5, 0x00, 0x00],
+        vec![0xF5, 0x00, 0x00],
+    ]);
+    Ok(())
+}
+
+#[test]
+fn switch_from_swd_to_jtag() -> io::Result<()> {
+    let sim = StLinkSim::v2(28);
+    let log = sim.clone();
+    sim.register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    handle.enter_swd()?;
+    handle.enter_jtag()?;
+    assert_eq!(handle.get_mode()?, Mode::Debug);
+    let commands = log.commands(3);
+    let exit = commands.iter().position(|cmd| cmd == &[0xF2, 0x21, 0x00]).expect("left SWD first");
+    assert_eq!(commands[exit + 1], vec![0xF2, 0x30, 0xA4]);
+    handle.exit_debug()?;
+    assert_eq!(handle.get_mode()?, Mode::MassStorage);
+    Ok(())
+}
+
+#[test]
+fn leave_swim_before_enter() -> io::Result<()> {
+    let mut sim = StLinkSim::v2(28);
+    sim.mode = 0x03;
+    let log = sim.clone();
+    sim.register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.get_mode()?, Mode::Swim);
+    handle.enter_swd()?;
+    assert!(log.commands(2).contains(&vec![0xF4, 0x01]));
+    assert_eq!(handle.get_mode()?, Mode::Debug);
+    Ok(())
+}
+
+#[test]
+fn unknown_mode() -> io::Result<()> {
+    let mut sim = StLinkSim::v2(28);
+    sim.mode = 0x42;
+    sim.register();
+    let handle = nihao_stlink::handles()?.iter().next().expect("one dongle")?;
+    assert_eq!(handle.get_mode()?, Mode::Unknown(0x42));
+    Ok(())
+}
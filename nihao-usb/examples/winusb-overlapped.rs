@@ -1,8 +1,33 @@
+#[cfg(windows)]
 use nihao_usb::sys::windows::{setup::ListOptions, usb::ListOptionsExt};
-// use nihao_usb::{DeviceDescriptor, InterfaceDescriptor};
-use std::io;
-use core::task::Poll;
+#[cfg(windows)]
+use std::{future::Future, io, sync::Arc, task::{Context, Poll, Wake}, thread};
 
+// Minimal executor: park the thread until the waker unparks it.
+#[cfg(windows)]
+struct ThreadWaker(thread::Thread);
+
+#[cfg(windows)]
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+#[cfg(windows)]
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Arc::new(ThreadWaker(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(ans) = future.as_mut().poll(&mut cx) {
+            return ans
+        }
+        thread::park();
+    }
+}
+
+#[cfg(windows)]
 fn main() -> io::Result<()> {
     let info_handle = ListOptions::all_usb_interfaces()
         .present()
@@ -10,30 +35,21 @@ fn main() -> io::Result<()> {
     for info in info_handle.iter() {
         if let Ok(info) = info {
             if let Ok(usb) = info.open() {
-                // println!("=1= Device: {:?}", usb.device_descriptor()
-                //     .map(|d| DeviceDescriptor::from(d)));
-                // println!("=2= Speed: {:?}", usb.speed());
-                // println!("=3= Interface 0: {:?}", usb.interface_settings(0).unwrap()
-                //     .map(|d| InterfaceDescriptor::from(d)));
-                // println!("=4= Has pipe 2:{}", usb.query_pipe(0, 2).expect("query pipe").is_some());
-                let buf_send: &[u8] = &[
-                    0xF1, 0x80
-                ];
-                let mut buf_recv = vec![0u8; 1024];
-                let ov_write = usb.write_pipe_overlapped(0x02, &buf_send).unwrap();
-                let ov_read = usb.read_pipe_overlapped(0x81, &mut buf_recv).unwrap();
-                while let Poll::Pending = usb.poll_overlapped(ov_write.as_ref()) {}
-                loop {
-                    if let Poll::Ready(len) = usb.poll_overlapped(ov_read.as_ref()) {
-                        let len = len.unwrap();
-                        println!("Bytes read: {:?}", len);
-                        println!("{:?}", &buf_recv[..len]);
-                        break;
-                    }
-                } 
-                // println!("== Finished");
+                let buf_send = vec![0xF1, 0x80];
+                let buf_recv = vec![0u8; 1024];
+                let ov_write = usb.write_pipe_overlapped(0x02, buf_send);
+                let ov_read = usb.read_pipe_overlapped(0x81, buf_recv);
+                block_on(ov_write)?;
+                let (buf_recv, len) = block_on(ov_read)?;
+                println!("Bytes read: {:?}", len);
+                println!("{:?}", &buf_recv[..len]);
             }
         }
     }
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("This example uses WinUSB and only runs on Windows.");
+}
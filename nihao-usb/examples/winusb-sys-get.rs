@@ -1,5 +1,7 @@
+#[cfg(windows)]
 use std::io;
 
+#[cfg(windows)]
 fn main() -> io::Result<()> {
     for device in nihao_usb::sys::windows::devices()?.iter() {
         if let Ok(handle) = device?.open() {
@@ -12,3 +14,8 @@ fn main() -> io::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("This example uses WinUSB and only runs on Windows.");
+}
@@ -0,0 +1,128 @@
+//! Setup packets for transfers on the default control pipe.
+use crate::Direction;
+
+// Standard requests, `bRequest` values from chapter 9 of the USB specification
+pub const REQUEST_GET_STATUS: u8 = 0x00;
+pub const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+pub const REQUEST_SET_FEATURE: u8 = 0x03;
+pub const REQUEST_SET_ADDRESS: u8 = 0x05;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+pub const REQUEST_SET_DESCRIPTOR: u8 = 0x07;
+pub const REQUEST_GET_CONFIGURATION: u8 = 0x08;
+pub const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+pub const REQUEST_GET_INTERFACE: u8 = 0x0A;
+pub const REQUEST_SET_INTERFACE: u8 = 0x0B;
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum RequestType {
+    Standard,
+    Class,
+    Vendor,
+}
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum Recipient {
+    Device,
+    Interface,
+    Endpoint,
+    Other,
+}
+
+/// The setup packet of a control transfer, except for `wLength` which is
+/// taken from the length of the data buffer.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct ControlRequest {
+    pub direction: Direction,
+    pub request_type: RequestType,
+    pub recipient: Recipient,
+    /// `bRequest`
+    pub request: u8,
+    /// `wValue`
+    pub value: u16,
+    /// `wIndex`
+    pub index: u16,
+}
+
+impl ControlRequest {
+    pub fn new(
+        direction: Direction,
+        request_type: RequestType,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+    ) -> ControlRequest {
+        ControlRequest { direction, request_type, recipient, request, value, index }
+    }
+
+    /// The standard `GET_DESCRIPTOR` request.
+    pub fn get_descriptor(descriptor_type: u8, index: u8, language_id: u16) -> ControlRequest {
+        Self::new(
+            Direction::In,
+            RequestType::Standard,
+            Recipient::Device,
+            REQUEST_GET_DESCRIPTOR,
+            u16::from_be_bytes([descriptor_type, index]),
+            language_id,
+        )
+    }
+
+    /// A vendor request from device to host.
+    pub fn vendor_in(recipient: Recipient, request: u8, value: u16, index: u16) -> ControlRequest {
+        Self::new(Direction::In, RequestType::Vendor, recipient, request, value, index)
+    }
+
+    /// A vendor request from host to device.
+    pub fn vendor_out(recipient: Recipient, request: u8, value: u16, index: u16) -> ControlRequest {
+        Self::new(Direction::Out, RequestType::Vendor, recipient, request, value, index)
+    }
+
+    /// A class request from device to host.
+    pub fn class_in(recipient: Recipient, request: u8, value: u16, index: u16) -> ControlRequest {
+        Self::new(Direction::In, RequestType::Class, recipient, request, value, index)
+    }
+
+    /// A class request from host to device.
+    pub fn class_out(recipient: Recipient, request: u8, value: u16, index: u16) -> ControlRequest {
+        Self::new(Direction::Out, RequestType::Class, recipient, request, value, index)
+    }
+
+    /// Encode the `bmRequestType` field of the setup packet.
+    pub fn request_type_bits(&self) -> u8 {
+        let direction = match self.direction {
+            Direction::Out => 0x00,
+            Direction::In => 0x80,
+        };
+        let request_type = match self.request_type {
+            RequestType::Standard => 0x00,
+            RequestType::Class => 0x20,
+            RequestType::Vendor => 0x40,
+        };
+        let recipient = match self.recipient {
+            Recipient::Device => 0x00,
+            Recipient::Interface => 0x01,
+            Recipient::Endpoint => 0x02,
+            Recipient::Other => 0x03,
+        };
+        direction | request_type | recipient
+    }
+
+    /// Decode a `bmRequestType` field, `None` for the reserved type or recipients.
+    pub fn from_bits(request_type: u8, request: u8, value: u16, index: u16) -> Option<ControlRequest> {
+        let direction = if request_type & 0x80 != 0 { Direction::In } else { Direction::Out };
+        let ty = match (request_type >> 5) & 0x03 {
+            0 => RequestType::Standard,
+            1 => RequestType::Class,
+            2 => RequestType::Vendor,
+            _ => return None,
+        };
+        let recipient = match request_type & 0x1F {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            3 => Recipient::Other,
+            _ => return None,
+        };
+        Some(Self::new(direction, ty, recipient, request, value, index))
+    }
+}
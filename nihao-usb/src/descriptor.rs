@@ -0,0 +1,296 @@
+//! Platform independent parsing of standard USB descriptors.
+use std::io;
+use crate::{DeviceDescriptor, InterfaceDescriptor};
+
+pub const DT_DEVICE: u8 = 0x01;
+pub const DT_CONFIG: u8 = 0x02;
+pub const DT_STRING: u8 = 0x03;
+pub const DT_INTERFACE: u8 = 0x04;
+pub const DT_ENDPOINT: u8 = 0x05;
+
+pub const DT_DEVICE_SIZE: usize = 18;
+pub const DT_CONFIG_SIZE: usize = 9;
+pub const DT_INTERFACE_SIZE: usize = 9;
+pub const DT_ENDPOINT_SIZE: usize = 7;
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl DeviceDescriptor {
+    /// Parse a device descriptor from its raw bytes.
+    pub fn parse(buf: &[u8]) -> io::Result<DeviceDescriptor> {
+        if buf.len() < DT_DEVICE_SIZE || buf[1] != DT_DEVICE {
+            return Err(invalid_data("not a device descriptor"))
+        }
+        Ok(DeviceDescriptor {
+            length: buf[0],
+            descriptor_type: buf[1],
+            bcd_usb: u16::from_le_bytes([buf[2], buf[3]]),
+            device_class: buf[4],
+            device_sub_class: buf[5],
+            device_protocol: buf[6],
+            max_packet_size_0: buf[7],
+            id_vendor: u16::from_le_bytes([buf[8], buf[9]]),
+            id_product: u16::from_le_bytes([buf[10], buf[11]]),
+            bcd_device: u16::from_le_bytes([buf[12], buf[13]]),
+            manufacturer: buf[14],
+            product: buf[15],
+            serial_number: buf[16],
+            num_configurations: buf[17],
+        })
+    }
+}
+
+impl InterfaceDescriptor {
+    fn parse(buf: &[u8]) -> io::Result<InterfaceDescriptor> {
+        if buf.len() < DT_INTERFACE_SIZE {
+            return Err(invalid_data("interface descriptor too short"))
+        }
+        Ok(InterfaceDescriptor {
+            length: buf[0],
+            descriptor_type: buf[1],
+            interface_number: buf[2],
+            alternate_setting: buf[3],
+            num_endpoints: buf[4],
+            interface_class: buf[5],
+            interface_subclass: buf[6],
+            interface_protocol: buf[7],
+            index_interface: buf[8],
+        })
+    }
+}
+
+/// A configuration descriptor with all interfaces that belong to it.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct ConfigDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    pub configuration_value: u8,
+    pub index_configuration: u8,
+    pub attributes: u8,
+    pub max_power: u8,
+    pub interfaces: Vec<Interface>,
+    /// Class or vendor specific descriptors following the configuration descriptor
+    pub extra: Vec<u8>,
+}
+
+/// All alternate settings sharing one interface number.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Interface {
+    pub interface_number: u8,
+    pub alt_settings: Vec<AltSetting>,
+}
+
+/// One alternate setting of an interface, with its endpoints.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct AltSetting {
+    pub descriptor: InterfaceDescriptor,
+    pub endpoints: Vec<EndpointDescriptor>,
+    /// Class specific descriptors, e.g. HID or CDC functional descriptors
+    pub extra: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct EndpointDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub endpoint_address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    /// Class specific descriptors, e.g. SuperSpeed endpoint companions
+    pub extra: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum Direction {
+    /// Host to device
+    Out,
+    /// Device to host
+    In,
+}
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+impl EndpointDescriptor {
+    fn parse(buf: &[u8]) -> io::Result<EndpointDescriptor> {
+        if buf.len() < DT_ENDPOINT_SIZE {
+            return Err(invalid_data("endpoint descriptor too short"))
+        }
+        Ok(EndpointDescriptor {
+            length: buf[0],
+            descriptor_type: buf[1],
+            endpoint_address: buf[2],
+            attributes: buf[3],
+            max_packet_size: u16::from_le_bytes([buf[4], buf[5]]),
+            interval: buf[6],
+            extra: Vec::new(),
+        })
+    }
+
+    /// Endpoint number without the direction bit.
+    pub fn number(&self) -> u8 {
+        self.endpoint_address & 0x0F
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.endpoint_address & 0x80 != 0 { Direction::In } else { Direction::Out }
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match self.attributes & 0x03 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+}
+
+impl ConfigDescriptor {
+    /// Parse a whole configuration descriptor blob, as returned by a
+    /// `GET_DESCRIPTOR` request of `wTotalLength` bytes.
+    ///
+    /// Descriptors other than interfaces and endpoints are kept as raw bytes
+    /// in the `extra` field of the configuration, alternate setting or endpoint
+    /// they follow.
+    pub fn parse(buf: &[u8]) -> io::Result<ConfigDescriptor> {
+        if buf.len() < DT_CONFIG_SIZE || buf[1] != DT_CONFIG {
+            return Err(invalid_data("not a configuration descriptor"))
+        }
+        let total_length = u16::from_le_bytes([buf[2], buf[3]]);
+        if buf.len() < total_length as usize {
+            return Err(invalid_data("configuration descriptor truncated"))
+        }
+        let mut ans = ConfigDescriptor {
+            length: buf[0],
+            descriptor_type: buf[1],
+            total_length,
+            num_interfaces: buf[4],
+            configuration_value: buf[5],
+            index_configuration: buf[6],
+            attributes: buf[7],
+            max_power: buf[8],
+            interfaces: Vec::new(),
+            extra: Vec::new(),
+        };
+        // (interface, alternate setting) most recently parsed
+        let mut current: Option<(usize, usize)> = None;
+        let mut offset = (buf[0] as usize).max(DT_CONFIG_SIZE);
+        while offset < total_length as usize {
+            let rest = &buf[offset..total_length as usize];
+            let len = rest[0] as usize;
+            if len < 2 || len > rest.len() {
+                return Err(invalid_data("descriptor length out of range"))
+            }
+            let desc = &rest[..len];
+            match desc[1] {
+                DT_INTERFACE => {
+                    let alt = AltSetting {
+                        descriptor: InterfaceDescriptor::parse(desc)?,
+                        endpoints: Vec::new(),
+                        extra: Vec::new(),
+                    };
+                    let number = alt.descriptor.interface_number;
+                    let i = match ans.interfaces.iter().position(|i| i.interface_number == number) {
+                        Some(i) => i,
+                        None => {
+                            ans.interfaces.push(Interface { interface_number: number, alt_settings: Vec::new() });
+                            ans.interfaces.len() - 1
+                        },
+                    };
+                    ans.interfaces[i].alt_settings.push(alt);
+                    current = Some((i, ans.interfaces[i].alt_settings.len() - 1));
+                },
+                DT_ENDPOINT => {
+                    let (i, a) = current.ok_or_else(|| invalid_data("endpoint outside of an interface"))?;
+                    let endpoint = EndpointDescriptor::parse(desc)?;
+                    ans.interfaces[i].alt_settings[a].endpoints.push(endpoint);
+                },
+                _ => {
+                    let extra = match current {
+                        Some((i, a)) => {
+                            let alt = &mut ans.interfaces[i].alt_settings[a];
+                            match alt.endpoints.last_mut() {
+                                Some(endpoint) => &mut endpoint.extra,
+                                None => &mut alt.extra,
+                            }
+                        },
+                        None => &mut ans.extra,
+                    };
+                    extra.extend_from_slice(desc);
+                },
+            }
+            offset += len;
+        }
+        Ok(ans)
+    }
+
+    /// Iterate over endpoints of every interface and alternate setting.
+    pub fn endpoints(&self) -> impl Iterator<Item = &EndpointDescriptor> {
+        self.interfaces.iter()
+            .flat_map(|i| i.alt_settings.iter())
+            .flat_map(|alt| alt.endpoints.iter())
+    }
+
+    /// Find an alternate setting of an interface.
+    pub fn alt_setting(&self, interface_number: u8, alternate_setting: u8) -> Option<&AltSetting> {
+        self.interfaces.iter()
+            .filter(|i| i.interface_number == interface_number)
+            .flat_map(|i| i.alt_settings.iter())
+            .find(|alt| alt.descriptor.alternate_setting == alternate_setting)
+    }
+
+    /// Number of the interface `index + 1` places after `interface_number`,
+    /// counting like `WinUsb_GetAssociatedInterface`.
+    pub(crate) fn associated_interface(&self, interface_number: u8, index: u8) -> Option<u8> {
+        let first = self.interfaces.iter().position(|i| i.interface_number == interface_number)?;
+        self.interfaces.get(first + 1 + index as usize).map(|i| i.interface_number)
+    }
+}
+
+/// Decode a string descriptor into its UTF-16LE encoded text.
+pub fn parse_string(buf: &[u8]) -> io::Result<String> {
+    let units = string_units(buf)?;
+    String::from_utf16(&units).map_err(|_| invalid_data("string descriptor is not valid UTF-16"))
+}
+
+/// Decode string descriptor zero into the language IDs supported by the device.
+pub fn parse_languages(buf: &[u8]) -> io::Result<Vec<u16>> {
+    string_units(buf)
+}
+
+fn string_units(buf: &[u8]) -> io::Result<Vec<u16>> {
+    if buf.len() < 2 || buf[1] != DT_STRING {
+        return Err(invalid_data("not a string descriptor"))
+    }
+    let len = (buf[0] as usize).min(buf.len());
+    Ok(buf[2..len].chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect())
+}
+
+/// Split the cached descriptors of a device, as read from usbfs or sysfs,
+/// into the device descriptor and each raw configuration descriptor.
+#[cfg(target_os = "linux")]
+pub(crate) fn split_cached(buf: &[u8]) -> io::Result<(DeviceDescriptor, Vec<&[u8]>)> {
+    let device = DeviceDescriptor::parse(buf)?;
+    let mut configs = Vec::new();
+    let mut offset = device.length as usize;
+    while offset + DT_CONFIG_SIZE <= buf.len() {
+        let total_length = u16::from_le_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        let end = (offset + total_length.max(DT_CONFIG_SIZE)).min(buf.len());
+        configs.push(&buf[offset..end]);
+        offset = end;
+    }
+    Ok((device, configs))
+}
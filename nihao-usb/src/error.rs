@@ -1,2 +1,149 @@
-pub type Result<T> = std::io::Result<T>;
-// todo
+//! Errors of USB operations.
+use core::fmt;
+use std::io;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Why a USB operation failed.
+///
+/// Converts from and into `io::Error`, so it mixes with other I/O code through
+/// the `?` operator. Converting an `io::Error` classifies it by its raw
+/// operating system error code first, then by its kind.
+///
+/// ```no_run
+/// use nihao_usb::Error;
+/// match nihao_usb::Location::new(1, &[3, 2]).open() {
+///     Ok(handle) => println!("{:?}", handle.product()),
+///     Err(Error::AccessDenied) => println!("no permission; is a udev rule installed?"),
+///     Err(Error::NotFound) => println!("nothing plugged into port 1-3.2"),
+///     Err(e) => println!("{}", e),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// No such device, interface, configuration, descriptor or pipe
+    NotFound,
+    /// The device was unplugged, or re-enumerated after a reset
+    Disconnected,
+    /// The device or interface is used by another handle, program or driver
+    Busy,
+    /// The process may not open the device; on Linux, usually because no udev
+    /// rule grants access to its device node
+    AccessDenied,
+    /// The device did not answer in time
+    Timeout,
+    /// The device stalled the endpoint, refusing the transfer or request
+    Stall,
+    /// The device sent more data than the buffer holds
+    Overflow,
+    /// The transfer failed on the bus, like on CRC or bit stuffing errors
+    Pipe,
+    /// Any other operating system error, by its raw error code
+    Os(i32),
+    /// Any other failure, like invalid arguments
+    Other(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound => f.write_str("entity not found"),
+            Error::Disconnected => f.write_str("device disconnected"),
+            Error::Busy => f.write_str("device or interface busy"),
+            Error::AccessDenied => f.write_str("access to device denied"),
+            Error::Timeout => f.write_str("operation timed out"),
+            Error::Stall => f.write_str("endpoint stalled"),
+            Error::Overflow => f.write_str("device sent more data than requested"),
+            Error::Pipe => f.write_str("transfer failed on the bus"),
+            Error::Os(code) => io::Error::from_raw_os_error(*code).fmt(f),
+            Error::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Other(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(src: io::Error) -> Error {
+        // errors converted into `io::Error` before come back unchanged
+        if src.get_ref().is_some_and(|e| e.is::<Error>()) {
+            let inner = src.into_inner().expect("checked to wrap an error");
+            return *inner.downcast::<Error>().expect("checked to be an `Error`")
+        }
+        if let Some(ans) = src.raw_os_error().and_then(from_raw_os_error) {
+            return ans
+        }
+        match src.kind() {
+            io::ErrorKind::NotFound => Error::NotFound,
+            io::ErrorKind::NotConnected => Error::Disconnected,
+            io::ErrorKind::ResourceBusy => Error::Busy,
+            io::ErrorKind::PermissionDenied => Error::AccessDenied,
+            io::ErrorKind::TimedOut => Error::Timeout,
+            io::ErrorKind::BrokenPipe => Error::Stall,
+            _ => match src.raw_os_error() {
+                Some(code) => Error::Os(code),
+                None => Error::Other(src),
+            },
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(src: Error) -> io::Error {
+        let kind = match src {
+            Error::NotFound => io::ErrorKind::NotFound,
+            Error::Disconnected => io::ErrorKind::NotConnected,
+            Error::Busy => io::ErrorKind::ResourceBusy,
+            Error::AccessDenied => io::ErrorKind::PermissionDenied,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::Stall => io::ErrorKind::BrokenPipe,
+            Error::Overflow => io::ErrorKind::InvalidData,
+            Error::Pipe => io::ErrorKind::Other,
+            Error::Os(code) => return io::Error::from_raw_os_error(code),
+            Error::Other(e) => return e,
+        };
+        io::Error::new(kind, src)
+    }
+}
+
+// Codes whose kind is too coarse or missing; usbfs reports transfer errors
+// with the errno values of `Documentation/driver-api/usb/error-codes.rst`.
+#[cfg(target_os = "linux")]
+fn from_raw_os_error(code: i32) -> Option<Error> {
+    Some(match code {
+        libc::ENODEV | libc::ESHUTDOWN => Error::Disconnected,
+        libc::EOVERFLOW => Error::Overflow,
+        libc::EPROTO | libc::EILSEQ | libc::ECOMM | libc::ENOSR | libc::ETIME => Error::Pipe,
+        _ => return None,
+    })
+}
+
+// WinUSB reports a stalled endpoint as a general failure, and a device node
+// already opened by another program as access denied.
+#[cfg(windows)]
+fn from_raw_os_error(code: i32) -> Option<Error> {
+    use winapi::shared::winerror::{
+        ERROR_ACCESS_DENIED, ERROR_BAD_COMMAND, ERROR_DEVICE_NOT_CONNECTED, ERROR_DEV_NOT_EXIST,
+        ERROR_GEN_FAILURE, ERROR_NO_MORE_ITEMS, ERROR_SEM_TIMEOUT,
+    };
+    Some(match code as u32 {
+        ERROR_DEVICE_NOT_CONNECTED | ERROR_DEV_NOT_EXIST | ERROR_BAD_COMMAND => Error::Disconnected,
+        ERROR_ACCESS_DENIED => Error::Busy,
+        ERROR_SEM_TIMEOUT => Error::Timeout,
+        ERROR_GEN_FAILURE => Error::Stall,
+        ERROR_NO_MORE_ITEMS => Error::NotFound,
+        _ => return None,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn from_raw_os_error(_code: i32) -> Option<Error> {
+    None
+}
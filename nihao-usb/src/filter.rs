@@ -0,0 +1,156 @@
+//! Select devices by what the operating system knows before opening them.
+use crate::{DeviceList, Location, Result, Watcher};
+
+/// What the operating system reports about a device without opening it.
+///
+/// Fields are `None` or empty when the backend cannot tell without opening
+/// the device; on Windows this is the case for the interface classes.
+#[derive(Debug, Clone, Default, Hash, Eq, PartialEq)]
+pub struct DeviceInfo {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    /// Serial number string as cached by the operating system
+    pub serial_number: Option<String>,
+    /// Bus number and chain of hub ports, like `1-3.2` in Linux sysfs
+    pub port_path: Option<String>,
+    /// `bInterfaceClass` of every interface in every configuration
+    pub interface_classes: Vec<u8>,
+}
+
+// the backends reading descriptors without opening the device
+#[cfg(any(target_os = "linux", feature = "mock"))]
+impl DeviceInfo {
+    /// Fill in identifiers and interface classes from cached raw descriptors,
+    /// a device descriptor followed by configuration descriptors.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn from_descriptors(buf: &[u8]) -> std::io::Result<DeviceInfo> {
+        let (device, configs) = crate::descriptor::split_cached(buf)?;
+        let mut ans = DeviceInfo::from_device_descriptor(&device);
+        for raw in configs {
+            ans.add_config(&crate::ConfigDescriptor::parse(raw)?);
+        }
+        Ok(ans)
+    }
+
+    pub(crate) fn from_device_descriptor(device: &crate::DeviceDescriptor) -> DeviceInfo {
+        DeviceInfo {
+            vendor_id: Some(device.id_vendor),
+            product_id: Some(device.id_product),
+            ..DeviceInfo::default()
+        }
+    }
+
+    pub(crate) fn add_config(&mut self, config: &crate::ConfigDescriptor) {
+        let classes = config.interfaces.iter()
+            .flat_map(|i| i.alt_settings.iter())
+            .map(|alt| alt.descriptor.interface_class);
+        for class in classes {
+            if !self.interface_classes.contains(&class) {
+                self.interface_classes.push(class);
+            }
+        }
+    }
+}
+
+/// Builder selecting devices at enumeration time.
+///
+/// Every criterion set must hold for a device to match. A criterion the
+/// backend cannot evaluate without opening the device never matches, so no
+/// unrelated device is ever opened.
+///
+/// ```no_run
+/// use nihao_usb::DeviceFilter;
+/// for device in DeviceFilter::new().vendor_id(0x0483).product_ids(&[0x3748, 0x374B]).list()? {
+///     let handle = device?.open()?;
+///     println!("{:?}", handle.serial_number()?);
+/// }
+/// # Ok::<(), nihao_usb::Error>(())
+/// ```
+#[derive(Debug, Clone, Default, Hash, Eq, PartialEq)]
+pub struct DeviceFilter {
+    vendor_id: Option<u16>,
+    product_ids: Vec<u16>,
+    serial_number: Option<String>,
+    port_path: Option<String>,
+    interface_class: Option<u8>,
+}
+
+impl DeviceFilter {
+    /// A filter matching every device.
+    pub fn new() -> DeviceFilter {
+        DeviceFilter::default()
+    }
+
+    pub fn vendor_id(mut self, vendor_id: u16) -> Self {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+
+    /// Also accept product ID `product_id`; any product matches if none is given.
+    pub fn product_id(mut self, product_id: u16) -> Self {
+        self.product_ids.push(product_id);
+        self
+    }
+
+    /// Also accept all of `product_ids`.
+    pub fn product_ids(mut self, product_ids: &[u16]) -> Self {
+        self.product_ids.extend_from_slice(product_ids);
+        self
+    }
+
+    pub fn serial_number(mut self, serial_number: &str) -> Self {
+        self.serial_number = Some(serial_number.to_string());
+        self
+    }
+
+    /// Only accept the device plugged into this port, like `1-3.2`.
+    pub fn port_path(mut self, port_path: &str) -> Self {
+        self.port_path = Some(port_path.to_string());
+        self
+    }
+
+    /// Only accept the device plugged in at `location`.
+    pub fn location(self, location: &Location) -> Self {
+        self.port_path(&location.to_string())
+    }
+
+    /// Only accept devices with an interface of this class.
+    pub fn interface_class(mut self, interface_class: u8) -> Self {
+        self.interface_class = Some(interface_class);
+        self
+    }
+
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        if self.vendor_id.is_some() && info.vendor_id != self.vendor_id {
+            return false
+        }
+        if !self.product_ids.is_empty() {
+            match info.product_id {
+                Some(id) if self.product_ids.contains(&id) => {},
+                _ => return false,
+            }
+        }
+        if self.serial_number.is_some() && info.serial_number != self.serial_number {
+            return false
+        }
+        if self.port_path.is_some() && info.port_path != self.port_path {
+            return false
+        }
+        if let Some(class) = self.interface_class {
+            if !info.interface_classes.contains(&class) {
+                return false
+            }
+        }
+        true
+    }
+
+    /// List all matching devices identified by the operating system.
+    pub fn list<'list>(&self) -> Result<DeviceList<'list>> {
+        crate::devices().map(|list| list.filter(self.clone()))
+    }
+
+    /// Watch matching devices being plugged in and unplugged.
+    pub fn watch<'w>(&self) -> Result<Watcher<'w>> {
+        Watcher::with_filters(vec![self.clone()])
+    }
+}
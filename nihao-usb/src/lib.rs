@@ -1,9 +1,32 @@
 pub mod sys;
 pub mod error;
+pub mod descriptor;
+pub mod control;
+pub mod filter;
+pub mod watch;
+pub mod location;
+pub mod trace;
+pub mod queue;
+pub mod policy;
 
-use core::iter::FusedIterator;
+pub use descriptor::{
+    ConfigDescriptor, Interface, AltSetting, EndpointDescriptor, Direction, TransferType
+};
+pub use control::{ControlRequest, RequestType, Recipient};
+pub use filter::{DeviceFilter, DeviceInfo};
+pub use watch::{Event, Watcher};
+pub use location::Location;
+pub use queue::TransferQueue;
+pub use policy::PipePolicy;
+pub use error::{Error, Result};
 
-use std::io;
+use core::{future::Future, iter::FusedIterator, pin::Pin, task::{Context, Poll, ready}};
+
+use std::{hash::{Hash, Hasher}, io, sync::Arc};
+use std::time::{Duration, Instant};
+
+/// How often `Handle::reset_device` looks for the device to come back.
+const REENUMERATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Get an `Iterator` over all USB devices identified by your operating system.
 /// 
@@ -12,36 +35,77 @@ use std::io;
 /// if you want to iterate everything in it by using `for` statements. 
 /// That's because a `Result` is also an `Iterator`, and its `Item` is `Devices`
 /// other than `Device` expected.
-pub fn devices<'list>() -> io::Result<DeviceList<'list>> {
-    sys::devices().map(|inner| DeviceList { inner })
+pub fn devices<'list>() -> Result<DeviceList<'list>> {
+    sys::devices().map(|inner| DeviceList { inner, filters: Vec::new() }).map_err(Error::from)
 }
 
 #[derive(Debug, Clone)]
 pub struct DeviceList<'list> {
-    inner: sys::DeviceList<'list>
+    inner: sys::DeviceList<'list>,
+    filters: Vec<DeviceFilter>,
 }
 
 impl<'list> DeviceList<'list> {
     pub fn iter<'iter>(&self) -> Devices<'iter> {
-        Devices { inner: self.inner.iter() }
+        Devices { inner: self.inner.iter(), filters: self.filters.clone() }
     }
 
     pub fn len(&self) -> usize {
-        self.inner.len()
+        if self.filters.is_empty() {
+            return self.inner.len()
+        }
+        self.iter().count()
     } 
+
+    pub fn is_empty(&self) -> bool {
+        if self.filters.is_empty() {
+            return self.inner.is_empty()
+        }
+        self.iter().next().is_none()
+    }
+
+    /// Keep only devices matching `filter`, deciding without opening them.
+    pub fn filter(mut self, filter: DeviceFilter) -> DeviceList<'list> {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Keep only devices with this vendor and product ID.
+    pub fn filter_vid_pid(self, vendor_id: u16, product_id: u16) -> DeviceList<'list> {
+        self.filter(DeviceFilter::new().vendor_id(vendor_id).product_id(product_id))
+    }
+}
+
+// Skip devices rejected by any filter; errors are passed on to the caller.
+fn next_admitted<'a, I>(iter: &mut I, filters: &[DeviceFilter]) -> Option<Result<Device<'a>>>
+where
+    I: Iterator<Item = io::Result<sys::Device<'a>>>
+{
+    loop {
+        match iter.next()? {
+            Ok(inner) if !filters.is_empty() => {
+                let info = inner.device_info();
+                if filters.iter().all(|f| f.matches(&info)) {
+                    return Some(Ok(Device { inner }))
+                }
+            },
+            res => return Some(res.map(|inner| Device { inner }).map_err(Error::from)),
+        }
+    }
 }
 
 /// An `Iterator` for USB devices.
 #[derive(Debug, Clone)]
 pub struct Devices<'iter> {
     inner: sys::Devices<'iter>,
+    filters: Vec<DeviceFilter>,
 }
 
 impl<'iter> Iterator for Devices<'iter> {
-    type Item = io::Result<Device<'iter>>;
+    type Item = Result<Device<'iter>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|res| res.map(|inner| Device { inner }))
+        next_admitted(&mut self.inner, &self.filters)
     }
 }
 
@@ -51,22 +115,23 @@ impl FusedIterator for Devices<'_> {}
 #[derive(Debug, Clone)]
 pub struct DeviceIntoIter<'iter> {
     inner: sys::DeviceIntoIter<'iter>,
+    filters: Vec<DeviceFilter>,
 }
 
 impl<'list> IntoIterator for DeviceList<'list> {
-    type Item = io::Result<Device<'list>>;
+    type Item = Result<Device<'list>>;
     type IntoIter = DeviceIntoIter<'list>;
 
     fn into_iter(self) -> Self::IntoIter {
-        DeviceIntoIter { inner: self.inner.into_iter() }
+        DeviceIntoIter { inner: self.inner.into_iter(), filters: self.filters }
     }
 }
 
 impl<'iter> Iterator for DeviceIntoIter<'iter> {
-    type Item = io::Result<Device<'iter>>;
+    type Item = Result<Device<'iter>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|res| res.map(|inner| Device { inner }))
+        next_admitted(&mut self.inner, &self.filters)
     }
 }
 
@@ -77,42 +142,626 @@ pub struct Device<'device> {
 }
 
 impl<'device> Device<'device> {
-    pub fn open<'handle>(&self) -> io::Result<Handle<'handle>> {
-        self.inner.open().map(|inner| Handle { inner })
+    pub fn open<'handle>(&self) -> Result<Handle<'handle>> {
+        self.inner.open().map(Handle::new).map_err(Error::from)
+    }
+
+    /// Identifiers of this device known to the operating system, read without
+    /// opening it.
+    pub fn info(&self) -> DeviceInfo {
+        self.inner.device_info()
+    }
+
+    /// Identify this device among the devices connected at the same time.
+    ///
+    /// On Linux and the mock backend the identifier changes whenever the 
+    /// device enumerates again, so a board swapped in the same port gets a 
+    /// new one.
+    pub fn id(&self) -> DeviceId {
+        DeviceId { inner: self.inner.id() }
     }
+
+    /// Bus number and hub ports this device is plugged into.
+    pub fn location(&self) -> Result<Location> {
+        self.inner.location().map_err(Error::from)
+    }
+}
+
+/// Identifies a connected device, also after it is unplugged.
+///
+/// Two devices never share an identifier while both are connected. Beyond 
+/// that, what the identifier is made of depends on the backend:
+///
+/// - on Linux, the port path and the address on the bus, which changes every 
+///   time the device enumerates;
+/// - on Windows, the device interface path; it contains the serial number, so 
+///   a device with one keeps its identifier on any port, and only devices 
+///   without one get an instance ID derived from the port;
+/// - on USB/IP, the remote host and the bus ID there, so a device replaced at 
+///   the same remote port gets the same identifier;
+/// - on the mock backend, the port path and the number from `register`.
+///
+/// To find whatever is plugged into a given port, use `Location` instead.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct DeviceId {
+    inner: sys::DeviceId,
 }
 
 /// A connection handle to the remote device.
 /// 
 /// Underlying code must ensure that this handle implements `Drop` and all relevant
 /// resources are freed during their `drop` operations.
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct Handle<'handle> {
     inner: sys::Handle<'handle>,
+    // shared by clones, so that tracing covers all of them
+    trace: Arc<trace::TraceSlot>,
+}
+
+impl PartialEq for Handle<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl Eq for Handle<'_> {}
+
+impl Hash for Handle<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.inner.hash(state)
+    }
 }
 
 impl<'handle> Handle<'handle> {
-    pub fn device_descriptor(&self) -> io::Result<DeviceDescriptor> {
-        self.inner.device_descriptor()
+    fn new(inner: sys::Handle<'handle>) -> Handle<'handle> {
+        Handle { inner, trace: Arc::new(trace::TraceSlot::default()) }
     }
 
-    pub fn speed(&self) -> io::Result<crate::Speed>  {
-        self.inner.speed()
+    pub fn device_descriptor(&self) -> Result<DeviceDescriptor> {
+        self.inner.device_descriptor().map_err(Error::from)
     }
 
-    pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read_pipe(pipe_index, buf)
+    /// Get the configuration descriptor at `index` with all its interfaces,
+    /// alternate settings and endpoints.
+    pub fn config_descriptor(&self, index: u8) -> Result<ConfigDescriptor> {
+        self.inner.config_descriptor(index).map_err(Error::from)
     }
 
-    pub fn write_pipe(&self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
-        self.inner.write_pipe(pipe_index, buf)
+    /// Read a raw descriptor by its type and index into `buf`, returning its length.
+    ///
+    /// `language_id` is only used by string descriptors, and zero otherwise.
+    pub fn get_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16, buf: &mut [u8]) 
+        -> Result<usize> 
+    {
+        let request = ControlRequest::get_descriptor(descriptor_type, index, language_id);
+        let pending = self.trace.submit_control(&request, buf.len(), &[]);
+        let ans = self.inner.get_descriptor(descriptor_type, index, language_id, buf).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|&len| &buf[..len]));
+        ans
+    }
+
+    /// Get language IDs supported by string descriptors of this device.
+    pub fn languages(&self) -> Result<Vec<u16>> {
+        let mut buf = [0u8; 255];
+        let len = self.get_descriptor(descriptor::DT_STRING, 0, 0, &mut buf)?;
+        Ok(descriptor::parse_languages(&buf[..len])?)
+    }
+
+    /// Read and decode the string descriptor at `index` in language `language_id`.
+    pub fn string_descriptor(&self, index: u8, language_id: u16) -> Result<String> {
+        let mut buf = [0u8; 255];
+        let len = self.get_descriptor(descriptor::DT_STRING, index, language_id, &mut buf)?;
+        Ok(descriptor::parse_string(&buf[..len])?)
+    }
+
+    /// Manufacturer string in the first language of the device, if any.
+    pub fn manufacturer(&self) -> Result<Option<String>> {
+        let index = self.device_descriptor()?.manufacturer;
+        self.default_string(index)
+    }
+
+    /// Product string in the first language of the device, if any.
+    pub fn product(&self) -> Result<Option<String>> {
+        let index = self.device_descriptor()?.product;
+        self.default_string(index)
+    }
+
+    /// Serial number string in the first language of the device, if any.
+    pub fn serial_number(&self) -> Result<Option<String>> {
+        let index = self.device_descriptor()?.serial_number;
+        self.default_string(index)
+    }
+
+    fn default_string(&self, index: u8) -> Result<Option<String>> {
+        if index == 0 {
+            return Ok(None);
+        }
+        // some devices ship an empty language table, assume English (US) then
+        let language_id = self.languages()?.first().cloned().unwrap_or(LANGUAGE_ID_EN_US);
+        self.string_descriptor(index, language_id).map(Some)
+    }
+
+    pub fn speed(&self) -> Result<crate::Speed>  {
+        self.inner.speed().map_err(Error::from)
+    }
+
+    /// Pipes of the interface of this handle, in its current alternate setting.
+    ///
+    /// The default control pipe is not listed.
+    pub fn pipes(&self) -> Result<Pipes> {
+        self.inner.pipes().map(|pipes| Pipes { iter: pipes.into_iter() }).map_err(Error::from)
+    }
+
+    /// Read from a pipe, waiting at most for the timeout set by `set_timeout`.
+    pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> Result<usize> {
+        let pending = self.trace.submit_bulk(pipe_index, &[]);
+        let ans = self.inner.read_pipe(pipe_index, buf).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|&len| &buf[..len]));
+        ans
+    }
+
+    /// Write to a pipe, waiting at most for the timeout set by `set_timeout`.
+    pub fn write_pipe(&self, pipe_index: u8, buf: &[u8]) -> Result<usize> {
+        let pending = self.trace.submit_bulk(pipe_index, buf);
+        let ans = self.inner.write_pipe(pipe_index, buf).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|_| &[][..]));
+        ans
+    }
+
+    /// Read from a pipe, failing with `Error::Timeout` if the device 
+    /// does not answer in time. A zero `timeout` waits forever.
+    ///
+    /// On Windows, a shorter timeout set with `set_timeout` still applies.
+    pub fn read_pipe_timeout(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) 
+        -> Result<usize> 
+    {
+        let pending = self.trace.submit_bulk(pipe_index, &[]);
+        let ans = self.inner.read_pipe_timeout(pipe_index, buf, timeout).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|&len| &buf[..len]));
+        ans
+    }
+
+    /// Write to a pipe, failing with `Error::Timeout` if the device 
+    /// does not accept the data in time. A zero `timeout` waits forever.
+    ///
+    /// On Windows, a shorter timeout set with `set_timeout` still applies.
+    pub fn write_pipe_timeout(&self, pipe_index: u8, buf: &[u8], timeout: Duration) 
+        -> Result<usize> 
+    {
+        let pending = self.trace.submit_bulk(pipe_index, buf);
+        let ans = self.inner.write_pipe_timeout(pipe_index, buf, timeout).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|_| &[][..]));
+        ans
+    }
+
+    /// Read from an interrupt pipe, failing with `Error::Timeout` if the
+    /// device has nothing to report in time. A zero `timeout` waits forever.
+    ///
+    /// The host asks the device once per polling interval only, so a shorter
+    /// `timeout` is extended to one interval. Fails with `Error::NotFound` if
+    /// there is no such pipe, and on pipes of other transfer types.
+    pub fn read_interrupt(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let timeout = self.interrupt_timeout(pipe_index, timeout)?;
+        let pending = self.trace.submit_interrupt(pipe_index, &[]);
+        let ans = self.inner.read_pipe_timeout(pipe_index, buf, timeout).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|&len| &buf[..len]));
+        ans
+    }
+
+    /// Write to an interrupt pipe, failing with `Error::Timeout` if the
+    /// device does not accept the data in time. A zero `timeout` waits forever.
+    ///
+    /// As with `read_interrupt`, the timeout lasts one polling interval at least.
+    pub fn write_interrupt(&self, pipe_index: u8, buf: &[u8], timeout: Duration) -> Result<usize> {
+        let timeout = self.interrupt_timeout(pipe_index, timeout)?;
+        let pending = self.trace.submit_interrupt(pipe_index, buf);
+        let ans = self.inner.write_pipe_timeout(pipe_index, buf, timeout).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|_| &[][..]));
+        ans
+    }
+
+    fn interrupt_timeout(&self, pipe_index: u8, timeout: Duration) -> Result<Duration> {
+        let pipe = self.pipe(pipe_index, TransferType::Interrupt)?;
+        if timeout == Duration::from_secs(0) {
+            return Ok(timeout)
+        }
+        Ok(timeout.max(pipe.polling_interval(self.speed()?)))
+    }
+
+    /// Receive `packets` isochronous packets from a pipe, one per polling
+    /// interval, into `buf` split evenly among them.
+    ///
+    /// Packets are not retried: one lost or damaged on the bus fails alone,
+    /// with the error in its `IsoPacket::status`, and an interval the device
+    /// had nothing to send for gives an empty packet. The data of each packet
+    /// starts at its `offset` in `buf`, whatever the length of the packets
+    /// before it.
+    ///
+    /// `buf.len()` must be a multiple of `packets`, and a packet is at most
+    /// what the endpoint moves in one interval. Fails with `Error::NotFound`
+    /// if there is no such pipe, and on pipes of other transfer types.
+    pub fn read_isochronous(&self, pipe_index: u8, buf: &mut [u8], packets: usize) -> Result<Vec<IsoPacket>> {
+        self.pipe(pipe_index, TransferType::Isochronous)?;
+        check_isochronous(buf.len(), packets)?;
+        let pending = self.trace.submit_isochronous(pipe_index, buf.len() / packets, packets, &[]);
+        let ans = self.inner.read_isochronous(pipe_index, buf, packets).map_err(Error::from);
+        pending.complete_isochronous(ans.as_deref(), buf);
+        ans
+    }
+
+    /// Send `buf` as `packets` isochronous packets of equal length on a pipe,
+    /// one per polling interval.
+    ///
+    /// Like `read_isochronous`, the transfer succeeds even if packets fail.
+    /// WinUSB does not report packets sent one by one; they are all reported
+    /// sent once the transfer completes.
+    pub fn write_isochronous(&self, pipe_index: u8, buf: &[u8], packets: usize) -> Result<Vec<IsoPacket>> {
+        self.pipe(pipe_index, TransferType::Isochronous)?;
+        check_isochronous(buf.len(), packets)?;
+        let pending = self.trace.submit_isochronous(pipe_index, buf.len() / packets, packets, buf);
+        let ans = self.inner.write_isochronous(pipe_index, buf, packets).map_err(Error::from);
+        pending.complete_isochronous(ans.as_deref(), &[]);
+        ans
+    }
+
+    // a pipe of the current alternate setting, of the transfer type expected
+    fn pipe(&self, pipe_index: u8, transfer_type: TransferType) -> Result<PipeInfo> {
+        self.pipes()?
+            .find(|pipe| pipe.endpoint_address == pipe_index && pipe.transfer_type == transfer_type)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Read up to `buf.len()` bytes from a pipe without blocking the thread.
+    ///
+    /// The transfer is submitted right away; the future resolves to `buf`
+    /// truncated to the bytes received. Completion wakes the task through its
+    /// `Waker`, so any executor can drive it, and several pipes or devices can
+    /// be kept busy from one thread. Dropping the future cancels the transfer.
+    ///
+    /// The timeout set by `set_timeout` applies.
+    pub fn read_pipe_async(&self, pipe_index: u8, buf: Vec<u8>) -> ReadPipe<'_> {
+        let pending = Some(self.trace.submit_bulk(pipe_index, &[]));
+        ReadPipe { inner: self.inner.read_pipe_async(pipe_index, buf), pending }
+    }
+
+    /// Write all of `buf` to a pipe without blocking the thread.
+    ///
+    /// The future resolves to the number of bytes written, and behaves
+    /// like the one of `read_pipe_async` otherwise.
+    pub fn write_pipe_async(&self, pipe_index: u8, buf: Vec<u8>) -> WritePipe<'_> {
+        let pending = Some(self.trace.submit_bulk(pipe_index, &buf));
+        WritePipe { inner: self.inner.write_pipe_async(pipe_index, buf), pending }
+    }
+
+    /// Keep up to `depth` transfers submitted on a pipe, see `TransferQueue`.
+    ///
+    /// The timeout set by `set_timeout` applies to each transfer. Panics if
+    /// `depth` is zero.
+    pub fn transfer_queue(&self, pipe_index: u8, depth: usize) -> TransferQueue<'_> {
+        TransferQueue::new(self, pipe_index, depth)
+    }
+
+    /// Set the timeout of `read_pipe` and `write_pipe` on a pipe.
+    /// 
+    /// Pipes wait forever by default, which is also what a zero `timeout` means.
+    pub fn set_timeout(&self, pipe_index: u8, timeout: Duration) -> Result<()> {
+        self.inner.set_timeout(pipe_index, timeout).map_err(Error::from)
     }
     
-    pub fn flush_pipe(&self, pipe_index: u8) -> io::Result<()> {
-        self.inner.flush_pipe(pipe_index)
+    /// Set how transfers on a pipe behave from now on, see `PipePolicy`.
+    ///
+    /// Fails with `Error::NotFound` if the current alternate setting has no
+    /// such pipe.
+    pub fn set_pipe_policy(&self, pipe_index: u8, policy: PipePolicy) -> Result<()> {
+        self.inner.set_pipe_policy(pipe_index, policy).map_err(Error::from)
+    }
+
+    /// Get the policy of a pipe, the default one unless set before.
+    pub fn pipe_policy(&self, pipe_index: u8) -> Result<PipePolicy> {
+        self.inner.pipe_policy(pipe_index).map_err(Error::from)
+    }
+
+    pub fn flush_pipe(&self, pipe_index: u8) -> Result<()> {
+        self.inner.flush_pipe(pipe_index).map_err(Error::from)
+    }
+
+    /// Claim an interface for this handle, so that its endpoints can be used.
+    ///
+    /// Opening a device claims its first interface already. Interfaces stay
+    /// claimed until released or until the last clone of this handle drops;
+    /// claiming an interface claimed by another handle fails with
+    /// `Error::Busy`.
+    ///
+    /// On Windows every interface is bound to WinUSB separately unless the
+    /// device groups them into one function; only interfaces of the function
+    /// this handle was opened on can be claimed.
+    pub fn claim_interface(&self, interface_number: u8) -> Result<()> {
+        self.inner.claim_interface(interface_number).map_err(Error::from)
+    }
+
+    /// Release an interface claimed by `claim_interface`.
+    pub fn release_interface(&self, interface_number: u8) -> Result<()> {
+        self.inner.release_interface(interface_number).map_err(Error::from)
+    }
+
+    /// Tell whether a kernel driver is bound to an interface, which keeps
+    /// it from being claimed.
+    ///
+    /// Only Linux binds kernel drivers to single interfaces, such as
+    /// `usb-storage`, `cdc_acm` or `usbhid`; elsewhere this is always `false`.
+    pub fn kernel_driver_active(&self, interface_number: u8) -> Result<bool> {
+        self.inner.kernel_driver_active(interface_number).map_err(Error::from)
+    }
+
+    /// Unbind the kernel driver of an interface, so that it can be claimed.
+    ///
+    /// Fails with `Error::NotFound` if no kernel driver is bound, and with
+    /// `Error::AccessDenied` if the process may not unbind it. On systems
+    /// without kernel drivers for single interfaces this fails with an
+    /// `Error::Other` of kind `io::ErrorKind::Unsupported`.
+    pub fn detach_kernel_driver(&self, interface_number: u8) -> Result<()> {
+        self.inner.detach_kernel_driver(interface_number).map_err(Error::from)
+    }
+
+    /// Let the kernel bind its driver to an interface again.
+    pub fn attach_kernel_driver(&self, interface_number: u8) -> Result<()> {
+        self.inner.attach_kernel_driver(interface_number).map_err(Error::from)
+    }
+
+    /// Detach kernel drivers from interfaces claimed by `claim_interface`
+    /// from now on, and attach them again when the interfaces are released
+    /// or the last clone of this handle drops.
+    ///
+    /// The first interface is claimed when opening a device, before this can
+    /// be enabled; release and claim it again to detach its kernel driver.
+    pub fn set_auto_detach_kernel_driver(&self, enable: bool) -> Result<()> {
+        self.inner.set_auto_detach_kernel_driver(enable).map_err(Error::from)
+    }
+
+    /// Select an alternate setting of a claimed interface.
+    pub fn set_alternate_setting(&self, interface_number: u8, alternate_setting: u8) -> Result<()> {
+        self.inner.set_alternate_setting(interface_number, alternate_setting).map_err(Error::from)
+    }
+
+    /// Open the interface `index + 1` places after the first interface of this
+    /// handle, like `WinUsb_GetAssociatedInterface`.
+    ///
+    /// The new handle claims that interface, which is released after the last
+    /// clone of it drops. It may be used alongside this handle, for example
+    /// from another thread.
+    pub fn associated_interface(&self, index: u8) -> Result<Handle<'handle>> {
+        let trace = Arc::new(self.trace.inherit());
+        self.inner.associated_interface(index).map(|inner| Handle { inner, trace }).map_err(Error::from)
+    }
+
+    /// Record transfers of this handle and its clones with `tracer`, or
+    /// stop recording them with `None`.
+    ///
+    /// Control transfers, including descriptor requests, and pipe transfers
+    /// are recorded when they are submitted and when they complete. Handles
+    /// of associated interfaces and handles returned by `reset_device` keep
+    /// recording with the tracer of this handle.
+    pub fn set_tracer(&self, tracer: Option<trace::Tracer>) {
+        let bus = self.location().map(|location| location.bus_number()).unwrap_or(0);
+        self.trace.attach(tracer, bus)
+    }
+
+    /// Bus number and hub ports the device of this handle is plugged into.
+    pub fn location(&self) -> Result<Location> {
+        self.inner.location().map_err(Error::from)
+    }
+
+    /// Reset the device and open it again once it re-enumerated.
+    ///
+    /// The device is reset through its hub port, so it re-enumerates as if
+    /// it was plugged in again; this and all other handles on it stop working.
+    /// The new handle is for the device at the same location with the same
+    /// serial number. If no such device shows up within `timeout`, this fails
+    /// with `Error::Timeout`.
+    pub fn reset_device(&self, timeout: Duration) -> Result<Handle<'handle>> {
+        let location = self.location()?;
+        let serial_number = self.serial_number()?;
+        self.inner.reset_device()?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            // the device may show up before it answers requests, try again then
+            if let Ok(handle) = location.open() {
+                if handle.serial_number().ok().as_ref() == Some(&serial_number) {
+                    return Ok(Handle { trace: Arc::new(self.trace.inherit()), ..handle })
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout)
+            }
+            std::thread::sleep(REENUMERATION_POLL_INTERVAL);
+        }
+    }
+
+    /// Clear a stall condition on a pipe and reset its data toggle.
+    pub fn reset_pipe(&self, pipe_index: u8) -> Result<()> {
+        self.inner.reset_pipe(pipe_index).map_err(Error::from)
+    }
+
+    /// Cancel all transfers pending on a pipe.
+    pub fn abort_pipe(&self, pipe_index: u8) -> Result<()> {
+        self.inner.abort_pipe(pipe_index).map_err(Error::from)
+    }
+
+    /// Issue a control transfer on the default pipe, reading at most `buf.len()`
+    /// bytes of data from the device.
+    /// 
+    /// A zero `timeout` waits forever, except on Windows, where WinUSB gives
+    /// up on control transfers after five seconds.
+    pub fn control_in(&self, request: ControlRequest, buf: &mut [u8], timeout: Duration) 
+        -> Result<usize> 
+    {
+        check_control(&request, Direction::In, buf.len())?;
+        let pending = self.trace.submit_control(&request, buf.len(), &[]);
+        let ans = self.inner.control_in(request, buf, timeout).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|&len| &buf[..len]));
+        ans
+    }
+
+    /// Issue a control transfer on the default pipe, sending all of `buf`
+    /// as the data stage.
+    /// 
+    /// A zero `timeout` waits forever, except on Windows, where WinUSB gives
+    /// up on control transfers after five seconds.
+    pub fn control_out(&self, request: ControlRequest, buf: &[u8], timeout: Duration) 
+        -> Result<usize> 
+    {
+        check_control(&request, Direction::Out, buf.len())?;
+        let pending = self.trace.submit_control(&request, buf.len(), buf);
+        let ans = self.inner.control_out(request, buf, timeout).map_err(Error::from);
+        pending.complete(ans.as_ref().map(|_| &[][..]));
+        ans
+    }
+}
+
+/// A read from a pipe in progress, see `Handle::read_pipe_async`.
+#[derive(Debug)]
+#[must_use = "dropping a pipe future cancels its transfer"]
+pub struct ReadPipe<'a> {
+    inner: sys::PipeFuture<'a>,
+    pending: Option<trace::Pending>,
+}
+
+impl Future for ReadPipe<'_> {
+    type Output = Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ans = ready!(Pin::new(&mut self.inner).poll(cx)).map(|(mut buf, len)| {
+            buf.truncate(len);
+            buf
+        }).map_err(Error::from);
+        if let Some(pending) = self.pending.take() {
+            pending.complete(ans.as_deref());
+        }
+        Poll::Ready(ans)
     }
 }
 
+/// A write to a pipe in progress, see `Handle::write_pipe_async`.
+#[derive(Debug)]
+#[must_use = "dropping a pipe future cancels its transfer"]
+pub struct WritePipe<'a> {
+    inner: sys::PipeFuture<'a>,
+    pending: Option<trace::Pending>,
+}
+
+impl Future for WritePipe<'_> {
+    type Output = Result<usize>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ans = ready!(Pin::new(&mut self.inner).poll(cx)).map(|(_, len)| len).map_err(Error::from);
+        if let Some(pending) = self.pending.take() {
+            pending.complete(ans.as_ref().map(|_| &[][..]));
+        }
+        Poll::Ready(ans)
+    }
+}
+
+/// A pipe of an interface, see `Handle::pipes`.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct PipeInfo {
+    /// Endpoint address, bit 7 set for IN pipes; the `pipe_index` of transfers
+    pub endpoint_address: u8,
+    pub transfer_type: TransferType,
+    /// Largest packet of the endpoint in bytes, without the additional
+    /// transactions of high-bandwidth endpoints
+    pub max_packet_size: u16,
+    /// Polling interval of interrupt and isochronous endpoints, encoded
+    /// as in the endpoint descriptor
+    pub interval: u8,
+}
+
+impl PipeInfo {
+    pub fn direction(&self) -> Direction {
+        if self.endpoint_address & 0x80 != 0 { Direction::In } else { Direction::Out }
+    }
+
+    /// Time between two transactions on an interrupt or isochronous pipe of
+    /// a device running at `speed`; zero for bulk and control pipes, which
+    /// get whatever bandwidth is left.
+    pub fn polling_interval(&self, speed: Speed) -> Duration {
+        // exponents of 2 from 1 to 16, in frames or microframes
+        let exponent = self.interval.clamp(1, 16) as u32 - 1;
+        match (self.transfer_type, speed) {
+            (TransferType::Interrupt, Speed::Low | Speed::Full | Speed::Unknown) =>
+                Duration::from_millis(self.interval.max(1) as u64),
+            (TransferType::Isochronous, Speed::Low | Speed::Full | Speed::Unknown) =>
+                Duration::from_millis(1 << exponent),
+            (TransferType::Interrupt | TransferType::Isochronous, Speed::High | Speed::Super) =>
+                Duration::from_micros(125 << exponent),
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+impl From<&EndpointDescriptor> for PipeInfo {
+    fn from(src: &EndpointDescriptor) -> PipeInfo {
+        PipeInfo {
+            endpoint_address: src.endpoint_address,
+            transfer_type: src.transfer_type(),
+            max_packet_size: src.max_packet_size & 0x07FF,
+            interval: src.interval,
+        }
+    }
+}
+
+/// A packet of an isochronous transfer, see `Handle::read_isochronous`.
+#[derive(Debug)]
+pub struct IsoPacket {
+    /// Where the packet starts in the buffer of the transfer
+    pub offset: usize,
+    /// Bytes received or sent
+    pub length: usize,
+    /// Whether the packet made it; failed packets do not fail the transfer
+    pub status: Result<()>,
+}
+
+/// An `Iterator` over the pipes of an interface.
+#[derive(Debug, Clone)]
+pub struct Pipes {
+    iter: std::vec::IntoIter<PipeInfo>,
+}
+
+impl Iterator for Pipes {
+    type Item = PipeInfo;
+
+    fn next(&mut self) -> Option<PipeInfo> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Pipes {}
+
+impl FusedIterator for Pipes {}
+
+fn check_isochronous(len: usize, packets: usize) -> Result<()> {
+    if packets == 0 || !len.is_multiple_of(packets) {
+        return Err(Error::Other(io::Error::new(io::ErrorKind::InvalidInput, "buffer not split evenly into packets")))
+    }
+    Ok(())
+}
+
+fn check_control(request: &ControlRequest, direction: Direction, len: usize) -> Result<()> {
+    if request.direction != direction {
+        return Err(Error::Other(io::Error::new(io::ErrorKind::InvalidInput, "control request has the wrong direction")))
+    }
+    if len > u16::MAX as usize {
+        return Err(Error::Other(io::Error::new(io::ErrorKind::InvalidInput, "control data stage longer than 65535 bytes")))
+    }
+    Ok(())
+}
+
+/// Language ID of English (United States), used by most devices.
+pub const LANGUAGE_ID_EN_US: u16 = 0x0409;
+
 /// A `DeviceDescriptor` describing what this name represents in the USB specification
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct DeviceDescriptor {
@@ -0,0 +1,91 @@
+//! Physical position of a device in the USB tree.
+use core::{fmt, str::FromStr};
+use std::io;
+use crate::{Device, Error, Handle, Result};
+
+/// Bus number and chain of hub ports leading to a device.
+///
+/// Formats and parses like Linux sysfs names devices: `1-3.2` is the device
+/// on port 2 of the hub on port 3 of the root hub of bus 1, and `usb1` is that
+/// root hub itself. A location stays the same as long as the device is plugged
+/// into the same port, so it can be saved to find the device again later.
+///
+/// ```no_run
+/// use nihao_usb::Location;
+/// let slot: Location = "1-3.2".parse()?;
+/// let handle = slot.open()?;
+/// println!("{:?}", handle.serial_number()?);
+/// # Ok::<(), nihao_usb::Error>(())
+/// ```
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Location {
+    bus_number: u8,
+    port_numbers: Vec<u8>,
+}
+
+impl Location {
+    pub fn new(bus_number: u8, port_numbers: &[u8]) -> Location {
+        Location { bus_number, port_numbers: port_numbers.to_vec() }
+    }
+
+    #[inline]
+    pub fn bus_number(&self) -> u8 {
+        self.bus_number
+    }
+
+    /// Ports from the root hub down to the device; empty for a root hub.
+    #[inline]
+    pub fn port_numbers(&self) -> &[u8] {
+        &self.port_numbers
+    }
+
+    /// Find the device currently plugged in at this location.
+    pub fn device<'device>(&self) -> Result<Device<'device>> {
+        for device in crate::devices()? {
+            let device = device?;
+            if device.location().ok().as_ref() == Some(self) {
+                return Ok(device)
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    /// Open the device currently plugged in at this location.
+    pub fn open<'handle>(&self) -> Result<Handle<'handle>> {
+        self.device()?.open()
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.port_numbers.is_empty() {
+            return write!(f, "usb{}", self.bus_number)
+        }
+        write!(f, "{}-", self.bus_number)?;
+        for (i, port) in self.port_numbers.iter().enumerate() {
+            if i != 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Location {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Location> {
+        let invalid = || Error::Other(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid USB location `{}`", s)));
+        if let Some(bus) = s.strip_prefix("usb") {
+            let bus_number = bus.parse().map_err(|_| invalid())?;
+            return Ok(Location { bus_number, port_numbers: Vec::new() })
+        }
+        let (bus, ports) = s.split_once('-').ok_or_else(invalid)?;
+        let bus_number = bus.parse().map_err(|_| invalid())?;
+        let port_numbers = ports.split('.')
+            .map(|port| port.parse().map_err(|_| invalid()))
+            .collect::<Result<Vec<u8>>>()?;
+        Ok(Location { bus_number, port_numbers })
+    }
+}
@@ -0,0 +1,78 @@
+//! How transfers on a pipe treat packet boundaries and stalls.
+use std::io;
+
+/// Behavior of transfers on one pipe, see `Handle::set_pipe_policy`.
+///
+/// These are the pipe policies of WinUSB. The Linux and USB/IP backends apply
+/// them through URB flags or by acting like WinUSB would, so a policy means
+/// the same on every backend. Fields for the other direction than the one of
+/// the pipe are ignored, as WinUSB only allows setting them on one direction.
+///
+/// ```no_run
+/// use nihao_usb::PipePolicy;
+/// # let handle: nihao_usb::Handle = unimplemented!();
+/// // commands of a multiple of 64 bytes end with a zero-length packet
+/// handle.set_pipe_policy(0x01, PipePolicy { short_packet_terminate: true, ..Default::default() })?;
+/// # Ok::<(), nihao_usb::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct PipePolicy {
+    /// OUT pipes: end writes of a multiple of the maximum packet size with a
+    /// zero-length packet, so that the device sees where the write ends.
+    /// `ZERO_PACKET` URB flag on Linux.
+    pub short_packet_terminate: bool,
+    /// IN pipes: hand buffers to the host controller without WinUSB queueing
+    /// them. Reads must then be a multiple of the maximum packet size, which
+    /// every backend checks.
+    pub raw_io: bool,
+    /// IN pipes: clear a stall of the endpoint right after the transfer that
+    /// stalled fails, so the next transfer goes through.
+    pub auto_clear_stall: bool,
+    /// IN pipes: let a read shorter than the packet received complete with
+    /// what fits. WinUSB keeps the rest of the packet for the next read; the
+    /// other backends have nowhere to keep it and fail with `Error::Overflow`
+    /// either way, so only buffers of a multiple of the maximum packet size
+    /// behave the same everywhere.
+    pub allow_partial_reads: bool,
+}
+
+/// The policy every pipe starts with, as with WinUSB.
+impl Default for PipePolicy {
+    fn default() -> PipePolicy {
+        PipePolicy {
+            short_packet_terminate: false,
+            raw_io: false,
+            auto_clear_stall: false,
+            allow_partial_reads: true,
+        }
+    }
+}
+
+/// A policy with the maximum packet size of the pipe it is set on, kept by
+/// backends applying policies themselves.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Applied {
+    pub policy: PipePolicy,
+    pub max_packet_size: u16,
+}
+
+impl Applied {
+    pub fn check_read(&self, len: usize) -> io::Result<()> {
+        if self.policy.raw_io && self.max_packet_size != 0 && !len.is_multiple_of(self.max_packet_size as usize) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "raw I/O reads must be a multiple of the maximum packet size"))
+        }
+        Ok(())
+    }
+
+    /// Whether a write of `len` bytes needs a zero-length packet to end it.
+    pub fn zero_packet_after(&self, len: usize) -> bool {
+        self.policy.short_packet_terminate && self.max_packet_size != 0
+            && len != 0 && len.is_multiple_of(self.max_packet_size as usize)
+    }
+
+    /// Whether a failed transfer stalled and the stall is to be cleared.
+    pub fn clears_stall(&self, error: &io::Error) -> bool {
+        self.policy.auto_clear_stall && error.kind() == io::ErrorKind::BrokenPipe
+    }
+}
@@ -0,0 +1,175 @@
+//! Several transfers kept in flight on one pipe.
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker, ready}};
+use std::{collections::VecDeque, sync::Arc, task::Wake, thread::{self, Thread}};
+use crate::{sys, trace, Error, Handle, Result};
+
+/// Keeps up to `depth` transfers submitted on a pipe, delivering them in order.
+///
+/// One transfer at a time leaves the pipe idle between the end of a transfer
+/// and the submission of the next one, which is enough for a device to drop
+/// data it cannot buffer. A queue hands the buffers to the operating system
+/// ahead of time instead: overlapped requests on Windows, asynchronous URBs
+/// on Linux. The host controller works through them in order, and so does
+/// the queue when giving them back.
+///
+/// Once `depth` transfers are in flight, `push` waits for the oldest one to
+/// complete before submitting another. A reader that falls behind thus makes
+/// the device wait, and a writer cannot get ahead of the device by more than
+/// `depth` buffers.
+///
+/// ```no_run
+/// # fn consume(_: &[u8]) {}
+/// # let handle: nihao_usb::Handle = unimplemented!();
+/// let mut queue = handle.transfer_queue(0x83, 4);
+/// for _ in 0..4 {
+///     queue.push(vec![0; 4096])?;
+/// }
+/// while let Some(buf) = queue.pop() {
+///     let mut buf = buf?;
+///     consume(&buf);
+///     buf.resize(4096, 0);
+///     queue.push(buf)?;
+/// }
+/// # Ok::<(), nihao_usb::Error>(())
+/// ```
+///
+/// Dropping the queue cancels the transfers still in flight.
+#[derive(Debug)]
+#[must_use = "dropping a transfer queue cancels its transfers"]
+pub struct TransferQueue<'a> {
+    handle: &'a Handle<'a>,
+    pipe_index: u8,
+    depth: usize,
+    transfers: VecDeque<Transfer<'a>>,
+}
+
+impl<'a> TransferQueue<'a> {
+    pub(crate) fn new(handle: &'a Handle<'a>, pipe_index: u8, depth: usize) -> TransferQueue<'a> {
+        assert!(depth > 0, "transfer queue of depth zero");
+        TransferQueue { handle, pipe_index, depth, transfers: VecDeque::with_capacity(depth) }
+    }
+
+    /// Endpoint address of the pipe, bit 7 set for IN pipes.
+    pub fn pipe_index(&self) -> u8 {
+        self.pipe_index
+    }
+
+    /// Number of transfers kept in flight at most.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Number of transfers in flight.
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+
+    /// Whether `push` would wait for a transfer to complete first.
+    pub fn is_full(&self) -> bool {
+        self.transfers.len() >= self.depth
+    }
+
+    /// Submit a transfer: on an IN pipe, `buf` is filled with up to
+    /// `buf.len()` bytes; on an OUT pipe, all of `buf` is sent.
+    ///
+    /// If the queue is full, this waits for the oldest transfer first and
+    /// returns its buffer, like `pop` would. Should that transfer have failed,
+    /// its error is returned and `buf` is not submitted.
+    pub fn push(&mut self, buf: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let done = if self.is_full() { self.pop().transpose()? } else { None };
+        self.transfers.push_back(Transfer::submit(self.handle, self.pipe_index, buf));
+        Ok(done)
+    }
+
+    /// Wait for the oldest transfer in flight, or return `None` if there is none.
+    ///
+    /// Its buffer comes back truncated to the bytes transferred: the data
+    /// received on an IN pipe, the data written on an OUT pipe. Either way it
+    /// can be resized and pushed again.
+    pub fn pop(&mut self) -> Option<Result<Vec<u8>>> {
+        let ans = block_on(self.transfers.front_mut()?);
+        self.transfers.pop_front();
+        Some(ans)
+    }
+
+    /// Like `pop`, without blocking the thread: the task is woken when the
+    /// oldest transfer completes.
+    pub fn poll_pop(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Vec<u8>>>> {
+        let front = match self.transfers.front_mut() {
+            Some(front) => front,
+            None => return Poll::Ready(None),
+        };
+        let ans = ready!(Pin::new(front).poll(cx));
+        self.transfers.pop_front();
+        Poll::Ready(Some(ans))
+    }
+}
+
+/// Yields the transfers in flight in order, as `pop` does, without submitting more.
+impl Iterator for TransferQueue<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pop()
+    }
+}
+
+// a pipe transfer in either direction, keeping its buffer
+#[derive(Debug)]
+struct Transfer<'a> {
+    inner: sys::PipeFuture<'a>,
+    pending: Option<trace::Pending>,
+    direction_in: bool,
+}
+
+impl<'a> Transfer<'a> {
+    fn submit(handle: &'a Handle<'a>, pipe_index: u8, buf: Vec<u8>) -> Transfer<'a> {
+        if pipe_index & 0x80 != 0 {
+            let pending = Some(handle.trace.submit_bulk(pipe_index, &[]));
+            Transfer { inner: handle.inner.read_pipe_async(pipe_index, buf), pending, direction_in: true }
+        } else {
+            let pending = Some(handle.trace.submit_bulk(pipe_index, &buf));
+            Transfer { inner: handle.inner.write_pipe_async(pipe_index, buf), pending, direction_in: false }
+        }
+    }
+}
+
+impl Future for Transfer<'_> {
+    type Output = Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ans = ready!(Pin::new(&mut self.inner).poll(cx)).map(|(mut buf, len)| {
+            buf.truncate(len);
+            buf
+        }).map_err(Error::from);
+        if let Some(pending) = self.pending.take() {
+            let data = if self.direction_in { ans.as_deref() } else { ans.as_ref().map(|_| &[][..]) };
+            pending.complete(data);
+        }
+        Poll::Ready(ans)
+    }
+}
+
+/// Run a future to completion on this thread, parking it while pending.
+pub(crate) fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match Pin::new(&mut future).poll(&mut cx) {
+            Poll::Ready(ans) => return ans,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
@@ -1,5 +1,23 @@
-#[cfg(any(windows, doc))]
+#[cfg(windows)]
 pub mod windows;
 
-#[cfg(any(windows, doc))]
-pub use windows::{devices, DeviceList, Devices, DeviceIntoIter, Device, Handle};
+#[cfg(all(windows, not(any(feature = "mock", feature = "usbip"))))]
+pub use windows::{devices, DeviceList, Devices, DeviceIntoIter, Device, DeviceId, Handle, PipeFuture, Monitor};
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+
+#[cfg(all(target_os = "linux", not(any(feature = "mock", feature = "usbip"))))]
+pub use linux::{devices, DeviceList, Devices, DeviceIntoIter, Device, DeviceId, Handle, PipeFuture, Monitor};
+
+#[cfg(feature = "usbip")]
+pub mod usbip;
+
+#[cfg(all(feature = "usbip", not(feature = "mock")))]
+pub use usbip::{devices, DeviceList, Devices, DeviceIntoIter, Device, DeviceId, Handle, PipeFuture, Monitor};
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
+#[cfg(feature = "mock")]
+pub use mock::{devices, DeviceList, Devices, DeviceIntoIter, Device, DeviceId, Handle, PipeFuture, Monitor};
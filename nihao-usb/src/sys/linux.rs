@@ -0,0 +1,306 @@
+pub mod sysfs;
+pub mod usbfs;
+pub mod urb;
+pub mod uevent;
+pub mod kernel_driver;
+
+pub use urb::UrbFuture as PipeFuture;
+pub use uevent::Monitor;
+
+use core::{iter::FusedIterator, marker::PhantomData};
+use std::io;
+use std::time::Duration;
+
+pub fn devices<'list>() -> io::Result<DeviceList<'list>> {
+    let info_list = sysfs::ListOptions::new().list()?;
+    Ok(info_list.into())
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceList<'list> {
+    info_list: sysfs::InfoList,
+    _lifetime_of_list: PhantomData<&'list ()>,
+}
+
+impl<'list> From<sysfs::InfoList> for DeviceList<'list> {
+    fn from(src: sysfs::InfoList) -> DeviceList<'list> {
+        DeviceList { info_list: src, _lifetime_of_list: PhantomData }
+    }
+}
+
+impl<'list> DeviceList<'list> {
+    pub fn iter<'iter>(&self) -> Devices<'iter> {
+        Devices { iter: self.info_list.iter(), _lifetime_of_iter: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.info_list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.info_list.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceIntoIter<'iter> {
+    iter: Devices<'iter>,
+}
+
+impl<'list> IntoIterator for DeviceList<'list> {
+    type Item = io::Result<Device<'list>>;
+    type IntoIter = DeviceIntoIter<'list>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DeviceIntoIter { iter: self.iter() }
+    }
+}
+
+impl<'iter> Iterator for DeviceIntoIter<'iter> {
+    type Item = io::Result<Device<'iter>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Devices<'iter> {
+    iter: sysfs::InfoIter,
+    _lifetime_of_iter: PhantomData<&'iter ()>,
+}
+
+impl<'iter> Iterator for Devices<'iter> {
+    type Item = io::Result<Device<'iter>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|info| Ok(Device { info, _lifetime_of_device: PhantomData }))
+    }
+}
+
+impl FusedIterator for Devices<'_> {}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Device<'device> {
+    info: sysfs::Info,
+    _lifetime_of_device: PhantomData<&'device ()>,
+}
+
+impl<'device> Device<'device> {
+    pub fn open<'handle>(&self) -> io::Result<Handle<'handle>> {
+        let info = self.info.clone();
+        usbfs::UsbFs::open(&self.info).map(|usbfs| Handle { usbfs, info })
+    }
+
+    pub fn info(&self) -> &sysfs::Info {
+        &self.info
+    }
+
+    /// Read identifiers from sysfs, which caches descriptors and the serial
+    /// number of every device.
+    pub fn device_info(&self) -> crate::DeviceInfo {
+        let mut ans = self.info.descriptors().ok()
+            .and_then(|buf| crate::DeviceInfo::from_descriptors(&buf).ok())
+            .unwrap_or_default();
+        ans.serial_number = self.info.attr("serial").ok();
+        ans.port_path = Some(port_path(&self.info));
+        ans
+    }
+
+    pub fn id(&self) -> DeviceId {
+        DeviceId {
+            port_path: port_path(&self.info),
+            bus_number: self.info.bus_number(),
+            device_address: self.info.device_address(),
+        }
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        Ok(port_path(&self.info).parse()?)
+    }
+}
+
+// sysfs names devices by bus and hub ports, like `1-3.2`
+fn port_path(info: &sysfs::Info) -> String {
+    info.sysfs_path().file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// A device is identified by the port it is plugged into and its address on 
+/// the bus, which the kernel assigns anew on every enumeration.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct DeviceId {
+    port_path: String,
+    bus_number: u8,
+    device_address: u8,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Handle<'handle> {
+    usbfs: usbfs::UsbFs<'handle>,
+    info: sysfs::Info,
+}
+
+impl Handle<'_> {
+    pub fn device_descriptor(&self) -> io::Result<crate::DeviceDescriptor> {
+        self.usbfs.device_descriptor()
+    }
+
+    pub fn config_descriptor(&self, index: u8) -> io::Result<crate::ConfigDescriptor> {
+        self.usbfs.config_descriptor(index)
+    }
+
+    pub fn get_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16, buf: &mut [u8])
+        -> io::Result<usize>
+    {
+        self.usbfs.get_descriptor(descriptor_type, index, language_id, buf)
+    }
+
+    pub fn speed(&self) -> io::Result<crate::Speed> {
+        self.usbfs.speed()
+    }
+
+    // the kernel keeps the active configuration and alternate settings in sysfs
+    pub fn pipes(&self) -> io::Result<Vec<crate::PipeInfo>> {
+        let configuration_value = match self.info.configuration_value()? {
+            Some(value) => value,
+            None => return Ok(Vec::new()),
+        };
+        let interface_number = self.usbfs.interface_number();
+        let alternate_setting = self.info.alternate_setting(configuration_value, interface_number)?;
+        let buf = self.usbfs.descriptors()?;
+        let (_, configs) = crate::descriptor::split_cached(&buf)?;
+        for raw in configs {
+            let config = crate::ConfigDescriptor::parse(raw)?;
+            if config.configuration_value != configuration_value {
+                continue
+            }
+            return Ok(config.alt_setting(interface_number, alternate_setting)
+                .map(|alt| alt.endpoints.iter().map(crate::PipeInfo::from).collect())
+                .unwrap_or_default())
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, "active configuration not described"))
+    }
+
+    pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self.usbfs.read_pipe(pipe_index, buf)
+    }
+
+    pub fn write_pipe(&self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.usbfs.write_pipe(pipe_index, buf)
+    }
+
+    pub fn read_pipe_timeout(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.usbfs.read_pipe_timeout(pipe_index, buf, timeout)
+    }
+
+    pub fn write_pipe_timeout(&self, pipe_index: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.usbfs.write_pipe_timeout(pipe_index, buf, timeout)
+    }
+
+    pub fn read_isochronous(&self, pipe_index: u8, buf: &mut [u8], packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        let future = self.usbfs.submit_isochronous(pipe_index, vec![0; buf.len()], packets);
+        let (data, packets) = crate::queue::block_on(future)?;
+        buf.copy_from_slice(&data);
+        Ok(packets)
+    }
+
+    pub fn write_isochronous(&self, pipe_index: u8, buf: &[u8], packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        let future = self.usbfs.submit_isochronous(pipe_index, buf.to_vec(), packets);
+        crate::queue::block_on(future).map(|(_, packets)| packets)
+    }
+
+    pub fn read_pipe_async<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> PipeFuture<'a> {
+        self.usbfs.submit_bulk(pipe_index, buf)
+    }
+
+    pub fn write_pipe_async<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> PipeFuture<'a> {
+        self.usbfs.submit_bulk(pipe_index, buf)
+    }
+
+    pub fn set_timeout(&self, pipe_index: u8, timeout: Duration) -> io::Result<()> {
+        self.usbfs.set_timeout(pipe_index, timeout)
+    }
+
+    pub fn set_pipe_policy(&self, pipe_index: u8, policy: crate::PipePolicy) -> io::Result<()> {
+        let max_packet_size = self.pipes()?.into_iter()
+            .find(|pipe| pipe.endpoint_address == pipe_index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such pipe"))?
+            .max_packet_size;
+        self.usbfs.set_pipe_policy(pipe_index, policy, max_packet_size)
+    }
+
+    pub fn pipe_policy(&self, pipe_index: u8) -> io::Result<crate::PipePolicy> {
+        self.usbfs.pipe_policy(pipe_index)
+    }
+
+    pub fn claim_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.usbfs.claim_interface(interface_number)
+    }
+
+    pub fn release_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.usbfs.release_interface(interface_number)
+    }
+
+    pub fn kernel_driver_active(&self, interface_number: u8) -> io::Result<bool> {
+        self.usbfs.kernel_driver_active(interface_number)
+    }
+
+    pub fn detach_kernel_driver(&self, interface_number: u8) -> io::Result<()> {
+        self.usbfs.detach_kernel_driver(interface_number)
+    }
+
+    pub fn attach_kernel_driver(&self, interface_number: u8) -> io::Result<()> {
+        self.usbfs.attach_kernel_driver(interface_number)
+    }
+
+    pub fn set_auto_detach_kernel_driver(&self, enable: bool) -> io::Result<()> {
+        self.usbfs.set_auto_detach_kernel_driver(enable)
+    }
+
+    pub fn set_alternate_setting(&self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        self.usbfs.set_alternate_setting(interface_number, alternate_setting)
+    }
+
+    pub fn associated_interface<'a>(&self, index: u8) -> io::Result<Handle<'a>> {
+        let info = self.info.clone();
+        self.usbfs.associated_interface(index).map(|usbfs| Handle { usbfs, info })
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        Ok(port_path(&self.info).parse()?)
+    }
+
+    pub fn reset_device(&self) -> io::Result<()> {
+        self.usbfs.reset_device()
+    }
+
+    pub fn flush_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.usbfs.flush_pipe(pipe_index)
+    }
+
+    pub fn reset_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.usbfs.reset_pipe(pipe_index)
+    }
+
+    pub fn abort_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.usbfs.abort_pipe(pipe_index)
+    }
+
+    pub fn control_in(&self, request: crate::ControlRequest, buf: &mut [u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        self.usbfs.control_in(&request, buf, timeout)
+    }
+
+    pub fn control_out(&self, request: crate::ControlRequest, buf: &[u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        self.usbfs.control_out(&request, buf, timeout)
+    }
+}
@@ -0,0 +1,135 @@
+//! Kernel drivers bound to interfaces, like `usb-storage`, `cdc_acm` or
+//! `usbhid`, which keep usbfs from claiming those interfaces.
+use std::{fs, io, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}};
+use super::sysfs;
+
+/// Unbinding and binding kernel drivers, done by usbfs ioctls on a device node.
+pub trait DriverIoctl {
+    /// Unbind the kernel driver of an interface.
+    fn disconnect(&self, interface_number: u8) -> io::Result<()>;
+
+    /// Let the kernel bind a driver to an interface again.
+    fn connect(&self, interface_number: u8) -> io::Result<()>;
+}
+
+impl<T: DriverIoctl + ?Sized> DriverIoctl for Arc<T> {
+    fn disconnect(&self, interface_number: u8) -> io::Result<()> {
+        (**self).disconnect(interface_number)
+    }
+
+    fn connect(&self, interface_number: u8) -> io::Result<()> {
+        (**self).connect(interface_number)
+    }
+}
+
+/// The driver usbfs binds to interfaces it claims, which does not count as
+/// a kernel driver.
+const USBFS_DRIVER: &str = "usbfs";
+
+/// Kernel drivers of the interfaces of one device, read from sysfs.
+///
+/// With automatic detaching enabled, the driver of an interface is detached
+/// before claiming it and attached again after releasing it.
+#[derive(Debug)]
+pub struct KernelDrivers<I> {
+    ioctl: I,
+    // directory of the device in sysfs, e.g. `/sys/bus/usb/devices/1-3.2`
+    sysfs_path: PathBuf,
+    auto_detach: AtomicBool,
+    // interfaces detached automatically, to be attached again
+    detached: Mutex<Vec<u8>>,
+}
+
+impl<I: DriverIoctl> KernelDrivers<I> {
+    pub fn new(ioctl: I, sysfs_path: PathBuf) -> KernelDrivers<I> {
+        KernelDrivers { ioctl, sysfs_path, auto_detach: AtomicBool::new(false), detached: Mutex::new(Vec::new()) }
+    }
+
+    /// Name of the driver bound to an interface of the active configuration,
+    /// if any; usbfs counts as a driver here.
+    pub fn driver(&self, interface_number: u8) -> io::Result<Option<String>> {
+        let configuration = sysfs::read_attr(&self.sysfs_path, "bConfigurationValue")?;
+        if configuration.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "device is not configured"))
+        }
+        let name = self.sysfs_path.file_name().unwrap_or_default().to_string_lossy();
+        // interfaces are named by device, configuration and number, like `1-3.2:1.0`
+        let interface_path = self.sysfs_path.with_file_name(
+            format!("{}:{}.{}", name, configuration, interface_number));
+        if !interface_path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such interface"))
+        }
+        match fs::read_link(interface_path.join("driver")) {
+            Ok(target) => Ok(target.file_name().map(|name| name.to_string_lossy().into_owned())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Name of the kernel driver bound to an interface, if any.
+    pub fn kernel_driver(&self, interface_number: u8) -> io::Result<Option<String>> {
+        Ok(self.driver(interface_number)?.filter(|name| name != USBFS_DRIVER))
+    }
+
+    pub fn is_active(&self, interface_number: u8) -> io::Result<bool> {
+        Ok(self.kernel_driver(interface_number)?.is_some())
+    }
+
+    /// Unbind the kernel driver of an interface; fails with
+    /// `io::ErrorKind::NotFound` if none is bound.
+    pub fn detach(&self, interface_number: u8) -> io::Result<()> {
+        let driver = self.kernel_driver(interface_number)?.ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound, format!("no kernel driver bound to interface {}", interface_number)))?;
+        self.ioctl.disconnect(interface_number).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => io::Error::new(io::ErrorKind::PermissionDenied, format!(
+                "no permission to detach kernel driver `{}` from interface {}; \
+                write access to the device node is needed", driver, interface_number)),
+            _ => e,
+        })
+    }
+
+    pub fn attach(&self, interface_number: u8) -> io::Result<()> {
+        self.ioctl.connect(interface_number)
+    }
+
+    #[inline]
+    pub fn sysfs_path(&self) -> &Path {
+        &self.sysfs_path
+    }
+
+    pub fn set_auto_detach(&self, enable: bool) {
+        self.auto_detach.store(enable, Ordering::SeqCst)
+    }
+
+    pub fn auto_detach(&self) -> bool {
+        self.auto_detach.load(Ordering::SeqCst)
+    }
+
+    fn detached(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.detached.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Detach the kernel driver of an interface about to be claimed, if
+    /// detaching automatically.
+    pub fn before_claim(&self, interface_number: u8) -> io::Result<()> {
+        if !self.auto_detach() || !self.is_active(interface_number)? {
+            return Ok(())
+        }
+        self.detach(interface_number)?;
+        self.detached().push(interface_number);
+        Ok(())
+    }
+
+    /// Attach the kernel driver of a released interface again, if it was
+    /// detached automatically.
+    pub fn after_release(&self, interface_number: u8) -> io::Result<()> {
+        let mut detached = self.detached();
+        match detached.iter().position(|&i| i == interface_number) {
+            Some(index) => {
+                detached.remove(index);
+                self.attach(interface_number)
+            },
+            None => Ok(()),
+        }
+    }
+}
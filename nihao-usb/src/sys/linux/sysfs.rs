@@ -0,0 +1,226 @@
+use core::iter::FusedIterator;
+use std::{fs, io, sync::Arc};
+use std::path::{Path, PathBuf};
+
+/// Default mount point of USB devices in the sysfs tree.
+pub const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// Default root of usbfs device nodes, each named `BBB/DDD` after bus number
+/// and device address.
+pub const DEVFS_USB: &str = "/dev/bus/usb";
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct ListOptions {
+    sysfs_root: PathBuf,
+    devfs_root: PathBuf,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ListOptions {
+    #[inline]
+    pub fn new() -> Self {
+        ListOptions {
+            sysfs_root: PathBuf::from(SYSFS_USB_DEVICES),
+            devfs_root: PathBuf::from(DEVFS_USB),
+        }
+    }
+
+    /// Enumerate devices under another directory laid out like `/sys/bus/usb/devices`.
+    #[inline]
+    pub fn sysfs_root<P: AsRef<Path>>(&mut self, sysfs_root: P) -> &mut Self {
+        self.sysfs_root = sysfs_root.as_ref().to_path_buf();
+        self
+    }
+
+    /// Open device nodes under another directory laid out like `/dev/bus/usb`.
+    #[inline]
+    pub fn devfs_root<P: AsRef<Path>>(&mut self, devfs_root: P) -> &mut Self {
+        self.devfs_root = devfs_root.as_ref().to_path_buf();
+        self
+    }
+
+    /// Take a snapshot of all USB devices currently present.
+    ///
+    /// A missing sysfs root is treated as a system without any USB bus,
+    /// thus an empty list is returned.
+    pub fn list(&self) -> io::Result<InfoList> {
+        let read_dir = match fs::read_dir(&self.sysfs_root) {
+            Ok(read_dir) => read_dir,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                return Ok(InfoList::from_vec(Vec::new())),
+            Err(e) => return Err(e),
+        };
+        let mut infos = Vec::new();
+        for entry in read_dir {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            // interfaces are listed as `1-3.2:1.0` next to their devices
+            if name.contains(':') {
+                continue;
+            }
+            let sysfs_path = self.sysfs_root.join(name);
+            // devices being unplugged during enumeration lose their attributes
+            let (bus_number, device_address) = match (
+                read_attr_u8(&sysfs_path, "busnum"),
+                read_attr_u8(&sysfs_path, "devnum"),
+            ) {
+                (Ok(busnum), Ok(devnum)) => (busnum, devnum),
+                _ => continue,
+            };
+            let dev_path = self.devfs_root
+                .join(format!("{:03}", bus_number))
+                .join(format!("{:03}", device_address));
+            infos.push(Info {
+                sysfs_path,
+                dev_path,
+                bus_number,
+                device_address,
+            });
+        }
+        infos.sort_by(|a, b| a.sysfs_path.cmp(&b.sysfs_path));
+        Ok(InfoList::from_vec(infos))
+    }
+}
+
+/// A snapshot of the sysfs device tree.
+#[derive(Debug, Clone)]
+pub struct InfoList {
+    infos: Arc<[Info]>,
+}
+
+impl InfoList {
+    fn from_vec(infos: Vec<Info>) -> Self {
+        InfoList { infos: infos.into() }
+    }
+
+    #[inline]
+    pub fn iter(&self) -> InfoIter {
+        InfoIter { infos: self.infos.clone(), iter_index: 0 }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.infos.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.infos.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InfoIter {
+    infos: Arc<[Info]>,
+    iter_index: usize,
+}
+
+impl Iterator for InfoIter {
+    type Item = Info;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let info = self.infos.get(self.iter_index)?;
+        self.iter_index += 1;
+        Some(info.clone())
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.infos.len() - self.iter_index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for InfoIter {}
+
+impl FusedIterator for InfoIter {}
+
+/// A USB device found in sysfs; the device node is not opened.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Info {
+    sysfs_path: PathBuf,
+    dev_path: PathBuf,
+    bus_number: u8,
+    device_address: u8,
+}
+
+impl Info {
+    /// Directory of this device in sysfs, e.g. `/sys/bus/usb/devices/1-3.2`.
+    #[inline]
+    pub fn sysfs_path(&self) -> &Path {
+        &self.sysfs_path
+    }
+
+    /// The usbfs device node, e.g. `/dev/bus/usb/001/005`.
+    #[inline]
+    pub fn dev_path(&self) -> &Path {
+        &self.dev_path
+    }
+
+    #[inline]
+    pub fn bus_number(&self) -> u8 {
+        self.bus_number
+    }
+
+    #[inline]
+    pub fn device_address(&self) -> u8 {
+        self.device_address
+    }
+
+    /// Raw descriptors as cached by the kernel: the device descriptor
+    /// followed by all configuration descriptors.
+    ///
+    /// Reading this attribute does not need access rights to the device node.
+    pub fn descriptors(&self) -> io::Result<Vec<u8>> {
+        fs::read(self.sysfs_path.join("descriptors"))
+    }
+
+    /// Read a sysfs attribute of this device as a trimmed string.
+    pub fn attr(&self, name: &str) -> io::Result<String> {
+        read_attr(&self.sysfs_path, name)
+    }
+
+    /// Value of the active configuration, `None` if the device is unconfigured.
+    pub fn configuration_value(&self) -> io::Result<Option<u8>> {
+        let value = self.attr("bConfigurationValue")?;
+        if value.is_empty() {
+            return Ok(None)
+        }
+        value.parse().map(Some).map_err(|_| invalid_attr("bConfigurationValue"))
+    }
+
+    /// Current alternate setting of an interface in the active configuration.
+    pub fn alternate_setting(&self, configuration_value: u8, interface_number: u8) -> io::Result<u8> {
+        let name = self.sysfs_path.file_name().unwrap_or_default().to_string_lossy();
+        // interfaces are named like `1-3.2:1.0`, by configuration and number
+        let interface = format!("{}:{}.{}", name, configuration_value, interface_number);
+        let value = read_attr(&self.sysfs_path.with_file_name(interface), "bAlternateSetting")?;
+        value.trim_start().parse().map_err(|_| invalid_attr("bAlternateSetting"))
+    }
+}
+
+fn invalid_attr(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected value of sysfs attribute {}", name))
+}
+
+pub(crate) fn read_attr(sysfs_path: &Path, name: &str) -> io::Result<String> {
+    let mut s = fs::read_to_string(sysfs_path.join(name))?;
+    let trimmed_len = s.trim_end().len();
+    s.truncate(trimmed_len);
+    Ok(s)
+}
+
+fn read_attr_u8(sysfs_path: &Path, name: &str) -> io::Result<u8> {
+    read_attr(sysfs_path, name)?.parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
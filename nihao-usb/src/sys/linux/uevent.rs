@@ -0,0 +1,91 @@
+//! Device change notifications over the kernel uevent netlink socket.
+use core::mem;
+use std::{io, path::Path};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use libc::{c_int, c_void, sockaddr, sockaddr_nl};
+
+// multicast groups of NETLINK_KOBJECT_UEVENT
+const GROUP_KERNEL: u32 = 1;
+const GROUP_UDEV: u32 = 2;
+
+/// Wakes up whenever any device is added to or removed from the system.
+///
+/// If udev runs, this listens to the events udev sends after it processed
+/// a device, so that the device node has its final permissions; otherwise it
+/// listens to the kernel directly.
+#[derive(Debug)]
+pub struct Monitor {
+    socket: OwnedFd,
+}
+
+impl Monitor {
+    pub fn new() -> io::Result<Monitor> {
+        let fd = unsafe { libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_KOBJECT_UEVENT,
+        ) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = if Path::new("/run/udev/control").exists() { GROUP_UDEV } else { GROUP_KERNEL };
+        let ans = unsafe { libc::bind(
+            fd,
+            &addr as *const sockaddr_nl as *const sockaddr,
+            mem::size_of::<sockaddr_nl>() as u32,
+        ) };
+        if ans < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(Monitor { socket })
+    }
+
+    /// Wait until something changed or `timeout` passed; returns whether
+    /// anything changed. Events are not parsed: any of them means a rescan.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_millis().min(c_int::MAX as u128) as c_int,
+            None => -1,
+        };
+        let mut fd = libc::pollfd { fd: self.socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut fd, 1, timeout) } < 0 {
+            let err = io::Error::last_os_error();
+            // a signal arrived; let the caller check for changes anyway
+            return if err.kind() == io::ErrorKind::Interrupted { Ok(true) } else { Err(err) }
+        }
+        if fd.revents == 0 {
+            return Ok(false)
+        }
+        self.drain()?;
+        Ok(true)
+    }
+
+    // Events come in bursts, one per interface and driver binding; read all
+    // queued so that the whole burst causes one rescan.
+    fn drain(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = unsafe { libc::recv(
+                self.socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                0,
+            ) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    // the receive buffer overflowed and events were lost,
+                    // which a rescan makes up for
+                    _ if err.raw_os_error() == Some(libc::ENOBUFS) => continue,
+                    _ => Err(err),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! Asynchronous transfers on usbfs URBs.
+//!
+//! Submitted URBs are reaped by a thread per device node, which stores each
+//! result and wakes the task waiting for it through its `Waker`. The thread
+//! only lives while URBs are pending, and needs no particular executor.
+use core::{cell::UnsafeCell, fmt, future::Future, marker::PhantomData, mem, pin::Pin, ptr};
+use core::task::{Context, Poll, Waker, ready};
+use std::{collections::HashMap, fs, io, thread, sync::{Arc, Mutex, MutexGuard, Weak}};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use libc::{c_int, c_uint, c_void};
+use super::usbfs::{
+    usbdevfs_urb, usbdevfs_iso_packet_desc,
+    USBDEVFS_SUBMITURB, USBDEVFS_DISCARDURB, USBDEVFS_REAPURBNDELAY, USBDEVFS_CLEAR_HALT,
+    USBDEVFS_URB_TYPE_ISO, USBDEVFS_URB_ISO_ASAP,
+};
+
+// the reaper sleeps at most this long, which bounds how late timeouts fire
+const REAPER_TICK: Duration = Duration::from_millis(10);
+
+/// URBs in flight on one device node, keyed by their address.
+pub struct Reaper {
+    file: Weak<fs::File>,
+    state: Mutex<ReaperState>,
+}
+
+#[derive(Default)]
+struct ReaperState {
+    running: bool,
+    pending: HashMap<usize, Pending>,
+}
+
+struct Pending {
+    urb: Arc<Urb>,
+    endpoint: u8,
+    deadline: Option<Instant>,
+    timed_out: bool,
+    // clear the halt of the endpoint before completing with a stall
+    clear_stall: bool,
+}
+
+/// An URB with the buffer it transfers.
+///
+/// The kernel owns both from submitting until reaping; the reaper only reads
+/// the URB after reaping it, and the future only takes the buffer after the
+/// reaper published the result through `completion`.
+struct Urb {
+    raw: UnsafeCell<RawUrb>,
+    buffer: UnsafeCell<Vec<u8>>,
+    completion: Mutex<Completion>,
+}
+
+unsafe impl Send for Urb {}
+unsafe impl Sync for Urb {}
+
+#[derive(Default)]
+struct Completion {
+    result: Option<io::Result<usize>>,
+    waker: Option<Waker>,
+}
+
+/// A `usbdevfs_urb` followed by the packet descriptors of an isochronous URB,
+/// in memory aligned for both.
+struct RawUrb {
+    words: Box<[u64]>,
+}
+
+impl RawUrb {
+    /// An URB transferring `buffer`, in packets of `packet_lengths` if isochronous.
+    fn new(urb_type: u8, endpoint: u8, flags: c_uint, buffer: &mut [u8], packet_lengths: &[c_uint]) -> RawUrb {
+        let urb = usbdevfs_urb {
+            type_: urb_type,
+            endpoint,
+            status: 0,
+            flags,
+            buffer: buffer.as_mut_ptr() as *mut c_void,
+            buffer_length: buffer.len() as c_int,
+            actual_length: 0,
+            start_frame: 0,
+            number_of_packets: packet_lengths.len() as c_int,
+            error_count: 0,
+            signr: 0,
+            usercontext: ptr::null_mut(),
+        };
+        let len = mem::size_of::<usbdevfs_urb>()
+            + mem::size_of::<usbdevfs_iso_packet_desc>() * packet_lengths.len();
+        let mut ans = RawUrb { words: vec![0; len.div_ceil(8)].into_boxed_slice() };
+        unsafe { ptr::write(ans.as_mut_ptr(), urb) };
+        for (desc, &length) in ans.packets_mut().iter_mut().zip(packet_lengths) {
+            desc.length = length;
+        }
+        ans
+    }
+
+    // what the kernel is given and hands back when reaping
+    fn as_mut_ptr(&mut self) -> *mut usbdevfs_urb {
+        self.words.as_mut_ptr() as *mut usbdevfs_urb
+    }
+
+    fn urb(&self) -> &usbdevfs_urb {
+        unsafe { &*(self.words.as_ptr() as *const usbdevfs_urb) }
+    }
+
+    // the descriptors follow the URB, like the flexible array member in C
+    fn packets(&self) -> &[usbdevfs_iso_packet_desc] {
+        let len = self.urb().number_of_packets as usize;
+        unsafe {
+            let first = (self.words.as_ptr() as *const u8).add(mem::size_of::<usbdevfs_urb>());
+            core::slice::from_raw_parts(first as *const usbdevfs_iso_packet_desc, len)
+        }
+    }
+
+    fn packets_mut(&mut self) -> &mut [usbdevfs_iso_packet_desc] {
+        let len = self.urb().number_of_packets as usize;
+        unsafe {
+            let first = (self.words.as_mut_ptr() as *mut u8).add(mem::size_of::<usbdevfs_urb>());
+            core::slice::from_raw_parts_mut(first as *mut usbdevfs_iso_packet_desc, len)
+        }
+    }
+}
+
+impl Urb {
+    fn completion(&self) -> MutexGuard<'_, Completion> {
+        self.completion.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // only once reaped, when the kernel is done with the buffer
+    fn take_buffer(&self) -> Vec<u8> {
+        unsafe { mem::take(&mut *self.buffer.get()) }
+    }
+
+    fn complete(&self, result: io::Result<usize>) {
+        let waker = {
+            let mut completion = self.completion();
+            completion.result = Some(result);
+            completion.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake()
+        }
+    }
+}
+
+impl Reaper {
+    pub fn new(file: &Arc<fs::File>) -> Reaper {
+        Reaper { file: Arc::downgrade(file), state: Mutex::new(ReaperState::default()) }
+    }
+
+    fn state(&self) -> MutexGuard<'_, ReaperState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Submit an URB transferring `buffer`, cancelled if it takes longer than a
+    /// non-zero `timeout`.
+    ///
+    /// `flags` are `USBDEVFS_URB_*` flags; with `clear_stall`, a stall of the
+    /// endpoint is cleared before the URB completes.
+    pub fn submit<'a>(self: &Arc<Self>, urb_type: u8, endpoint: u8, mut buffer: Vec<u8>, timeout: Duration,
+        flags: c_uint, clear_stall: bool) -> UrbFuture<'a>
+    {
+        let raw = RawUrb::new(urb_type, endpoint, flags, &mut buffer, &[]);
+        self.submit_raw(raw, buffer, timeout, clear_stall)
+    }
+
+    /// Submit an isochronous URB, splitting `buffer` into `packets` packets
+    /// of equal length, sent or received as soon as possible.
+    pub fn submit_isochronous<'a>(self: &Arc<Self>, endpoint: u8, mut buffer: Vec<u8>, packets: usize,
+        timeout: Duration) -> IsoFuture<'a>
+    {
+        let packet_lengths = vec![(buffer.len() / packets) as c_uint; packets];
+        let raw = RawUrb::new(USBDEVFS_URB_TYPE_ISO, endpoint, USBDEVFS_URB_ISO_ASAP, &mut buffer, &packet_lengths);
+        IsoFuture { urb: self.submit_raw(raw, buffer, timeout, false) }
+    }
+
+    // `raw` points into the heap storage of `buffer`, which moving it keeps
+    fn submit_raw<'a>(self: &Arc<Self>, raw: RawUrb, buffer: Vec<u8>, timeout: Duration, clear_stall: bool)
+        -> UrbFuture<'a>
+    {
+        if buffer.len() > c_int::MAX as usize {
+            return self.failed(io::Error::new(io::ErrorKind::InvalidInput, "transfer buffer too long"))
+        }
+        let file = match self.file.upgrade() {
+            Some(file) => file,
+            None => return self.failed(io::Error::new(io::ErrorKind::NotConnected, "device node closed")),
+        };
+        let endpoint = raw.urb().endpoint;
+        let urb = Arc::new(Urb {
+            raw: UnsafeCell::new(raw),
+            buffer: UnsafeCell::new(buffer),
+            completion: Mutex::new(Completion::default()),
+        });
+        // hold the lock so that the reaper cannot see the URB before it is recorded
+        let mut state = self.state();
+        let address = unsafe { (*urb.raw.get()).as_mut_ptr() };
+        if unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_SUBMITURB, address) } < 0 {
+            return self.failed(io::Error::last_os_error())
+        }
+        let deadline = if timeout == Duration::from_secs(0) { None } else { Some(Instant::now() + timeout) };
+        let pending = Pending { urb: urb.clone(), endpoint, deadline, timed_out: false, clear_stall };
+        state.pending.insert(address as usize, pending);
+        if !state.running {
+            state.running = true;
+            let reaper = self.clone();
+            thread::spawn(move || reaper.run());
+        }
+        UrbFuture { reaper: self.clone(), urb: Some(urb), error: None, _lifetime: PhantomData }
+    }
+
+    /// A future failing with `error` without submitting anything.
+    pub fn failed<'a>(self: &Arc<Self>, error: io::Error) -> UrbFuture<'a> {
+        UrbFuture { reaper: self.clone(), urb: None, error: Some(error), _lifetime: PhantomData }
+    }
+
+    /// Cancel all URBs pending on `endpoint`; they complete as aborted.
+    pub fn discard_endpoint(&self, endpoint: u8) {
+        let file = match self.file.upgrade() {
+            Some(file) => file,
+            None => return,
+        };
+        let state = self.state();
+        for (&address, pending) in state.pending.iter() {
+            if pending.endpoint == endpoint {
+                // fails harmlessly for URBs completed but not yet reaped
+                unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_DISCARDURB, address as *mut c_void) };
+            }
+        }
+    }
+
+    fn discard(&self, urb: &Arc<Urb>) {
+        let file = match self.file.upgrade() {
+            Some(file) => file,
+            None => return,
+        };
+        let state = self.state();
+        let address = unsafe { (*urb.raw.get()).as_mut_ptr() };
+        if state.pending.contains_key(&(address as usize)) {
+            unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_DISCARDURB, address) };
+        }
+    }
+
+    fn run(self: Arc<Self>) {
+        loop {
+            let wait = {
+                let mut state = self.state();
+                if state.pending.is_empty() {
+                    state.running = false;
+                    return
+                }
+                let now = Instant::now();
+                state.pending.values()
+                    .filter_map(|pending| pending.deadline)
+                    .map(|deadline| deadline.saturating_duration_since(now))
+                    .fold(REAPER_TICK, Duration::min)
+            };
+            // closing the node makes the kernel drop every URB still pending
+            let file = match self.file.upgrade() {
+                Some(file) => file,
+                None => return self.fail_all(io::ErrorKind::NotConnected.into()),
+            };
+            // usbfs reports completed URBs as writable
+            let mut fd = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLOUT, revents: 0 };
+            unsafe { libc::poll(&mut fd, 1, wait.as_millis() as c_int) };
+            if let Err(e) = self.reap(&file) {
+                return self.fail_all(e)
+            }
+            self.discard_expired(&file);
+        }
+    }
+
+    fn reap(&self, file: &fs::File) -> io::Result<()> {
+        loop {
+            let mut address: *mut usbdevfs_urb = ptr::null_mut();
+            let ans = unsafe {
+                libc::ioctl(file.as_raw_fd(), USBDEVFS_REAPURBNDELAY, &mut address as *mut _ as *mut c_void)
+            };
+            if ans < 0 {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(libc::EAGAIN) => Ok(()),
+                    _ => Err(err),
+                }
+            }
+            let pending = self.state().pending.remove(&(address as usize));
+            if let Some(pending) = pending {
+                let raw = unsafe { (*pending.urb.raw.get()).urb() };
+                let result = match -raw.status {
+                    0 => Ok(raw.actual_length as usize),
+                    // some packets failed, each descriptor tells which
+                    libc::EXDEV if raw.type_ == USBDEVFS_URB_TYPE_ISO => Ok(raw.actual_length as usize),
+                    libc::ENOENT | libc::ECONNRESET if pending.timed_out =>
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out")),
+                    libc::ENOENT | libc::ECONNRESET =>
+                        Err(io::Error::new(io::ErrorKind::ConnectionAborted, "transfer aborted")),
+                    libc::EPIPE if pending.clear_stall => {
+                        let mut endpoint = pending.endpoint as c_uint;
+                        unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_CLEAR_HALT, &mut endpoint as *mut c_uint) };
+                        Err(io::Error::from_raw_os_error(libc::EPIPE))
+                    },
+                    errno => Err(io::Error::from_raw_os_error(errno)),
+                };
+                pending.urb.complete(result);
+            }
+        }
+    }
+
+    fn discard_expired(&self, file: &fs::File) {
+        let now = Instant::now();
+        let mut state = self.state();
+        for (&address, pending) in state.pending.iter_mut() {
+            if pending.deadline.is_some_and(|deadline| deadline <= now) {
+                pending.deadline = None;
+                pending.timed_out = true;
+                unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_DISCARDURB, address as *mut c_void) };
+            }
+        }
+    }
+
+    /// The device is gone and the kernel has given up every URB.
+    fn fail_all(&self, error: io::Error) {
+        let mut state = self.state();
+        state.running = false;
+        for (_, pending) in state.pending.drain() {
+            let error = match error.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::new(error.kind(), error.to_string()),
+            };
+            pending.urb.complete(Err(error));
+        }
+    }
+}
+
+/// A submitted URB, resolving to its buffer and the length transferred.
+///
+/// Dropping it before completion discards the URB; the buffer is freed once
+/// the kernel has given it back.
+pub struct UrbFuture<'a> {
+    reaper: Arc<Reaper>,
+    urb: Option<Arc<Urb>>,
+    error: Option<io::Error>,
+    _lifetime: PhantomData<&'a ()>,
+}
+
+impl UrbFuture<'_> {
+    // the URB once reaped, so that the kernel is done with it and its buffer
+    fn poll_reaped(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Arc<Urb>, usize)>> {
+        if let Some(error) = self.error.take() {
+            return Poll::Ready(Err(error))
+        }
+        let urb = self.urb.as_ref().expect("URB future polled after completion");
+        let result = {
+            let mut completion = urb.completion();
+            match completion.result.take() {
+                Some(result) => result,
+                None => {
+                    completion.waker = Some(cx.waker().clone());
+                    return Poll::Pending
+                },
+            }
+        };
+        let urb = self.urb.take().unwrap();
+        Poll::Ready(result.map(|len| (urb, len)))
+    }
+}
+
+impl Future for UrbFuture<'_> {
+    type Output = io::Result<(Vec<u8>, usize)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ans = ready!(self.get_mut().poll_reaped(cx));
+        Poll::Ready(ans.map(|(urb, len)| (urb.take_buffer(), len)))
+    }
+}
+
+/// A submitted isochronous URB, resolving to its buffer and its packets.
+///
+/// Dropping it before completion discards the URB like an `UrbFuture`.
+#[derive(Debug)]
+pub struct IsoFuture<'a> {
+    urb: UrbFuture<'a>,
+}
+
+impl Future for IsoFuture<'_> {
+    type Output = io::Result<(Vec<u8>, Vec<crate::IsoPacket>)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (urb, _) = ready!(self.get_mut().urb.poll_reaped(cx))?;
+        let raw = unsafe { &*urb.raw.get() };
+        let mut offset = 0;
+        let packets = raw.packets().iter().map(|desc| {
+            let status = match desc.status as c_int {
+                0 => Ok(()),
+                status => Err(io::Error::from_raw_os_error(-status).into()),
+            };
+            let packet = crate::IsoPacket { offset, length: desc.actual_length as usize, status };
+            offset += desc.length as usize;
+            packet
+        }).collect();
+        Poll::Ready(Ok((urb.take_buffer(), packets)))
+    }
+}
+
+impl Drop for UrbFuture<'_> {
+    fn drop(&mut self) {
+        if let Some(urb) = &self.urb {
+            if urb.completion().result.is_none() {
+                self.reaper.discard(urb);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for UrbFuture<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UrbFuture")
+            .field("pending", &self.urb.is_some())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Reaper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reaper")
+            .field("pending", &self.state().pending.len())
+            .finish()
+    }
+}
@@ -0,0 +1,542 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use core::{marker::PhantomData, mem};
+use std::{collections::HashMap, fs, hash, io, path::Path, sync::{Arc, Mutex}};
+use std::os::unix::{fs::FileExt, io::AsRawFd};
+use libc::{c_int, c_uint, c_void, Ioctl};
+use std::time::Duration;
+use crate::{descriptor, policy, ConfigDescriptor, DeviceDescriptor, PipePolicy, Speed};
+use crate::ControlRequest;
+use super::{kernel_driver::{DriverIoctl, KernelDrivers}, sysfs, urb};
+
+// Generic ioctl request encoding from `asm-generic/ioctl.h`
+const IOC_NONE: Ioctl = 0;
+const IOC_WRITE: Ioctl = 1;
+const IOC_READ: Ioctl = 2;
+
+const fn ioc(dir: Ioctl, nr: Ioctl, size: usize) -> Ioctl {
+    (dir << 30) | ((size as Ioctl) << 16) | ((b'U' as Ioctl) << 8) | nr
+}
+
+const fn io(nr: Ioctl) -> Ioctl {
+    ioc(IOC_NONE, nr, 0)
+}
+
+const fn ior<T>(nr: Ioctl) -> Ioctl {
+    ioc(IOC_READ, nr, mem::size_of::<T>())
+}
+
+const fn iow<T>(nr: Ioctl) -> Ioctl {
+    ioc(IOC_WRITE, nr, mem::size_of::<T>())
+}
+
+const fn iowr<T>(nr: Ioctl) -> Ioctl {
+    ioc(IOC_READ | IOC_WRITE, nr, mem::size_of::<T>())
+}
+
+#[repr(C)]
+pub struct usbdevfs_ctrltransfer {
+    pub bRequestType: u8,
+    pub bRequest: u8,
+    pub wValue: u16,
+    pub wIndex: u16,
+    pub wLength: u16,
+    pub timeout: u32, // in milliseconds, zero for infinite
+    pub data: *mut c_void,
+}
+
+#[repr(C)]
+pub struct usbdevfs_bulktransfer {
+    pub ep: c_uint,
+    pub len: c_uint,
+    pub timeout: c_uint, // in milliseconds, zero for infinite
+    pub data: *mut c_void,
+}
+
+// `type` field of `usbdevfs_urb`
+pub const USBDEVFS_URB_TYPE_ISO: u8 = 0;
+pub const USBDEVFS_URB_TYPE_INTERRUPT: u8 = 1;
+pub const USBDEVFS_URB_TYPE_CONTROL: u8 = 2;
+pub const USBDEVFS_URB_TYPE_BULK: u8 = 3;
+
+// `flags` of `usbdevfs_urb`
+pub const USBDEVFS_URB_ISO_ASAP: c_uint = 0x02;
+pub const USBDEVFS_URB_ZERO_PACKET: c_uint = 0x40;
+
+#[repr(C)]
+pub struct usbdevfs_urb {
+    pub type_: u8,
+    pub endpoint: u8,
+    pub status: c_int, // negative errno once reaped
+    pub flags: c_uint,
+    pub buffer: *mut c_void,
+    pub buffer_length: c_int,
+    pub actual_length: c_int,
+    pub start_frame: c_int,
+    pub number_of_packets: c_int, // shared with `stream_id`
+    pub error_count: c_int,
+    pub signr: c_uint,
+    pub usercontext: *mut c_void,
+    // followed by `number_of_packets` iso packet descriptors
+}
+
+#[repr(C)]
+pub struct usbdevfs_iso_packet_desc {
+    pub length: c_uint,
+    pub actual_length: c_uint,
+    pub status: c_uint, // negative errno once reaped
+}
+
+#[repr(C)]
+pub struct usbdevfs_setinterface {
+    pub interface: c_uint,
+    pub altsetting: c_uint,
+}
+
+#[repr(C)]
+pub struct usbdevfs_ioctl {
+    pub ifno: c_int,
+    pub ioctl_code: c_int,
+    pub data: *mut c_void,
+}
+
+pub const USBDEVFS_CONTROL: Ioctl = iowr::<usbdevfs_ctrltransfer>(0);
+pub const USBDEVFS_BULK: Ioctl = iowr::<usbdevfs_bulktransfer>(2);
+pub const USBDEVFS_SETINTERFACE: Ioctl = ior::<usbdevfs_setinterface>(4);
+pub const USBDEVFS_SUBMITURB: Ioctl = ior::<usbdevfs_urb>(10);
+pub const USBDEVFS_DISCARDURB: Ioctl = io(11);
+pub const USBDEVFS_REAPURBNDELAY: Ioctl = iow::<*mut c_void>(13);
+pub const USBDEVFS_CLAIMINTERFACE: Ioctl = ior::<c_uint>(15);
+pub const USBDEVFS_RELEASEINTERFACE: Ioctl = ior::<c_uint>(16);
+pub const USBDEVFS_IOCTL: Ioctl = iowr::<usbdevfs_ioctl>(18);
+pub const USBDEVFS_RESET: Ioctl = io(20);
+pub const USBDEVFS_CLEAR_HALT: Ioctl = ior::<c_uint>(21);
+pub const USBDEVFS_DISCONNECT: Ioctl = io(22);
+pub const USBDEVFS_CONNECT: Ioctl = io(23);
+pub const USBDEVFS_GET_SPEED: Ioctl = io(31);
+
+// well-behaved devices answer descriptor requests within milliseconds
+const DESCRIPTOR_TIMEOUT_MS: u32 = 1000;
+
+// `enum usb_device_speed` from `linux/usb/ch9.h`
+const USB_SPEED_LOW: c_int = 1;
+const USB_SPEED_FULL: c_int = 2;
+const USB_SPEED_HIGH: c_int = 3;
+const USB_SPEED_SUPER: c_int = 5;
+const USB_SPEED_SUPER_PLUS: c_int = 6;
+
+/// An opened usbfs device node.
+///
+/// The file is shared between clones and associated interfaces; it is closed
+/// after the last of them drops.
+#[derive(Debug, Clone)]
+pub struct UsbFs<'h> {
+    file: Arc<fs::File>,
+    timeouts: Arc<Mutex<HashMap<u8, Duration>>>,
+    policies: Arc<Mutex<HashMap<u8, policy::Applied>>>,
+    reaper: Arc<urb::Reaper>,
+    // first interface of this handle, the one associated interfaces count from
+    interface_number: u8,
+    claims: Arc<Claims>,
+    _lifetime_of_handle: PhantomData<&'h ()>,
+}
+
+/// Interfaces claimed through one handle and its clones, released after the
+/// last of them drops, and the kernel drivers detached to claim them.
+#[derive(Debug)]
+struct Claims {
+    file: Arc<fs::File>,
+    interfaces: Mutex<Vec<u8>>,
+    drivers: KernelDrivers<Arc<fs::File>>,
+}
+
+impl Claims {
+    fn new(file: &Arc<fs::File>, sysfs_path: &Path, auto_detach: bool) -> Arc<Claims> {
+        let drivers = KernelDrivers::new(file.clone(), sysfs_path.to_path_buf());
+        drivers.set_auto_detach(auto_detach);
+        Arc::new(Claims { file: file.clone(), interfaces: Mutex::new(Vec::new()), drivers })
+    }
+
+    fn interfaces(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.interfaces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn claim(&self, interface_number: u8) -> io::Result<()> {
+        let mut interfaces = self.interfaces();
+        if interfaces.contains(&interface_number) {
+            return Ok(())
+        }
+        self.drivers.before_claim(interface_number)?;
+        let mut arg = interface_number as c_uint;
+        if let Err(e) = unsafe { usbfs_ioctl(&self.file, USBDEVFS_CLAIMINTERFACE, &mut arg as *mut _ as *mut _) } {
+            let _ = self.drivers.after_release(interface_number);
+            return Err(e)
+        }
+        interfaces.push(interface_number);
+        Ok(())
+    }
+
+    fn release(&self, interface_number: u8) -> io::Result<()> {
+        let mut interfaces = self.interfaces();
+        let mut arg = interface_number as c_uint;
+        unsafe { usbfs_ioctl(&self.file, USBDEVFS_RELEASEINTERFACE, &mut arg as *mut _ as *mut _) }?;
+        interfaces.retain(|&i| i != interface_number);
+        self.drivers.after_release(interface_number)
+    }
+}
+
+impl Drop for Claims {
+    fn drop(&mut self) {
+        for &interface_number in self.interfaces().iter() {
+            let mut arg = interface_number as c_uint;
+            let _ = unsafe { usbfs_ioctl(&self.file, USBDEVFS_RELEASEINTERFACE, &mut arg as *mut _ as *mut _) };
+            let _ = self.drivers.after_release(interface_number);
+        }
+    }
+}
+
+impl DriverIoctl for fs::File {
+    fn disconnect(&self, interface_number: u8) -> io::Result<()> {
+        driver_ioctl(self, interface_number, USBDEVFS_DISCONNECT)
+    }
+
+    fn connect(&self, interface_number: u8) -> io::Result<()> {
+        driver_ioctl(self, interface_number, USBDEVFS_CONNECT)
+    }
+}
+
+// ioctls of the usbfs device driver itself, forwarded to an interface
+fn driver_ioctl(file: &fs::File, interface_number: u8, ioctl_code: Ioctl) -> io::Result<()> {
+    let mut arg = usbdevfs_ioctl {
+        ifno: interface_number as c_int,
+        ioctl_code: ioctl_code as c_int,
+        data: core::ptr::null_mut(),
+    };
+    unsafe { usbfs_ioctl(file, USBDEVFS_IOCTL, &mut arg as *mut _ as *mut _) }?;
+    Ok(())
+}
+
+// Safety: as for `UsbFs::ioctl`
+unsafe fn usbfs_ioctl(file: &fs::File, request: Ioctl, arg: *mut c_void) -> io::Result<c_int> {
+    let ans = libc::ioctl(file.as_raw_fd(), request, arg);
+    if ans < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(ans)
+}
+
+impl<'h> UsbFs<'h> {
+    pub fn open(info: &sysfs::Info) -> io::Result<UsbFs<'h>> {
+        let file = Arc::new(fs::OpenOptions::new().read(true).write(true).open(info.dev_path())?);
+        let ans = UsbFs {
+            reaper: Arc::new(urb::Reaper::new(&file)),
+            claims: Claims::new(&file, info.sysfs_path(), false),
+            file,
+            timeouts: Arc::new(Mutex::new(HashMap::new())),
+            policies: Arc::new(Mutex::new(HashMap::new())),
+            interface_number: 0,
+            _lifetime_of_handle: PhantomData,
+        };
+        // WinUSB always opens the first interface of a device; do the same here
+        // so bulk transfers do not rely on the kernel claiming it implicitly.
+        // Failing is fine, the device may be serving a kernel driver instead.
+        let _ = ans.claim_interface(0);
+        Ok(ans)
+    }
+
+    /// Issue an ioctl on this device node, mapping `-1` to the last OS error.
+    ///
+    /// # Safety
+    ///
+    /// `arg` must point to memory laid out as `request` expects,
+    /// valid for the whole call.
+    pub unsafe fn ioctl(&self, request: Ioctl, arg: *mut c_void) -> io::Result<c_int> {
+        usbfs_ioctl(&self.file, request, arg)
+    }
+
+    /// Reading a usbfs node yields the device descriptor followed by all
+    /// configuration descriptors, served from the kernel's cache.
+    pub fn descriptors(&self) -> io::Result<Vec<u8>> {
+        let mut ans = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = self.file.read_at(&mut buf, ans.len() as u64)?;
+            if len == 0 {
+                return Ok(ans)
+            }
+            ans.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    pub fn device_descriptor(&self) -> io::Result<DeviceDescriptor> {
+        let mut buf = [0u8; descriptor::DT_DEVICE_SIZE];
+        self.file.read_exact_at(&mut buf, 0)?;
+        DeviceDescriptor::parse(&buf)
+    }
+
+    pub fn config_descriptor(&self, index: u8) -> io::Result<ConfigDescriptor> {
+        let buf = self.descriptors()?;
+        let (_, configs) = descriptor::split_cached(&buf)?;
+        match configs.get(index as usize) {
+            Some(raw) => ConfigDescriptor::parse(raw),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such configuration")),
+        }
+    }
+
+    /// Issue a `GET_DESCRIPTOR` standard request to the device.
+    pub fn get_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16, buf: &mut [u8])
+        -> io::Result<usize>
+    {
+        let request = ControlRequest::get_descriptor(descriptor_type, index, language_id);
+        self.control_transfer(&request, buf.as_mut_ptr(), buf.len(), DESCRIPTOR_TIMEOUT_MS)
+    }
+
+    pub fn control_transfer(&self, request: &ControlRequest, data: *mut u8, len: usize, timeout_ms: u32)
+        -> io::Result<usize>
+    {
+        let mut arg = usbdevfs_ctrltransfer {
+            bRequestType: request.request_type_bits(),
+            bRequest: request.request,
+            wValue: request.value,
+            wIndex: request.index,
+            wLength: len.min(u16::MAX as usize) as u16,
+            timeout: timeout_ms,
+            data: data as *mut _,
+        };
+        let ans = unsafe { self.ioctl(USBDEVFS_CONTROL, &mut arg as *mut _ as *mut _) }?;
+        Ok(ans as usize)
+    }
+
+    pub fn control_in(&self, request: &ControlRequest, buf: &mut [u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        self.control_transfer(request, buf.as_mut_ptr(), buf.len(), timeout_ms(timeout))
+    }
+
+    pub fn control_out(&self, request: &ControlRequest, buf: &[u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        // usbfs copies OUT data from this pointer and never writes into it
+        self.control_transfer(request, buf.as_ptr() as *mut u8, buf.len(), timeout_ms(timeout))
+    }
+
+    pub fn speed(&self) -> io::Result<Speed> {
+        let ans = unsafe { self.ioctl(USBDEVFS_GET_SPEED, core::ptr::null_mut()) }?;
+        Ok(match ans {
+            USB_SPEED_LOW => Speed::Low,
+            USB_SPEED_FULL => Speed::Full,
+            USB_SPEED_HIGH => Speed::High,
+            USB_SPEED_SUPER | USB_SPEED_SUPER_PLUS => Speed::Super,
+            _ => Speed::Unknown,
+        })
+    }
+
+    /// Claim an interface until it is released or the last clone of this
+    /// handle drops; claiming an interface twice is fine.
+    pub fn claim_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.claims.claim(interface_number)
+    }
+
+    pub fn release_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.claims.release(interface_number)
+    }
+
+    pub fn kernel_driver_active(&self, interface_number: u8) -> io::Result<bool> {
+        self.claims.drivers.is_active(interface_number)
+    }
+
+    pub fn detach_kernel_driver(&self, interface_number: u8) -> io::Result<()> {
+        self.claims.drivers.detach(interface_number)
+    }
+
+    pub fn attach_kernel_driver(&self, interface_number: u8) -> io::Result<()> {
+        self.claims.drivers.attach(interface_number)
+    }
+
+    /// Detach kernel drivers when claiming interfaces through this handle and
+    /// its clones, and attach them again when releasing the interfaces.
+    pub fn set_auto_detach_kernel_driver(&self, enable: bool) -> io::Result<()> {
+        self.claims.drivers.set_auto_detach(enable);
+        Ok(())
+    }
+
+    pub fn set_alternate_setting(&self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        let mut arg = usbdevfs_setinterface {
+            interface: interface_number as c_uint,
+            altsetting: alternate_setting as c_uint,
+        };
+        unsafe { self.ioctl(USBDEVFS_SETINTERFACE, &mut arg as *mut _ as *mut _) }?;
+        Ok(())
+    }
+
+    /// First interface of this handle, the one associated interfaces count from.
+    pub fn interface_number(&self) -> u8 {
+        self.interface_number
+    }
+
+    /// Share this device node with a handle of its own for a sibling interface,
+    /// which claims that interface until the last clone of it drops.
+    pub fn associated_interface<'a>(&self, index: u8) -> io::Result<UsbFs<'a>> {
+        let interface_number = self.config_descriptor(0)?
+            .associated_interface(self.interface_number, index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such associated interface"))?;
+        let ans = UsbFs {
+            file: self.file.clone(),
+            timeouts: self.timeouts.clone(),
+            policies: self.policies.clone(),
+            reaper: self.reaper.clone(),
+            interface_number,
+            claims: Claims::new(&self.file, self.claims.drivers.sysfs_path(), self.claims.drivers.auto_detach()),
+            _lifetime_of_handle: PhantomData,
+        };
+        ans.claim_interface(interface_number)?;
+        Ok(ans)
+    }
+
+    /// Reset the device through its hub port.
+    ///
+    /// The kernel re-enumerates the device if its descriptors changed, in
+    /// which case the device node is gone once this returns.
+    pub fn reset_device(&self) -> io::Result<()> {
+        match unsafe { self.ioctl(USBDEVFS_RESET, core::ptr::null_mut()) } {
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn bulk_transfer(&self, endpoint: u8, data: *mut u8, len: usize, timeout: Duration)
+        -> io::Result<usize>
+    {
+        let mut arg = usbdevfs_bulktransfer {
+            ep: endpoint as c_uint,
+            len: len as c_uint,
+            timeout: timeout_ms(timeout),
+            data: data as *mut _,
+        };
+        let ans = unsafe { self.ioctl(USBDEVFS_BULK, &mut arg as *mut _ as *mut _) }?;
+        Ok(ans as usize)
+    }
+
+    /// Default timeout of transfers on this pipe, zero for waiting forever.
+    pub fn timeout(&self, pipe_index: u8) -> Duration {
+        let timeouts = self.timeouts.lock().unwrap_or_else(|e| e.into_inner());
+        timeouts.get(&pipe_index).cloned().unwrap_or_default()
+    }
+
+    pub fn set_timeout(&self, pipe_index: u8, timeout: Duration) -> io::Result<()> {
+        let mut timeouts = self.timeouts.lock().unwrap_or_else(|e| e.into_inner());
+        timeouts.insert(pipe_index, timeout);
+        Ok(())
+    }
+
+    fn policy(&self, pipe_index: u8) -> policy::Applied {
+        let policies = self.policies.lock().unwrap_or_else(|e| e.into_inner());
+        policies.get(&pipe_index).cloned().unwrap_or_default()
+    }
+
+    /// Apply a policy to transfers on a pipe of `max_packet_size` from now on.
+    pub fn set_pipe_policy(&self, pipe_index: u8, policy: PipePolicy, max_packet_size: u16) -> io::Result<()> {
+        let mut policies = self.policies.lock().unwrap_or_else(|e| e.into_inner());
+        policies.insert(pipe_index, policy::Applied { policy, max_packet_size });
+        Ok(())
+    }
+
+    pub fn pipe_policy(&self, pipe_index: u8) -> io::Result<PipePolicy> {
+        Ok(self.policy(pipe_index).policy)
+    }
+
+    pub fn write_pipe(&self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.write_pipe_timeout(pipe_index, buf, self.timeout(pipe_index))
+    }
+
+    pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_pipe_timeout(pipe_index, buf, self.timeout(pipe_index))
+    }
+
+    pub fn write_pipe_timeout(&self, pipe_index: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        // usbfs copies OUT data from this pointer and never writes into it
+        let len = self.bulk_transfer(pipe_index, buf.as_ptr() as *mut u8, buf.len(), timeout)?;
+        // `USBDEVFS_BULK` takes no URB flags, send the zero-length packet here
+        if self.policy(pipe_index).zero_packet_after(len) {
+            self.bulk_transfer(pipe_index, core::ptr::null_mut(), 0, timeout)?;
+        }
+        Ok(len)
+    }
+
+    pub fn read_pipe_timeout(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let policy = self.policy(pipe_index);
+        policy.check_read(buf.len())?;
+        let ans = self.bulk_transfer(pipe_index, buf.as_mut_ptr(), buf.len(), timeout);
+        if let Err(e) = &ans {
+            if policy.clears_stall(e) {
+                self.reset_pipe(pipe_index)?;
+            }
+        }
+        ans
+    }
+
+    /// Submit an isochronous URB on `endpoint`, bounded by the timeout of the pipe.
+    pub fn submit_isochronous<'a>(&self, endpoint: u8, buf: Vec<u8>, packets: usize) -> urb::IsoFuture<'a> {
+        self.reaper.submit_isochronous(endpoint, buf, packets, self.timeout(endpoint))
+    }
+
+    /// Submit a bulk URB on `endpoint`, bounded by the timeout of the pipe.
+    pub fn submit_bulk<'a>(&self, endpoint: u8, buf: Vec<u8>) -> urb::UrbFuture<'a> {
+        let policy = self.policy(endpoint);
+        let timeout = self.timeout(endpoint);
+        if endpoint & 0x80 == 0 {
+            let flags = if policy.policy.short_packet_terminate { USBDEVFS_URB_ZERO_PACKET } else { 0 };
+            return self.reaper.submit(USBDEVFS_URB_TYPE_BULK, endpoint, buf, timeout, flags, false)
+        }
+        if let Err(e) = policy.check_read(buf.len()) {
+            return self.reaper.failed(e)
+        }
+        self.reaper.submit(USBDEVFS_URB_TYPE_BULK, endpoint, buf, timeout, 0, policy.policy.auto_clear_stall)
+    }
+
+    /// usbfs transfers leave nothing cached to flush.
+    pub fn flush_pipe(&self, _pipe_index: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Clear a halt condition on the endpoint and reset its data toggle.
+    pub fn reset_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        let mut arg = pipe_index as c_uint;
+        unsafe { self.ioctl(USBDEVFS_CLEAR_HALT, &mut arg as *mut _ as *mut _) }?;
+        Ok(())
+    }
+
+    /// Discard all URBs pending on the pipe.
+    ///
+    /// Synchronous transfers are ioctls which the kernel cannot cancel from
+    /// another thread; they are bounded by their timeouts instead.
+    pub fn abort_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.reaper.discard_endpoint(pipe_index);
+        Ok(())
+    }
+}
+
+impl PartialEq for UsbFs<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.file.as_raw_fd() == other.file.as_raw_fd() && self.interface_number == other.interface_number
+    }
+}
+
+impl Eq for UsbFs<'_> {}
+
+impl hash::Hash for UsbFs<'_> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.file.as_raw_fd().hash(state);
+        self.interface_number.hash(state)
+    }
+}
+
+/// Convert to usbfs milliseconds, where zero waits forever; a non-zero timeout
+/// never rounds down to zero.
+pub(crate) fn timeout_ms(timeout: Duration) -> u32 {
+    if timeout == Duration::from_secs(0) {
+        return 0
+    }
+    timeout.as_millis().clamp(1, u32::MAX as u128) as u32
+}
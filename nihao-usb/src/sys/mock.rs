@@ -0,0 +1,1069 @@
+//! In-memory virtual devices, replacing the operating system backend when
+//! the `mock` feature is enabled.
+//!
+//! Devices are registered per thread, so tests running in parallel never see
+//! each other's devices. Once listed, devices and handles may be moved to
+//! other threads.
+//!
+//! ```
+//! use nihao_usb::sys::mock::{self, Transfer, VirtualDevice};
+//! # let descriptor = nihao_usb::DeviceDescriptor {
+//! #     length: 18, descriptor_type: 1, bcd_usb: 0x0200,
+//! #     device_class: 0, device_sub_class: 0, device_protocol: 0,
+//! #     max_packet_size_0: 64, id_vendor: 0x0483, id_product: 0x3748,
+//! #     bcd_device: 0x0100, manufacturer: 0, product: 0, serial_number: 0,
+//! #     num_configurations: 1,
+//! # };
+//! mock::register(VirtualDevice::new(descriptor, |transfer: Transfer| match transfer {
+//!     Transfer::Out { buf, .. } => Ok(buf.len()),
+//!     Transfer::In { buf, .. } => { buf[0] = 0x80; Ok(1) },
+//! }));
+//! for device in nihao_usb::devices()? {
+//!     let handle = device?.open()?;
+//!     handle.write_pipe(0x02, &[0xF5, 0x00])?;
+//!     let mut buf = [0u8; 2];
+//!     assert_eq!(handle.read_pipe(0x81, &mut buf)?, 1);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use core::{cell::RefCell, fmt, future::Future, hash, iter::FusedIterator, marker::PhantomData, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use std::{collections::HashMap, io, thread, sync::{Arc, Condvar, Mutex, atomic::{AtomicBool, Ordering}}};
+use std::time::{Duration, Instant};
+use crate::{descriptor, policy, ConfigDescriptor, DeviceDescriptor, PipePolicy, Speed, LANGUAGE_ID_EN_US};
+use crate::ControlRequest;
+
+/// One bulk transfer as seen by the virtual device.
+#[derive(Debug)]
+pub enum Transfer<'a> {
+    /// Data sent by the host on an OUT endpoint
+    Out { pipe_index: u8, buf: &'a [u8] },
+    /// Buffer to be filled by the device on an IN endpoint
+    In { pipe_index: u8, buf: &'a mut [u8] },
+}
+
+/// Answers traffic of a virtual device.
+///
+/// All methods return the number of bytes transferred. Closures taking a
+/// `Transfer` implement this trait as well, answering bulk traffic only.
+///
+/// A bulk method returning `io::ErrorKind::WouldBlock` means the device NAKs:
+/// it is polled again until it answers or the transfer times out.
+///
+/// Standard `GET_DESCRIPTOR` requests are answered by the mock backend from
+/// the descriptors given to `VirtualDevice`, and never reach the responder.
+pub trait Responder: Send {
+    fn write_pipe(&mut self, pipe_index: u8, buf: &[u8]) -> io::Result<usize>;
+
+    fn read_pipe(&mut self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Answer a control request with a device to host data stage.
+    ///
+    /// By default every request is stalled.
+    fn control_in(&mut self, request: &ControlRequest, buf: &mut [u8]) -> io::Result<usize> {
+        let _ = (request, buf);
+        Err(stall())
+    }
+
+    /// Answer a control request with a host to device data stage.
+    ///
+    /// By default every request is stalled.
+    fn control_out(&mut self, request: &ControlRequest, buf: &[u8]) -> io::Result<usize> {
+        let _ = (request, buf);
+        Err(stall())
+    }
+
+    /// Switch an interface to an alternate setting found in its descriptors.
+    ///
+    /// By default every switch succeeds.
+    fn set_alternate_setting(&mut self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        let _ = (interface_number, alternate_setting);
+        Ok(())
+    }
+
+    /// Answer one packet of an isochronous transfer on an IN endpoint.
+    ///
+    /// Isochronous packets are never retried, so `io::ErrorKind::WouldBlock`
+    /// sends an empty packet and any other error fails the packet alone. By
+    /// default packets are read with `read_pipe`.
+    fn read_iso_packet(&mut self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_pipe(pipe_index, buf)
+    }
+
+    /// Take one packet of an isochronous transfer on an OUT endpoint.
+    ///
+    /// By default packets are written with `write_pipe`.
+    fn write_iso_packet(&mut self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.write_pipe(pipe_index, buf)
+    }
+
+    /// Clear a halt of an endpoint, as the host does after it stalled.
+    ///
+    /// By default every clear succeeds.
+    fn clear_halt(&mut self, pipe_index: u8) -> io::Result<()> {
+        let _ = pipe_index;
+        Ok(())
+    }
+
+    /// Come back from a port reset; failing keeps the device off the bus,
+    /// as if it did not re-enumerate.
+    ///
+    /// By default every reset succeeds.
+    fn reset(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F> Responder for F
+where
+    F: FnMut(Transfer) -> io::Result<usize> + Send
+{
+    fn write_pipe(&mut self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self(Transfer::Out { pipe_index, buf })
+    }
+
+    fn read_pipe(&mut self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self(Transfer::In { pipe_index, buf })
+    }
+}
+
+/// Description of a virtual device before it is registered.
+pub struct VirtualDevice {
+    device_descriptor: DeviceDescriptor,
+    config_descriptors: Vec<Vec<u8>>,
+    strings: Vec<(u16, u8, String)>,
+    speed: Speed,
+    port_path: Option<String>,
+    responder: Box<dyn Responder>,
+}
+
+impl VirtualDevice {
+    pub fn new<R: Responder + 'static>(device_descriptor: DeviceDescriptor, responder: R) -> Self {
+        VirtualDevice {
+            device_descriptor,
+            config_descriptors: Vec::new(),
+            strings: Vec::new(),
+            speed: Speed::Full,
+            port_path: None,
+            responder: Box::new(responder),
+        }
+    }
+
+    pub fn speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Plug the device into this port, like `1-3.2`.
+    ///
+    /// By default each device gets a root hub port of bus 1 of its own.
+    pub fn port_path(mut self, port_path: &str) -> Self {
+        self.port_path = Some(port_path.to_string());
+        self
+    }
+
+    /// Append a raw configuration descriptor blob, answered for the next
+    /// configuration index.
+    pub fn config_descriptor(mut self, raw: Vec<u8>) -> Self {
+        self.config_descriptors.push(raw);
+        self
+    }
+
+    /// Answer string descriptor `index` with `s` in English (United States).
+    pub fn string(self, index: u8, s: &str) -> Self {
+        self.string_in(LANGUAGE_ID_EN_US, index, s)
+    }
+
+    /// Answer string descriptor `index` with `s` in language `language_id`.
+    ///
+    /// String descriptor zero lists every language used, in order of first use.
+    pub fn string_in(mut self, language_id: u16, index: u8, s: &str) -> Self {
+        self.strings.retain(|(l, i, _)| (*l, *i) != (language_id, index));
+        self.strings.push((language_id, index, s.to_string()));
+        self
+    }
+}
+
+impl fmt::Debug for VirtualDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtualDevice")
+            .field("device_descriptor", &self.device_descriptor)
+            .field("speed", &self.speed)
+            .finish()
+    }
+}
+
+struct Shared {
+    number: u64,
+    device_descriptor: DeviceDescriptor,
+    config_descriptors: Vec<Vec<u8>>,
+    strings: Vec<(u16, u8, String)>,
+    speed: Speed,
+    port_path: String,
+    // shared with the device this one re-enumerates as after a reset
+    responder: Arc<Mutex<Box<dyn Responder>>>,
+    connected: AtomicBool,
+    // interfaces claimed by any handle
+    claimed: Mutex<Vec<u8>>,
+    // current alternate setting of each interface, zero if not listed
+    alt_settings: Mutex<HashMap<u8, u8>>,
+}
+
+impl Shared {
+    fn check_connected(&self) -> io::Result<()> {
+        if self.connected.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "virtual device unplugged"))
+        }
+    }
+
+    fn claimed(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.claimed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn has_interface(&self, interface_number: u8) -> bool {
+        self.config_descriptors.first()
+            .and_then(|raw| ConfigDescriptor::parse(raw).ok())
+            .is_some_and(|config| config.interfaces.iter().any(|i| i.interface_number == interface_number))
+    }
+
+    fn responder(&self) -> io::Result<std::sync::MutexGuard<'_, Box<dyn Responder>>> {
+        self.check_connected()?;
+        self.responder.lock()
+            .map_err(|_| io::Error::other("virtual device responder panicked"))
+    }
+
+    /// The same device after re-enumerating, under another number.
+    fn reenumerated(&self, number: u64) -> Shared {
+        Shared {
+            number,
+            device_descriptor: self.device_descriptor.clone(),
+            config_descriptors: self.config_descriptors.clone(),
+            strings: self.strings.clone(),
+            speed: self.speed.clone(),
+            port_path: self.port_path.clone(),
+            responder: self.responder.clone(),
+            connected: AtomicBool::new(true),
+            claimed: Mutex::new(Vec::new()),
+            alt_settings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn raw_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16) -> Option<Vec<u8>> {
+        match descriptor_type {
+            descriptor::DT_DEVICE if index == 0 => {
+                let d = &self.device_descriptor;
+                let mut raw = vec![d.length, d.descriptor_type];
+                raw.extend_from_slice(&d.bcd_usb.to_le_bytes());
+                raw.extend_from_slice(&[d.device_class, d.device_sub_class, d.device_protocol, d.max_packet_size_0]);
+                raw.extend_from_slice(&d.id_vendor.to_le_bytes());
+                raw.extend_from_slice(&d.id_product.to_le_bytes());
+                raw.extend_from_slice(&d.bcd_device.to_le_bytes());
+                raw.extend_from_slice(&[d.manufacturer, d.product, d.serial_number, d.num_configurations]);
+                Some(raw)
+            },
+            descriptor::DT_CONFIG => self.config_descriptors.get(index as usize).cloned(),
+            descriptor::DT_STRING if index == 0 => {
+                let mut languages: Vec<u16> = Vec::new();
+                for (l, _, _) in &self.strings {
+                    if !languages.contains(l) {
+                        languages.push(*l);
+                    }
+                }
+                Some(string_descriptor(&languages))
+            },
+            descriptor::DT_STRING => self.strings.iter()
+                .find(|(l, i, _)| (*l, *i) == (language_id, index))
+                .map(|(_, _, s)| string_descriptor(&s.encode_utf16().collect::<Vec<_>>())),
+            _ => None,
+        }
+    }
+}
+
+fn string_descriptor(units: &[u16]) -> Vec<u8> {
+    let mut raw = vec![(2 + units.len() * 2) as u8, descriptor::DT_STRING];
+    for unit in units {
+        raw.extend_from_slice(&unit.to_le_bytes());
+    }
+    raw
+}
+
+/// Requests a device does not understand are answered with a STALL handshake,
+/// reported the way usbfs does.
+fn stall() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "endpoint stalled")
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtualDevice")
+            .field("number", &self.number)
+            .field("id_vendor", &self.device_descriptor.id_vendor)
+            .field("id_product", &self.device_descriptor.id_product)
+            .field("connected", &self.connected.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+#[derive(Default)]
+struct Registry {
+    next_number: u64,
+    devices: Vec<Arc<Shared>>,
+    // bumped on every plug and unplug, for `Monitor`
+    generation: u64,
+}
+
+/// Plug a virtual device into this thread's mock bus.
+///
+/// Returns a number identifying the device for `unregister`.
+pub fn register(device: VirtualDevice) -> u64 {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let number = registry.next_number;
+        registry.next_number += 1;
+        registry.generation += 1;
+        registry.devices.push(Arc::new(Shared {
+            number,
+            device_descriptor: device.device_descriptor,
+            config_descriptors: device.config_descriptors,
+            strings: device.strings,
+            speed: device.speed,
+            port_path: device.port_path.unwrap_or_else(|| format!("1-{}", number + 1)),
+            responder: Arc::new(Mutex::new(device.responder)),
+            connected: AtomicBool::new(true),
+            claimed: Mutex::new(Vec::new()),
+            alt_settings: Mutex::new(HashMap::new()),
+        }));
+        number
+    })
+}
+
+/// Unplug a virtual device; handles already opened on it fail from now on.
+///
+/// Returns `false` if no such device is registered.
+pub fn unregister(number: u64) -> bool {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        match registry.devices.iter().position(|s| s.number == number) {
+            Some(index) => {
+                let shared = registry.devices.remove(index);
+                registry.generation += 1;
+                shared.connected.store(false, Ordering::SeqCst);
+                true
+            },
+            None => false,
+        }
+    })
+}
+
+/// Unplug all virtual devices of this thread.
+pub fn clear() {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        registry.generation += 1;
+        for shared in registry.devices.drain(..) {
+            shared.connected.store(false, Ordering::SeqCst);
+        }
+    })
+}
+
+pub fn devices<'list>() -> io::Result<DeviceList<'list>> {
+    let devices = REGISTRY.with(|registry| registry.borrow().devices.clone());
+    Ok(DeviceList { devices: devices.into(), _lifetime_of_list: PhantomData })
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceList<'list> {
+    devices: Arc<[Arc<Shared>]>,
+    _lifetime_of_list: PhantomData<&'list ()>,
+}
+
+impl<'list> DeviceList<'list> {
+    pub fn iter<'iter>(&self) -> Devices<'iter> {
+        Devices { devices: self.devices.clone(), iter_index: 0, _lifetime_of_iter: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceIntoIter<'iter> {
+    iter: Devices<'iter>,
+}
+
+impl<'list> IntoIterator for DeviceList<'list> {
+    type Item = io::Result<Device<'list>>;
+    type IntoIter = DeviceIntoIter<'list>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DeviceIntoIter { iter: self.iter() }
+    }
+}
+
+impl<'iter> Iterator for DeviceIntoIter<'iter> {
+    type Item = io::Result<Device<'iter>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Devices<'iter> {
+    devices: Arc<[Arc<Shared>]>,
+    iter_index: usize,
+    _lifetime_of_iter: PhantomData<&'iter ()>,
+}
+
+impl<'iter> Iterator for Devices<'iter> {
+    type Item = io::Result<Device<'iter>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let shared = self.devices.get(self.iter_index)?.clone();
+        self.iter_index += 1;
+        Some(Ok(Device { shared, _lifetime_of_device: PhantomData }))
+    }
+}
+
+impl FusedIterator for Devices<'_> {}
+
+#[derive(Debug, Clone)]
+pub struct Device<'device> {
+    shared: Arc<Shared>,
+    _lifetime_of_device: PhantomData<&'device ()>,
+}
+
+impl<'device> Device<'device> {
+    pub fn open<'handle>(&self) -> io::Result<Handle<'handle>> {
+        self.shared.check_connected()?;
+        let ans = Handle {
+            shared: self.shared.clone(),
+            state: Arc::new(HandleState::new(&self.shared, 0)),
+            _lifetime_of_handle: PhantomData,
+        };
+        // like the Linux backend, which claims the first interface if it can
+        let _ = ans.claim_interface(0);
+        Ok(ans)
+    }
+
+    pub fn device_info(&self) -> crate::DeviceInfo {
+        let shared = &self.shared;
+        let mut ans = crate::DeviceInfo::from_device_descriptor(&shared.device_descriptor);
+        for raw in &shared.config_descriptors {
+            if let Ok(config) = ConfigDescriptor::parse(raw) {
+                ans.add_config(&config);
+            }
+        }
+        let serial_index = shared.device_descriptor.serial_number;
+        if serial_index != 0 {
+            // like an operating system, cache the serial number in the first language
+            ans.serial_number = shared.strings.iter()
+                .find(|(_, i, _)| *i == serial_index)
+                .map(|(_, _, s)| s.clone());
+        }
+        ans.port_path = Some(shared.port_path.clone());
+        ans
+    }
+
+    pub fn id(&self) -> DeviceId {
+        DeviceId { port_path: self.shared.port_path.clone(), number: self.shared.number }
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        Ok(self.shared.port_path.parse()?)
+    }
+
+    /// The number returned by `register` for this device.
+    pub fn number(&self) -> u64 {
+        self.shared.number
+    }
+}
+
+/// Virtual devices are told apart by their port and the number they were 
+/// registered under, like real ones by port and bus address.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct DeviceId {
+    port_path: String,
+    number: u64,
+}
+
+/// Notices virtual devices being registered and unregistered on this thread.
+#[derive(Debug)]
+pub struct Monitor {
+    generation: u64,
+}
+
+fn generation() -> u64 {
+    REGISTRY.with(|registry| registry.borrow().generation)
+}
+
+impl Monitor {
+    pub fn new() -> io::Result<Monitor> {
+        Ok(Monitor { generation: generation() })
+    }
+
+    /// Tell whether devices were plugged or unplugged since the last call.
+    ///
+    /// The registry is local to this thread and cannot change while it waits,
+    /// so this returns at once; waiting without a timeout would never end and
+    /// fails with `WouldBlock` instead.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let current = generation();
+        if current != self.generation {
+            self.generation = current;
+            return Ok(true)
+        }
+        match timeout {
+            Some(_) => Ok(false),
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock,
+                "no virtual device can be plugged while this thread waits")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Handle<'handle> {
+    shared: Arc<Shared>,
+    state: Arc<HandleState>,
+    _lifetime_of_handle: PhantomData<&'handle ()>,
+}
+
+/// Per-pipe state of one opened handle, like a pipe policy of WinUSB, and
+/// the interfaces it claimed.
+#[derive(Debug)]
+struct HandleState {
+    timeouts: Mutex<HashMap<u8, Duration>>,
+    policies: Mutex<HashMap<u8, policy::Applied>>,
+    // bumped by `abort_pipe` so that transfers waiting on NAKs give up
+    aborts: Mutex<HashMap<u8, u64>>,
+    // transfers on a pipe are served one at a time in the order submitted,
+    // like the queue of an endpoint in a host controller
+    turns: Mutex<HashMap<u8, Turns>>,
+    turn_over: Condvar,
+    // first interface of this handle, the one associated interfaces count from
+    interface_number: u8,
+    claims: Mutex<Vec<u8>>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for HandleState {
+    fn drop(&mut self) {
+        let claims = self.claims();
+        self.shared.claimed().retain(|i| !claims.contains(i));
+    }
+}
+
+impl HandleState {
+    fn new(shared: &Arc<Shared>, interface_number: u8) -> HandleState {
+        HandleState {
+            timeouts: Mutex::new(HashMap::new()),
+            policies: Mutex::new(HashMap::new()),
+            aborts: Mutex::new(HashMap::new()),
+            turns: Mutex::new(HashMap::new()),
+            turn_over: Condvar::new(),
+            interface_number,
+            claims: Mutex::new(Vec::new()),
+            shared: shared.clone(),
+        }
+    }
+
+    fn claims(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.claims.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn timeout(&self, pipe_index: u8) -> Duration {
+        let timeouts = self.timeouts.lock().unwrap_or_else(|e| e.into_inner());
+        timeouts.get(&pipe_index).cloned().unwrap_or_default()
+    }
+
+    fn policy(&self, pipe_index: u8) -> policy::Applied {
+        let policies = self.policies.lock().unwrap_or_else(|e| e.into_inner());
+        policies.get(&pipe_index).cloned().unwrap_or_default()
+    }
+
+    fn abort_generation(&self, pipe_index: u8) -> u64 {
+        let aborts = self.aborts.lock().unwrap_or_else(|e| e.into_inner());
+        aborts.get(&pipe_index).cloned().unwrap_or_default()
+    }
+
+    fn take_turn(&self, pipe_index: u8) -> u64 {
+        let mut turns = self.turns.lock().unwrap_or_else(|e| e.into_inner());
+        let turns = turns.entry(pipe_index).or_default();
+        turns.next += 1;
+        turns.next - 1
+    }
+
+    fn wait_turn(&self, pipe_index: u8, ticket: u64) -> Turn<'_> {
+        let mut turns = self.turns.lock().unwrap_or_else(|e| e.into_inner());
+        while turns.get(&pipe_index).map_or(0, |t| t.serving) != ticket {
+            turns = self.turn_over.wait(turns).unwrap_or_else(|e| e.into_inner());
+        }
+        Turn { state: self, pipe_index }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Turns {
+    next: u64,
+    serving: u64,
+}
+
+// ends the turn of a transfer however it finishes
+struct Turn<'a> {
+    state: &'a HandleState,
+    pipe_index: u8,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut turns = self.state.turns.lock().unwrap_or_else(|e| e.into_inner());
+        turns.entry(self.pipe_index).or_default().serving += 1;
+        self.state.turn_over.notify_all();
+    }
+}
+
+/// How often a NAKing virtual device is polled again.
+const NAK_INTERVAL: Duration = Duration::from_millis(1);
+
+impl Handle<'_> {
+    pub fn device_descriptor(&self) -> io::Result<DeviceDescriptor> {
+        self.shared.check_connected()?;
+        Ok(self.shared.device_descriptor.clone())
+    }
+
+    pub fn config_descriptor(&self, index: u8) -> io::Result<ConfigDescriptor> {
+        self.shared.check_connected()?;
+        match self.shared.config_descriptors.get(index as usize) {
+            Some(raw) => ConfigDescriptor::parse(raw),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such configuration")),
+        }
+    }
+
+    pub fn get_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16, buf: &mut [u8])
+        -> io::Result<usize>
+    {
+        self.shared.check_connected()?;
+        let raw = self.shared.raw_descriptor(descriptor_type, index, language_id)
+            .ok_or_else(stall)?;
+        let len = raw.len().min(buf.len());
+        buf[..len].copy_from_slice(&raw[..len]);
+        Ok(len)
+    }
+
+    pub fn speed(&self) -> io::Result<Speed> {
+        self.shared.check_connected()?;
+        Ok(self.shared.speed.clone())
+    }
+
+    pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_pipe_timeout(pipe_index, buf, self.state.timeout(pipe_index))
+    }
+
+    pub fn write_pipe(&self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.write_pipe_timeout(pipe_index, buf, self.state.timeout(pipe_index))
+    }
+
+    pub fn read_pipe_timeout(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let pending = self.pending(pipe_index, timeout);
+        self.read(pipe_index, &pending, buf)
+    }
+
+    pub fn write_pipe_timeout(&self, pipe_index: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        let pending = self.pending(pipe_index, timeout);
+        self.write(pipe_index, &pending, buf)
+    }
+
+    // policies apply the way WinUSB applies them
+    fn read(&self, pipe_index: u8, pending: &PendingTransfer, buf: &mut [u8]) -> io::Result<usize> {
+        let _turn = self.state.wait_turn(pipe_index, pending.ticket);
+        let policy = self.state.policy(pipe_index);
+        policy.check_read(buf.len())?;
+        let ans = self.transfer(pipe_index, pending, |r| r.read_pipe(pipe_index, buf));
+        if let Err(e) = &ans {
+            if policy.clears_stall(e) {
+                self.shared.responder()?.clear_halt(pipe_index)?;
+            }
+        }
+        ans
+    }
+
+    fn write(&self, pipe_index: u8, pending: &PendingTransfer, buf: &[u8]) -> io::Result<usize> {
+        let _turn = self.state.wait_turn(pipe_index, pending.ticket);
+        let len = self.transfer(pipe_index, pending, |r| r.write_pipe(pipe_index, buf))?;
+        if self.state.policy(pipe_index).zero_packet_after(len) {
+            self.transfer(pipe_index, pending, |r| r.write_pipe(pipe_index, &[]))?;
+        }
+        Ok(len)
+    }
+
+    fn pending(&self, pipe_index: u8, timeout: Duration) -> PendingTransfer {
+        PendingTransfer {
+            start: Instant::now(),
+            timeout,
+            abort_generation: self.state.abort_generation(pipe_index),
+            ticket: self.state.take_turn(pipe_index),
+            cancelled: AtomicBool::new(false),
+            completion: Mutex::new((None, None)),
+        }
+    }
+
+    pub fn read_isochronous(&self, pipe_index: u8, buf: &mut [u8], packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        self.isochronous(pipe_index, buf.len() / packets, packets, |r, offset, len| {
+            r.read_iso_packet(pipe_index, &mut buf[offset..offset + len])
+        })
+    }
+
+    pub fn write_isochronous(&self, pipe_index: u8, buf: &[u8], packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        self.isochronous(pipe_index, buf.len() / packets, packets, |r, offset, len| {
+            r.write_iso_packet(pipe_index, &buf[offset..offset + len])
+        })
+    }
+
+    // one packet per polling interval, which the mock does not wait for
+    fn isochronous<F>(&self, pipe_index: u8, packet_len: usize, packets: usize, mut f: F)
+        -> io::Result<Vec<crate::IsoPacket>>
+    where
+        F: FnMut(&mut dyn Responder, usize, usize) -> io::Result<usize>
+    {
+        let pending = self.pending(pipe_index, self.state.timeout(pipe_index));
+        let _turn = self.state.wait_turn(pipe_index, pending.ticket);
+        let mut ans = Vec::with_capacity(packets);
+        for offset in (0..packets).map(|i| i * packet_len) {
+            let (length, status) = match f(&mut **self.shared.responder()?, offset, packet_len) {
+                Ok(len) => (len.min(packet_len), Ok(())),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (0, Ok(())),
+                Err(e) => (0, Err(e.into())),
+            };
+            ans.push(crate::IsoPacket { offset, length, status });
+        }
+        Ok(ans)
+    }
+
+    /// Run a read on a thread of its own, like a host controller would.
+    pub fn read_pipe_async<'a>(&'a self, pipe_index: u8, mut buf: Vec<u8>) -> PipeFuture<'a> {
+        self.spawn_transfer(pipe_index, move |handle, pending| {
+            handle.read(pipe_index, pending, &mut buf).map(|len| (buf, len))
+        })
+    }
+
+    /// Run a write on a thread of its own, like a host controller would.
+    pub fn write_pipe_async<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> PipeFuture<'a> {
+        self.spawn_transfer(pipe_index, move |handle, pending| {
+            handle.write(pipe_index, pending, &buf).map(|len| (buf, len))
+        })
+    }
+
+    // the transfer counts as submitted now, so later aborts apply to it
+    fn spawn_transfer<'a, F>(&self, pipe_index: u8, f: F) -> PipeFuture<'a>
+    where
+        F: FnOnce(&Handle, &PendingTransfer) -> AsyncResult + Send + 'static
+    {
+        let handle: Handle<'static> = Handle {
+            shared: self.shared.clone(),
+            state: self.state.clone(),
+            _lifetime_of_handle: PhantomData,
+        };
+        let shared = Arc::new(self.pending(pipe_index, self.state.timeout(pipe_index)));
+        let transfer = shared.clone();
+        thread::spawn(move || {
+            let ans = f(&handle, &transfer);
+            let waker = {
+                let mut completion = transfer.completion.lock().unwrap_or_else(|e| e.into_inner());
+                completion.0 = Some(ans);
+                completion.1.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake()
+            }
+        });
+        PipeFuture { transfer: shared, done: false, _lifetime: PhantomData }
+    }
+
+    fn transfer<F>(&self, pipe_index: u8, pending: &PendingTransfer, mut f: F) -> io::Result<usize>
+    where
+        F: FnMut(&mut dyn Responder) -> io::Result<usize>
+    {
+        loop {
+            // checked before every attempt, so an aborted transfer never takes data
+            if self.state.abort_generation(pipe_index) != pending.abort_generation
+                || pending.cancelled.load(Ordering::SeqCst)
+            {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "transfer aborted"))
+            }
+            match f(&mut **self.shared.responder()?) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                ans => return ans,
+            }
+            if pending.timeout != Duration::from_secs(0) && pending.start.elapsed() >= pending.timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out"))
+            }
+            thread::sleep(NAK_INTERVAL);
+        }
+    }
+
+    pub fn set_timeout(&self, pipe_index: u8, timeout: Duration) -> io::Result<()> {
+        self.shared.check_connected()?;
+        let mut timeouts = self.state.timeouts.lock().unwrap_or_else(|e| e.into_inner());
+        timeouts.insert(pipe_index, timeout);
+        Ok(())
+    }
+
+    pub fn claim_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.shared.check_connected()?;
+        if !self.shared.has_interface(interface_number) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such interface"))
+        }
+        let mut claims = self.state.claims();
+        if claims.contains(&interface_number) {
+            return Ok(())
+        }
+        let mut claimed = self.shared.claimed();
+        if claimed.contains(&interface_number) {
+            return Err(io::Error::new(io::ErrorKind::ResourceBusy, "interface claimed by another handle"))
+        }
+        claimed.push(interface_number);
+        claims.push(interface_number);
+        Ok(())
+    }
+
+    pub fn release_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.check_claimed(interface_number)?;
+        self.state.claims().retain(|&i| i != interface_number);
+        self.shared.claimed().retain(|&i| i != interface_number);
+        Ok(())
+    }
+
+    // no kernel drivers are bound to virtual devices
+    pub fn kernel_driver_active(&self, interface_number: u8) -> io::Result<bool> {
+        self.shared.check_connected()?;
+        if !self.shared.has_interface(interface_number) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such interface"))
+        }
+        Ok(false)
+    }
+
+    pub fn detach_kernel_driver(&self, interface_number: u8) -> io::Result<()> {
+        self.kernel_driver_active(interface_number)?;
+        Err(io::Error::new(io::ErrorKind::NotFound,
+            format!("no kernel driver bound to interface {}", interface_number)))
+    }
+
+    pub fn attach_kernel_driver(&self, interface_number: u8) -> io::Result<()> {
+        self.kernel_driver_active(interface_number).map(drop)
+    }
+
+    pub fn set_auto_detach_kernel_driver(&self, _enable: bool) -> io::Result<()> {
+        self.shared.check_connected()
+    }
+
+    pub fn set_alternate_setting(&self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        self.check_claimed(interface_number)?;
+        let exists = self.shared.config_descriptors.first()
+            .and_then(|raw| ConfigDescriptor::parse(raw).ok())
+            .is_some_and(|config| config.alt_setting(interface_number, alternate_setting).is_some());
+        if !exists {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such alternate setting"))
+        }
+        self.shared.responder()?.set_alternate_setting(interface_number, alternate_setting)?;
+        let mut alt_settings = self.shared.alt_settings.lock().unwrap_or_else(|e| e.into_inner());
+        alt_settings.insert(interface_number, alternate_setting);
+        Ok(())
+    }
+
+    pub fn pipes(&self) -> io::Result<Vec<crate::PipeInfo>> {
+        let config = self.config_descriptor(0)?;
+        let interface_number = self.state.interface_number;
+        let alternate_setting = {
+            let alt_settings = self.shared.alt_settings.lock().unwrap_or_else(|e| e.into_inner());
+            alt_settings.get(&interface_number).cloned().unwrap_or_default()
+        };
+        Ok(config.alt_setting(interface_number, alternate_setting)
+            .map(|alt| alt.endpoints.iter().map(crate::PipeInfo::from).collect())
+            .unwrap_or_default())
+    }
+
+    fn check_claimed(&self, interface_number: u8) -> io::Result<()> {
+        self.shared.check_connected()?;
+        if !self.state.claims().contains(&interface_number) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface not claimed by this handle"))
+        }
+        Ok(())
+    }
+
+    pub fn associated_interface<'a>(&self, index: u8) -> io::Result<Handle<'a>> {
+        let interface_number = self.config_descriptor(0)?
+            .associated_interface(self.state.interface_number, index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such associated interface"))?;
+        let ans = Handle {
+            shared: self.shared.clone(),
+            state: Arc::new(HandleState::new(&self.shared, interface_number)),
+            _lifetime_of_handle: PhantomData,
+        };
+        ans.claim_interface(interface_number)?;
+        Ok(ans)
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        Ok(self.shared.port_path.parse()?)
+    }
+
+    /// Unplug the device and plug it into the same port again under a new
+    /// number, unless its responder fails to come back from the reset.
+    ///
+    /// This acts on the registry of the calling thread, like `register`.
+    pub fn reset_device(&self) -> io::Result<()> {
+        let came_back = self.shared.responder()?.reset().is_ok();
+        self.shared.connected.store(false, Ordering::SeqCst);
+        REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            registry.devices.retain(|s| s.number != self.shared.number);
+            registry.generation += 1;
+            if came_back {
+                let number = registry.next_number;
+                registry.next_number += 1;
+                registry.devices.push(Arc::new(self.shared.reenumerated(number)));
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_pipe_policy(&self, pipe_index: u8, policy: PipePolicy) -> io::Result<()> {
+        let max_packet_size = self.pipes()?.into_iter()
+            .find(|pipe| pipe.endpoint_address == pipe_index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such pipe"))?
+            .max_packet_size;
+        let mut policies = self.state.policies.lock().unwrap_or_else(|e| e.into_inner());
+        policies.insert(pipe_index, policy::Applied { policy, max_packet_size });
+        Ok(())
+    }
+
+    pub fn pipe_policy(&self, pipe_index: u8) -> io::Result<PipePolicy> {
+        self.shared.check_connected()?;
+        Ok(self.state.policy(pipe_index).policy)
+    }
+
+    pub fn flush_pipe(&self, _pipe_index: u8) -> io::Result<()> {
+        self.shared.check_connected()
+    }
+
+    pub fn reset_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.shared.responder()?.clear_halt(pipe_index)
+    }
+
+    pub fn abort_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.shared.check_connected()?;
+        let mut aborts = self.state.aborts.lock().unwrap_or_else(|e| e.into_inner());
+        *aborts.entry(pipe_index).or_insert(0) += 1;
+        Ok(())
+    }
+
+    pub fn control_in(&self, request: ControlRequest, buf: &mut [u8], _timeout: Duration)
+        -> io::Result<usize>
+    {
+        let [descriptor_type, index] = request.value.to_be_bytes();
+        if request == ControlRequest::get_descriptor(descriptor_type, index, request.index) {
+            return self.get_descriptor(descriptor_type, index, request.index, buf)
+        }
+        self.shared.responder()?.control_in(&request, buf)
+    }
+
+    pub fn control_out(&self, request: ControlRequest, buf: &[u8], _timeout: Duration)
+        -> io::Result<usize>
+    {
+        self.shared.responder()?.control_out(&request, buf)
+    }
+}
+
+/// A transfer from submission until the device answers.
+struct PendingTransfer {
+    start: Instant,
+    timeout: Duration,
+    abort_generation: u64,
+    // place in the queue of the pipe
+    ticket: u64,
+    // set when an asynchronous transfer is dropped
+    cancelled: AtomicBool,
+    // the result of an asynchronous transfer, and the task to wake for it
+    completion: Mutex<(Option<AsyncResult>, Option<Waker>)>,
+}
+
+type AsyncResult = io::Result<(Vec<u8>, usize)>;
+
+/// A transfer running on its own thread, resolving to its buffer and the
+/// length transferred.
+///
+/// Dropping it before completion cancels the transfer at the next NAK.
+pub struct PipeFuture<'a> {
+    transfer: Arc<PendingTransfer>,
+    done: bool,
+    _lifetime: PhantomData<&'a ()>,
+}
+
+impl Future for PipeFuture<'_> {
+    type Output = AsyncResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        assert!(!this.done, "pipe future polled after completion");
+        let mut completion = this.transfer.completion.lock().unwrap_or_else(|e| e.into_inner());
+        match completion.0.take() {
+            Some(ans) => {
+                this.done = true;
+                Poll::Ready(ans)
+            },
+            None => {
+                completion.1 = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+impl Drop for PipeFuture<'_> {
+    fn drop(&mut self) {
+        self.transfer.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for PipeFuture<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PipeFuture")
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+macro_rules! impl_eq_hash_by_number {
+    ($($ty:ident),+) => { $(
+        impl PartialEq for $ty<'_> {
+            fn eq(&self, other: &Self) -> bool {
+                self.shared.number == other.shared.number
+            }
+        }
+
+        impl Eq for $ty<'_> {}
+
+        impl hash::Hash for $ty<'_> {
+            fn hash<H: hash::Hasher>(&self, state: &mut H) {
+                self.shared.number.hash(state)
+            }
+        }
+    )+ };
+}
+
+impl_eq_hash_by_number!(Device, Handle);
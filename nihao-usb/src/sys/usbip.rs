@@ -0,0 +1,1182 @@
+//! Devices exported by USB/IP hosts, replacing the operating system backend
+//! when the `usbip` feature is enabled.
+//!
+//! Hosts are added with `add_host`; `devices` then lists what every host
+//! exports, and opening a device imports it over a TCP connection of its
+//! own. Devices keep the bus ID they have on their host as location, so
+//! devices of different hosts may share a location.
+//!
+//! ```no_run
+//! use nihao_usb::sys::usbip;
+//! usbip::add_host("hil-rack-3.lab");
+//! for device in nihao_usb::devices()? {
+//!     let handle = device?.open()?;
+//!     println!("{:?}", handle.product()?);
+//! }
+//! # Ok::<(), nihao_usb::Error>(())
+//! ```
+pub mod protocol;
+
+use core::{fmt, future::Future, hash, iter::FusedIterator, marker::PhantomData, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use std::{collections::HashMap, io, thread};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, atomic::{AtomicBool, AtomicU32, Ordering}};
+use std::time::{Duration, Instant};
+use crate::{descriptor, policy, ConfigDescriptor, ControlRequest, DeviceDescriptor, Direction, Error, PipePolicy};
+use crate::{Recipient, RequestType, Speed, TransferType};
+use protocol::{Command, ExportedDevice, HeaderBasic, OpRequest, Reply, Submitted};
+
+/// How long connecting to a host may take before it counts as unreachable.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Timeout of control requests issued by this backend itself.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often `Monitor` asks hosts for their devices again; USB/IP has no
+/// notifications of devices being plugged or unplugged.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static HOSTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn hosts_mut() -> MutexGuard<'static, Vec<String>> {
+    HOSTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Add a host to list devices from, like `192.168.1.20`, `[::1]:3240` or
+/// `hil-rack-3.lab`; the port defaults to the one of `usbipd`.
+pub fn add_host(host: &str) {
+    let host = with_default_port(host);
+    let mut hosts = hosts_mut();
+    if !hosts.contains(&host) {
+        hosts.push(host);
+    }
+}
+
+/// Stop listing devices of a host; handles already opened stay usable.
+///
+/// Returns `false` if no such host was added.
+pub fn remove_host(host: &str) -> bool {
+    let host = with_default_port(host);
+    let mut hosts = hosts_mut();
+    let len = hosts.len();
+    hosts.retain(|h| *h != host);
+    hosts.len() != len
+}
+
+/// Hosts added by `add_host`, with their ports.
+pub fn hosts() -> Vec<String> {
+    hosts_mut().clone()
+}
+
+fn with_default_port(host: &str) -> String {
+    if host.parse::<SocketAddr>().is_ok() {
+        return host.to_string()
+    }
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return SocketAddr::new(ip, protocol::DEFAULT_PORT).to_string()
+    }
+    match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+        _ => format!("{}:{}", host, protocol::DEFAULT_PORT),
+    }
+}
+
+fn connect(host: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host name resolves to no address");
+    for addr in host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                // commands are small and answered one by one, don't hold them back
+                stream.set_nodelay(true)?;
+                return Ok(stream)
+            },
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// List the devices all hosts added by `add_host` export.
+///
+/// Fails if any host cannot be reached.
+pub fn devices<'list>() -> io::Result<DeviceList<'list>> {
+    let mut devices = Vec::new();
+    for host in hosts() {
+        devices.extend(list_host(&host)?);
+    }
+    Ok(DeviceList { devices: devices.into(), _lifetime_of_list: PhantomData })
+}
+
+/// List the devices one host exports, whether it was added or not.
+pub fn devices_on<'list>(host: &str) -> io::Result<DeviceList<'list>> {
+    let devices = list_host(&with_default_port(host))?;
+    Ok(DeviceList { devices: devices.into(), _lifetime_of_list: PhantomData })
+}
+
+fn list_host(host: &str) -> io::Result<Vec<Arc<Exported>>> {
+    let mut stream = connect(host)?;
+    OpRequest::DevList.write(&mut stream)?;
+    let devices = protocol::read_devlist(&mut stream)?;
+    Ok(devices.into_iter().map(|device| Arc::new(Exported { host: host.to_string(), device })).collect())
+}
+
+/// A device as listed by its host.
+#[derive(Debug, Hash, Eq, PartialEq)]
+struct Exported {
+    host: String,
+    device: ExportedDevice,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceList<'list> {
+    devices: Arc<[Arc<Exported>]>,
+    _lifetime_of_list: PhantomData<&'list ()>,
+}
+
+impl<'list> DeviceList<'list> {
+    pub fn iter<'iter>(&self) -> Devices<'iter> {
+        Devices { devices: self.devices.clone(), iter_index: 0, _lifetime_of_iter: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceIntoIter<'iter> {
+    iter: Devices<'iter>,
+}
+
+impl<'list> IntoIterator for DeviceList<'list> {
+    type Item = io::Result<Device<'list>>;
+    type IntoIter = DeviceIntoIter<'list>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DeviceIntoIter { iter: self.iter() }
+    }
+}
+
+impl<'iter> Iterator for DeviceIntoIter<'iter> {
+    type Item = io::Result<Device<'iter>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Devices<'iter> {
+    devices: Arc<[Arc<Exported>]>,
+    iter_index: usize,
+    _lifetime_of_iter: PhantomData<&'iter ()>,
+}
+
+impl<'iter> Iterator for Devices<'iter> {
+    type Item = io::Result<Device<'iter>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let exported = self.devices.get(self.iter_index)?.clone();
+        self.iter_index += 1;
+        Some(Ok(Device { exported, _lifetime_of_device: PhantomData }))
+    }
+}
+
+impl FusedIterator for Devices<'_> {}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Device<'device> {
+    exported: Arc<Exported>,
+    _lifetime_of_device: PhantomData<&'device ()>,
+}
+
+impl<'device> Device<'device> {
+    /// Import the device from its host; it stays imported until the last
+    /// handle on it drops.
+    pub fn open<'handle>(&self) -> io::Result<Handle<'handle>> {
+        let connection = Connection::import(&self.exported.host, &self.exported.device.busid)?;
+        let connection = Arc::new(connection);
+        let ans = Handle {
+            state: Arc::new(HandleState::new(&connection, 0)),
+            connection,
+            _lifetime_of_handle: PhantomData,
+        };
+        // like the Linux backend, which claims the first interface if it can
+        let _ = ans.claim_interface(0);
+        Ok(ans)
+    }
+
+    pub fn device_info(&self) -> crate::DeviceInfo {
+        let device = &self.exported.device;
+        let mut ans = crate::DeviceInfo {
+            vendor_id: Some(device.id_vendor),
+            product_id: Some(device.id_product),
+            port_path: Some(device.busid.clone()),
+            ..crate::DeviceInfo::default()
+        };
+        for interface in &device.interfaces {
+            if !ans.interface_classes.contains(&interface.interface_class) {
+                ans.interface_classes.push(interface.interface_class);
+            }
+        }
+        ans
+    }
+
+    pub fn id(&self) -> DeviceId {
+        DeviceId { host: self.exported.host.clone(), busid: self.exported.device.busid.clone() }
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        Ok(self.exported.device.busid.parse()?)
+    }
+
+    /// The host exporting this device, with its port.
+    pub fn host(&self) -> &str {
+        &self.exported.host
+    }
+}
+
+/// A remote device is identified by its host and its bus ID there.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct DeviceId {
+    host: String,
+    busid: String,
+}
+
+/// Asks hosts for their devices again every `POLL_INTERVAL`.
+#[derive(Debug)]
+pub struct Monitor {
+    last_poll: Instant,
+}
+
+impl Monitor {
+    pub fn new() -> io::Result<Monitor> {
+        Ok(Monitor { last_poll: Instant::now() })
+    }
+
+    /// Tell whether it is time to list devices again, waiting for it at
+    /// most `timeout`.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let until_poll = POLL_INTERVAL.saturating_sub(self.last_poll.elapsed());
+        match timeout {
+            Some(timeout) if timeout < until_poll => {
+                thread::sleep(timeout);
+                Ok(false)
+            },
+            _ => {
+                thread::sleep(until_poll);
+                self.last_poll = Instant::now();
+                Ok(true)
+            },
+        }
+    }
+}
+
+// errno values of Linux, which hosts report whatever the client runs on
+const ENOENT: i32 = 2;
+const ENODEV: i32 = 19;
+const EPIPE: i32 = 32;
+const ETIME: i32 = 62;
+const ENOSR: i32 = 63;
+const ECOMM: i32 = 70;
+const EPROTO: i32 = 71;
+const EOVERFLOW: i32 = 75;
+const EILSEQ: i32 = 84;
+const ECONNRESET: i32 = 104;
+const ESHUTDOWN: i32 = 108;
+const ETIMEDOUT: i32 = 110;
+
+/// Classify the status of a failed URB like `Error` classifies usbfs errors.
+fn status_error(status: i32) -> io::Error {
+    match -status {
+        EPIPE => Error::Stall.into(),
+        ENODEV | ESHUTDOWN => Error::Disconnected.into(),
+        ENOENT | ECONNRESET => aborted(),
+        ETIMEDOUT => Error::Timeout.into(),
+        EOVERFLOW => Error::Overflow.into(),
+        EPROTO | EILSEQ | ECOMM | ENOSR | ETIME => Error::Pipe.into(),
+        errno => io::Error::other(format!("USB/IP host failed the transfer with errno {}", errno)),
+    }
+}
+
+// `protocol` refuses isochronous packet descriptors as well
+fn isochronous_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "isochronous USB/IP transfers are not supported")
+}
+
+fn aborted() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "transfer aborted")
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "connection to USB/IP host closed")
+}
+
+fn import_error(status: u32) -> io::Error {
+    match status {
+        protocol::ST_NA | protocol::ST_DEV_BUSY => io::Error::new(io::ErrorKind::ResourceBusy,
+            "device is imported by another client or unavailable on its host"),
+        protocol::ST_NODEV => io::Error::new(io::ErrorKind::NotFound, "host exports no such device"),
+        _ => io::Error::other(format!("host refused to export the device, status {}", status)),
+    }
+}
+
+/// Data received by a URB and the length transferred.
+type UrbResult = io::Result<(Vec<u8>, usize)>;
+
+/// A URB submitted to the host, until its reply arrives.
+struct Urb {
+    pipe_index: u8,
+    submitted: Submitted,
+    // set before unlinking a URB which took too long
+    timed_out: AtomicBool,
+    // the result, and the task to wake for it
+    completion: Mutex<(Option<UrbResult>, Option<Waker>)>,
+    done: Condvar,
+}
+
+impl Urb {
+    fn completion(&self) -> MutexGuard<'_, (Option<UrbResult>, Option<Waker>)> {
+        self.completion.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn finish(&self, ans: UrbResult) {
+        let ans = match ans {
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted && self.timed_out.load(Ordering::SeqCst) =>
+                Err(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out")),
+            ans => ans,
+        };
+        let waker = {
+            let mut completion = self.completion();
+            completion.0 = Some(ans);
+            completion.1.take()
+        };
+        self.done.notify_all();
+        if let Some(waker) = waker {
+            waker.wake()
+        }
+    }
+
+    /// Wait for the reply; a zero `timeout` waits forever.
+    fn wait(&self, timeout: Duration) -> Option<UrbResult> {
+        let deadline = Instant::now() + timeout;
+        let mut completion = self.completion();
+        loop {
+            if let Some(ans) = completion.0.take() {
+                return Some(ans)
+            }
+            if timeout == Duration::from_secs(0) {
+                completion = self.done.wait(completion).unwrap_or_else(|e| e.into_inner());
+                continue
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return None
+            }
+            completion = self.done.wait_timeout(completion, remaining)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+}
+
+/// URBs waiting for replies on one connection, by sequence number.
+#[derive(Default)]
+struct Urbs {
+    table: Mutex<UrbTable>,
+}
+
+#[derive(Default)]
+struct UrbTable {
+    closed: bool,
+    pending: HashMap<u32, Arc<Urb>>,
+    // sequence numbers of unlink commands, and of the URBs they unlink
+    unlinks: HashMap<u32, u32>,
+    // URBs given up on while the host still owes a reply, to read it past
+    abandoned: HashMap<u32, Submitted>,
+}
+
+impl Urbs {
+    fn table(&self) -> MutexGuard<'_, UrbTable> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn complete(&self, seqnum: u32, ans: UrbResult) {
+        let urb = {
+            let mut table = self.table();
+            table.abandoned.remove(&seqnum);
+            table.pending.remove(&seqnum)
+        };
+        if let Some(urb) = urb {
+            urb.finish(ans)
+        }
+    }
+
+    /// Fail a URB the host does not answer in time, as timed out.
+    fn abandon(&self, seqnum: u32) {
+        let urb = {
+            let mut table = self.table();
+            let urb = table.pending.remove(&seqnum);
+            if let Some(urb) = &urb {
+                table.abandoned.insert(seqnum, urb.submitted);
+            }
+            urb
+        };
+        if let Some(urb) = urb {
+            urb.timed_out.store(true, Ordering::SeqCst);
+            urb.finish(Err(aborted()))
+        }
+    }
+
+    fn close(&self) {
+        let pending: Vec<Arc<Urb>> = {
+            let mut table = self.table();
+            table.closed = true;
+            table.unlinks.clear();
+            table.abandoned.clear();
+            table.pending.drain().map(|(_, urb)| urb).collect()
+        };
+        for urb in pending {
+            urb.finish(Err(disconnected()))
+        }
+    }
+
+    /// Dispatch replies of the host until the connection closes.
+    fn receive(&self, mut stream: TcpStream) {
+        loop {
+            let reply = Reply::read(&mut stream, |seqnum| {
+                let table = self.table();
+                table.pending.get(&seqnum).map(|urb| urb.submitted).or_else(|| table.abandoned.get(&seqnum).cloned())
+            });
+            match reply {
+                Ok(Reply::Submit { header, status: 0, actual_length, data }) =>
+                    self.complete(header.seqnum, Ok((data, actual_length.max(0) as usize))),
+                Ok(Reply::Submit { header, status, .. }) =>
+                    self.complete(header.seqnum, Err(status_error(status))),
+                // a URB completed before its unlink arrived was answered already
+                Ok(Reply::Unlink { header, .. }) => {
+                    let target = self.table().unlinks.remove(&header.seqnum);
+                    if let Some(target) = target {
+                        self.complete(target, Err(aborted()))
+                    }
+                },
+                Err(_) => return self.close(),
+            }
+        }
+    }
+}
+
+/// One imported device.
+struct Connection {
+    device: ExportedDevice,
+    stream: Mutex<TcpStream>,
+    urbs: Arc<Urbs>,
+    next_seqnum: AtomicU32,
+    // interfaces claimed by any handle on this connection
+    claimed: Mutex<Vec<u8>>,
+    // alternate settings chosen through this connection, zero if not listed
+    alt_settings: Mutex<HashMap<u8, u8>>,
+    // `interval` of URBs by endpoint, read from the descriptors when first needed
+    intervals: Mutex<Option<HashMap<u8, i32>>>,
+}
+
+impl Connection {
+    fn import(host: &str, busid: &str) -> io::Result<Connection> {
+        let mut stream = connect(host)?;
+        OpRequest::Import { busid: busid.to_string() }.write(&mut stream)?;
+        let device = protocol::read_import(&mut stream)?.map_err(import_error)?;
+        let urbs = Arc::new(Urbs::default());
+        let reader = stream.try_clone()?;
+        let receiving = urbs.clone();
+        thread::spawn(move || receiving.receive(reader));
+        Ok(Connection {
+            device,
+            stream: Mutex::new(stream),
+            urbs,
+            next_seqnum: AtomicU32::new(1),
+            claimed: Mutex::new(Vec::new()),
+            alt_settings: Mutex::new(HashMap::new()),
+            intervals: Mutex::new(None),
+        })
+    }
+
+    fn claimed(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.claimed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_connected(&self) -> io::Result<()> {
+        if self.urbs.table().closed {
+            return Err(disconnected())
+        }
+        Ok(())
+    }
+
+    fn next_seqnum(&self) -> u32 {
+        self.next_seqnum.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn send(&self, command: &Command) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        command.write(&mut *stream)
+    }
+
+    /// Submit a URB; `setup` is all zeroes except on the default pipe.
+    fn submit(&self, pipe_index: u8, direction: Direction, flags: u32, setup: [u8; 8], out: &[u8], in_len: usize)
+        -> io::Result<(u32, Arc<Urb>)>
+    {
+        let (direction, transfer_flags, len) = match direction {
+            Direction::In => (protocol::USBIP_DIR_IN, flags | protocol::URB_DIR_IN, in_len),
+            Direction::Out => (protocol::USBIP_DIR_OUT, flags, out.len()),
+        };
+        let interval = self.interval(pipe_index)?;
+        let seqnum = self.next_seqnum();
+        let urb = Arc::new(Urb {
+            pipe_index,
+            submitted: Submitted { direction, transfer_buffer_length: len as i32 },
+            timed_out: AtomicBool::new(false),
+            completion: Mutex::new((None, None)),
+            done: Condvar::new(),
+        });
+        {
+            let mut table = self.urbs.table();
+            if table.closed {
+                return Err(disconnected())
+            }
+            // registered first, so the reply finds it however fast it comes
+            table.pending.insert(seqnum, urb.clone());
+        }
+        let command = Command::Submit {
+            header: HeaderBasic {
+                command: protocol::USBIP_CMD_SUBMIT,
+                seqnum,
+                devid: self.device.devid(),
+                direction,
+                ep: (pipe_index & 0x0F) as u32,
+            },
+            transfer_flags,
+            transfer_buffer_length: len as i32,
+            interval,
+            setup,
+            data: out.to_vec(),
+        };
+        if let Err(e) = self.send(&command) {
+            self.urbs.table().pending.remove(&seqnum);
+            return Err(e)
+        }
+        Ok((seqnum, urb))
+    }
+
+    /// Ask the host to cancel a URB; it completes once the host answers.
+    fn unlink(&self, seqnum: u32) {
+        let unlink_seqnum = self.next_seqnum();
+        {
+            let mut table = self.urbs.table();
+            if !table.pending.contains_key(&seqnum) {
+                return
+            }
+            table.unlinks.insert(unlink_seqnum, seqnum);
+        }
+        let command = Command::Unlink {
+            header: HeaderBasic {
+                command: protocol::USBIP_CMD_UNLINK,
+                seqnum: unlink_seqnum,
+                devid: self.device.devid(),
+                direction: protocol::USBIP_DIR_OUT,
+                ep: 0,
+            },
+            unlink_seqnum: seqnum,
+        };
+        if self.send(&command).is_err() {
+            self.urbs.complete(seqnum, Err(aborted()))
+        }
+    }
+
+    /// Unlink a URB which took too long. The host answers an unlink even if
+    /// the URB completed meanwhile; should it not do so in time, the URB is
+    /// given up on.
+    fn time_out(&self, seqnum: u32, urb: &Urb) {
+        urb.timed_out.store(true, Ordering::SeqCst);
+        self.unlink(seqnum);
+        let completion = urb.completion();
+        let unanswered = urb.done.wait_timeout_while(completion, CONTROL_TIMEOUT, |c| c.0.is_none())
+            .map(|(_, ans)| ans.timed_out())
+            .unwrap_or(false);
+        if unanswered {
+            self.urbs.abandon(seqnum)
+        }
+    }
+
+    /// Wait for a submitted URB, unlinking it after `timeout`.
+    fn wait(&self, (seqnum, urb): (u32, Arc<Urb>), timeout: Duration) -> UrbResult {
+        match urb.wait(timeout) {
+            Some(ans) => ans,
+            None => {
+                self.time_out(seqnum, &urb);
+                // completed by now, unless its reply is being dispatched
+                urb.wait(Duration::from_secs(0)).unwrap_or_else(|| Err(aborted()))
+            },
+        }
+    }
+
+    fn get_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16, buf: &mut [u8])
+        -> io::Result<usize>
+    {
+        let request = ControlRequest::get_descriptor(descriptor_type, index, language_id);
+        self.control(request, &[], buf, CONTROL_TIMEOUT)
+    }
+
+    fn config_descriptor(&self, index: u8) -> io::Result<ConfigDescriptor> {
+        let mut header = [0u8; 9];
+        let len = self.get_descriptor(descriptor::DT_CONFIG, index, 0, &mut header)?;
+        if len < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "configuration descriptor too short"))
+        }
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let mut raw = vec![0u8; total_length as usize];
+        let len = self.get_descriptor(descriptor::DT_CONFIG, index, 0, &mut raw)?;
+        ConfigDescriptor::parse(&raw[..len])
+    }
+
+    /// The active configuration, `None` if the device is unconfigured.
+    fn active_config(&self) -> io::Result<Option<ConfigDescriptor>> {
+        for index in 0..self.device.num_configurations {
+            let candidate = self.config_descriptor(index)?;
+            if candidate.configuration_value == self.device.configuration_value {
+                return Ok(Some(candidate))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Pipes of an interface in its current alternate setting.
+    fn pipes(&self, config: &ConfigDescriptor, interface_number: u8) -> Vec<crate::PipeInfo> {
+        let alternate_setting = {
+            let alt_settings = self.alt_settings.lock().unwrap_or_else(|e| e.into_inner());
+            alt_settings.get(&interface_number).cloned().unwrap_or_default()
+        };
+        config.alt_setting(interface_number, alternate_setting)
+            .map(|alt| alt.endpoints.iter().map(crate::PipeInfo::from).collect())
+            .unwrap_or_default()
+    }
+
+    // `enum usb_device_speed` of Linux
+    fn speed(&self) -> Speed {
+        match self.device.speed {
+            1 => Speed::Low,
+            2 => Speed::Full,
+            3 => Speed::High,
+            5 | 6 => Speed::Super,
+            _ => Speed::Unknown,
+        }
+    }
+
+    /// `interval` of URBs on a pipe, which the host copies into its own URBs;
+    /// Linux refuses interrupt URBs without one.
+    fn interval(&self, pipe_index: u8) -> io::Result<i32> {
+        if pipe_index & 0x0F == 0 {
+            return Ok(0)
+        }
+        if let Some(intervals) = &*self.intervals.lock().unwrap_or_else(|e| e.into_inner()) {
+            return Ok(intervals.get(&pipe_index).cloned().unwrap_or_default())
+        }
+        let mut intervals = HashMap::new();
+        if let Some(config) = self.active_config()? {
+            for interface in &config.interfaces {
+                for pipe in self.pipes(&config, interface.interface_number) {
+                    intervals.insert(pipe.endpoint_address, urb_interval(&pipe, self.speed()));
+                }
+            }
+        }
+        let ans = intervals.get(&pipe_index).cloned().unwrap_or_default();
+        *self.intervals.lock().unwrap_or_else(|e| e.into_inner()) = Some(intervals);
+        Ok(ans)
+    }
+
+    fn control(&self, request: ControlRequest, out: &[u8], buf: &mut [u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        let len = match request.direction {
+            Direction::In => buf.len(),
+            Direction::Out => out.len(),
+        };
+        let setup = setup_packet(&request, len);
+        let (data, len) = self.wait(self.submit(0, request.direction, 0, setup, out, buf.len())?, timeout)?;
+        Ok(copy_received(&data, len, buf))
+    }
+
+    /// Clear a halt of an endpoint without waiting for the host to answer.
+    ///
+    /// URBs submitted later queue up behind the request on the host, so this
+    /// also works while completing a transfer, where waiting is no option.
+    fn clear_halt(&self, pipe_index: u8) {
+        let setup = setup_packet(&clear_halt_request(pipe_index), 0);
+        // the reply completes a URB nobody waits for
+        let _ = self.submit(0, Direction::Out, 0, setup, &[], 0);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // ends the receiving thread, and with it the import on the host
+        let stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("device", &self.device)
+            .finish_non_exhaustive()
+    }
+}
+
+// In frames at low and full speed and in microframes above, like the
+// `usb_fill_int_urb` of Linux; zero for pipes without polling.
+fn urb_interval(pipe: &crate::PipeInfo, speed: Speed) -> i32 {
+    if pipe.transfer_type != TransferType::Interrupt {
+        return 0
+    }
+    let microframes = matches!(speed, Speed::High | Speed::Super);
+    let interval = pipe.polling_interval(speed);
+    if microframes {
+        (interval.as_micros() / 125) as i32
+    } else {
+        interval.as_millis() as i32
+    }
+}
+
+fn setup_packet(request: &ControlRequest, len: usize) -> [u8; 8] {
+    let mut setup = [0u8; 8];
+    setup[0] = request.request_type_bits();
+    setup[1] = request.request;
+    setup[2..4].copy_from_slice(&request.value.to_le_bytes());
+    setup[4..6].copy_from_slice(&request.index.to_le_bytes());
+    setup[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+    setup
+}
+
+fn clear_halt_request(pipe_index: u8) -> ControlRequest {
+    ControlRequest::new(Direction::Out, RequestType::Standard, Recipient::Endpoint,
+        REQUEST_CLEAR_FEATURE, FEATURE_ENDPOINT_HALT, pipe_index as u16)
+}
+
+/// Copy data received by an IN URB into `buf`, returning the length
+/// transferred; OUT URBs transfer their length without receiving data.
+fn copy_received(data: &[u8], len: usize, buf: &mut [u8]) -> usize {
+    let copied = data.len().min(buf.len());
+    buf[..copied].copy_from_slice(&data[..copied]);
+    if data.is_empty() { len } else { copied }
+}
+
+#[derive(Debug, Clone)]
+pub struct Handle<'handle> {
+    connection: Arc<Connection>,
+    state: Arc<HandleState>,
+    _lifetime_of_handle: PhantomData<&'handle ()>,
+}
+
+/// Pipe timeouts and policies of one opened handle, and the interfaces it claimed.
+#[derive(Debug)]
+struct HandleState {
+    timeouts: Mutex<HashMap<u8, Duration>>,
+    policies: Mutex<HashMap<u8, policy::Applied>>,
+    // first interface of this handle, the one associated interfaces count from
+    interface_number: u8,
+    claims: Mutex<Vec<u8>>,
+    connection: Arc<Connection>,
+}
+
+impl Drop for HandleState {
+    fn drop(&mut self) {
+        let claims = self.claims();
+        self.connection.claimed().retain(|i| !claims.contains(i));
+    }
+}
+
+impl HandleState {
+    fn new(connection: &Arc<Connection>, interface_number: u8) -> HandleState {
+        HandleState {
+            timeouts: Mutex::new(HashMap::new()),
+            policies: Mutex::new(HashMap::new()),
+            interface_number,
+            claims: Mutex::new(Vec::new()),
+            connection: connection.clone(),
+        }
+    }
+
+    fn claims(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.claims.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn timeout(&self, pipe_index: u8) -> Duration {
+        let timeouts = self.timeouts.lock().unwrap_or_else(|e| e.into_inner());
+        timeouts.get(&pipe_index).cloned().unwrap_or_default()
+    }
+
+    fn policy(&self, pipe_index: u8) -> policy::Applied {
+        let policies = self.policies.lock().unwrap_or_else(|e| e.into_inner());
+        policies.get(&pipe_index).cloned().unwrap_or_default()
+    }
+}
+
+// the host kernel checks the maximum packet size itself
+fn transfer_flags(policy: &policy::Applied) -> u32 {
+    if policy.policy.short_packet_terminate { protocol::URB_ZERO_PACKET } else { 0 }
+}
+
+fn kernel_drivers_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported,
+        "kernel drivers of USB/IP devices are bound and unbound on their host")
+}
+
+impl Handle<'_> {
+    pub fn device_descriptor(&self) -> io::Result<DeviceDescriptor> {
+        let mut raw = [0u8; 18];
+        let len = self.get_descriptor(descriptor::DT_DEVICE, 0, 0, &mut raw)?;
+        DeviceDescriptor::parse(&raw[..len])
+    }
+
+    pub fn config_descriptor(&self, index: u8) -> io::Result<ConfigDescriptor> {
+        self.connection.config_descriptor(index)
+    }
+
+    pub fn get_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16, buf: &mut [u8])
+        -> io::Result<usize>
+    {
+        self.connection.get_descriptor(descriptor_type, index, language_id, buf)
+    }
+
+    pub fn speed(&self) -> io::Result<Speed> {
+        self.connection.check_connected()?;
+        Ok(self.connection.speed())
+    }
+
+    pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_pipe_timeout(pipe_index, buf, self.state.timeout(pipe_index))
+    }
+
+    pub fn write_pipe(&self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.write_pipe_timeout(pipe_index, buf, self.state.timeout(pipe_index))
+    }
+
+    pub fn read_pipe_timeout(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let policy = self.state.policy(pipe_index);
+        policy.check_read(buf.len())?;
+        let submitted = self.connection.submit(pipe_index, Direction::In, 0, [0; 8], &[], buf.len())?;
+        match self.connection.wait(submitted, timeout) {
+            Ok((data, len)) => Ok(copy_received(&data, len, buf)),
+            Err(e) if policy.clears_stall(&e) => {
+                self.reset_pipe(pipe_index)?;
+                Err(e)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write_pipe_timeout(&self, pipe_index: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        let flags = transfer_flags(&self.state.policy(pipe_index));
+        let submitted = self.connection.submit(pipe_index, Direction::Out, flags, [0; 8], buf, 0)?;
+        let (_, len) = self.connection.wait(submitted, timeout)?;
+        Ok(len)
+    }
+
+    pub fn read_isochronous(&self, _pipe_index: u8, _buf: &mut [u8], _packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        Err(isochronous_unsupported())
+    }
+
+    pub fn write_isochronous(&self, _pipe_index: u8, _buf: &[u8], _packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        Err(isochronous_unsupported())
+    }
+
+    pub fn read_pipe_async<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> PipeFuture<'a> {
+        self.submit_async(pipe_index, Direction::In, buf)
+    }
+
+    pub fn write_pipe_async<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> PipeFuture<'a> {
+        self.submit_async(pipe_index, Direction::Out, buf)
+    }
+
+    // a pipe timeout is kept by a thread of its own, unlinking the URB once over
+    fn submit_async<'a>(&self, pipe_index: u8, direction: Direction, buf: Vec<u8>) -> PipeFuture<'a> {
+        let policy = self.state.policy(pipe_index);
+        let submitted = match direction {
+            Direction::In => policy.check_read(buf.len())
+                .and_then(|_| self.connection.submit(pipe_index, direction, 0, [0; 8], &[], buf.len())),
+            Direction::Out => {
+                self.connection.submit(pipe_index, direction, transfer_flags(&policy), [0; 8], &buf, 0)
+            },
+        };
+        let (seqnum, urb) = match submitted {
+            Ok(submitted) => submitted,
+            Err(e) => return PipeFuture { state: PipeFutureState::Failed(Some(e)), buf, _lifetime: PhantomData },
+        };
+        let timeout = self.state.timeout(pipe_index);
+        if timeout != Duration::from_secs(0) {
+            let connection = self.connection.clone();
+            let urb = urb.clone();
+            thread::spawn(move || {
+                let completion = urb.completion();
+                let expired = urb.done.wait_timeout_while(completion, timeout, |c| c.0.is_none())
+                    .map(|(_, ans)| ans.timed_out())
+                    .unwrap_or(false);
+                if expired {
+                    connection.time_out(seqnum, &urb);
+                }
+            });
+        }
+        let clear_stall = direction == Direction::In && policy.policy.auto_clear_stall;
+        let state = PipeFutureState::Submitted { connection: self.connection.clone(), seqnum, urb, clear_stall };
+        PipeFuture { state, buf, _lifetime: PhantomData }
+    }
+
+    pub fn set_timeout(&self, pipe_index: u8, timeout: Duration) -> io::Result<()> {
+        self.connection.check_connected()?;
+        let mut timeouts = self.state.timeouts.lock().unwrap_or_else(|e| e.into_inner());
+        timeouts.insert(pipe_index, timeout);
+        Ok(())
+    }
+
+    pub fn set_pipe_policy(&self, pipe_index: u8, policy: PipePolicy) -> io::Result<()> {
+        let max_packet_size = self.pipes()?.into_iter()
+            .find(|pipe| pipe.endpoint_address == pipe_index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such pipe"))?
+            .max_packet_size;
+        let mut policies = self.state.policies.lock().unwrap_or_else(|e| e.into_inner());
+        policies.insert(pipe_index, policy::Applied { policy, max_packet_size });
+        Ok(())
+    }
+
+    pub fn pipe_policy(&self, pipe_index: u8) -> io::Result<PipePolicy> {
+        self.connection.check_connected()?;
+        Ok(self.state.policy(pipe_index).policy)
+    }
+
+    /// Interfaces are claimed on the host when importing; claims here only
+    /// keep handles on the same import apart.
+    pub fn claim_interface(&self, interface_number: u8) -> io::Result<()> {
+        let has_interface = self.config_descriptor(0)?.interfaces.iter()
+            .any(|i| i.interface_number == interface_number);
+        if !has_interface {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such interface"))
+        }
+        let mut claims = self.state.claims();
+        if claims.contains(&interface_number) {
+            return Ok(())
+        }
+        let mut claimed = self.connection.claimed();
+        if claimed.contains(&interface_number) {
+            return Err(io::Error::new(io::ErrorKind::ResourceBusy, "interface claimed by another handle"))
+        }
+        claimed.push(interface_number);
+        claims.push(interface_number);
+        Ok(())
+    }
+
+    pub fn release_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.check_claimed(interface_number)?;
+        self.state.claims().retain(|&i| i != interface_number);
+        self.connection.claimed().retain(|&i| i != interface_number);
+        Ok(())
+    }
+
+    fn check_claimed(&self, interface_number: u8) -> io::Result<()> {
+        self.connection.check_connected()?;
+        if !self.state.claims().contains(&interface_number) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface not claimed by this handle"))
+        }
+        Ok(())
+    }
+
+    // the host binds its USB/IP driver to the whole device
+    pub fn kernel_driver_active(&self, _interface_number: u8) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    pub fn detach_kernel_driver(&self, _interface_number: u8) -> io::Result<()> {
+        Err(kernel_drivers_unsupported())
+    }
+
+    pub fn attach_kernel_driver(&self, _interface_number: u8) -> io::Result<()> {
+        Err(kernel_drivers_unsupported())
+    }
+
+    // there is never a kernel driver to detach, so enabling this is harmless
+    pub fn set_auto_detach_kernel_driver(&self, _enable: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Send `SET_INTERFACE`, which the host applies to its device.
+    pub fn set_alternate_setting(&self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        self.check_claimed(interface_number)?;
+        let request = ControlRequest::new(Direction::Out, RequestType::Standard, Recipient::Interface,
+            REQUEST_SET_INTERFACE, alternate_setting as u16, interface_number as u16);
+        self.connection.control(request, &[], &mut [], CONTROL_TIMEOUT)?;
+        let mut alt_settings = self.connection.alt_settings.lock().unwrap_or_else(|e| e.into_inner());
+        alt_settings.insert(interface_number, alternate_setting);
+        // endpoints of the new setting may poll at other intervals
+        *self.connection.intervals.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        Ok(())
+    }
+
+    pub fn pipes(&self) -> io::Result<Vec<crate::PipeInfo>> {
+        // an unconfigured device has no pipes besides the default one
+        Ok(self.connection.active_config()?
+            .map(|config| self.connection.pipes(&config, self.state.interface_number))
+            .unwrap_or_default())
+    }
+
+    pub fn associated_interface<'a>(&self, index: u8) -> io::Result<Handle<'a>> {
+        let interface_number = self.config_descriptor(0)?
+            .associated_interface(self.state.interface_number, index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such associated interface"))?;
+        let ans = Handle {
+            connection: self.connection.clone(),
+            state: Arc::new(HandleState::new(&self.connection, interface_number)),
+            _lifetime_of_handle: PhantomData,
+        };
+        ans.claim_interface(interface_number)?;
+        Ok(ans)
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        Ok(self.connection.device.busid.parse()?)
+    }
+
+    // a device stays imported by this connection while it re-enumerates on
+    // its host, so it could never be opened again
+    pub fn reset_device(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "USB/IP devices cannot be reset from the client"))
+    }
+
+    pub fn flush_pipe(&self, _pipe_index: u8) -> io::Result<()> {
+        self.connection.check_connected()
+    }
+
+    /// Send `CLEAR_FEATURE(ENDPOINT_HALT)`, which the host also applies to
+    /// its own endpoint state.
+    pub fn reset_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.connection.control(clear_halt_request(pipe_index), &[], &mut [], CONTROL_TIMEOUT).map(drop)
+    }
+
+    /// Unlink all URBs pending on a pipe.
+    pub fn abort_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.connection.check_connected()?;
+        let seqnums: Vec<u32> = self.connection.urbs.table().pending.iter()
+            .filter(|(_, urb)| urb.pipe_index == pipe_index)
+            .map(|(&seqnum, _)| seqnum)
+            .collect();
+        for seqnum in seqnums {
+            self.connection.unlink(seqnum);
+        }
+        Ok(())
+    }
+
+    pub fn control_in(&self, request: ControlRequest, buf: &mut [u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        self.connection.control(request, &[], buf, timeout)
+    }
+
+    pub fn control_out(&self, request: ControlRequest, buf: &[u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        self.connection.control(request, buf, &mut [], timeout)
+    }
+}
+
+const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+const REQUEST_SET_INTERFACE: u8 = 0x0B;
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+impl PartialEq for Handle<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.connection, &other.connection)
+    }
+}
+
+impl Eq for Handle<'_> {}
+
+impl hash::Hash for Handle<'_> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.connection).hash(state)
+    }
+}
+
+/// A URB submitted without waiting for it, resolving to its buffer and the
+/// length transferred.
+///
+/// Dropping it before completion unlinks the URB.
+pub struct PipeFuture<'a> {
+    state: PipeFutureState,
+    buf: Vec<u8>,
+    _lifetime: PhantomData<&'a ()>,
+}
+
+enum PipeFutureState {
+    Submitted { connection: Arc<Connection>, seqnum: u32, urb: Arc<Urb>, clear_stall: bool },
+    // submitting failed, the error is reported on the first poll
+    Failed(Option<io::Error>),
+    Done,
+}
+
+impl Future for PipeFuture<'_> {
+    type Output = io::Result<(Vec<u8>, usize)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let ans = match &mut this.state {
+            PipeFutureState::Submitted { connection, urb, clear_stall, .. } => {
+                let ans = {
+                    let mut completion = urb.completion();
+                    match completion.0.take() {
+                        Some(ans) => ans,
+                        None => {
+                            completion.1 = Some(cx.waker().clone());
+                            return Poll::Pending
+                        },
+                    }
+                };
+                match ans {
+                    Err(e) if *clear_stall && e.kind() == io::ErrorKind::BrokenPipe => {
+                        connection.clear_halt(urb.pipe_index);
+                        Err(e)
+                    },
+                    ans => ans,
+                }
+            },
+            PipeFutureState::Failed(e) => Err(e.take().expect("pipe future polled after completion")),
+            PipeFutureState::Done => panic!("pipe future polled after completion"),
+        };
+        this.state = PipeFutureState::Done;
+        let mut buf = core::mem::take(&mut this.buf);
+        Poll::Ready(ans.map(|(data, len)| {
+            let len = copy_received(&data, len, &mut buf);
+            (buf, len)
+        }))
+    }
+}
+
+impl Drop for PipeFuture<'_> {
+    fn drop(&mut self) {
+        if let PipeFutureState::Submitted { connection, seqnum, .. } = &self.state {
+            connection.unlink(*seqnum);
+        }
+    }
+}
+
+impl fmt::Debug for PipeFuture<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PipeFuture")
+            .field("done", &matches!(self.state, PipeFutureState::Done))
+            .finish()
+    }
+}
@@ -0,0 +1,482 @@
+//! Messages of the USB/IP protocol, as described in the Linux kernel's
+//! `Documentation/usb/usbip_protocol.rst`.
+//!
+//! All fields are big endian. Operations (`OP_*`) list and import devices;
+//! once a device is imported, the connection carries URB commands
+//! (`USBIP_CMD_*`) and their replies (`USBIP_RET_*`).
+use std::io::{self, Read, Write};
+
+/// Port `usbipd` listens on by default.
+pub const DEFAULT_PORT: u16 = 3240;
+
+pub const USBIP_VERSION: u16 = 0x0111;
+
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+pub const OP_REP_IMPORT: u16 = 0x0003;
+
+/// Statuses of operation replies; hosts refuse imports with the others.
+pub const ST_OK: u32 = 0;
+pub const ST_NA: u32 = 1;
+pub const ST_DEV_BUSY: u32 = 2;
+pub const ST_DEV_ERR: u32 = 3;
+pub const ST_NODEV: u32 = 4;
+pub const ST_ERROR: u32 = 5;
+
+pub const USBIP_CMD_SUBMIT: u32 = 0x0001;
+pub const USBIP_CMD_UNLINK: u32 = 0x0002;
+pub const USBIP_RET_SUBMIT: u32 = 0x0003;
+pub const USBIP_RET_UNLINK: u32 = 0x0004;
+
+pub const USBIP_DIR_OUT: u32 = 0;
+pub const USBIP_DIR_IN: u32 = 1;
+
+/// `URB_DIR_IN` of Linux, set in the transfer flags of IN transfers.
+pub const URB_DIR_IN: u32 = 0x0200;
+
+/// `URB_ZERO_PACKET` of Linux: end an OUT transfer of a multiple of the
+/// maximum packet size with a zero-length packet.
+pub const URB_ZERO_PACKET: u32 = 0x0040;
+
+/// Length of `path` and `busid` fields, including the trailing zeroes.
+const PATH_LEN: usize = 256;
+const BUSID_LEN: usize = 32;
+
+fn read_u8(r: &mut dyn Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(r: &mut dyn Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_i32(r: &mut dyn Read) -> io::Result<i32> {
+    read_u32(r).map(|n| n as i32)
+}
+
+fn read_string(r: &mut dyn Read, len: usize) -> io::Result<String> {
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(len);
+    String::from_utf8(buf[..end].to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "USB/IP string is not UTF-8"))
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str, len: usize) {
+    // leave room for at least one trailing zero
+    let bytes = &s.as_bytes()[..s.len().min(len - 1)];
+    buf.extend_from_slice(bytes);
+    buf.resize(buf.len() + len - bytes.len(), 0);
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Header of operations and their replies.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct OpHeader {
+    pub version: u16,
+    pub code: u16,
+    pub status: u32,
+}
+
+impl OpHeader {
+    pub fn new(code: u16, status: u32) -> OpHeader {
+        OpHeader { version: USBIP_VERSION, code, status }
+    }
+
+    pub fn read(r: &mut dyn Read) -> io::Result<OpHeader> {
+        Ok(OpHeader { version: read_u16(r)?, code: read_u16(r)?, status: read_u32(r)? })
+    }
+
+    /// Read a reply header, failing unless it has the expected code.
+    pub fn read_reply(r: &mut dyn Read, code: u16) -> io::Result<OpHeader> {
+        let ans = OpHeader::read(r)?;
+        if ans.code != code {
+            return Err(invalid_data("unexpected USB/IP operation reply"))
+        }
+        Ok(ans)
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.code.to_be_bytes());
+        buf.extend_from_slice(&self.status.to_be_bytes());
+    }
+}
+
+/// `bInterfaceClass`, `bInterfaceSubClass` and `bInterfaceProtocol` of an
+/// interface, as listed by `OP_REP_DEVLIST`.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct ExportedInterface {
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+}
+
+/// A device exported by a USB/IP host.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct ExportedDevice {
+    /// Directory of the device in the sysfs of the host
+    pub path: String,
+    /// Bus number and chain of hub ports on the host, like `1-3.2`
+    pub busid: String,
+    pub busnum: u32,
+    pub devnum: u32,
+    /// `enum usb_device_speed` of Linux
+    pub speed: u32,
+    pub id_vendor: u16,
+    pub id_product: u16,
+    pub bcd_device: u16,
+    pub device_class: u8,
+    pub device_sub_class: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+    /// Only sent by `OP_REP_DEVLIST`; empty in `OP_REP_IMPORT`
+    pub interfaces: Vec<ExportedInterface>,
+}
+
+impl ExportedDevice {
+    /// Read a device, followed by its interfaces if `with_interfaces`.
+    pub fn read(r: &mut dyn Read, with_interfaces: bool) -> io::Result<ExportedDevice> {
+        let mut ans = ExportedDevice {
+            path: read_string(r, PATH_LEN)?,
+            busid: read_string(r, BUSID_LEN)?,
+            busnum: read_u32(r)?,
+            devnum: read_u32(r)?,
+            speed: read_u32(r)?,
+            id_vendor: read_u16(r)?,
+            id_product: read_u16(r)?,
+            bcd_device: read_u16(r)?,
+            device_class: read_u8(r)?,
+            device_sub_class: read_u8(r)?,
+            device_protocol: read_u8(r)?,
+            configuration_value: read_u8(r)?,
+            num_configurations: read_u8(r)?,
+            num_interfaces: read_u8(r)?,
+            interfaces: Vec::new(),
+        };
+        if with_interfaces {
+            for _ in 0..ans.num_interfaces {
+                let mut buf = [0u8; 4]; // class, subclass, protocol, padding
+                r.read_exact(&mut buf)?;
+                ans.interfaces.push(ExportedInterface {
+                    interface_class: buf[0],
+                    interface_subclass: buf[1],
+                    interface_protocol: buf[2],
+                });
+            }
+        }
+        Ok(ans)
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>, with_interfaces: bool) {
+        write_string(buf, &self.path, PATH_LEN);
+        write_string(buf, &self.busid, BUSID_LEN);
+        buf.extend_from_slice(&self.busnum.to_be_bytes());
+        buf.extend_from_slice(&self.devnum.to_be_bytes());
+        buf.extend_from_slice(&self.speed.to_be_bytes());
+        buf.extend_from_slice(&self.id_vendor.to_be_bytes());
+        buf.extend_from_slice(&self.id_product.to_be_bytes());
+        buf.extend_from_slice(&self.bcd_device.to_be_bytes());
+        buf.extend_from_slice(&[
+            self.device_class, self.device_sub_class, self.device_protocol,
+            self.configuration_value, self.num_configurations, self.num_interfaces,
+        ]);
+        if with_interfaces {
+            for i in &self.interfaces {
+                buf.extend_from_slice(&[i.interface_class, i.interface_subclass, i.interface_protocol, 0]);
+            }
+        }
+    }
+
+    /// Device ID of URB commands for this device.
+    pub fn devid(&self) -> u32 {
+        self.busnum << 16 | (self.devnum & 0xFFFF)
+    }
+}
+
+/// An operation requested from a host, before any device is imported.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum OpRequest {
+    /// `OP_REQ_DEVLIST`
+    DevList,
+    /// `OP_REQ_IMPORT` of the device with this bus ID
+    Import { busid: String },
+}
+
+impl OpRequest {
+    pub fn read(r: &mut dyn Read) -> io::Result<OpRequest> {
+        let header = OpHeader::read(r)?;
+        match header.code {
+            OP_REQ_DEVLIST => Ok(OpRequest::DevList),
+            OP_REQ_IMPORT => Ok(OpRequest::Import { busid: read_string(r, BUSID_LEN)? }),
+            _ => Err(invalid_data("unknown USB/IP operation")),
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        match self {
+            OpRequest::DevList => OpHeader::new(OP_REQ_DEVLIST, ST_OK).encode(&mut buf),
+            OpRequest::Import { busid } => {
+                OpHeader::new(OP_REQ_IMPORT, ST_OK).encode(&mut buf);
+                write_string(&mut buf, busid, BUSID_LEN);
+            },
+        }
+        w.write_all(&buf)
+    }
+}
+
+/// Read the reply to `OP_REQ_DEVLIST`.
+pub fn read_devlist(r: &mut dyn Read) -> io::Result<Vec<ExportedDevice>> {
+    OpHeader::read_reply(r, OP_REP_DEVLIST)?;
+    let count = read_u32(r)?;
+    (0..count).map(|_| ExportedDevice::read(r, true)).collect()
+}
+
+pub fn write_devlist(w: &mut dyn Write, devices: &[ExportedDevice]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    OpHeader::new(OP_REP_DEVLIST, ST_OK).encode(&mut buf);
+    buf.extend_from_slice(&(devices.len() as u32).to_be_bytes());
+    for device in devices {
+        device.encode(&mut buf, true);
+    }
+    w.write_all(&buf)
+}
+
+/// Read the reply to `OP_REQ_IMPORT`: the imported device, or the status
+/// the host refused the import with.
+pub fn read_import(r: &mut dyn Read) -> io::Result<Result<ExportedDevice, u32>> {
+    let header = OpHeader::read_reply(r, OP_REP_IMPORT)?;
+    if header.status != ST_OK {
+        return Ok(Err(header.status))
+    }
+    ExportedDevice::read(r, false).map(Ok)
+}
+
+pub fn write_import(w: &mut dyn Write, ans: Result<&ExportedDevice, u32>) -> io::Result<()> {
+    let mut buf = Vec::new();
+    match ans {
+        Ok(device) => {
+            OpHeader::new(OP_REP_IMPORT, ST_OK).encode(&mut buf);
+            device.encode(&mut buf, false);
+        },
+        Err(status) => OpHeader::new(OP_REP_IMPORT, status).encode(&mut buf),
+    }
+    w.write_all(&buf)
+}
+
+/// Header shared by all URB commands and replies.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct HeaderBasic {
+    pub command: u32,
+    pub seqnum: u32,
+    pub devid: u32,
+    pub direction: u32,
+    pub ep: u32,
+}
+
+impl HeaderBasic {
+    /// Header of a reply, which leaves device, direction and endpoint zero.
+    pub fn reply(command: u32, seqnum: u32) -> HeaderBasic {
+        HeaderBasic { command, seqnum, devid: 0, direction: 0, ep: 0 }
+    }
+
+    fn read(r: &mut dyn Read) -> io::Result<HeaderBasic> {
+        Ok(HeaderBasic {
+            command: read_u32(r)?,
+            seqnum: read_u32(r)?,
+            devid: read_u32(r)?,
+            direction: read_u32(r)?,
+            ep: read_u32(r)?,
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for field in &[self.command, self.seqnum, self.devid, self.direction, self.ep] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+    }
+}
+
+/// A URB command sent to the host.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum Command {
+    /// `USBIP_CMD_SUBMIT`, with the data of OUT transfers
+    Submit {
+        header: HeaderBasic,
+        transfer_flags: u32,
+        transfer_buffer_length: i32,
+        interval: i32,
+        setup: [u8; 8],
+        data: Vec<u8>,
+    },
+    /// `USBIP_CMD_UNLINK`
+    Unlink {
+        header: HeaderBasic,
+        unlink_seqnum: u32,
+    },
+}
+
+impl Command {
+    pub fn read(r: &mut dyn Read) -> io::Result<Command> {
+        let header = HeaderBasic::read(r)?;
+        match header.command {
+            USBIP_CMD_SUBMIT => {
+                let transfer_flags = read_u32(r)?;
+                let transfer_buffer_length = read_i32(r)?;
+                let _start_frame = read_i32(r)?;
+                let number_of_packets = read_i32(r)?;
+                let interval = read_i32(r)?;
+                let mut setup = [0u8; 8];
+                r.read_exact(&mut setup)?;
+                // isochronous packet descriptors would follow the data
+                if number_of_packets > 0 {
+                    return Err(invalid_data("isochronous USB/IP transfers are not supported"))
+                }
+                let mut data = Vec::new();
+                if header.direction == USBIP_DIR_OUT {
+                    data.resize(transfer_buffer_length.max(0) as usize, 0);
+                    r.read_exact(&mut data)?;
+                }
+                Ok(Command::Submit { header, transfer_flags, transfer_buffer_length, interval, setup, data })
+            },
+            USBIP_CMD_UNLINK => {
+                let unlink_seqnum = read_u32(r)?;
+                r.read_exact(&mut [0u8; 24])?;
+                Ok(Command::Unlink { header, unlink_seqnum })
+            },
+            _ => Err(invalid_data("unknown USB/IP command")),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Command::Submit { header, transfer_flags, transfer_buffer_length, interval, setup, data } => {
+                header.encode(buf);
+                buf.extend_from_slice(&transfer_flags.to_be_bytes());
+                buf.extend_from_slice(&transfer_buffer_length.to_be_bytes());
+                buf.extend_from_slice(&0i32.to_be_bytes()); // start_frame
+                buf.extend_from_slice(&0i32.to_be_bytes()); // number_of_packets
+                buf.extend_from_slice(&interval.to_be_bytes());
+                buf.extend_from_slice(setup);
+                buf.extend_from_slice(data);
+            },
+            Command::Unlink { header, unlink_seqnum } => {
+                header.encode(buf);
+                buf.extend_from_slice(&unlink_seqnum.to_be_bytes());
+                buf.extend_from_slice(&[0u8; 24]);
+            },
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        w.write_all(&buf)
+    }
+}
+
+/// What reading a reply needs to know of the command it answers.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct Submitted {
+    pub direction: u32,
+    pub transfer_buffer_length: i32,
+}
+
+/// A reply to a URB command, sent by the host.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum Reply {
+    /// `USBIP_RET_SUBMIT`, with the data of IN transfers
+    Submit {
+        header: HeaderBasic,
+        /// Zero, or a negative Linux `errno` value
+        status: i32,
+        actual_length: i32,
+        data: Vec<u8>,
+    },
+    /// `USBIP_RET_UNLINK`; the status is `-ECONNRESET` if the URB was
+    /// unlinked, and zero if it completed before
+    Unlink {
+        header: HeaderBasic,
+        status: i32,
+    },
+}
+
+impl Reply {
+    /// Read a reply; `submitted` looks up the command a submit reply answers
+    /// by its sequence number, which tells whether data follows and how much
+    /// may. Replies to unknown commands carry no data.
+    pub fn read(r: &mut dyn Read, submitted: impl FnOnce(u32) -> Option<Submitted>) -> io::Result<Reply> {
+        let header = HeaderBasic::read(r)?;
+        match header.command {
+            USBIP_RET_SUBMIT => {
+                let status = read_i32(r)?;
+                let actual_length = read_i32(r)?;
+                let _start_frame = read_i32(r)?;
+                let number_of_packets = read_i32(r)?;
+                let _error_count = read_i32(r)?;
+                r.read_exact(&mut [0u8; 8])?;
+                if number_of_packets > 0 {
+                    return Err(invalid_data("isochronous USB/IP transfers are not supported"))
+                }
+                let mut data = Vec::new();
+                match submitted(header.seqnum) {
+                    Some(submitted) if actual_length > submitted.transfer_buffer_length =>
+                        return Err(invalid_data("USB/IP reply longer than its transfer buffer")),
+                    Some(submitted) if submitted.direction == USBIP_DIR_IN => {
+                        data.resize(actual_length.max(0) as usize, 0);
+                        r.read_exact(&mut data)?;
+                    },
+                    _ => {},
+                }
+                Ok(Reply::Submit { header, status, actual_length, data })
+            },
+            USBIP_RET_UNLINK => {
+                let status = read_i32(r)?;
+                r.read_exact(&mut [0u8; 24])?;
+                Ok(Reply::Unlink { header, status })
+            },
+            _ => Err(invalid_data("unknown USB/IP reply")),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Reply::Submit { header, status, actual_length, data } => {
+                header.encode(buf);
+                buf.extend_from_slice(&status.to_be_bytes());
+                buf.extend_from_slice(&actual_length.to_be_bytes());
+                buf.extend_from_slice(&[0u8; 12]); // start_frame, number_of_packets, error_count
+                buf.extend_from_slice(&[0u8; 8]);
+                buf.extend_from_slice(data);
+            },
+            Reply::Unlink { header, status } => {
+                header.encode(buf);
+                buf.extend_from_slice(&status.to_be_bytes());
+                buf.extend_from_slice(&[0u8; 24]);
+            },
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        w.write_all(&buf)
+    }
+}
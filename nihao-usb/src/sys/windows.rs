@@ -1,7 +1,13 @@
 pub mod setup;
 pub mod usb;
+pub mod notify;
+pub mod hub;
 
 use std::io;
+use std::time::Duration;
+
+pub use usb::Overlapped as PipeFuture;
+pub use notify::Monitor;
 
 pub fn devices<'list>() -> io::Result<DeviceList<'list>> {
     use usb::ListOptionsExt;
@@ -30,6 +36,10 @@ impl<'list> DeviceList<'list> {
     pub fn len(&self) -> usize {
         self.iter().count()
     } 
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -85,6 +95,30 @@ impl<'device> Device<'device> {
     pub fn open<'handle>(&self) -> io::Result<Handle<'handle>> {
         self.info.open().map(|handle| Handle { winusb_interface: handle })
     }
+
+    pub fn device_info(&self) -> crate::DeviceInfo {
+        self.info.device_info()
+    }
+
+    pub fn id(&self) -> DeviceId {
+        DeviceId { path: self.info.path() }
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        self.info.location()
+    }
+}
+
+/// A device is identified by its interface path, which contains the serial
+/// number or, for devices without one, an instance ID derived from the port.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct DeviceId {
+    path: std::ffi::OsString,
+}
+
+fn kernel_drivers_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported,
+        "Windows binds drivers to whole devices or functions, not to single interfaces")
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -97,10 +131,33 @@ impl Handle<'_> {
         self.winusb_interface.device_descriptor().map(|s| s.into())
     }
 
+    pub fn config_descriptor(&self, index: u8) -> io::Result<crate::ConfigDescriptor> {
+        let raw = self.winusb_interface.config_descriptor(index)?;
+        crate::ConfigDescriptor::parse(&raw)
+    }
+
+    pub fn get_descriptor(&self, descriptor_type: u8, index: u8, language_id: u16, buf: &mut [u8])
+        -> io::Result<usize>
+    {
+        self.winusb_interface.get_descriptor(descriptor_type, index, language_id, buf)
+    }
+
     pub fn speed(&self) -> io::Result<crate::Speed> {
         self.winusb_interface.speed().map(|s| s.into())
     }
 
+    pub fn pipes(&self) -> io::Result<Vec<crate::PipeInfo>> {
+        let alternate_setting = self.winusb_interface.current_alternate_setting()?;
+        let mut ans = Vec::new();
+        for pipe_index in 0.. {
+            match self.winusb_interface.query_pipe(alternate_setting, pipe_index)? {
+                Some(info) => ans.push(info.into()),
+                None => break,
+            }
+        }
+        Ok(ans)
+    }
+
     pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
         self.winusb_interface.read_pipe(pipe_index, buf)
     }
@@ -109,7 +166,113 @@ impl Handle<'_> {
         self.winusb_interface.write_pipe(pipe_index, buf)
     }
 
+    pub fn read_pipe_timeout(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.winusb_interface.read_pipe_timeout(pipe_index, buf, timeout)
+    }
+
+    pub fn write_pipe_timeout(&self, pipe_index: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.winusb_interface.write_pipe_timeout(pipe_index, buf, timeout)
+    }
+
+    pub fn read_isochronous(&self, pipe_index: u8, buf: &mut [u8], packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        self.winusb_interface.read_isochronous(pipe_index, buf, packets)
+    }
+
+    pub fn write_isochronous(&self, pipe_index: u8, buf: &[u8], packets: usize)
+        -> io::Result<Vec<crate::IsoPacket>>
+    {
+        self.winusb_interface.write_isochronous(pipe_index, buf, packets)
+    }
+
+    pub fn read_pipe_async<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> PipeFuture<'a> {
+        self.winusb_interface.read_pipe_overlapped(pipe_index, buf)
+    }
+
+    pub fn write_pipe_async<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> PipeFuture<'a> {
+        self.winusb_interface.write_pipe_overlapped(pipe_index, buf)
+    }
+
+    pub fn set_timeout(&self, pipe_index: u8, timeout: Duration) -> io::Result<()> {
+        self.winusb_interface.set_timeout(pipe_index, timeout)
+    }
+
+    pub fn set_pipe_policy(&self, pipe_index: u8, policy: crate::PipePolicy) -> io::Result<()> {
+        if !self.pipes()?.iter().any(|pipe| pipe.endpoint_address == pipe_index) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such pipe"))
+        }
+        self.winusb_interface.set_policy(pipe_index, policy)
+    }
+
+    pub fn pipe_policy(&self, pipe_index: u8) -> io::Result<crate::PipePolicy> {
+        self.winusb_interface.policy(pipe_index)
+    }
+
+    pub fn claim_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.winusb_interface.claim_interface(interface_number)
+    }
+
+    pub fn release_interface(&self, interface_number: u8) -> io::Result<()> {
+        self.winusb_interface.release_interface(interface_number)
+    }
+
+    // WinUSB is the only driver of the interfaces this handle can claim
+    pub fn kernel_driver_active(&self, _interface_number: u8) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    pub fn detach_kernel_driver(&self, _interface_number: u8) -> io::Result<()> {
+        Err(kernel_drivers_unsupported())
+    }
+
+    pub fn attach_kernel_driver(&self, _interface_number: u8) -> io::Result<()> {
+        Err(kernel_drivers_unsupported())
+    }
+
+    // there is never a kernel driver to detach, so enabling this is harmless
+    pub fn set_auto_detach_kernel_driver(&self, _enable: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn set_alternate_setting(&self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        self.winusb_interface.set_alternate_setting(interface_number, alternate_setting)
+    }
+
+    pub fn associated_interface<'a>(&self, index: u8) -> io::Result<Handle<'a>> {
+        self.winusb_interface.associated_interface(index).map(|winusb_interface| Handle { winusb_interface })
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        self.winusb_interface.location()
+    }
+
+    pub fn reset_device(&self) -> io::Result<()> {
+        self.winusb_interface.reset_device()
+    }
+
     pub fn flush_pipe(&self, pipe_index: u8) -> io::Result<()> {
         self.winusb_interface.flush_pipe(pipe_index)
     }
+
+    pub fn reset_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.winusb_interface.reset_pipe(pipe_index)
+    }
+
+    pub fn abort_pipe(&self, pipe_index: u8) -> io::Result<()> {
+        self.winusb_interface.abort_pipe(pipe_index)
+    }
+
+    pub fn control_in(&self, request: crate::ControlRequest, buf: &mut [u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        self.winusb_interface.control_transfer(&request, buf.as_mut_ptr(), buf.len(), timeout)
+    }
+
+    pub fn control_out(&self, request: crate::ControlRequest, buf: &[u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        // WinUSB reads OUT data from this pointer and never writes into it
+        self.winusb_interface.control_transfer(&request, buf.as_ptr() as *mut u8, buf.len(), timeout)
+    }
 }
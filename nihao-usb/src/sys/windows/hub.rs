@@ -0,0 +1,149 @@
+//! Where a device node sits in the USB tree, and operations on its hub port.
+use core::{mem, ptr};
+use std::{io, time::Duration};
+use super::setup;
+use winapi::{
+    shared::{
+        minwindef::{DWORD, FALSE, ULONG},
+        usbiodef::{GUID_DEVINTERFACE_USB_HOST_CONTROLLER, GUID_DEVINTERFACE_USB_HUB},
+        usbioctl::{IOCTL_USB_HUB_CYCLE_PORT, USB_CYCLE_PORT_PARAMS},
+    },
+    um::{
+        cfgmgr32::{
+            CM_Get_Parent, CM_Get_Device_IDW,
+            CM_Get_Device_Interface_List_SizeW, CM_Get_Device_Interface_ListW,
+            CM_GET_DEVICE_INTERFACE_LIST_PRESENT, CR_SUCCESS, MAX_DEVICE_ID_LEN,
+        },
+        fileapi::{CreateFileW, OPEN_EXISTING},
+        handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
+        ioapiset::DeviceIoControl,
+        winnt::{GENERIC_WRITE, FILE_SHARE_WRITE, FILE_ATTRIBUTE_NORMAL},
+    },
+};
+
+/// How long a cycled device may take to disappear from its old device node.
+pub const CYCLE_PORT_REMOVAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Derive bus and ports from the location path of a device, like
+/// `PCIROOT(0)#PCI(1400)#USBROOT(0)#USB(3)#USB(2)` for `1-3.2`.
+///
+/// Windows does not number buses; host controllers are numbered from 1
+/// in the order of their own location paths, which stays the same until
+/// controllers are added or removed.
+pub fn location(dev_inst: DWORD) -> io::Result<crate::Location> {
+    let path = setup::location_path(dev_inst)?;
+    let (controller, port_numbers) = split_location_path(&path)?;
+    let bus_index = host_controller_location_paths()?.iter()
+        .position(|p| p == controller)
+        .ok_or_else(|| invalid_location_path(&path))?;
+    Ok(crate::Location::new(bus_index as u8 + 1, &port_numbers))
+}
+
+/// Split a location path into the host controller part and the hub ports.
+fn split_location_path(path: &str) -> io::Result<(&str, Vec<u8>)> {
+    let root = path.find("#USBROOT(").ok_or_else(|| invalid_location_path(path))?;
+    let mut port_numbers = Vec::new();
+    for part in path[root + 1..].split('#').skip(1) {
+        let port = part.strip_prefix("USB(").and_then(|p| p.strip_suffix(')'))
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(|| invalid_location_path(path))?;
+        port_numbers.push(port);
+    }
+    Ok((&path[..root], port_numbers))
+}
+
+fn invalid_location_path(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected device location path `{}`", path))
+}
+
+fn host_controller_location_paths() -> io::Result<Vec<String>> {
+    let handle: setup::InfoHandle = setup::ListOptions::<setup::Interface, _>
+        ::interface_by_class(&GUID_DEVINTERFACE_USB_HOST_CONTROLLER)
+        .present()
+        .list()?;
+    let mut ans = Vec::new();
+    for info in handle.iter(&GUID_DEVINTERFACE_USB_HOST_CONTROLLER) {
+        // controllers without a location path cannot have devices matched to them
+        if let Ok(path) = info?.location_path() {
+            ans.push(path);
+        }
+    }
+    ans.sort();
+    Ok(ans)
+}
+
+/// Power cycle the hub port a device is plugged into, so that it
+/// re-enumerates as if replugged.
+pub fn cycle_port(dev_inst: DWORD) -> io::Result<()> {
+    let path = setup::location_path(dev_inst)?;
+    let port = *split_location_path(&path)?.1.last()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "a root hub has no port to cycle"))?;
+    let mut hub_inst: DWORD = 0;
+    let ans = unsafe { CM_Get_Parent(&mut hub_inst, dev_inst, 0) };
+    if ans != CR_SUCCESS {
+        return Err(setup::config_error(ans))
+    }
+    let hub_path = hub_interface_path(hub_inst)?;
+    let hub = unsafe { CreateFileW(
+        hub_path.as_ptr(),
+        GENERIC_WRITE,
+        FILE_SHARE_WRITE,
+        ptr::null_mut(),
+        OPEN_EXISTING,
+        FILE_ATTRIBUTE_NORMAL,
+        ptr::null_mut(),
+    ) };
+    if hub == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error())
+    }
+    let mut params = USB_CYCLE_PORT_PARAMS { ConnectionIndex: port as ULONG, StatusReturned: 0 };
+    let mut len: DWORD = 0;
+    let ans = unsafe { DeviceIoControl(
+        hub,
+        IOCTL_USB_HUB_CYCLE_PORT,
+        &mut params as *mut _ as *mut _,
+        mem::size_of::<USB_CYCLE_PORT_PARAMS>() as DWORD,
+        &mut params as *mut _ as *mut _,
+        mem::size_of::<USB_CYCLE_PORT_PARAMS>() as DWORD,
+        &mut len,
+        ptr::null_mut(),
+    ) };
+    let err = io::Error::last_os_error();
+    unsafe { CloseHandle(hub) };
+    if ans == FALSE {
+        return Err(err)
+    }
+    Ok(())
+}
+
+/// Null-terminated path of the hub interface exposed by a hub device node.
+fn hub_interface_path(hub_inst: DWORD) -> io::Result<Vec<u16>> {
+    let mut instance_id = [0u16; MAX_DEVICE_ID_LEN + 1];
+    let ans = unsafe { CM_Get_Device_IDW(hub_inst, instance_id.as_mut_ptr(), instance_id.len() as ULONG, 0) };
+    if ans != CR_SUCCESS {
+        return Err(setup::config_error(ans))
+    }
+    let guid = &GUID_DEVINTERFACE_USB_HUB as *const _ as *mut _;
+    let mut len: ULONG = 0;
+    let ans = unsafe { CM_Get_Device_Interface_List_SizeW(
+        &mut len, guid, instance_id.as_mut_ptr(), CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
+    ) };
+    if ans != CR_SUCCESS {
+        return Err(setup::config_error(ans))
+    }
+    let mut list = vec![0u16; len as usize];
+    let ans = unsafe { CM_Get_Device_Interface_ListW(
+        guid, instance_id.as_mut_ptr(), list.as_mut_ptr(), len, CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
+    ) };
+    if ans != CR_SUCCESS {
+        return Err(setup::config_error(ans))
+    }
+    // a list of strings, each terminated by a null character
+    let first_len = list.iter().position(|&c| c == 0).unwrap_or(list.len());
+    if first_len == 0 {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "parent of the device is not a USB hub"))
+    }
+    list.truncate(first_len);
+    list.push(0);
+    Ok(list)
+}
@@ -0,0 +1,101 @@
+//! Device interface arrival and removal notifications from the configuration
+//! manager.
+use core::{mem, ptr};
+use std::{io, sync::{Condvar, Mutex}, time::Duration};
+use winapi::{
+    shared::{
+        minwindef::DWORD,
+        ntdef::PVOID,
+        usbiodef::GUID_DEVINTERFACE_USB_DEVICE,
+        winerror::ERROR_SUCCESS,
+    },
+    um::cfgmgr32::{
+        CONFIGRET, CR_SUCCESS,
+        CM_NOTIFY_ACTION, CM_NOTIFY_FILTER, CM_NOTIFY_FILTER_TYPE_DEVICEINTERFACE,
+        HCMNOTIFICATION, PCM_NOTIFY_CALLBACK, PCM_NOTIFY_EVENT_DATA, PCM_NOTIFY_FILTER,
+        PHCMNOTIFICATION,
+    },
+};
+
+// not declared by winapi 0.3
+#[link(name = "cfgmgr32")]
+extern "system" {
+    fn CM_Register_Notification(
+        pFilter: PCM_NOTIFY_FILTER,
+        pContext: PVOID,
+        pCallback: PCM_NOTIFY_CALLBACK,
+        pNotifyContext: PHCMNOTIFICATION,
+    ) -> CONFIGRET;
+    fn CM_Unregister_Notification(NotifyContext: HCMNOTIFICATION) -> CONFIGRET;
+}
+
+/// Wakes up whenever a USB device interface arrives or is removed.
+///
+/// The configuration manager calls back on a thread pool thread, which only
+/// raises a flag for `wait` to pick up.
+#[derive(Debug)]
+pub struct Monitor {
+    notification: HCMNOTIFICATION,
+    signal: Box<Signal>,
+}
+
+#[derive(Debug, Default)]
+struct Signal {
+    changed: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Monitor {
+    pub fn new() -> io::Result<Monitor> {
+        let signal = Box::new(Signal::default());
+        let mut filter: CM_NOTIFY_FILTER = unsafe { mem::zeroed() };
+        filter.cbSize = mem::size_of::<CM_NOTIFY_FILTER>() as DWORD;
+        filter.FilterType = CM_NOTIFY_FILTER_TYPE_DEVICEINTERFACE;
+        unsafe { filter.u.DeviceInterface_mut().ClassGuid = GUID_DEVINTERFACE_USB_DEVICE };
+        let mut notification: HCMNOTIFICATION = ptr::null_mut();
+        let ans = unsafe { CM_Register_Notification(
+            &mut filter,
+            &*signal as *const Signal as PVOID,
+            Some(notify),
+            &mut notification,
+        ) };
+        if ans != CR_SUCCESS {
+            return Err(io::Error::other(format!("CM_Register_Notification failed with CONFIGRET {}", ans)))
+        }
+        Ok(Monitor { notification, signal })
+    }
+
+    /// Wait until something changed or `timeout` passed; returns whether
+    /// anything changed.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let signal = &self.signal;
+        let changed = signal.changed.lock().unwrap_or_else(|e| e.into_inner());
+        let mut changed = match timeout {
+            Some(timeout) => signal.condvar.wait_timeout_while(changed, timeout, |changed| !*changed)
+                .unwrap_or_else(|e| e.into_inner()).0,
+            None => signal.condvar.wait_while(changed, |changed| !*changed)
+                .unwrap_or_else(|e| e.into_inner()),
+        };
+        Ok(mem::replace(&mut *changed, false))
+    }
+}
+
+unsafe extern "system" fn notify(
+    _notification: HCMNOTIFICATION,
+    context: PVOID,
+    _action: CM_NOTIFY_ACTION,
+    _event_data: PCM_NOTIFY_EVENT_DATA,
+    _event_data_size: DWORD,
+) -> DWORD {
+    let signal = &*(context as *const Signal);
+    *signal.changed.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    signal.condvar.notify_all();
+    ERROR_SUCCESS
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        // waits for callbacks in progress, so `signal` outlives them
+        unsafe { CM_Unregister_Notification(self.notification) };
+    }
+}
@@ -4,6 +4,10 @@ use std::{ffi::*, io};
 use winapi::{
     shared::{guiddef::GUID, minwindef::*, windef::HWND, winerror::*},
     um::{errhandlingapi::*, handleapi::*, heapapi::*, setupapi::*, winnt::*},
+    um::cfgmgr32::{
+        CM_Get_DevNode_Registry_PropertyW, CM_DRP_LOCATION_PATHS,
+        CONFIGRET, CR_SUCCESS, CR_BUFFER_SMALL, CR_NO_SUCH_VALUE,
+    },
 };
 
 pub use winapi::um::setupapi::HDEVINFO;
@@ -33,14 +37,16 @@ impl<'h> Drop for InfoHandle<'h> {
 pub struct Info<'p> {
     path_ptr: LPCWSTR,
     path_len_in_u16: DWORD,
+    dev_inst: DWORD,
     _lifetime_of_path: PhantomData<&'p ()>,
 }
 
 impl<'p> Info<'p> {
-    fn from_device_path(path_ptr: LPCWSTR, path_len_in_u16: DWORD) -> Self {
+    fn from_device_path(path_ptr: LPCWSTR, path_len_in_u16: DWORD, dev_inst: DWORD) -> Self {
         Info {
             path_ptr,
             path_len_in_u16,
+            dev_inst,
             _lifetime_of_path: PhantomData,
         }
     }
@@ -56,6 +62,57 @@ impl<'p> Info<'p> {
     pub fn path_ptr(&self) -> LPCWSTR {
         self.path_ptr
     }
+
+    /// Configuration manager handle of the device exposing this interface,
+    /// valid independently of the device information set.
+    pub fn dev_inst(&self) -> DWORD {
+        self.dev_inst
+    }
+
+    /// First location path of the device, like
+    /// `PCIROOT(0)#PCI(1400)#USBROOT(0)#USB(3)#USB(2)`.
+    pub fn location_path(&self) -> io::Result<String> {
+        location_path(self.dev_inst)
+    }
+}
+
+/// First location path of a device node; see `Info::location_path`.
+pub fn location_path(dev_inst: DWORD) -> io::Result<String> {
+    let mut len: ULONG = 0;
+    let ans = unsafe { CM_Get_DevNode_Registry_PropertyW(
+        dev_inst,
+        CM_DRP_LOCATION_PATHS,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        &mut len,
+        0,
+    ) };
+    if ans != CR_BUFFER_SMALL {
+        return Err(config_error(ans))
+    }
+    let mut buf = vec![0u16; (len as usize + 1) / 2];
+    let ans = unsafe { CM_Get_DevNode_Registry_PropertyW(
+        dev_inst,
+        CM_DRP_LOCATION_PATHS,
+        ptr::null_mut(),
+        buf.as_mut_ptr() as PVOID,
+        &mut len,
+        0,
+    ) };
+    if ans != CR_SUCCESS {
+        return Err(config_error(ans))
+    }
+    // a list of strings, each terminated by a null character
+    let first = buf.split(|&c| c == 0).next().unwrap_or(&[]);
+    Ok(String::from_utf16_lossy(first))
+}
+
+/// Convert a configuration manager error code.
+pub fn config_error(ans: CONFIGRET) -> io::Error {
+    match ans {
+        CR_NO_SUCH_VALUE => io::Error::new(io::ErrorKind::NotFound, "device property not set"),
+        _ => io::Error::other(format!("configuration manager failed with CONFIGRET {}", ans)),
+    }
 }
 
 impl fmt::Debug for Info<'_> {
@@ -74,6 +131,7 @@ pub struct InfoIter<'iter> {
     interface_class_guid: *const GUID, // must be non-null
     _lifetime_of_guid: PhantomData<&'iter ()>,
     dev_interface_data: SP_DEVICE_INTERFACE_DATA,
+    dev_info_data: SP_DEVINFO_DATA,
     detail_ptr: PSP_DEVICE_INTERFACE_DETAIL_DATA_W, 
     detail_len: DWORD, // size in u8, not in u16
     detail_cap: DWORD,
@@ -88,6 +146,7 @@ impl<'iter> InfoIter<'iter> {
             interface_class_guid: guid as *const _,
             _lifetime_of_guid: PhantomData,
             dev_interface_data: create_sp_dev_interface_data(),
+            dev_info_data: create_sp_devinfo_data(),
             detail_ptr: core::ptr::null_mut(),
             detail_len: 0,
             detail_cap: 0,
@@ -117,6 +176,13 @@ fn create_sp_dev_interface_data() -> SP_DEVICE_INTERFACE_DATA {
     ans
 }
 
+#[inline]
+fn create_sp_devinfo_data() -> SP_DEVINFO_DATA {
+    let mut ans: SP_DEVINFO_DATA = unsafe { mem::zeroed() };
+    ans.cbSize = mem::size_of::<SP_DEVINFO_DATA>() as DWORD;
+    ans
+}
+
 impl<'iter> Drop for InfoIter<'iter> {
     fn drop(&mut self) {
         if self.detail_ptr != core::ptr::null_mut() {
@@ -155,7 +221,7 @@ impl<'iter> Iterator for InfoIter<'iter> {
                     self.detail_ptr,
                     self.detail_cap,
                     &self.detail_len as *const _ as *mut _,
-                    core::ptr::null_mut(),
+                    &mut self.dev_info_data,
                 )
             };
             if ans == TRUE {
@@ -188,6 +254,7 @@ impl<'iter> Iterator for InfoIter<'iter> {
         let ret = Info::from_device_path(
             unsafe { &(*self.detail_ptr).DevicePath as *const _ },
             (self.detail_len / 2) - 3, // path_len_in_u16
+            self.dev_info_data.DevInst,
         );
         Some(Ok(ret))
     }
@@ -1,18 +1,25 @@
 #![allow(non_upper_case_globals)]
 
 use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
     iter::FusedIterator,
     marker::PhantomData,
     mem,
-    task::Poll,
+    task::{Context, Poll, Waker},
     pin::Pin,
 };
-use std::io;
+use std::{hash, io, sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant}};
 use super::setup;
 use crate::{
     DeviceDescriptor,
     InterfaceDescriptor,
-    Speed
+    IsoPacket,
+    PipeInfo,
+    PipePolicy,
+    Speed,
+    TransferType,
 };
 
 use winapi::{
@@ -23,9 +30,13 @@ use winapi::{
             FILE_SHARE_READ, FILE_SHARE_WRITE,
             FILE_ATTRIBUTE_NORMAL,
             LANG_NEUTRAL,
+            WT_EXECUTEONLYONCE,
         },
-        winbase::{FILE_FLAG_OVERLAPPED},
-        minwinbase::{OVERLAPPED},
+        winbase::{FILE_FLAG_OVERLAPPED, INFINITE, RegisterWaitForSingleObject},
+        minwinbase::{OVERLAPPED, LPOVERLAPPED},
+        synchapi::{CreateEventW, WaitForSingleObject},
+        ioapiset::CancelIoEx,
+        threadpoollegacyapiset::UnregisterWaitEx,
         fileapi::{CreateFileW, OPEN_EXISTING},
         handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
         errhandlingapi::GetLastError,
@@ -41,26 +52,51 @@ use winapi::{
             WinUsb_FlushPipe,
             WinUsb_ResetPipe,
             WinUsb_AbortPipe,
+            WinUsb_ControlTransfer,
+            WinUsb_SetPipePolicy,
+            WinUsb_GetPipePolicy,
+            WinUsb_GetAssociatedInterface,
+            WinUsb_GetCurrentAlternateSetting,
+            WinUsb_SetCurrentAlternateSetting,
+            WinUsb_RegisterIsochBuffer,
+            WinUsb_UnregisterIsochBuffer,
+            WinUsb_ReadIsochPipeAsap,
+            WinUsb_WriteIsochPipeAsap,
             WINUSB_INTERFACE_HANDLE,
+            WINUSB_ISOCH_BUFFER_HANDLE,
+            WINUSB_SETUP_PACKET,
             USB_INTERFACE_DESCRIPTOR,
         },
     },
     shared::{
-        minwindef::{FALSE, DWORD, UCHAR},
+        minwindef::{TRUE, FALSE, BOOL, DWORD, UCHAR, PUCHAR, USHORT, ULONG, PULONG},
+        ntdef::{BOOLEAN, PVOID},
         winerror::{
             ERROR_NO_MORE_ITEMS,
             ERROR_IO_PENDING,
             ERROR_IO_INCOMPLETE,
+            ERROR_ALREADY_EXISTS,
+            ERROR_OPERATION_ABORTED,
+            ERROR_SEM_TIMEOUT,
+            WAIT_TIMEOUT,
+        },
+        usb::{
+            USBD_PIPE_TYPE,
+            UsbdPipeTypeControl, UsbdPipeTypeIsochronous, UsbdPipeTypeBulk,
+            USBD_ISO_PACKET_DESCRIPTOR, USBD_STATUS,
         },
         usbiodef::GUID_DEVINTERFACE_USB_DEVICE,
         usbspec::{
             USB_DEVICE_DESCRIPTOR,
             USB_DEVICE_DESCRIPTOR_TYPE,
+            USB_CONFIGURATION_DESCRIPTOR_TYPE,
             USB_DEVICE_SPEED,
             UsbLowSpeed, UsbFullSpeed, UsbHighSpeed, UsbSuperSpeed,
         },
         winusbio::{
             DEVICE_SPEED,
+            PIPE_TRANSFER_TIMEOUT,
+            SHORT_PACKET_TERMINATE, AUTO_CLEAR_STALL, ALLOW_PARTIAL_READS, RAW_IO,
             WINUSB_PIPE_INFORMATION,
         },
     },
@@ -138,24 +174,260 @@ impl<'a> Info<'a> {
             unsafe { CloseHandle(device_handle) };
             return Err(err)
         }
-        Ok(WinUsbInterface::new(device_handle, winusb_handle))
+        Ok(WinUsbInterface::new(device_handle, winusb_handle, self.inner.dev_inst()))
+    }
+
+    /// Copy the device interface path.
+    pub fn path(&self) -> std::ffi::OsString {
+        self.inner.to_os_string()
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        super::hub::location(self.inner.dev_inst())
+    }
+
+    /// Parse identifiers from the device interface path, which looks like
+    /// `\\?\usb#vid_0483&pid_3748#<instance>#{<interface class guid>}`.
+    ///
+    /// The instance part is the serial number if the device has one; otherwise
+    /// it is generated by Windows and contains `&`.
+    pub fn device_info(&self) -> crate::DeviceInfo {
+        let path = self.inner.to_os_string().to_string_lossy().into_owned();
+        let mut parts = path.split('#').skip(1);
+        let mut ans = crate::DeviceInfo::default();
+        if let Some(ids) = parts.next() {
+            for id in ids.split('&') {
+                let id = id.to_ascii_lowercase();
+                if let Some(hex) = id.strip_prefix("vid_") {
+                    ans.vendor_id = u16::from_str_radix(hex, 16).ok();
+                } else if let Some(hex) = id.strip_prefix("pid_") {
+                    ans.product_id = u16::from_str_radix(hex, 16).ok();
+                }
+            }
+        }
+        ans.serial_number = parts.next()
+            .filter(|instance| !instance.contains('&'))
+            .map(|instance| instance.to_string());
+        ans.port_path = self.location().ok().map(|location| location.to_string());
+        ans
     }
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+/// A WinUSB interface of an opened device.
+///
+/// The device handle and the handle of its first interface are shared between
+/// clones and associated interfaces, and freed after the last of them drops.
+#[derive(Debug, Clone)]
 pub struct WinUsbInterface<'h> {
-    device_handle: HANDLE,
+    // declared before `device`: associated interfaces are freed first
+    claims: Arc<Claims>,
+    device: Arc<DeviceHandles>,
+    // handle of this interface, the first one or an associated one
     winusb_handle: WINUSB_INTERFACE_HANDLE,
     _lifetime_of_handles: PhantomData<&'h ()>,
 }
 
+#[derive(Debug)]
+struct DeviceHandles {
+    device_handle: HANDLE,
+    winusb_handle: WINUSB_INTERFACE_HANDLE,
+    // configuration manager handle of the device node
+    dev_inst: DWORD,
+}
+
+impl Drop for DeviceHandles {
+    fn drop(&mut self) {
+        unsafe {
+            // reversed free order in destructor
+            WinUsb_Free(self.winusb_handle);
+            CloseHandle(self.device_handle);
+        }
+    }
+}
+
+/// Interfaces claimed through one handle and its clones, freed after the
+/// last of them drops.
+#[derive(Debug, Default)]
+struct Claims {
+    interfaces: Mutex<Vec<Claim>>,
+}
+
+#[derive(Debug)]
+struct Claim {
+    interface_number: u8,
+    winusb_handle: WINUSB_INTERFACE_HANDLE,
+    // the first interface is freed with the device instead
+    owned: bool,
+    // pipes of the current alternate setting, routed to `winusb_handle`
+    endpoints: Vec<u8>,
+}
+
+impl Claims {
+    fn interfaces(&self) -> MutexGuard<'_, Vec<Claim>> {
+        self.interfaces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Claims {
+    fn drop(&mut self) {
+        for claim in self.interfaces().drain(..) {
+            if claim.owned {
+                unsafe { WinUsb_Free(claim.winusb_handle) };
+            }
+        }
+    }
+}
+
 impl<'h> WinUsbInterface<'h> {
-    fn new(device_handle: HANDLE, winusb_handle: WINUSB_INTERFACE_HANDLE) -> Self {
+    fn new(device_handle: HANDLE, winusb_handle: WINUSB_INTERFACE_HANDLE, dev_inst: DWORD) -> Self {
         WinUsbInterface {
-            device_handle, winusb_handle, _lifetime_of_handles: PhantomData
+            claims: Arc::new(Claims::default()),
+            device: Arc::new(DeviceHandles { device_handle, winusb_handle, dev_inst }),
+            winusb_handle,
+            _lifetime_of_handles: PhantomData,
         }
     }
 
+    /// WinUSB handle serving `pipe_index`: the one of a claimed interface
+    /// owning that endpoint, or the one of this interface.
+    fn pipe_handle(&self, pipe_index: u8) -> WINUSB_INTERFACE_HANDLE {
+        self.claims.interfaces().iter()
+            .find(|claim| claim.endpoints.contains(&pipe_index))
+            .map(|claim| claim.winusb_handle)
+            .unwrap_or(self.winusb_handle)
+    }
+
+    fn interface_number_of(winusb_handle: WINUSB_INTERFACE_HANDLE) -> io::Result<u8> {
+        let mut dest = mem::MaybeUninit::<USB_INTERFACE_DESCRIPTOR>::uninit();
+        let ans = unsafe { WinUsb_QueryInterfaceSettings(winusb_handle, 0, dest.as_mut_ptr()) };
+        if ans == FALSE {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(unsafe { dest.assume_init() }.bInterfaceNumber)
+    }
+
+    /// Endpoints of the current alternate setting of an interface.
+    fn endpoints_of(winusb_handle: WINUSB_INTERFACE_HANDLE) -> io::Result<Vec<u8>> {
+        let mut alternate_setting: UCHAR = 0;
+        if unsafe { WinUsb_GetCurrentAlternateSetting(winusb_handle, &mut alternate_setting) } == FALSE {
+            return Err(io::Error::last_os_error())
+        }
+        let mut ans = Vec::new();
+        for pipe_index in 0.. {
+            let mut info = mem::MaybeUninit::<WINUSB_PIPE_INFORMATION>::uninit();
+            let ok = unsafe { WinUsb_QueryPipe(winusb_handle, alternate_setting, pipe_index, info.as_mut_ptr()) };
+            if ok == FALSE {
+                if unsafe { GetLastError() } == ERROR_NO_MORE_ITEMS {
+                    break
+                }
+                return Err(io::Error::last_os_error())
+            }
+            ans.push(unsafe { info.assume_init() }.PipeId);
+        }
+        Ok(ans)
+    }
+
+    /// Open an interface of the same WinUSB function, counting from the first
+    /// interface the device was opened on.
+    fn open_associated(&self, interface_number: u8) -> io::Result<Claim> {
+        let first = Self::interface_number_of(self.device.winusb_handle)?;
+        if interface_number == first {
+            let endpoints = Self::endpoints_of(self.device.winusb_handle)?;
+            return Ok(Claim { interface_number, winusb_handle: self.device.winusb_handle, owned: false, endpoints })
+        }
+        let index = interface_number.checked_sub(first + 1).ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound, "interface not part of the function this handle was opened on"))?;
+        let mut winusb_handle: WINUSB_INTERFACE_HANDLE = core::ptr::null_mut();
+        let ans = unsafe { WinUsb_GetAssociatedInterface(self.device.winusb_handle, index, &mut winusb_handle) };
+        if ans == FALSE {
+            return Err(match unsafe { GetLastError() } {
+                ERROR_NO_MORE_ITEMS => io::Error::new(io::ErrorKind::NotFound, "no such associated interface"),
+                ERROR_ALREADY_EXISTS => io::Error::new(io::ErrorKind::ResourceBusy, "interface claimed by another handle"),
+                _ => io::Error::last_os_error(),
+            })
+        }
+        match Self::endpoints_of(winusb_handle) {
+            Ok(endpoints) => Ok(Claim { interface_number, winusb_handle, owned: true, endpoints }),
+            Err(e) => {
+                unsafe { WinUsb_Free(winusb_handle) };
+                Err(e)
+            },
+        }
+    }
+
+    pub fn location(&self) -> io::Result<crate::Location> {
+        super::hub::location(self.device.dev_inst)
+    }
+
+    /// Cycle the hub port of the device, then wait until this handle stops
+    /// working, so that the device is not found again before it re-enumerates.
+    pub fn reset_device(&self) -> io::Result<()> {
+        super::hub::cycle_port(self.device.dev_inst)?;
+        let deadline = Instant::now() + super::hub::CYCLE_PORT_REMOVAL_TIMEOUT;
+        while self.speed().is_ok() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// Make the endpoints of a sibling interface usable through this handle.
+    pub fn claim_interface(&self, interface_number: u8) -> io::Result<()> {
+        if interface_number == Self::interface_number_of(self.winusb_handle)? {
+            return Ok(())
+        }
+        let mut interfaces = self.claims.interfaces();
+        if interfaces.iter().any(|claim| claim.interface_number == interface_number) {
+            return Ok(())
+        }
+        interfaces.push(self.open_associated(interface_number)?);
+        Ok(())
+    }
+
+    pub fn release_interface(&self, interface_number: u8) -> io::Result<()> {
+        let mut interfaces = self.claims.interfaces();
+        let position = interfaces.iter().position(|claim| claim.interface_number == interface_number)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                "interface not claimed by this handle; its own interface is released on drop"))?;
+        let claim = interfaces.remove(position);
+        if claim.owned {
+            unsafe { WinUsb_Free(claim.winusb_handle) };
+        }
+        Ok(())
+    }
+
+    pub fn set_alternate_setting(&self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        let mut interfaces = self.claims.interfaces();
+        let claim = interfaces.iter_mut().find(|claim| claim.interface_number == interface_number);
+        let winusb_handle = match &claim {
+            Some(claim) => claim.winusb_handle,
+            None if interface_number == Self::interface_number_of(self.winusb_handle)? => self.winusb_handle,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface not claimed by this handle")),
+        };
+        if unsafe { WinUsb_SetCurrentAlternateSetting(winusb_handle, alternate_setting) } == FALSE {
+            return Err(io::Error::last_os_error())
+        }
+        if let Some(claim) = claim {
+            claim.endpoints = Self::endpoints_of(winusb_handle)?;
+        }
+        Ok(())
+    }
+
+    /// A handle of its own on the interface `index + 1` places after this one.
+    pub fn associated_interface<'a>(&self, index: u8) -> io::Result<WinUsbInterface<'a>> {
+        let own = Self::interface_number_of(self.winusb_handle)?;
+        let interface_number = index.checked_add(1).and_then(|i| own.checked_add(i))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such associated interface"))?;
+        let claim = self.open_associated(interface_number)?;
+        let winusb_handle = claim.winusb_handle;
+        let claims = Claims { interfaces: Mutex::new(vec![claim]) };
+        Ok(WinUsbInterface {
+            claims: Arc::new(claims),
+            device: self.device.clone(),
+            winusb_handle,
+            _lifetime_of_handles: PhantomData,
+        })
+    }
+
     pub fn device_descriptor(&self) -> io::Result<USB_DEVICE_DESCRIPTOR> {
         let mut dest = mem::MaybeUninit::<USB_DEVICE_DESCRIPTOR>::uninit();
         let len = 0;
@@ -178,6 +450,39 @@ impl<'h> WinUsbInterface<'h> {
         Ok(unsafe { dest.assume_init() })
     }
 
+    pub fn get_descriptor(&self, descriptor_type: UCHAR, index: UCHAR, language_id: USHORT, buf: &mut [u8])
+        -> io::Result<usize>
+    {
+        let mut len: ULONG = 0;
+        let ans = unsafe { WinUsb_GetDescriptor(
+            self.winusb_handle,
+            descriptor_type,
+            index,
+            language_id,
+            buf.as_mut_ptr(),
+            buf.len() as ULONG,
+            &mut len,
+        ) };
+        if ans == FALSE {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(len as usize)
+    }
+
+    /// Read the whole configuration descriptor blob of `wTotalLength` bytes.
+    pub fn config_descriptor(&self, index: u8) -> io::Result<Vec<u8>> {
+        let mut header = [0u8; crate::descriptor::DT_CONFIG_SIZE];
+        let len = self.get_descriptor(USB_CONFIGURATION_DESCRIPTOR_TYPE, index, 0, &mut header)?;
+        if len < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "configuration descriptor too short"))
+        }
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let mut buf = vec![0u8; total_length as usize];
+        let len = self.get_descriptor(USB_CONFIGURATION_DESCRIPTOR_TYPE, index, 0, &mut buf)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
     pub fn speed(&self) -> io::Result<USB_DEVICE_SPEED> {
         let device_speed = 0 as UCHAR;
         // this variable cannot be `static`: otherwise there would be a 
@@ -220,6 +525,14 @@ impl<'h> WinUsbInterface<'h> {
         Ok(Some(unsafe { dest.assume_init() }))
     }
 
+    pub fn current_alternate_setting(&self) -> io::Result<u8> {
+        let mut alternate_setting: UCHAR = 0;
+        if unsafe { WinUsb_GetCurrentAlternateSetting(self.winusb_handle, &mut alternate_setting) } == FALSE {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(alternate_setting)
+    }
+
     pub fn query_pipe(&self, interface_number: u8, pipe_index: u8) 
         -> io::Result<Option<WINUSB_PIPE_INFORMATION>>
     {
@@ -244,7 +557,7 @@ impl<'h> WinUsbInterface<'h> {
     pub fn write_pipe(&self, pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
         let mut len = mem::MaybeUninit::<DWORD>::uninit();
         let ans = unsafe { WinUsb_WritePipe(
-            self.winusb_handle,
+            self.pipe_handle(pipe_index),
             pipe_index,
             buf.as_ptr() as *mut u8,
             buf.len() as DWORD,
@@ -260,7 +573,7 @@ impl<'h> WinUsbInterface<'h> {
     pub fn read_pipe(&self, pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
         let mut len = mem::MaybeUninit::<DWORD>::uninit();
         let ans = unsafe { WinUsb_ReadPipe(
-            self.winusb_handle,
+            self.pipe_handle(pipe_index),
             pipe_index,
             buf.as_ptr() as *mut u8,
             buf.len() as DWORD,
@@ -275,7 +588,7 @@ impl<'h> WinUsbInterface<'h> {
 
     pub fn flush_pipe(&self, pipe_index: u8) -> io::Result<()> {
         let ans = unsafe { WinUsb_FlushPipe (
-            self.winusb_handle,
+            self.pipe_handle(pipe_index),
             pipe_index,
         ) };
         if ans == FALSE {
@@ -284,9 +597,160 @@ impl<'h> WinUsbInterface<'h> {
         Ok(())
     }
 
+    pub fn set_pipe_policy<T>(&self, pipe_index: u8, policy_type: ULONG, value: &T) -> io::Result<()> {
+        let ans = unsafe { WinUsb_SetPipePolicy(
+            self.pipe_handle(pipe_index),
+            pipe_index,
+            policy_type,
+            mem::size_of::<T>() as ULONG,
+            value as *const T as *mut _,
+        ) };
+        if ans == FALSE {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    pub fn get_pipe_policy<T: Default>(&self, pipe_index: u8, policy_type: ULONG) -> io::Result<T> {
+        let mut value = T::default();
+        let mut len = mem::size_of::<T>() as ULONG;
+        let ans = unsafe { WinUsb_GetPipePolicy(
+            self.pipe_handle(pipe_index),
+            pipe_index,
+            policy_type,
+            &mut len,
+            &mut value as *mut T as *mut _,
+        ) };
+        if ans == FALSE {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(value)
+    }
+
+    pub fn set_timeout(&self, pipe_index: u8, timeout: Duration) -> io::Result<()> {
+        self.set_pipe_policy(pipe_index, PIPE_TRANSFER_TIMEOUT, &timeout_ms(timeout))
+    }
+
+    // WinUSB refuses the policies of the other direction than the one of the pipe
+    pub fn set_policy(&self, pipe_index: u8, policy: PipePolicy) -> io::Result<()> {
+        let flag = |on: bool| -> UCHAR { if on { TRUE as UCHAR } else { FALSE as UCHAR } };
+        if pipe_index & 0x80 == 0 {
+            return self.set_pipe_policy(pipe_index, SHORT_PACKET_TERMINATE, &flag(policy.short_packet_terminate))
+        }
+        self.set_pipe_policy(pipe_index, RAW_IO, &flag(policy.raw_io))?;
+        self.set_pipe_policy(pipe_index, AUTO_CLEAR_STALL, &flag(policy.auto_clear_stall))?;
+        self.set_pipe_policy(pipe_index, ALLOW_PARTIAL_READS, &flag(policy.allow_partial_reads))
+    }
+
+    pub fn policy(&self, pipe_index: u8) -> io::Result<PipePolicy> {
+        let mut policy = PipePolicy::default();
+        if pipe_index & 0x80 == 0 {
+            policy.short_packet_terminate = self.get_pipe_policy::<UCHAR>(pipe_index, SHORT_PACKET_TERMINATE)? != 0;
+        } else {
+            policy.raw_io = self.get_pipe_policy::<UCHAR>(pipe_index, RAW_IO)? != 0;
+            policy.auto_clear_stall = self.get_pipe_policy::<UCHAR>(pipe_index, AUTO_CLEAR_STALL)? != 0;
+            policy.allow_partial_reads = self.get_pipe_policy::<UCHAR>(pipe_index, ALLOW_PARTIAL_READS)? != 0;
+        }
+        Ok(policy)
+    }
+
+    /// Submit an overlapped transfer on `winusb_handle` started by `f`, and
+    /// wait at most `timeout` for it, cancelling it after.
+    ///
+    /// The transfer timeout policy belongs to the pipe, so it is left alone for
+    /// concurrent transfers on the same pipe; if shorter, it ends the transfer
+    /// first.
+    fn with_timeout<F>(&self, winusb_handle: WINUSB_INTERFACE_HANDLE, timeout: Duration, f: F) -> io::Result<usize>
+    where
+        F: FnOnce(LPOVERLAPPED) -> BOOL
+    {
+        let event = unsafe { CreateEventW(core::ptr::null_mut(), TRUE, FALSE, core::ptr::null()) };
+        if event.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+        // an all-zero OVERLAPPED is the documented initial state
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = event;
+        let submitted = f(&mut overlapped);
+        let ans = if submitted == FALSE && unsafe { GetLastError() } != ERROR_IO_PENDING {
+            Err(io::Error::last_os_error())
+        } else {
+            let wait_ms = if timeout == Duration::from_secs(0) { INFINITE } else { timeout_ms(timeout) };
+            let timed_out = unsafe { WaitForSingleObject(event, wait_ms) } == WAIT_TIMEOUT;
+            if timed_out {
+                unsafe { CancelIoEx(self.device.device_handle, &mut overlapped) };
+            }
+            // also waits until WinUSB has released a cancelled request
+            let mut len_transferred: ULONG = 0;
+            let ans = unsafe { WinUsb_GetOverlappedResult(
+                winusb_handle,
+                &mut overlapped,
+                &mut len_transferred,
+                TRUE,
+            ) };
+            if ans != FALSE {
+                Ok(len_transferred as usize)
+            } else if timed_out && unsafe { GetLastError() } == ERROR_OPERATION_ABORTED {
+                // reported like a timeout of WinUSB itself
+                Err(io::Error::from_raw_os_error(ERROR_SEM_TIMEOUT as i32))
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        };
+        unsafe { CloseHandle(event) };
+        ans
+    }
+
+    pub fn write_pipe_timeout(&self, pipe_index: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        let winusb_handle = self.pipe_handle(pipe_index);
+        self.with_timeout(winusb_handle, timeout, |overlapped| unsafe { WinUsb_WritePipe(
+            winusb_handle,
+            pipe_index,
+            // WinUSB only reads from the buffer of a write
+            buf.as_ptr() as *mut u8,
+            buf.len() as ULONG,
+            core::ptr::null_mut(),
+            overlapped,
+        ) })
+    }
+
+    pub fn read_pipe_timeout(&self, pipe_index: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let winusb_handle = self.pipe_handle(pipe_index);
+        self.with_timeout(winusb_handle, timeout, |overlapped| unsafe { WinUsb_ReadPipe(
+            winusb_handle,
+            pipe_index,
+            buf.as_mut_ptr(),
+            buf.len() as ULONG,
+            core::ptr::null_mut(),
+            overlapped,
+        ) })
+    }
+
+    /// Issue a control transfer on the default pipe; `data` is read from for
+    /// OUT requests and written into for IN requests.
+    pub fn control_transfer(&self, request: &crate::ControlRequest, data: *mut u8, len: usize, timeout: Duration)
+        -> io::Result<usize>
+    {
+        let setup_packet = WINUSB_SETUP_PACKET {
+            RequestType: request.request_type_bits(),
+            Request: request.request,
+            Value: request.value,
+            Index: request.index,
+            Length: len as u16,
+        };
+        self.with_timeout(self.winusb_handle, timeout, |overlapped| unsafe { WinUsb_ControlTransfer(
+            self.winusb_handle,
+            setup_packet,
+            data,
+            len as ULONG,
+            core::ptr::null_mut(),
+            overlapped,
+        ) })
+    }
+
     pub fn reset_pipe(&self, pipe_index: u8) -> io::Result<()> {
         let ans = unsafe { WinUsb_ResetPipe (
-            self.winusb_handle,
+            self.pipe_handle(pipe_index),
             pipe_index,
         ) };
         if ans == FALSE {
@@ -297,7 +761,7 @@ impl<'h> WinUsbInterface<'h> {
 
     pub fn abort_pipe(&self, pipe_index: u8) -> io::Result<()> {
         let ans = unsafe { WinUsb_AbortPipe (
-            self.winusb_handle,
+            self.pipe_handle(pipe_index),
             pipe_index,
         ) };
         if ans == FALSE {
@@ -306,89 +770,290 @@ impl<'h> WinUsbInterface<'h> {
         Ok(())
     }
 
-    // WinUsb_WritePipe with OVERLAPPED
-    pub fn write_pipe_overlapped(&self, pipe_index: u8, buf: &[u8])     
-        -> io::Result<Pin<Box<OVERLAPPED>>>
+    /// Receive isochronous packets as soon as possible into `buf`.
+    pub fn read_isochronous(&self, pipe_index: u8, buf: &mut [u8], packets: usize) -> io::Result<Vec<IsoPacket>> {
+        let empty = USBD_ISO_PACKET_DESCRIPTOR { Offset: 0, Length: 0, Status: 0 };
+        let mut descriptors = vec![empty; packets];
+        let len = buf.len() as ULONG;
+        self.isochronous(pipe_index, buf.as_mut_ptr(), len, |buffer_handle, overlapped| unsafe {
+            WinUsb_ReadIsochPipeAsap(
+                buffer_handle, 0, len, FALSE, packets as ULONG, descriptors.as_mut_ptr(), overlapped,
+            )
+        })?;
+        Ok(descriptors.iter().map(|desc| IsoPacket {
+            offset: desc.Offset as usize,
+            length: desc.Length as usize,
+            status: packet_status(desc.Status),
+        }).collect())
+    }
+
+    /// Send `buf` as isochronous packets as soon as possible.
+    ///
+    /// WinUSB reports no packets on writes, so each counts as sent in full.
+    pub fn write_isochronous(&self, pipe_index: u8, buf: &[u8], packets: usize) -> io::Result<Vec<IsoPacket>> {
+        let len = buf.len() as ULONG;
+        // WinUSB only reads from the buffer of a write
+        self.isochronous(pipe_index, buf.as_ptr() as *mut u8, len, |buffer_handle, overlapped| unsafe {
+            WinUsb_WriteIsochPipeAsap(buffer_handle, 0, len, FALSE, overlapped)
+        })?;
+        let packet_len = buf.len() / packets;
+        Ok((0..packets).map(|i| IsoPacket { offset: i * packet_len, length: packet_len, status: Ok(()) }).collect())
+    }
+
+    // register the buffer with WinUSB for one transfer started by `f`, and
+    // wait for it: packets complete on schedule whether the device answers or not
+    fn isochronous<F>(&self, pipe_index: u8, buf: PUCHAR, len: ULONG, f: F) -> io::Result<()>
+    where
+        F: FnOnce(WINUSB_ISOCH_BUFFER_HANDLE, LPOVERLAPPED) -> BOOL
     {
-        let mut overlapped = Box::pin(unsafe { 
-            mem::MaybeUninit::<OVERLAPPED>::uninit().assume_init()
-        });
-        overlapped.hEvent = core::ptr::null_mut();
-        let ans = unsafe { WinUsb_WritePipe(
-            self.winusb_handle,
+        let mut buffer_handle: WINUSB_ISOCH_BUFFER_HANDLE = core::ptr::null_mut();
+        let ans = unsafe { WinUsb_RegisterIsochBuffer(
+            self.pipe_handle(pipe_index),
             pipe_index,
-            buf.as_ptr() as *mut u8,
-            buf.len() as DWORD,
-            core::ptr::null_mut(),
-            &*overlapped as *const _ as *mut _,
-        ) }; 
+            buf,
+            len,
+            &mut buffer_handle,
+        ) };
         if ans == FALSE {
-            if unsafe { GetLastError() } == ERROR_IO_PENDING {
-                return Ok(overlapped)
-            }
             return Err(io::Error::last_os_error())
         }
-        // todo: check if correct
-        // return Ok(unsafe { overlapped.assume_init() })
-        panic!("returned true for overlapped write")
+        let event = unsafe { CreateEventW(core::ptr::null_mut(), TRUE, FALSE, core::ptr::null()) };
+        let ans = if event.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            // an all-zero OVERLAPPED is the documented initial state
+            let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+            overlapped.hEvent = event;
+            let submitted = f(buffer_handle, &mut overlapped);
+            let ans = if submitted == FALSE && unsafe { GetLastError() } != ERROR_IO_PENDING {
+                Err(io::Error::last_os_error())
+            } else {
+                let mut len_transferred: ULONG = 0;
+                let ans = unsafe { WinUsb_GetOverlappedResult(
+                    self.pipe_handle(pipe_index),
+                    &mut overlapped,
+                    &mut len_transferred,
+                    TRUE,
+                ) };
+                if ans == FALSE { Err(io::Error::last_os_error()) } else { Ok(()) }
+            };
+            unsafe { CloseHandle(event) };
+            ans
+        };
+        unsafe { WinUsb_UnregisterIsochBuffer(buffer_handle) };
+        ans
     }
 
-    pub fn read_pipe_overlapped(&self, pipe_index: u8, buf: &mut [u8]) 
-        -> io::Result<Pin<Box<OVERLAPPED>>>
-    {
-        let mut overlapped = Box::pin(unsafe { 
-            mem::MaybeUninit::<OVERLAPPED>::uninit().assume_init()
-        });
-        overlapped.hEvent = core::ptr::null_mut();
-        let ans = unsafe { WinUsb_ReadPipe(
-            self.winusb_handle,
+    /// Start an overlapped write owning `buf` until the returned future completes.
+    pub fn write_pipe_overlapped<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> Overlapped<'a> {
+        Overlapped::submit(self, pipe_index, buf, WinUsb_WritePipe)
+    }
+
+    /// Start an overlapped read owning `buf` until the returned future completes.
+    pub fn read_pipe_overlapped<'a>(&'a self, pipe_index: u8, buf: Vec<u8>) -> Overlapped<'a> {
+        Overlapped::submit(self, pipe_index, buf, WinUsb_ReadPipe)
+    }
+}
+
+type PipeFn = unsafe extern "system" fn(
+    WINUSB_INTERFACE_HANDLE, UCHAR, PUCHAR, ULONG, PULONG, LPOVERLAPPED,
+) -> BOOL;
+
+/// An overlapped pipe transfer, resolving to its buffer and the length transferred.
+///
+/// Completion of the request signals an event, on which a thread pool wait
+/// wakes the task; this works on any executor. Dropping the future before
+/// completion cancels the request and waits for WinUSB to release the buffer.
+pub struct Overlapped<'a> {
+    winusb_handle: WINUSB_INTERFACE_HANDLE,
+    device_handle: HANDLE,
+    // boxed so that addresses given to the system stay put while pending
+    inner: Box<OverlappedInner>,
+    buf: Vec<u8>,
+    state: OverlappedState,
+    _lifetime_of_handles: PhantomData<&'a ()>,
+}
+
+struct OverlappedInner {
+    overlapped: UnsafeCell<OVERLAPPED>,
+    wait_handle: UnsafeCell<HANDLE>,
+    waker: Mutex<Option<Waker>>,
+}
+
+enum OverlappedState {
+    Pending,
+    Failed(io::Error),
+    Done,
+}
+
+impl<'a> Overlapped<'a> {
+    fn submit(interface: &'a WinUsbInterface, pipe_index: u8, mut buf: Vec<u8>, f: PipeFn) -> Overlapped<'a> {
+        // an all-zero OVERLAPPED is the documented initial state
+        let overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        let mut ans = Overlapped {
+            winusb_handle: interface.pipe_handle(pipe_index),
+            device_handle: interface.device.device_handle,
+            inner: Box::new(OverlappedInner {
+                overlapped: UnsafeCell::new(overlapped),
+                wait_handle: UnsafeCell::new(core::ptr::null_mut()),
+                waker: Mutex::new(None),
+            }),
+            buf: Vec::new(),
+            state: OverlappedState::Done,
+            _lifetime_of_handles: PhantomData,
+        };
+        let event = unsafe { CreateEventW(core::ptr::null_mut(), TRUE, FALSE, core::ptr::null()) };
+        if event.is_null() {
+            ans.state = OverlappedState::Failed(io::Error::last_os_error());
+            return ans
+        }
+        unsafe { (*ans.inner.overlapped.get()).hEvent = event };
+        let len = buf.len() as ULONG;
+        let result = unsafe { f(
+            ans.winusb_handle,
             pipe_index,
-            buf.as_ptr() as *mut u8,
-            buf.len() as DWORD,
+            buf.as_mut_ptr(),
+            len,
             core::ptr::null_mut(),
-            &*overlapped as *const _ as *mut _,
-        ) }; 
-        if ans == FALSE {
-            if unsafe { GetLastError() } == ERROR_IO_PENDING {
-                return Ok(overlapped)
-            }
-            return Err(io::Error::last_os_error())
+            ans.inner.overlapped.get(),
+        ) };
+        ans.buf = buf;
+        // requests completing synchronously signal the event as well, so both
+        // cases are finished in `poll`
+        if result == FALSE && unsafe { GetLastError() } != ERROR_IO_PENDING {
+            ans.state = OverlappedState::Failed(io::Error::last_os_error());
+            return ans
         }
-        // todo: check if correct
-        // return Ok(unsafe { overlapped.assume_init() })
-        panic!("returned true for overlapped read")
+        ans.state = OverlappedState::Pending;
+        let registered = unsafe { RegisterWaitForSingleObject(
+            ans.inner.wait_handle.get(),
+            event,
+            Some(wake_overlapped),
+            &*ans.inner as *const OverlappedInner as PVOID,
+            INFINITE,
+            WT_EXECUTEONLYONCE,
+        ) };
+        if registered == FALSE {
+            let err = io::Error::last_os_error();
+            ans.cancel();
+            ans.state = OverlappedState::Failed(err);
+        }
+        ans
     }
 
-    pub fn poll_overlapped(&self, overlapped: Pin<&OVERLAPPED>) 
-        -> Poll<io::Result<usize>>
-    {
-        let mut bytes_transferred = mem::MaybeUninit::uninit();
+    /// Cancel a pending request and wait until WinUSB has released it.
+    fn cancel(&mut self) {
+        let mut len: ULONG = 0;
+        unsafe {
+            CancelIoEx(self.device_handle, self.inner.overlapped.get());
+            WinUsb_GetOverlappedResult(self.winusb_handle, self.inner.overlapped.get(), &mut len, TRUE);
+        }
+        self.state = OverlappedState::Done;
+    }
+}
+
+unsafe extern "system" fn wake_overlapped(context: PVOID, _timer_or_wait_fired: BOOLEAN) {
+    let inner = &*(context as *const OverlappedInner);
+    let waker = inner.waker.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(waker) = waker {
+        waker.wake()
+    }
+}
+
+impl Future for Overlapped<'_> {
+    type Output = io::Result<(Vec<u8>, usize)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match mem::replace(&mut this.state, OverlappedState::Done) {
+            OverlappedState::Pending => {},
+            OverlappedState::Failed(err) => return Poll::Ready(Err(err)),
+            OverlappedState::Done => panic!("overlapped future polled after completion"),
+        }
+        // store the waker before checking, so a completion in between still wakes us
+        *this.inner.waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(cx.waker().clone());
+        let mut len: ULONG = 0;
         let ans = unsafe { WinUsb_GetOverlappedResult(
-            self.winusb_handle,
-            &*overlapped as *const _ as *mut _,
-            bytes_transferred.as_mut_ptr(),
+            this.winusb_handle,
+            this.inner.overlapped.get(),
+            &mut len,
             FALSE,
         ) };
         if ans == FALSE {
             if unsafe { GetLastError() } == ERROR_IO_INCOMPLETE {
-                return Poll::Pending;
+                this.state = OverlappedState::Pending;
+                return Poll::Pending
             }
             return Poll::Ready(Err(io::Error::last_os_error()))
         }
-        return Poll::Ready(Ok(unsafe { bytes_transferred.assume_init() as usize }))
+        Poll::Ready(Ok((mem::take(&mut this.buf), len as usize)))
     }
 }
 
-impl Drop for WinUsbInterface<'_> {
+impl Drop for Overlapped<'_> {
     fn drop(&mut self) {
-        unsafe { 
-            // reversed free order in destructor
-            WinUsb_Free(self.winusb_handle);
-            CloseHandle(self.device_handle);
+        if let OverlappedState::Pending = self.state {
+            self.cancel();
+        }
+        unsafe {
+            let wait_handle = *self.inner.wait_handle.get();
+            if !wait_handle.is_null() {
+                // blocks until a running callback returned, which may use `inner`
+                UnregisterWaitEx(wait_handle, INVALID_HANDLE_VALUE);
+            }
+            let event = (*self.inner.overlapped.get()).hEvent;
+            if !event.is_null() {
+                CloseHandle(event);
+            }
         }
     }
 }
 
+impl fmt::Debug for Overlapped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Overlapped")
+            .field("pending", &matches!(self.state, OverlappedState::Pending))
+            .finish()
+    }
+}
+
+impl PartialEq for WinUsbInterface<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.winusb_handle == other.winusb_handle
+    }
+}
+
+impl Eq for WinUsbInterface<'_> {}
+
+impl hash::Hash for WinUsbInterface<'_> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.winusb_handle.hash(state)
+    }
+}
+
+// `USBD_STATUS_STALL_PID`, the only packet status with an `Error` of its own
+const USBD_STATUS_STALL_PID: USBD_STATUS = 0xC000_0004_u32 as USBD_STATUS;
+
+fn packet_status(status: USBD_STATUS) -> crate::Result<()> {
+    match status {
+        0 => Ok(()),
+        USBD_STATUS_STALL_PID => Err(crate::Error::Stall),
+        _ => {
+            let message = format!("isochronous packet failed with USBD status {:#010X}", status);
+            Err(crate::Error::Other(io::Error::other(message)))
+        },
+    }
+}
+
+/// Convert to WinUSB policy milliseconds, where zero waits forever; a non-zero
+/// timeout never rounds down to zero.
+fn timeout_ms(timeout: Duration) -> ULONG {
+    if timeout == Duration::from_secs(0) {
+        return 0
+    }
+    timeout.as_millis().clamp(1, ULONG::MAX as u128) as ULONG
+}
+
 impl From<USB_DEVICE_DESCRIPTOR> for DeviceDescriptor {
     fn from(src: USB_DEVICE_DESCRIPTOR) -> DeviceDescriptor {
         DeviceDescriptor {        
@@ -426,6 +1091,26 @@ impl From<USB_INTERFACE_DESCRIPTOR> for InterfaceDescriptor {
     }
 }
 
+impl From<WINUSB_PIPE_INFORMATION> for PipeInfo {
+    fn from(src: WINUSB_PIPE_INFORMATION) -> PipeInfo {
+        PipeInfo {
+            endpoint_address: src.PipeId,
+            transfer_type: transfer_type(src.PipeType),
+            max_packet_size: src.MaximumPacketSize & 0x07FF,
+            interval: src.Interval,
+        }
+    }
+}
+
+fn transfer_type(src: USBD_PIPE_TYPE) -> TransferType {
+    match src {
+        UsbdPipeTypeControl => TransferType::Control,
+        UsbdPipeTypeIsochronous => TransferType::Isochronous,
+        UsbdPipeTypeBulk => TransferType::Bulk,
+        _ => TransferType::Interrupt,
+    }
+}
+
 impl From<USB_DEVICE_SPEED> for Speed {
     fn from(src: USB_DEVICE_SPEED) -> Speed {
         match src {
@@ -0,0 +1,372 @@
+//! Capture of USB traffic to pcapng files, to be opened in Wireshark.
+//!
+//! Transfers are written with the USBPcap link type whatever the backend, so
+//! captures from Linux, Windows and the mock backend look the same.
+use core::fmt;
+use std::{fs, io::{self, Write}, path::Path, sync::{Arc, Mutex, MutexGuard}};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{ControlRequest, Error, IsoPacket, Result};
+
+// pcapng block types
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_USBPCAP: u16 = 249;
+
+// fields of `USBPCAP_BUFFER_PACKET_HEADER`
+const USBPCAP_HEADER_LEN: u16 = 27;
+const USBPCAP_INFO_PDO_TO_FDO: u8 = 0x01; // set on completions
+const USBPCAP_TRANSFER_ISOCHRONOUS: u8 = 0;
+const USBPCAP_TRANSFER_INTERRUPT: u8 = 1;
+const USBPCAP_TRANSFER_CONTROL: u8 = 2;
+const USBPCAP_TRANSFER_BULK: u8 = 3;
+const USBPCAP_CONTROL_STAGE_SETUP: u8 = 0;
+const USBPCAP_CONTROL_STAGE_DATA: u8 = 1;
+const USBPCAP_CONTROL_STAGE_COMPLETE: u8 = 3;
+const URB_FUNCTION_CONTROL_TRANSFER: u16 = 0x0008;
+const URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER: u16 = 0x0009;
+const URB_FUNCTION_ISOCH_TRANSFER: u16 = 0x000A;
+
+// `USBD_STATUS` values shown by Wireshark
+const USBD_STATUS_SUCCESS: u32 = 0x0000_0000;
+const USBD_STATUS_STALL_PID: u32 = 0xC000_0004;
+const USBD_STATUS_DEV_NOT_RESPONDING: u32 = 0xC000_0005;
+const USBD_STATUS_DATA_OVERRUN: u32 = 0xC000_0008;
+const USBD_STATUS_TIMEOUT: u32 = 0xC000_6000;
+const USBD_STATUS_DEVICE_GONE: u32 = 0xC000_7000;
+const USBD_STATUS_CANCELED: u32 = 0xC001_0000;
+const USBD_STATUS_REQUEST_FAILED: u32 = 0x8000_0500;
+
+/// Writes transfers of the handles it is attached to as a pcapng capture.
+///
+/// Clones write to the same capture, so one tracer can record several
+/// handles. Devices are numbered in the order they are first attached, on
+/// the bus of their location.
+///
+/// ```no_run
+/// use nihao_usb::trace::Tracer;
+/// let tracer = Tracer::create("stlink.pcapng")?;
+/// let handle = nihao_usb::DeviceFilter::new().vendor_id(0x0483).list()?
+///     .iter().next().expect("an ST-Link")?.open()?;
+/// handle.set_tracer(Some(tracer.clone()));
+/// handle.write_pipe(0x02, &[0xF1, 0x80])?;
+/// tracer.flush()?;
+/// # Ok::<(), nihao_usb::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Tracer {
+    inner: Arc<Mutex<Capture>>,
+}
+
+struct Capture {
+    out: Box<dyn Write + Send>,
+    // the first write error, reported by `flush`
+    error: Option<io::Error>,
+    next_irp_id: u64,
+    next_device: u16,
+}
+
+impl Tracer {
+    /// Create a capture file, replacing an existing one.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Tracer> {
+        let file = fs::File::create(path)?;
+        Tracer::new(io::BufWriter::new(file))
+    }
+
+    /// Write the capture to `out`, starting with the pcapng headers.
+    pub fn new<W: Write + Send + 'static>(out: W) -> Result<Tracer> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        let mut header = Vec::new();
+        header.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // version 1.0
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+        write_block(&mut out, BLOCK_SECTION_HEADER, &header)?;
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&LINKTYPE_USBPCAP.to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        interface.extend_from_slice(&0u32.to_le_bytes()); // no snapshot length limit
+        write_block(&mut out, BLOCK_INTERFACE_DESCRIPTION, &interface)?;
+        let capture = Capture { out, error: None, next_irp_id: 1, next_device: 1 };
+        Ok(Tracer { inner: Arc::new(Mutex::new(capture)) })
+    }
+
+    /// Write out buffered packets, and report the first error writing any
+    /// packet since the last call.
+    ///
+    /// Transfers never fail because of the capture, so errors are kept here.
+    pub fn flush(&self) -> Result<()> {
+        let mut capture = self.capture();
+        if let Some(e) = capture.error.take() {
+            return Err(e.into())
+        }
+        Ok(capture.out.flush()?)
+    }
+
+    fn capture(&self) -> MutexGuard<'_, Capture> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn next_device(&self) -> u16 {
+        let mut capture = self.capture();
+        let ans = capture.next_device;
+        capture.next_device = capture.next_device.wrapping_add(1);
+        ans
+    }
+
+    fn next_irp_id(&self) -> u64 {
+        let mut capture = self.capture();
+        let ans = capture.next_irp_id;
+        capture.next_irp_id += 1;
+        ans
+    }
+
+    fn write_packet(&self, packet: &[u8]) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        // timestamps in microseconds, the default resolution
+        let micros = timestamp.as_micros() as u64;
+        let mut body = Vec::with_capacity(20 + packet.len());
+        body.extend_from_slice(&0u32.to_le_bytes()); // interface
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        let mut capture = self.capture();
+        if capture.error.is_none() {
+            if let Err(e) = write_block(&mut capture.out, BLOCK_ENHANCED_PACKET, &body) {
+                capture.error = Some(e);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer").finish_non_exhaustive()
+    }
+}
+
+fn write_block(out: &mut dyn Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total_len = (12 + body.len() + padding) as u32;
+    let mut block = Vec::with_capacity(total_len as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total_len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(block.len() + padding, 0);
+    block.extend_from_slice(&total_len.to_le_bytes());
+    out.write_all(&block)
+}
+
+/// The tracer a handle and its clones write to, if any.
+#[derive(Debug, Default)]
+pub(crate) struct TraceSlot {
+    attached: Mutex<Option<Attached>>,
+}
+
+#[derive(Debug, Clone)]
+struct Attached {
+    tracer: Tracer,
+    bus: u16,
+    device: u16,
+}
+
+impl TraceSlot {
+    pub(crate) fn attach(&self, tracer: Option<Tracer>, bus: u8) {
+        let attached = tracer.map(|tracer| {
+            let device = tracer.next_device();
+            Attached { tracer, bus: bus as u16, device }
+        });
+        *self.attached() = attached;
+    }
+
+    /// Attach the tracer of this slot to a new handle of the same device.
+    pub(crate) fn inherit(&self) -> TraceSlot {
+        TraceSlot { attached: Mutex::new(self.attached().clone()) }
+    }
+
+    fn attached(&self) -> MutexGuard<'_, Option<Attached>> {
+        self.attached.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // a new transfer, if the slot is attached
+    fn transfer(&self, endpoint: u8, transfer_type: u8) -> Option<Transfer> {
+        let attached = self.attached().clone()?;
+        Some(Transfer { irp_id: attached.tracer.next_irp_id(), attached, endpoint, transfer_type })
+    }
+
+    /// Record the submission of a bulk transfer, with the data of OUT pipes.
+    pub(crate) fn submit_bulk(&self, pipe_index: u8, data: &[u8]) -> Pending {
+        self.submit(pipe_index, USBPCAP_TRANSFER_BULK, data)
+    }
+
+    /// Record the submission of an interrupt transfer, with the data of OUT pipes.
+    pub(crate) fn submit_interrupt(&self, pipe_index: u8, data: &[u8]) -> Pending {
+        self.submit(pipe_index, USBPCAP_TRANSFER_INTERRUPT, data)
+    }
+
+    fn submit(&self, pipe_index: u8, transfer_type: u8, data: &[u8]) -> Pending {
+        let transfer = match self.transfer(pipe_index, transfer_type) {
+            Some(transfer) => transfer,
+            None => return Pending(None),
+        };
+        transfer.write(false, None, data);
+        Pending(Some(transfer))
+    }
+
+    /// Record the submission of an isochronous transfer of `packets` packets
+    /// of `packet_len` bytes, with the data of OUT pipes.
+    pub(crate) fn submit_isochronous(&self, pipe_index: u8, packet_len: usize, packets: usize, data: &[u8])
+        -> Pending
+    {
+        let transfer = match self.transfer(pipe_index, USBPCAP_TRANSFER_ISOCHRONOUS) {
+            Some(transfer) => transfer,
+            None => return Pending(None),
+        };
+        let packets: Vec<_> = (0..packets)
+            .map(|i| (i * packet_len, packet_len, USBD_STATUS_SUCCESS))
+            .collect();
+        transfer.write_status(false, &isochronous_header(&packets), USBD_STATUS_SUCCESS, data);
+        Pending(Some(transfer))
+    }
+
+    /// Record the setup stage of a control transfer, and the data stage of
+    /// an OUT request.
+    pub(crate) fn submit_control(&self, request: &ControlRequest, len: usize, data: &[u8]) -> Pending {
+        let transfer = match self.transfer(request.request_type_bits() & 0x80, USBPCAP_TRANSFER_CONTROL) {
+            Some(transfer) => transfer,
+            None => return Pending(None),
+        };
+        let mut setup = vec![request.request_type_bits(), request.request];
+        setup.extend_from_slice(&request.value.to_le_bytes());
+        setup.extend_from_slice(&request.index.to_le_bytes());
+        setup.extend_from_slice(&(len as u16).to_le_bytes());
+        transfer.write(false, Some(USBPCAP_CONTROL_STAGE_SETUP), &setup);
+        if !data.is_empty() {
+            transfer.write(false, Some(USBPCAP_CONTROL_STAGE_DATA), data);
+        }
+        Pending(Some(transfer))
+    }
+}
+
+/// A traced transfer waiting for completion; does nothing if the handle was
+/// not traced when the transfer was submitted.
+///
+/// Dropping it records the transfer as canceled, like a dropped pipe future.
+#[derive(Debug)]
+pub(crate) struct Pending(Option<Transfer>);
+
+impl Pending {
+    /// Record the completion of the transfer, with the data of IN pipes.
+    pub(crate) fn complete(mut self, ans: core::result::Result<&[u8], &Error>) {
+        if let Some(transfer) = self.0.take() {
+            match ans {
+                Ok(data) => transfer.write_completion(USBD_STATUS_SUCCESS, data),
+                Err(e) => transfer.write_completion(usbd_status(e), &[]),
+            }
+        }
+    }
+
+    /// Record the completion of an isochronous transfer, with the data of IN
+    /// pipes; packets keep their place in `data`.
+    pub(crate) fn complete_isochronous(mut self, ans: core::result::Result<&[IsoPacket], &Error>, data: &[u8]) {
+        if let Some(transfer) = self.0.take() {
+            match ans {
+                Ok(packets) => {
+                    let packets: Vec<_> = packets.iter().map(|packet| {
+                        let status = packet.status.as_ref().err().map_or(USBD_STATUS_SUCCESS, usbd_status);
+                        (packet.offset, packet.length, status)
+                    }).collect();
+                    transfer.write_status(true, &isochronous_header(&packets), USBD_STATUS_SUCCESS, data)
+                },
+                Err(e) => transfer.write_completion(usbd_status(e), &[]),
+            }
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(transfer) = self.0.take() {
+            transfer.write_completion(USBD_STATUS_CANCELED, &[]);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Transfer {
+    attached: Attached,
+    irp_id: u64,
+    endpoint: u8,
+    transfer_type: u8,
+}
+
+impl Transfer {
+    fn write(&self, completion: bool, stage: Option<u8>, data: &[u8]) {
+        self.write_status(completion, stage.as_slice(), USBD_STATUS_SUCCESS, data)
+    }
+
+    fn write_completion(&self, status: u32, data: &[u8]) {
+        let stage = if self.transfer_type == USBPCAP_TRANSFER_CONTROL {
+            Some(USBPCAP_CONTROL_STAGE_COMPLETE)
+        } else {
+            None
+        };
+        self.write_status(true, stage.as_slice(), status, data)
+    }
+
+    // `extra` follows the common header: the stage of control transfers, the
+    // packets of isochronous ones
+    fn write_status(&self, completion: bool, extra: &[u8], status: u32, data: &[u8]) {
+        let header_len = USBPCAP_HEADER_LEN + extra.len() as u16;
+        let function = match self.transfer_type {
+            USBPCAP_TRANSFER_CONTROL => URB_FUNCTION_CONTROL_TRANSFER,
+            USBPCAP_TRANSFER_ISOCHRONOUS => URB_FUNCTION_ISOCH_TRANSFER,
+            _ => URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER,
+        };
+        let mut packet = Vec::with_capacity(header_len as usize + data.len());
+        packet.extend_from_slice(&header_len.to_le_bytes());
+        packet.extend_from_slice(&self.irp_id.to_le_bytes());
+        packet.extend_from_slice(&status.to_le_bytes());
+        packet.extend_from_slice(&function.to_le_bytes());
+        packet.push(if completion { USBPCAP_INFO_PDO_TO_FDO } else { 0 });
+        packet.extend_from_slice(&self.attached.bus.to_le_bytes());
+        packet.extend_from_slice(&self.attached.device.to_le_bytes());
+        packet.push(self.endpoint);
+        packet.push(self.transfer_type);
+        packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(extra);
+        packet.extend_from_slice(data);
+        self.attached.tracer.write_packet(&packet);
+    }
+}
+
+/// `USBPCAP_BUFFER_ISOCH_HEADER` after the common header, from the offset,
+/// length and status of each packet.
+fn isochronous_header(packets: &[(usize, usize, u32)]) -> Vec<u8> {
+    let errors = packets.iter().filter(|&&(_, _, status)| status != USBD_STATUS_SUCCESS).count();
+    let mut header = Vec::with_capacity(12 + 12 * packets.len());
+    header.extend_from_slice(&0u32.to_le_bytes()); // start frame
+    header.extend_from_slice(&(packets.len() as u32).to_le_bytes());
+    header.extend_from_slice(&(errors as u32).to_le_bytes());
+    for &(offset, length, status) in packets {
+        header.extend_from_slice(&(offset as u32).to_le_bytes());
+        header.extend_from_slice(&(length as u32).to_le_bytes());
+        header.extend_from_slice(&status.to_le_bytes());
+    }
+    header
+}
+
+fn usbd_status(error: &Error) -> u32 {
+    match error {
+        Error::Stall => USBD_STATUS_STALL_PID,
+        Error::Timeout => USBD_STATUS_TIMEOUT,
+        Error::Disconnected => USBD_STATUS_DEVICE_GONE,
+        Error::Overflow => USBD_STATUS_DATA_OVERRUN,
+        Error::Pipe => USBD_STATUS_DEV_NOT_RESPONDING,
+        Error::Other(e) if e.kind() == io::ErrorKind::ConnectionAborted => USBD_STATUS_CANCELED,
+        _ => USBD_STATUS_REQUEST_FAILED,
+    }
+}
@@ -0,0 +1,115 @@
+//! Notifications of devices being plugged in and unplugged.
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use crate::{sys, Device, DeviceFilter, DeviceId, Result};
+
+/// A change of the devices connected to the system.
+#[derive(Debug, Clone)]
+pub enum Event<'device> {
+    /// A device was plugged in, or was present when watching started
+    Arrived(Device<'device>),
+    /// A device reported as arrived before was unplugged
+    Left(DeviceId),
+}
+
+/// Watch devices being plugged in and unplugged.
+///
+/// The operating system only tells that something changed; the watcher then
+/// lists the devices again and reports the difference to the previous list.
+/// Devices already connected when watching starts are reported as arrived
+/// first, so no device slips through between listing and watching.
+///
+/// ```no_run
+/// use nihao_usb::{watch::Event, DeviceFilter};
+/// let mut watcher = DeviceFilter::new().vendor_id(0x0483).watch()?;
+/// loop {
+///     match watcher.next_event()? {
+///         Event::Arrived(device) => println!("arrived: {:?}", device.info()),
+///         Event::Left(id) => println!("left: {:?}", id),
+///     }
+/// }
+/// # Ok::<(), nihao_usb::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Watcher<'w> {
+    monitor: sys::Monitor,
+    filters: Vec<DeviceFilter>,
+    known: HashSet<DeviceId>,
+    events: VecDeque<Event<'w>>,
+}
+
+impl<'w> Watcher<'w> {
+    /// Watch all devices.
+    pub fn new() -> Result<Watcher<'w>> {
+        Watcher::with_filters(Vec::new())
+    }
+
+    /// Watch devices matching every filter in `filters`.
+    pub fn with_filters(filters: Vec<DeviceFilter>) -> Result<Watcher<'w>> {
+        // subscribe before listing, so no change between both is lost
+        let monitor = sys::Monitor::new()?;
+        let mut ans = Watcher { monitor, filters, known: HashSet::new(), events: VecDeque::new() };
+        ans.rescan()?;
+        Ok(ans)
+    }
+
+    /// Wait for the next event.
+    pub fn next_event(&mut self) -> Result<Event<'w>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event)
+            }
+            if self.monitor.wait(None)? {
+                self.rescan()?;
+            }
+        }
+    }
+
+    /// Wait at most `timeout` for the next event; a zero `timeout` only takes
+    /// changes already signalled into account.
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<Event<'w>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event))
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !self.monitor.wait(Some(remaining))? {
+                return Ok(None)
+            }
+            self.rescan()?;
+        }
+    }
+
+    fn rescan(&mut self) -> Result<()> {
+        let mut list = crate::devices()?;
+        for filter in &self.filters {
+            list = list.filter(filter.clone());
+        }
+        let mut present = HashSet::new();
+        let mut arrived = Vec::new();
+        for device in list {
+            // a device may vanish while being listed; it is gone either way
+            let device = match device {
+                Ok(device) => device,
+                Err(_) => continue,
+            };
+            let id = device.id();
+            if !self.known.contains(&id) {
+                arrived.push(device);
+            }
+            present.insert(id);
+        }
+        // a board swapped in the same port leaves before its successor arrives
+        let left: Vec<DeviceId> = self.known.difference(&present).cloned().collect();
+        for id in left {
+            self.known.remove(&id);
+            self.events.push_back(Event::Left(id));
+        }
+        for device in arrived {
+            self.known.insert(device.id());
+            self.events.push_back(Event::Arrived(device));
+        }
+        Ok(())
+    }
+}
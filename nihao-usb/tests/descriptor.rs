@@ -0,0 +1,48 @@
+use nihao_usb::{ConfigDescriptor, Direction, TransferType};
+
+// ST-Link V2-1 style: debug interface plus a CDC pair bound by an IAD
+const CONFIG: &[u8] = &[
+    0x09, 0x02, 0x54, 0x00, 0x03, 0x01, 0x00, 0x80, 0x32,
+    // interface 0: vendor specific, two bulk endpoints
+    0x09, 0x04, 0x00, 0x00, 0x02, 0xFF, 0xFF, 0xFF, 0x04,
+    0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00,
+    0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00,
+    // interface association descriptor
+    0x08, 0x0B, 0x01, 0x02, 0x02, 0x02, 0x01, 0x00,
+    // interface 1: CDC control with a header functional descriptor
+    0x09, 0x04, 0x01, 0x00, 0x01, 0x02, 0x02, 0x01, 0x00,
+    0x05, 0x24, 0x00, 0x10, 0x01,
+    0x07, 0x05, 0x84, 0x03, 0x02, 0x00, 0xFF,
+    // interface 2: CDC data
+    0x09, 0x04, 0x02, 0x00, 0x02, 0x0A, 0x00, 0x00, 0x00,
+    0x07, 0x05, 0x05, 0x02, 0x40, 0x00, 0x00,
+    0x07, 0x05, 0x85, 0x02, 0x40, 0x00, 0x00,
+];
+
+#[test]
+fn parse_composite_config() {
+    let config = ConfigDescriptor::parse(CONFIG).unwrap();
+    assert_eq!(config.total_length as usize, CONFIG.len());
+    assert_eq!(config.interfaces.len(), 3);
+    let debug = &config.interfaces[0].alt_settings[0];
+    assert_eq!(debug.descriptor.interface_class, 0xFF);
+    assert_eq!(debug.endpoints[0].endpoint_address, 0x81);
+    assert_eq!(debug.endpoints[0].direction(), Direction::In);
+    assert_eq!(debug.endpoints[1].direction(), Direction::Out);
+    assert_eq!(debug.endpoints[1].transfer_type(), TransferType::Bulk);
+    // the association descriptor follows the last endpoint of interface 0
+    assert_eq!(debug.endpoints[1].extra, &CONFIG[32..40]);
+    let control = &config.interfaces[1].alt_settings[0];
+    assert_eq!(control.extra, &[0x05, 0x24, 0x00, 0x10, 0x01]);
+    assert_eq!(control.endpoints[0].transfer_type(), TransferType::Interrupt);
+    assert_eq!(control.endpoints[0].interval, 0xFF);
+    assert_eq!(config.endpoints().count(), 5);
+}
+
+#[test]
+fn reject_broken_config() {
+    assert!(ConfigDescriptor::parse(&CONFIG[..20]).is_err());
+    let mut zero_length = CONFIG.to_vec();
+    zero_length[9] = 0;
+    assert!(ConfigDescriptor::parse(&zero_length).is_err());
+}
@@ -0,0 +1,39 @@
+use nihao_usb::Error;
+use std::io;
+
+#[test]
+fn classify_io_errors() {
+    let cases = [
+        (io::ErrorKind::NotFound, "NotFound"),
+        (io::ErrorKind::NotConnected, "Disconnected"),
+        (io::ErrorKind::ResourceBusy, "Busy"),
+        (io::ErrorKind::PermissionDenied, "AccessDenied"),
+        (io::ErrorKind::TimedOut, "Timeout"),
+        (io::ErrorKind::BrokenPipe, "Stall"),
+    ];
+    for (kind, variant) in cases {
+        let err = Error::from(io::Error::new(kind, "from a backend"));
+        assert_eq!(format!("{:?}", err), variant);
+        // and back, keeping the kind
+        assert_eq!(io::Error::from(err).kind(), kind);
+    }
+    let err = Error::from(io::Error::new(io::ErrorKind::InvalidInput, "interface not claimed"));
+    assert_eq!(err.to_string(), "interface not claimed");
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidInput);
+    // variants without a kind of their own survive a round trip
+    assert!(matches!(Error::from(io::Error::from(Error::Overflow)), Error::Overflow));
+    assert!(matches!(Error::from(io::Error::from(Error::Pipe)), Error::Pipe));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn classify_usbfs_errno() {
+    // what usbfs returns for an unplugged device, a babbling one and a CRC error
+    assert!(matches!(Error::from(io::Error::from_raw_os_error(19)), Error::Disconnected));
+    assert!(matches!(Error::from(io::Error::from_raw_os_error(75)), Error::Overflow));
+    assert!(matches!(Error::from(io::Error::from_raw_os_error(84)), Error::Pipe));
+    assert!(matches!(Error::from(io::Error::from_raw_os_error(13)), Error::AccessDenied));
+    let err = Error::from(io::Error::from_raw_os_error(28));
+    assert!(matches!(err, Error::Os(28)));
+    assert_eq!(io::Error::from(err).raw_os_error(), Some(28));
+}
@@ -0,0 +1,172 @@
+#![cfg(target_os = "linux")]
+
+use nihao_usb::sys::linux::{sysfs::ListOptions, DeviceList};
+use nihao_usb::sys::linux::kernel_driver::{DriverIoctl, KernelDrivers};
+use nihao_usb::Location;
+use std::{fs, io, os::unix::fs::symlink, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+const STLINK_V2_DESCRIPTOR: [u8; 18] = [
+    0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40,
+    0x83, 0x04, 0x48, 0x37, 0x00, 0x01, 0x01, 0x02, 0x03, 0x01,
+];
+
+fn fake_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir()
+        .join(format!("nihao-usb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+fn add_device(root: &Path, name: &str, busnum: u8, devnum: u8, descriptors: &[u8]) {
+    let dir = root.join(name);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("busnum"), format!("{}\n", busnum)).unwrap();
+    fs::write(dir.join("devnum"), format!("{}\n", devnum)).unwrap();
+    fs::write(dir.join("descriptors"), descriptors).unwrap();
+}
+
+#[test]
+fn enumerate_fake_tree() -> io::Result<()> {
+    let root = fake_root("enumerate");
+    add_device(&root, "usb1", 1, 1, &[]);
+    add_device(&root, "1-3.2", 1, 5, &STLINK_V2_DESCRIPTOR);
+    fs::write(root.join("1-3.2").join("serial"), "0669FF49\n")?;
+    // interfaces and half-removed devices must be skipped
+    fs::create_dir_all(root.join("1-3.2:1.0"))?;
+    fs::create_dir_all(root.join("1-4"))?;
+
+    let list = ListOptions::new()
+        .sysfs_root(&root)
+        .devfs_root("/dev/fake-usb")
+        .list()?;
+    assert_eq!(list.len(), 2);
+    let infos: Vec<_> = list.iter().collect();
+    assert_eq!(infos[0].sysfs_path(), root.join("1-3.2"));
+    assert_eq!(infos[0].bus_number(), 1);
+    assert_eq!(infos[0].device_address(), 5);
+    assert_eq!(infos[0].dev_path(), Path::new("/dev/fake-usb/001/005"));
+    assert_eq!(infos[0].descriptors()?, STLINK_V2_DESCRIPTOR);
+    assert_eq!(infos[1].dev_path(), Path::new("/dev/fake-usb/001/001"));
+
+    let devices = DeviceList::from(list);
+    assert_eq!(devices.iter().count(), 2);
+    // identifiers come from sysfs alone
+    let info = devices.iter().next().unwrap()?.device_info();
+    assert_eq!((info.vendor_id, info.product_id), (Some(0x0483), Some(0x3748)));
+    assert_eq!(info.serial_number.as_deref(), Some("0669FF49"));
+    assert_eq!(info.port_path.as_deref(), Some("1-3.2"));
+    let locations = devices.iter()
+        .map(|device| device?.location())
+        .collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(locations, [Location::new(1, &[3, 2]), Location::new(1, &[])]);
+    for device in devices {
+        // no such device node, opening must fail instead of panicking
+        assert!(device?.open().is_err());
+    }
+    fs::remove_dir_all(&root)
+}
+
+#[test]
+fn active_alternate_setting() -> io::Result<()> {
+    let root = fake_root("alternate");
+    add_device(&root, "1-3.2", 1, 5, &STLINK_V2_DESCRIPTOR);
+    add_device(&root, "1-4", 1, 6, &STLINK_V2_DESCRIPTOR);
+    fs::write(root.join("1-3.2").join("bConfigurationValue"), "1\n")?;
+    fs::write(root.join("1-4").join("bConfigurationValue"), "\n")?;
+    // the kernel pads alternate settings to two characters
+    fs::create_dir_all(root.join("1-3.2:1.1"))?;
+    fs::write(root.join("1-3.2:1.1").join("bAlternateSetting"), " 1\n")?;
+
+    let list = ListOptions::new().sysfs_root(&root).list()?;
+    let infos: Vec<_> = list.iter().collect();
+    assert_eq!(infos[0].configuration_value()?, Some(1));
+    assert_eq!(infos[0].alternate_setting(1, 1)?, 1);
+    assert_eq!(infos[0].alternate_setting(1, 0).unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(infos[1].configuration_value()?, None);
+    fs::remove_dir_all(&root)
+}
+
+#[test]
+fn missing_root_is_empty() -> io::Result<()> {
+    let root = fake_root("missing").join("absent");
+    let list = ListOptions::new().sysfs_root(&root).list()?;
+    assert!(list.is_empty());
+    Ok(())
+}
+
+/// Binds and unbinds drivers in a fake sysfs tree, like the kernel would.
+struct FakeIoctl {
+    device_path: PathBuf,
+    permitted: bool,
+    calls: Mutex<Vec<(&'static str, u8)>>,
+}
+
+impl FakeIoctl {
+    fn driver_link(&self, interface_number: u8) -> PathBuf {
+        self.device_path.with_file_name(format!("1-3.2:1.{}", interface_number)).join("driver")
+    }
+}
+
+impl DriverIoctl for FakeIoctl {
+    fn disconnect(&self, interface_number: u8) -> io::Result<()> {
+        if !self.permitted {
+            return Err(io::ErrorKind::PermissionDenied.into())
+        }
+        self.calls.lock().unwrap().push(("disconnect", interface_number));
+        fs::remove_file(self.driver_link(interface_number))
+    }
+
+    fn connect(&self, interface_number: u8) -> io::Result<()> {
+        self.calls.lock().unwrap().push(("connect", interface_number));
+        symlink("../../drivers/cdc_acm", self.driver_link(interface_number))
+    }
+}
+
+fn add_interface(root: &Path, name: &str, driver: Option<&str>) {
+    let dir = root.join(name);
+    fs::create_dir_all(&dir).unwrap();
+    if let Some(driver) = driver {
+        symlink(format!("../../drivers/{}", driver), dir.join("driver")).unwrap();
+    }
+}
+
+#[test]
+fn kernel_drivers() -> io::Result<()> {
+    let root = fake_root("drivers");
+    add_device(&root, "1-3.2", 1, 5, &STLINK_V2_DESCRIPTOR);
+    fs::write(root.join("1-3.2").join("bConfigurationValue"), "1\n")?;
+    // debug interface claimed through usbfs, mass storage and virtual COM port
+    add_interface(&root, "1-3.2:1.0", Some("usbfs"));
+    add_interface(&root, "1-3.2:1.1", Some("usb-storage"));
+    add_interface(&root, "1-3.2:1.2", Some("cdc_acm"));
+    add_interface(&root, "1-3.2:1.3", None);
+    let ioctl = Arc::new(FakeIoctl { device_path: root.join("1-3.2"), permitted: true, calls: Mutex::new(Vec::new()) });
+    let drivers = KernelDrivers::new(ioctl.clone(), root.join("1-3.2"));
+
+    assert_eq!(drivers.kernel_driver(1)?.as_deref(), Some("usb-storage"));
+    assert!(!drivers.is_active(0)?);
+    assert!(!drivers.is_active(3)?);
+    assert_eq!(drivers.is_active(4).unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(drivers.detach(3).unwrap_err().kind(), io::ErrorKind::NotFound);
+    drivers.detach(1)?;
+    assert!(!drivers.is_active(1)?);
+
+    // only drivers detached automatically are attached again
+    drivers.before_claim(2)?;
+    assert!(drivers.is_active(2)?);
+    drivers.set_auto_detach(true);
+    drivers.before_claim(2)?;
+    drivers.before_claim(3)?;
+    assert!(!drivers.is_active(2)?);
+    drivers.after_release(1)?;
+    drivers.after_release(2)?;
+    assert!(drivers.is_active(2)?);
+    assert_eq!(*ioctl.calls.lock().unwrap(), [("disconnect", 1), ("disconnect", 2), ("connect", 2)]);
+
+    let denied = FakeIoctl { device_path: root.join("1-3.2"), permitted: false, calls: Mutex::new(Vec::new()) };
+    let err = KernelDrivers::new(denied, root.join("1-3.2")).detach(2).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("cdc_acm"));
+    fs::remove_dir_all(&root)
+}
@@ -0,0 +1,18 @@
+use nihao_usb::{Error, Location};
+use std::io;
+
+#[test]
+fn parse_and_format() -> io::Result<()> {
+    let location: Location = "1-3.2".parse()?;
+    assert_eq!(location.bus_number(), 1);
+    assert_eq!(location.port_numbers(), [3, 2]);
+    assert_eq!(location.to_string(), "1-3.2");
+    let root: Location = "usb2".parse()?;
+    assert_eq!(root, Location::new(2, &[]));
+    assert_eq!(root.to_string(), "usb2");
+    for invalid in ["", "1", "1-", "1-3.", "x-3", "1-3:1.0", "usb"] {
+        let err = invalid.parse::<Location>().unwrap_err();
+        assert!(matches!(err, Error::Other(ref e) if e.kind() == io::ErrorKind::InvalidInput));
+    }
+    Ok(())
+}
@@ -0,0 +1,652 @@
+use nihao_usb::sys::mock::{self, Responder, VirtualDevice};
+use nihao_usb::{ControlRequest, DeviceDescriptor, DeviceFilter, Direction, Error, Event, Location, PipeInfo, PipePolicy, Recipient, Speed, TransferType};
+use std::{future::Future, io, pin::Pin, sync::Arc, thread, time::{Duration, Instant}};
+use std::task::{Context, Poll, Wake};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn descriptor(id_product: u16) -> DeviceDescriptor {
+    DeviceDescriptor {
+        length: 18,
+        descriptor_type: 1,
+        bcd_usb: 0x0200,
+        device_class: 0,
+        device_sub_class: 0,
+        device_protocol: 0,
+        max_packet_size_0: 64,
+        id_vendor: 0x0451,
+        id_product,
+        bcd_device: 0x0100,
+        manufacturer: 0,
+        product: 0,
+        serial_number: 0,
+        num_configurations: 1,
+    }
+}
+
+/// Builds a configuration descriptor, one interface or alternate setting at
+/// a time.
+#[derive(Default)]
+struct Config {
+    num_interfaces: u8,
+    body: Vec<u8>,
+}
+
+impl Config {
+    /// Add alternate setting `alternate_setting` of an interface of `class`,
+    /// with an endpoint for each of `pipes`.
+    fn interface(mut self, interface_number: u8, alternate_setting: u8, class: u8, pipes: &[PipeInfo]) -> Config {
+        if alternate_setting == 0 {
+            self.num_interfaces += 1;
+        }
+        self.body.extend_from_slice(&[9, 4, interface_number, alternate_setting, pipes.len() as u8, class, 0, 0, 0]);
+        for pipe in pipes {
+            let [lo, hi] = pipe.max_packet_size.to_le_bytes();
+            // transfer types are numbered as in `bmAttributes`
+            self.body.extend_from_slice(&[7, 5, pipe.endpoint_address, pipe.transfer_type as u8, lo, hi, pipe.interval]);
+        }
+        self
+    }
+
+    fn build(self) -> Vec<u8> {
+        let [lo, hi] = (9 + self.body.len() as u16).to_le_bytes();
+        let mut raw = vec![9, 2, lo, hi, self.num_interfaces, 1, 0, 0x80, 0x32];
+        raw.extend(self.body);
+        raw
+    }
+}
+
+fn pipe(endpoint_address: u8, transfer_type: TransferType, max_packet_size: u16, interval: u8) -> PipeInfo {
+    PipeInfo { endpoint_address, transfer_type, max_packet_size, interval }
+}
+
+/// Interface 0 with a bulk pipe each way.
+fn bulk_config() -> Config {
+    Config::default().interface(0, 0, 0xFF, &[pipe(0x01, TransferType::Bulk, 64, 0), pipe(0x81, TransferType::Bulk, 64, 0)])
+}
+
+/// Plug in a device answering through `responder`, and open it.
+fn open(responder: impl Responder + 'static, config: Config) -> nihao_usb::Result<nihao_usb::Handle<'static>> {
+    mock::register(VirtualDevice::new(descriptor(0xBEF3), responder).config_descriptor(config.build()));
+    nihao_usb::devices()?.iter().next().expect("one device")?.open()
+}
+
+/// Echoes bulk data back, keeps one vendor register, and records alternate
+/// settings selected by the host.
+#[derive(Default)]
+struct Echo {
+    data: Vec<u8>,
+    register: u16,
+    selected: Arc<std::sync::Mutex<Vec<(u8, u8)>>>,
+}
+
+impl Responder for Echo {
+    fn write_pipe(&mut self, _pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn read_pipe(&mut self, _pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data.drain(..len);
+        Ok(len)
+    }
+
+    fn control_in(&mut self, request: &ControlRequest, buf: &mut [u8]) -> io::Result<usize> {
+        match request.request {
+            0x01 => { buf[..2].copy_from_slice(&self.register.to_le_bytes()); Ok(2) },
+            _ => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn control_out(&mut self, request: &ControlRequest, buf: &[u8]) -> io::Result<usize> {
+        self.register = request.value;
+        Ok(buf.len())
+    }
+
+    fn set_alternate_setting(&mut self, interface_number: u8, alternate_setting: u8) -> io::Result<()> {
+        self.selected.lock().unwrap().push((interface_number, alternate_setting));
+        Ok(())
+    }
+}
+
+#[test]
+fn control_transfers() -> io::Result<()> {
+    let handle = open(Echo::default(), bulk_config())?;
+    let set = ControlRequest::vendor_out(Recipient::Device, 0x02, 0x1234, 0);
+    assert_eq!(handle.control_out(set, &[], TIMEOUT)?, 0);
+    let get = ControlRequest::vendor_in(Recipient::Device, 0x01, 0, 0);
+    let mut buf = [0u8; 2];
+    assert_eq!(handle.control_in(get, &mut buf, TIMEOUT)?, 2);
+    assert_eq!(u16::from_le_bytes(buf), 0x1234);
+    // unknown requests stall, requests in the wrong direction are rejected
+    let unknown = ControlRequest::vendor_in(Recipient::Device, 0x7F, 0, 0);
+    assert!(matches!(handle.control_in(unknown, &mut buf, TIMEOUT).unwrap_err(), Error::Stall));
+    assert!(matches!(handle.control_out(get, &[], TIMEOUT).unwrap_err(), Error::Other(ref e) if e.kind() == io::ErrorKind::InvalidInput));
+    // standard descriptor requests are answered by the mock itself
+    let mut raw = [0u8; 18];
+    let desc = ControlRequest::get_descriptor(0x01, 0, 0);
+    assert_eq!(handle.control_in(desc, &mut raw, TIMEOUT)?, 18);
+    assert_eq!(DeviceDescriptor::parse(&raw)?, handle.device_descriptor()?);
+    Ok(())
+}
+
+#[test]
+fn unplugged_device_fails() -> io::Result<()> {
+    let number = mock::register(VirtualDevice::new(descriptor(0xBEF3), Echo::default()));
+    let handle = nihao_usb::devices()?.iter().next().expect("one device")?.open()?;
+    assert_eq!(handle.write_pipe(0x01, &[1, 2, 3])?, 3);
+    assert!(mock::unregister(number));
+    assert!(handle.write_pipe(0x01, &[1, 2, 3]).is_err());
+    assert!(nihao_usb::devices()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn filter_without_opening() -> io::Result<()> {
+    let mut with_serial = descriptor(0xBEF3);
+    with_serial.serial_number = 3;
+    mock::register(VirtualDevice::new(with_serial, Echo::default())
+        .string(3, "0123456789AB")
+        .port_path("2-1.4")
+        .config_descriptor(Config::default().interface(0, 0, 0xFF, &[]).build()));
+    mock::register(VirtualDevice::new(descriptor(0xBEF4), Echo::default())
+        .config_descriptor(Config::default().interface(0, 0, 0x02, &[]).build()));
+    mock::register(VirtualDevice::new(descriptor(0xBEF5), Echo::default()));
+
+    let count = |filter: DeviceFilter| filter.list().map(|list| list.len());
+    assert_eq!(count(DeviceFilter::new())?, 3);
+    assert_eq!(count(DeviceFilter::new().vendor_id(0x0451))?, 3);
+    assert_eq!(count(DeviceFilter::new().vendor_id(0x0483))?, 0);
+    assert_eq!(count(DeviceFilter::new().product_ids(&[0xBEF3, 0xBEF5]))?, 2);
+    assert_eq!(count(DeviceFilter::new().serial_number("0123456789AB"))?, 1);
+    assert_eq!(count(DeviceFilter::new().port_path("2-1.4"))?, 1);
+    assert_eq!(count(DeviceFilter::new().interface_class(0x02))?, 1);
+    // every criterion must hold
+    assert_eq!(count(DeviceFilter::new().product_id(0xBEF4).interface_class(0xFF))?, 0);
+
+    let list = nihao_usb::devices()?.filter_vid_pid(0x0451, 0xBEF4);
+    let info = list.iter().next().expect("one device")?.info();
+    assert_eq!(info.product_id, Some(0xBEF4));
+    assert_eq!(info.interface_classes, [0x02]);
+    assert_eq!(info.serial_number, None);
+    assert_eq!(list.into_iter().count(), 1);
+    Ok(())
+}
+
+/// A device that accepts nothing and never answers, like a wedged firmware.
+fn wedged(_: mock::Transfer) -> io::Result<usize> {
+    Err(io::ErrorKind::WouldBlock.into())
+}
+
+#[test]
+fn transfers_time_out() -> io::Result<()> {
+    let handle = open(wedged, bulk_config())?;
+    let mut buf = [0u8; 64];
+    let err = handle.read_pipe_timeout(0x81, &mut buf, Duration::from_millis(20)).unwrap_err();
+    assert!(matches!(err, Error::Timeout));
+    let err = handle.write_pipe_timeout(0x01, &buf, Duration::from_millis(20)).unwrap_err();
+    assert!(matches!(err, Error::Timeout));
+    // the per-pipe policy applies to plain reads, other pipes are unaffected
+    handle.set_timeout(0x81, Duration::from_millis(20))?;
+    let err = handle.read_pipe(0x81, &mut buf).unwrap_err();
+    assert!(matches!(err, Error::Timeout));
+    Ok(())
+}
+
+#[test]
+fn abort_pending_transfer() -> nihao_usb::Result<()> {
+    let handle = open(wedged, bulk_config())?;
+    std::thread::scope(|s| {
+        let reader = s.spawn(|| {
+            let mut buf = [0u8; 64];
+            handle.read_pipe(0x81, &mut buf)
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        handle.abort_pipe(0x81)?;
+        let err = reader.join().unwrap().unwrap_err();
+        assert!(matches!(err, Error::Other(ref e) if e.kind() == io::ErrorKind::ConnectionAborted));
+        handle.reset_pipe(0x81)
+    })
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+/// Poll all futures on this thread until every one is ready.
+fn block_on_all<F: Future + Unpin>(mut futures: Vec<F>) -> Vec<F::Output> {
+    let waker = Arc::new(ThreadWaker(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut ans: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    while ans.iter().any(Option::is_none) {
+        for (future, slot) in futures.iter_mut().zip(ans.iter_mut()) {
+            if slot.is_none() {
+                if let Poll::Ready(out) = Pin::new(future).poll(&mut cx) {
+                    *slot = Some(out);
+                }
+            }
+        }
+        thread::park_timeout(Duration::from_millis(100));
+    }
+    ans.into_iter().map(Option::unwrap).collect()
+}
+
+#[test]
+fn async_transfers() -> io::Result<()> {
+    let handle = open(Echo::default(), bulk_config())?;
+    let written = block_on_all(vec![
+        handle.write_pipe_async(0x01, vec![1, 2, 3]),
+        handle.write_pipe_async(0x01, vec![4, 5]),
+    ]);
+    assert_eq!(written.into_iter().collect::<nihao_usb::Result<Vec<_>>>()?.iter().sum::<usize>(), 5);
+    let read = block_on_all(vec![handle.read_pipe_async(0x81, vec![0u8; 64])]);
+    let mut data = read.into_iter().next().unwrap()?;
+    data.sort();
+    assert_eq!(data, [1, 2, 3, 4, 5]);
+    Ok(())
+}
+
+#[test]
+fn transfer_queue_in_order() -> io::Result<()> {
+    let handle = open(Echo::default(), bulk_config())?;
+    let mut queue = handle.transfer_queue(0x01, 2);
+    let mut written = 0;
+    for i in 0..6u8 {
+        // waits for the oldest write once two are in flight
+        if let Some(done) = queue.push(vec![i; 3])? {
+            written += done.len();
+        }
+        assert!(queue.len() <= queue.depth());
+    }
+    for done in &mut queue {
+        written += done?.len();
+    }
+    assert_eq!(written, 18);
+    // reads come back in the order submitted, each with its part of the data
+    let mut queue = handle.transfer_queue(0x81, 3);
+    for _ in 0..3 {
+        assert!(queue.push(vec![0u8; 4])?.is_none());
+    }
+    assert!(queue.is_full());
+    let mut data = Vec::new();
+    while data.len() < 18 {
+        let mut buf = queue.pop().expect("reads in flight")?;
+        data.extend_from_slice(&buf);
+        buf.resize(4, 0);
+        queue.push(buf)?;
+    }
+    assert_eq!(data, (0..6u8).flat_map(|i| vec![i; 3]).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn async_transfers_time_out_and_cancel() -> io::Result<()> {
+    let handle = open(wedged, bulk_config())?;
+    handle.set_timeout(0x81, Duration::from_millis(20))?;
+    let ans = block_on_all(vec![handle.read_pipe_async(0x81, vec![0u8; 64])]);
+    assert!(matches!(ans[0].as_ref().unwrap_err(), Error::Timeout));
+    // aborting the pipe fails transfers submitted before, even if not polled yet
+    let pending = handle.write_pipe_async(0x01, vec![0u8; 64]);
+    handle.abort_pipe(0x01)?;
+    let ans = block_on_all(vec![pending]);
+    assert!(matches!(ans[0].as_ref().unwrap_err(), Error::Other(ref e) if e.kind() == io::ErrorKind::ConnectionAborted));
+    // dropping a pending future must not hang or panic
+    drop(handle.read_pipe_async(0x82, vec![0u8; 64]));
+    Ok(())
+}
+
+#[test]
+fn watch_plug_and_unplug() -> io::Result<()> {
+    let present = mock::register(VirtualDevice::new(descriptor(0xBEF3), Echo::default()));
+    let mut watcher = nihao_usb::Watcher::new()?;
+    // devices present when watching starts come first
+    let first = match watcher.next_event_timeout(Duration::ZERO)? {
+        Some(Event::Arrived(device)) => device,
+        other => panic!("expected arrival, got {:?}", other),
+    };
+    assert_eq!(first.info().product_id, Some(0xBEF3));
+    assert!(watcher.next_event_timeout(Duration::ZERO)?.is_none());
+
+    let plugged = mock::register(VirtualDevice::new(descriptor(0xBEF4), Echo::default()).port_path("1-3.2"));
+    let second = match watcher.next_event()? {
+        Event::Arrived(device) => device,
+        other => panic!("expected arrival, got {:?}", other),
+    };
+    assert_eq!(second.info().port_path.as_deref(), Some("1-3.2"));
+
+    assert!(mock::unregister(plugged));
+    assert!(mock::unregister(present));
+    let mut left = Vec::new();
+    while let Some(event) = watcher.next_event_timeout(Duration::ZERO)? {
+        match event {
+            Event::Left(id) => left.push(id),
+            other => panic!("expected removal, got {:?}", other),
+        }
+    }
+    assert_eq!(left.len(), 2);
+    assert!(left.contains(&first.id()) && left.contains(&second.id()));
+    Ok(())
+}
+
+#[test]
+fn watch_swap_in_same_port() -> io::Result<()> {
+    let board_a = mock::register(VirtualDevice::new(descriptor(0xBEF3), Echo::default()).port_path("1-4"));
+    let mut watcher = nihao_usb::Watcher::new()?;
+    let first = match watcher.next_event_timeout(Duration::ZERO)? {
+        Some(Event::Arrived(device)) => device,
+        other => panic!("expected arrival, got {:?}", other),
+    };
+    // board A leaves and board B arrives while nobody is watching
+    mock::unregister(board_a);
+    mock::register(VirtualDevice::new(descriptor(0xBEF3), Echo::default()).port_path("1-4"));
+    match watcher.next_event_timeout(TIMEOUT)? {
+        Some(Event::Left(id)) => assert_eq!(id, first.id()),
+        other => panic!("expected removal, got {:?}", other),
+    }
+    match watcher.next_event_timeout(TIMEOUT)? {
+        Some(Event::Arrived(device)) => assert_eq!(device.info().port_path.as_deref(), Some("1-4")),
+        other => panic!("expected arrival, got {:?}", other),
+    }
+    assert!(watcher.next_event_timeout(Duration::ZERO)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn watch_filtered() -> io::Result<()> {
+    let mut watcher = DeviceFilter::new().product_id(0xBEF4).watch()?;
+    mock::register(VirtualDevice::new(descriptor(0xBEF3), Echo::default()));
+    assert!(watcher.next_event_timeout(TIMEOUT)?.is_none());
+    let number = mock::register(VirtualDevice::new(descriptor(0xBEF4), Echo::default()));
+    assert!(matches!(watcher.next_event_timeout(TIMEOUT)?, Some(Event::Arrived(_))));
+    mock::unregister(number);
+    assert!(matches!(watcher.next_event_timeout(TIMEOUT)?, Some(Event::Left(_))));
+    // nothing can change while this thread waits
+    assert!(matches!(watcher.next_event().unwrap_err(), Error::Other(ref e) if e.kind() == io::ErrorKind::WouldBlock));
+    Ok(())
+}
+
+#[test]
+fn reopen_by_location() -> io::Result<()> {
+    mock::register(VirtualDevice::new(descriptor(0xBEF3), Echo::default()).port_path("1-3.1"));
+    let slot = mock::register(VirtualDevice::new(descriptor(0xBEF4), Echo::default()).port_path("1-3.2"));
+    let device = DeviceFilter::new().product_id(0xBEF4).list()?.iter().next().expect("one device")?;
+    let location = device.location()?;
+    assert_eq!(location, Location::new(1, &[3, 2]));
+    let id = device.id();
+
+    // the board in the slot is replaced; the location stays the same
+    mock::unregister(slot);
+    assert!(matches!(Location::new(1, &[3, 2]).open().unwrap_err(), Error::NotFound));
+    mock::register(VirtualDevice::new(descriptor(0xBEF5), Echo::default()).port_path("1-3.2"));
+    let device = location.device()?;
+    assert_eq!(device.info().product_id, Some(0xBEF5));
+    assert_ne!(device.id(), id);
+    location.open()?;
+    assert_eq!(DeviceFilter::new().location(&location).list()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn claim_interfaces() -> nihao_usb::Result<()> {
+    let echo = Echo::default();
+    let selected = echo.selected.clone();
+    // debug interface 0, interface 1 with two alternate settings, bridge interface 2
+    let config = Config::default()
+        .interface(0, 0, 0xFF, &[])
+        .interface(1, 0, 0x0A, &[])
+        .interface(1, 1, 0x0A, &[])
+        .interface(2, 0, 0xFF, &[]);
+    mock::register(VirtualDevice::new(descriptor(0x374E), echo)
+        .config_descriptor(config.build()));
+    let device = nihao_usb::devices()?.iter().next().expect("one device")?;
+    // opening claims the first interface
+    let handle = device.open()?;
+    let other = device.open()?;
+    assert!(matches!(other.claim_interface(0).unwrap_err(), Error::Busy));
+    assert!(matches!(handle.claim_interface(3).unwrap_err(), Error::NotFound));
+
+    assert!(matches!(handle.set_alternate_setting(1, 1).unwrap_err(), Error::Other(ref e) if e.kind() == io::ErrorKind::InvalidInput));
+    handle.claim_interface(1)?;
+    handle.set_alternate_setting(1, 1)?;
+    assert!(matches!(handle.set_alternate_setting(1, 2).unwrap_err(), Error::Other(ref e) if e.kind() == io::ErrorKind::InvalidInput));
+    assert_eq!(*selected.lock().unwrap(), [(1, 1)]);
+
+    // a sibling interface on a handle of its own, released when it drops
+    let bridge = handle.associated_interface(1)?;
+    assert_eq!(bridge.write_pipe(0x03, &[1, 2])?, 2);
+    assert!(matches!(other.claim_interface(2).unwrap_err(), Error::Busy));
+    drop(bridge);
+    other.claim_interface(2)?;
+    assert!(matches!(handle.associated_interface(2).unwrap_err(), Error::NotFound));
+
+    handle.release_interface(1)?;
+    other.claim_interface(1)?;
+    // clones share claims, which are released after the last clone drops
+    let clone = handle.clone();
+    drop(handle);
+    assert!(matches!(other.claim_interface(0).unwrap_err(), Error::Busy));
+    drop(clone);
+    other.claim_interface(0)
+}
+
+#[test]
+fn list_pipes() -> nihao_usb::Result<()> {
+    // an interrupt pipe with one additional transaction in alternate setting 1
+    let config = bulk_config().interface(0, 1, 0xFF, &[pipe(0x82, TransferType::Interrupt, 0x0808, 4)]);
+    let handle = open(Echo::default(), config)?;
+    let pipes: Vec<_> = handle.pipes()?.collect();
+    assert_eq!(pipes.iter().map(|p| p.endpoint_address).collect::<Vec<_>>(), [0x01, 0x81]);
+    assert_eq!(pipes[1].direction(), Direction::In);
+    assert_eq!(pipes[1].transfer_type, TransferType::Bulk);
+    assert_eq!(pipes[1].max_packet_size, 64);
+    // pipes follow the alternate setting; additional transactions are masked
+    handle.set_alternate_setting(0, 1)?;
+    let pipes: Vec<_> = handle.pipes()?.collect();
+    assert_eq!(pipes, [PipeInfo {
+        endpoint_address: 0x82,
+        transfer_type: TransferType::Interrupt,
+        max_packet_size: 8,
+        interval: 4,
+    }]);
+    Ok(())
+}
+
+/// Records the length of each write, and stalls reads until the halt is cleared.
+#[derive(Clone, Default)]
+struct Stalling {
+    writes: Arc<std::sync::Mutex<Vec<usize>>>,
+    stalled: Arc<std::sync::Mutex<bool>>,
+    clears: Arc<std::sync::Mutex<u32>>,
+}
+
+impl Responder for Stalling {
+    fn write_pipe(&mut self, _pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.writes.lock().unwrap().push(buf.len());
+        Ok(buf.len())
+    }
+
+    fn read_pipe(&mut self, _pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stalled = self.stalled.lock().unwrap();
+        if *stalled {
+            return Err(io::ErrorKind::BrokenPipe.into())
+        }
+        *stalled = true;
+        Ok(buf.len())
+    }
+
+    fn clear_halt(&mut self, _pipe_index: u8) -> io::Result<()> {
+        *self.clears.lock().unwrap() += 1;
+        *self.stalled.lock().unwrap() = false;
+        Ok(())
+    }
+}
+
+#[test]
+fn pipe_policies() -> nihao_usb::Result<()> {
+    let device = Stalling::default();
+    let handle = open(device.clone(), bulk_config())?;
+    assert_eq!(handle.pipe_policy(0x81)?, PipePolicy::default());
+    assert!(matches!(handle.set_pipe_policy(0x82, PipePolicy::default()).unwrap_err(), Error::NotFound));
+
+    // a zero-length packet ends writes of a multiple of 64 bytes
+    handle.set_pipe_policy(0x01, PipePolicy { short_packet_terminate: true, ..Default::default() })?;
+    handle.write_pipe(0x01, &[0; 64])?;
+    handle.write_pipe(0x01, &[0; 65])?;
+    assert_eq!(*device.writes.lock().unwrap(), [64, 0, 65]);
+
+    let policy = PipePolicy { raw_io: true, auto_clear_stall: true, ..Default::default() };
+    handle.set_pipe_policy(0x81, policy)?;
+    assert_eq!(handle.pipe_policy(0x81)?, policy);
+    let mut buf = [0u8; 128];
+    assert!(matches!(handle.read_pipe(0x81, &mut buf[..63]).unwrap_err(), Error::Other(ref e) if e.kind() == io::ErrorKind::InvalidInput));
+    assert_eq!(handle.read_pipe(0x81, &mut buf)?, 128);
+    // the stall fails the read and is cleared for the next one
+    assert!(matches!(handle.read_pipe(0x81, &mut buf).unwrap_err(), Error::Stall));
+    assert_eq!(*device.clears.lock().unwrap(), 1);
+    assert_eq!(handle.read_pipe(0x81, &mut buf)?, 128);
+    Ok(())
+}
+
+/// Reports on the interrupt pipe when it has something to say, and counts
+/// isochronous packets, skipping the second one and garbling the third.
+#[derive(Default)]
+struct Sensor {
+    reports: Vec<Vec<u8>>,
+    packets: u8,
+    received: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Responder for Sensor {
+    fn write_pipe(&mut self, _pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn read_pipe(&mut self, _pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let report = self.reports.pop().ok_or(io::ErrorKind::WouldBlock)?;
+        buf[..report.len()].copy_from_slice(&report);
+        Ok(report.len())
+    }
+
+    fn read_iso_packet(&mut self, _pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self.packets += 1;
+        match self.packets {
+            2 => Err(io::ErrorKind::WouldBlock.into()),
+            3 => Err(io::ErrorKind::InvalidData.into()),
+            n => {
+                buf[..4].fill(n);
+                Ok(4)
+            },
+        }
+    }
+
+    fn write_iso_packet(&mut self, _pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.received.lock().unwrap().push(buf.to_vec());
+        Ok(buf.len())
+    }
+}
+
+/// Interface 0 with an interrupt IN pipe polled every 10 frames, and
+/// isochronous pipes in both directions.
+fn sensor_config() -> Config {
+    Config::default().interface(0, 0, 0xFF, &[
+        pipe(0x81, TransferType::Interrupt, 8, 10),
+        pipe(0x82, TransferType::Isochronous, 16, 1),
+        pipe(0x02, TransferType::Isochronous, 16, 1),
+    ])
+}
+
+#[test]
+fn interrupt_transfers() -> nihao_usb::Result<()> {
+    let handle = open(Sensor { reports: vec![vec![0x01, 0x80]], ..Default::default() }, sensor_config())?;
+    let pipe = handle.pipes()?.next().expect("an interrupt pipe");
+    assert_eq!(pipe.polling_interval(Speed::Full), Duration::from_millis(10));
+    assert_eq!(pipe.polling_interval(Speed::High), Duration::from_millis(64));
+    let mut buf = [0u8; 8];
+    assert_eq!(handle.read_interrupt(0x81, &mut buf, TIMEOUT)?, 2);
+    assert_eq!(buf[..2], [0x01, 0x80]);
+    // the device is asked once per interval, so shorter timeouts last that long
+    let start = Instant::now();
+    assert!(matches!(handle.read_interrupt(0x81, &mut buf, Duration::from_millis(1)).unwrap_err(), Error::Timeout));
+    assert!(start.elapsed() >= Duration::from_millis(10));
+    assert!(matches!(handle.read_interrupt(0x82, &mut buf, TIMEOUT).unwrap_err(), Error::NotFound));
+    Ok(())
+}
+
+#[test]
+fn isochronous_transfers() -> nihao_usb::Result<()> {
+    let sensor = Sensor::default();
+    let received = sensor.received.clone();
+    let handle = open(sensor, sensor_config())?;
+    let mut buf = [0u8; 64];
+    let packets = handle.read_isochronous(0x82, &mut buf, 4)?;
+    assert_eq!(packets.iter().map(|p| (p.offset, p.length)).collect::<Vec<_>>(), [(0, 4), (16, 0), (32, 0), (48, 4)]);
+    // a packet lost on the bus fails alone
+    assert!(packets[1].status.is_ok());
+    assert!(matches!(packets[2].status, Err(Error::Other(_))));
+    assert_eq!(buf[48..52], [4; 4]);
+    assert!(matches!(handle.read_isochronous(0x82, &mut buf, 3).unwrap_err(), Error::Other(ref e) if e.kind() == io::ErrorKind::InvalidInput));
+    assert!(matches!(handle.read_isochronous(0x81, &mut buf, 4).unwrap_err(), Error::NotFound));
+
+    let packets = handle.write_isochronous(0x02, &[7; 32], 2)?;
+    assert!(packets.iter().all(|p| p.length == 16 && p.status.is_ok()));
+    assert_eq!(*received.lock().unwrap(), [[7; 16], [7; 16]]);
+    Ok(())
+}
+
+/// Comes back from the first reset only.
+#[derive(Default)]
+struct Resettable {
+    resets: u32,
+}
+
+impl Responder for Resettable {
+    fn write_pipe(&mut self, _pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn read_pipe(&mut self, _pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        buf[0] = self.resets as u8;
+        Ok(1)
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.resets += 1;
+        if self.resets > 1 {
+            return Err(io::ErrorKind::TimedOut.into())
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn reset_device() -> io::Result<()> {
+    let mut with_serial = descriptor(0x374B);
+    with_serial.serial_number = 3;
+    let number = mock::register(VirtualDevice::new(with_serial, Resettable::default())
+        .port_path("2-1")
+        .string(3, "066DFF"));
+    let handle = nihao_usb::devices()?.iter().next().expect("one device")?.open()?;
+    assert_eq!(handle.location()?, Location::new(2, &[1]));
+
+    let fresh = handle.reset_device(TIMEOUT)?;
+    assert!(matches!(handle.speed().unwrap_err(), Error::Disconnected));
+    assert_ne!(fresh, handle);
+    assert_eq!(fresh.location()?, Location::new(2, &[1]));
+    assert_eq!(fresh.serial_number()?.as_deref(), Some("066DFF"));
+    let mut buf = [0u8; 1];
+    fresh.read_pipe(0x81, &mut buf)?;
+    assert_eq!(buf, [1]);
+    assert!(!mock::unregister(number));
+
+    // the device stays away after the second reset
+    let err = fresh.reset_device(TIMEOUT).unwrap_err();
+    assert!(matches!(err, Error::Timeout));
+    assert!(nihao_usb::devices()?.is_empty());
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use nihao_usb::sys::mock::{self, Responder, VirtualDevice};
+use nihao_usb::{ControlRequest, DeviceDescriptor, Error, Recipient, trace::Tracer};
+use std::{io::{self, Write}, sync::{Arc, Mutex}, time::Duration};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Answers reads with a fixed reply, and stalls vendor requests.
+struct Reply;
+
+impl Responder for Reply {
+    fn write_pipe(&mut self, _pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn read_pipe(&mut self, _pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        buf[..2].copy_from_slice(&[0x26, 0x00]);
+        Ok(2)
+    }
+}
+
+fn open_reply() -> nihao_usb::Result<nihao_usb::Handle<'static>> {
+    mock::register(VirtualDevice::new(DeviceDescriptor {
+        length: 18, descriptor_type: 1, bcd_usb: 0x0200,
+        device_class: 0, device_sub_class: 0, device_protocol: 0,
+        max_packet_size_0: 64, id_vendor: 0x0483, id_product: 0x3748,
+        bcd_device: 0x0100, manufacturer: 0, product: 0, serial_number: 0,
+        num_configurations: 1,
+    }, Reply));
+    nihao_usb::devices()?.iter().next().expect("one device")?.open()
+}
+
+/// A capture kept in memory, shared with the tracer writing it.
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+/// Split a pcapng capture into block types and bodies.
+fn blocks(mut capture: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut ans = Vec::new();
+    while !capture.is_empty() {
+        let len = u32_at(capture, 4) as usize;
+        assert_eq!(u32_at(capture, len - 4) as usize, len);
+        ans.push((u32_at(capture, 0), capture[8..len - 4].to_vec()));
+        capture = &capture[len..];
+    }
+    ans
+}
+
+/// USBPcap packet fields: info, endpoint, transfer type, control stage,
+/// status and data.
+#[derive(Debug, PartialEq)]
+struct Packet {
+    info: u8,
+    endpoint: u8,
+    transfer: u8,
+    stage: Option<u8>,
+    status: u32,
+    data: Vec<u8>,
+}
+
+fn packet(block: &[u8]) -> Packet {
+    let captured_len = u32_at(block, 12) as usize;
+    let packet = &block[20..20 + captured_len];
+    let header_len = u16_at(packet, 0) as usize;
+    Packet {
+        info: packet[16],
+        endpoint: packet[21],
+        transfer: packet[22],
+        stage: if header_len == 28 { Some(packet[27]) } else { None },
+        status: u32_at(packet, 10),
+        data: packet[header_len..].to_vec(),
+    }
+}
+
+#[test]
+fn trace_transfers() -> nihao_usb::Result<()> {
+    let handle = open_reply()?;
+    let capture = Capture::default();
+    let tracer = Tracer::new(capture.clone())?;
+    // transfers before tracing and after stopping it are not recorded
+    handle.write_pipe(0x02, &[0xF5])?;
+    handle.set_tracer(Some(tracer.clone()));
+    handle.clone().write_pipe(0x02, &[0xF1, 0x80])?;
+    let mut buf = [0u8; 2];
+    handle.read_pipe_timeout(0x81, &mut buf, TIMEOUT)?;
+    let stalled = ControlRequest::vendor_in(Recipient::Device, 0x01, 0, 0);
+    assert!(matches!(handle.control_in(stalled, &mut buf, TIMEOUT).unwrap_err(), Error::Stall));
+    handle.set_tracer(None);
+    handle.read_pipe(0x81, &mut buf)?;
+    tracer.flush()?;
+
+    let capture = capture.0.lock().unwrap();
+    let blocks = blocks(&capture);
+    assert_eq!(blocks[0].0, 0x0A0D_0D0A);
+    assert_eq!(u32_at(&blocks[0].1, 0), 0x1A2B_3C4D);
+    assert_eq!(blocks[1].0, 1);
+    assert_eq!(u16_at(&blocks[1].1, 0), 249);
+    assert!(blocks[2..].iter().all(|(block_type, _)| *block_type == 6));
+    let packets: Vec<Packet> = blocks[2..].iter().map(|(_, body)| packet(body)).collect();
+    let expected = vec![
+        Packet { info: 0, endpoint: 0x02, transfer: 3, stage: None, status: 0, data: vec![0xF1, 0x80] },
+        Packet { info: 1, endpoint: 0x02, transfer: 3, stage: None, status: 0, data: vec![] },
+        Packet { info: 0, endpoint: 0x81, transfer: 3, stage: None, status: 0, data: vec![] },
+        Packet { info: 1, endpoint: 0x81, transfer: 3, stage: None, status: 0, data: vec![0x26, 0x00] },
+        Packet { info: 0, endpoint: 0x80, transfer: 2, stage: Some(0), status: 0,
+            data: vec![0xC0, 0x01, 0, 0, 0, 0, 2, 0] },
+        Packet { info: 1, endpoint: 0x80, transfer: 2, stage: Some(3), status: 0xC000_0004, data: vec![] },
+    ];
+    assert_eq!(packets, expected);
+    Ok(())
+}
+
+#[test]
+fn trace_dropped_future_as_canceled() -> nihao_usb::Result<()> {
+    let handle = open_reply()?;
+    let capture = Capture::default();
+    handle.set_tracer(Some(Tracer::new(capture.clone())?));
+    drop(handle.read_pipe_async(0x81, vec![0; 64]));
+
+    let capture = capture.0.lock().unwrap();
+    let packets: Vec<Packet> = blocks(&capture)[2..].iter().map(|(_, body)| packet(body)).collect();
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[1].status, 0xC001_0000);
+    Ok(())
+}
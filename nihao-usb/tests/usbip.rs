@@ -0,0 +1,444 @@
+use nihao_usb::sys::mock::{self, Responder, VirtualDevice};
+use nihao_usb::sys::usbip::{self, protocol::{self, Command, ExportedDevice, ExportedInterface, HeaderBasic, OpRequest, Reply}};
+use nihao_usb::{ControlRequest, DeviceDescriptor, Direction, Error, Handle, Location, Recipient, Speed, TransferType};
+use std::{collections::HashMap, future::Future, io, pin::Pin, thread, time::Duration};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn descriptor() -> DeviceDescriptor {
+    DeviceDescriptor {
+        length: 18,
+        descriptor_type: 1,
+        bcd_usb: 0x0200,
+        device_class: 0,
+        device_sub_class: 0,
+        device_protocol: 0,
+        max_packet_size_0: 64,
+        id_vendor: 0x0483,
+        id_product: 0x374B,
+        bcd_device: 0x0100,
+        manufacturer: 0,
+        product: 0,
+        serial_number: 3,
+        num_configurations: 1,
+    }
+}
+
+/// One vendor specific interface with a bulk pipe each way and an interrupt
+/// IN pipe polled every 8 microframes.
+fn config() -> Vec<u8> {
+    vec![
+        9, 2, 39, 0, 1, 1, 0, 0x80, 50,
+        9, 4, 0, 0, 3, 0xFF, 0xFF, 0xFF, 0,
+        7, 5, 0x81, 2, 64, 0, 0,
+        7, 5, 0x01, 2, 64, 0, 0,
+        7, 5, 0x82, 3, 16, 0, 4,
+    ]
+}
+
+/// Queues bulk data until read, NAKing reads while empty, and keeps one
+/// vendor register.
+#[derive(Default)]
+struct Fifo {
+    data: Vec<u8>,
+    register: u16,
+}
+
+impl Responder for Fifo {
+    fn write_pipe(&mut self, _pipe_index: u8, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn read_pipe(&mut self, _pipe_index: u8, buf: &mut [u8]) -> io::Result<usize> {
+        if self.data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+        let len = buf.len().min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data.drain(..len);
+        Ok(len)
+    }
+
+    fn control_in(&mut self, request: &ControlRequest, buf: &mut [u8]) -> io::Result<usize> {
+        match request.request {
+            0x01 => { buf[..2].copy_from_slice(&self.register.to_le_bytes()); Ok(2) },
+            _ => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn control_out(&mut self, request: &ControlRequest, buf: &[u8]) -> io::Result<usize> {
+        self.register = request.value;
+        Ok(buf.len())
+    }
+}
+
+/// Register a `Fifo` at `port_path` and open it through the mock backend.
+fn open_fifo(port_path: &str) -> nihao_usb::Result<Handle<'static>> {
+    mock::register(VirtualDevice::new(descriptor(), Fifo::default())
+        .port_path(port_path)
+        .config_descriptor(config())
+        .speed(Speed::High)
+        .string(3, "0672FF"));
+    port_path.parse::<Location>()?.open()
+}
+
+/// What `usbipd` would list about a device.
+fn exported(handle: &Handle) -> nihao_usb::Result<ExportedDevice> {
+    let device = handle.device_descriptor()?;
+    let config = handle.config_descriptor(0)?;
+    let location = handle.location()?;
+    Ok(ExportedDevice {
+        path: format!("/sys/devices/platform/mock/usb{}/{}", location.bus_number(), location),
+        busid: location.to_string(),
+        busnum: location.bus_number() as u32,
+        devnum: 2,
+        speed: match handle.speed()? { Speed::Low => 1, Speed::Full => 2, Speed::High => 3, Speed::Super => 5, Speed::Unknown => 0 },
+        id_vendor: device.id_vendor,
+        id_product: device.id_product,
+        bcd_device: device.bcd_device,
+        device_class: device.device_class,
+        device_sub_class: device.device_sub_class,
+        device_protocol: device.device_protocol,
+        configuration_value: config.configuration_value,
+        num_configurations: device.num_configurations,
+        num_interfaces: config.num_interfaces,
+        interfaces: config.interfaces.iter().map(|i| {
+            let alt = &i.alt_settings[0].descriptor;
+            ExportedInterface {
+                interface_class: alt.interface_class,
+                interface_subclass: alt.interface_subclass,
+                interface_protocol: alt.interface_protocol,
+            }
+        }).collect(),
+    })
+}
+
+/// The errno of Linux a host reports a failed transfer with.
+fn errno(error: &Error) -> i32 {
+    match error {
+        Error::Stall => -32,
+        Error::Timeout => -110,
+        Error::Disconnected => -19,
+        Error::Other(e) if e.kind() == io::ErrorKind::ConnectionAborted => -104,
+        _ => -71,
+    }
+}
+
+/// Stand-in for `usbipd`, exporting handles of the mock backend on a local
+/// port and running every URB on a thread of its own.
+struct Server {
+    devices: Vec<(ExportedDevice, Handle<'static>)>,
+    imported: Mutex<Vec<String>>,
+    // a misbehaving host answering commands itself instead of the devices
+    answer: Option<fn(Command) -> Option<Reply>>,
+}
+
+fn serve(handles: Vec<Handle<'static>>) -> nihao_usb::Result<String> {
+    serve_with(handles, None)
+}
+
+fn serve_with(handles: Vec<Handle<'static>>, answer: Option<fn(Command) -> Option<Reply>>)
+    -> nihao_usb::Result<String>
+{
+    let devices = handles.into_iter()
+        .map(|handle| Ok((exported(&handle)?, handle)))
+        .collect::<nihao_usb::Result<_>>()?;
+    let server = Arc::new(Server { devices, imported: Mutex::new(Vec::new()), answer });
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let server = server.clone();
+            thread::spawn(move || server.connection(stream));
+        }
+    });
+    Ok(addr)
+}
+
+impl Server {
+    fn connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        match OpRequest::read(&mut stream)? {
+            OpRequest::DevList => {
+                let devices: Vec<ExportedDevice> = self.devices.iter().map(|(d, _)| d.clone()).collect();
+                protocol::write_devlist(&mut stream, &devices)
+            },
+            OpRequest::Import { busid } => {
+                let (device, handle) = match self.devices.iter().find(|(d, _)| d.busid == busid) {
+                    Some(found) => found,
+                    None => return protocol::write_import(&mut stream, Err(protocol::ST_NODEV)),
+                };
+                {
+                    let mut imported = self.imported.lock().unwrap();
+                    if imported.contains(&busid) {
+                        return protocol::write_import(&mut stream, Err(protocol::ST_DEV_BUSY))
+                    }
+                    imported.push(busid.clone());
+                }
+                protocol::write_import(&mut stream, Ok(device))?;
+                let ans = match self.answer {
+                    Some(answer) => loop {
+                        if let Some(reply) = answer(Command::read(&mut stream)?) {
+                            reply.write(&mut stream)?;
+                        }
+                    },
+                    None => urbs(handle, stream),
+                };
+                self.imported.lock().unwrap().retain(|b| *b != busid);
+                ans
+            },
+        }
+    }
+}
+
+/// Run URBs of an imported device until the client disconnects.
+///
+/// Pipe transfers are submitted to the mock backend before the next command
+/// is read, so an unlink following them always aborts them.
+fn urbs(handle: &Handle<'static>, mut stream: TcpStream) -> io::Result<()> {
+    let writer = Mutex::new(stream.try_clone()?);
+    let interrupt_pipes: Vec<u8> = handle.pipes()?
+        .filter(|pipe| pipe.transfer_type == TransferType::Interrupt)
+        .map(|pipe| pipe.endpoint_address)
+        .collect();
+    // pipes of URBs in flight, by sequence number
+    let pending = Mutex::new(HashMap::new());
+    let answer = |seqnum: u32, ans: nihao_usb::Result<(usize, Vec<u8>)>| {
+        // unlinked URBs are answered by their unlink
+        if pending.lock().unwrap().remove(&seqnum).is_none() {
+            return
+        }
+        let (status, actual_length, data) = match ans {
+            Ok((len, data)) => (0, len, data),
+            Err(e) => (errno(&e), 0, Vec::new()),
+        };
+        let header = HeaderBasic::reply(protocol::USBIP_RET_SUBMIT, seqnum);
+        let reply = Reply::Submit { header, status, actual_length: actual_length as i32, data };
+        let _ = reply.write(&mut *writer.lock().unwrap());
+    };
+    thread::scope(|scope| loop {
+        let command = match Command::read(&mut stream) {
+            Ok(command) => command,
+            Err(_) => {
+                // let transfers still waiting for the device end with the scope
+                let pipes: Vec<u8> = pending.lock().unwrap().values().cloned().collect();
+                for pipe_index in pipes {
+                    handle.abort_pipe(pipe_index).ok();
+                }
+                return Ok(())
+            },
+        };
+        match command {
+            Command::Submit { header, transfer_buffer_length, interval, setup, data, .. } => {
+                let seqnum = header.seqnum;
+                let direction_in = header.direction == protocol::USBIP_DIR_IN;
+                let pipe_index = header.ep as u8 | if direction_in { 0x80 } else { 0 };
+                pending.lock().unwrap().insert(seqnum, pipe_index);
+                let answer = &answer;
+                if interrupt_pipes.contains(&pipe_index) && interval <= 0 {
+                    // `usb_submit_urb` refuses interrupt URBs without an interval
+                    let header = HeaderBasic::reply(protocol::USBIP_RET_SUBMIT, seqnum);
+                    pending.lock().unwrap().remove(&seqnum);
+                    let reply = Reply::Submit { header, status: -22, actual_length: 0, data: Vec::new() };
+                    reply.write(&mut *writer.lock().unwrap())?;
+                } else if header.ep == 0 {
+                    let request = ControlRequest::from_bits(setup[0], setup[1],
+                        u16::from_le_bytes([setup[2], setup[3]]), u16::from_le_bytes([setup[4], setup[5]]))
+                        .expect("valid setup packet");
+                    scope.spawn(move || {
+                        let mut buf = vec![0u8; transfer_buffer_length as usize];
+                        let ans = match request.direction {
+                            Direction::In => handle.control_in(request, &mut buf, Duration::from_secs(0))
+                                .map(|len| (len, buf[..len].to_vec())),
+                            Direction::Out => handle.control_out(request, &data, Duration::from_secs(0))
+                                .map(|len| (len, Vec::new())),
+                        };
+                        answer(seqnum, ans)
+                    });
+                } else if direction_in {
+                    let read = handle.read_pipe_async(pipe_index, vec![0u8; transfer_buffer_length as usize]);
+                    scope.spawn(move || answer(seqnum, block_on(read).map(|data| (data.len(), data))));
+                } else {
+                    let write = handle.write_pipe_async(pipe_index, data);
+                    scope.spawn(move || answer(seqnum, block_on(write).map(|len| (len, Vec::new()))));
+                }
+            },
+            Command::Unlink { header, unlink_seqnum } => {
+                let unlinked = pending.lock().unwrap().remove(&unlink_seqnum);
+                if let Some(pipe_index) = unlinked {
+                    handle.abort_pipe(pipe_index).ok();
+                }
+                let status = if unlinked.is_some() { -104 } else { 0 };
+                let header = HeaderBasic::reply(protocol::USBIP_RET_UNLINK, header.seqnum);
+                Reply::Unlink { header, status }.write(&mut *writer.lock().unwrap())?;
+            },
+        }
+    })
+}
+
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(ans) = Pin::new(&mut future).poll(&mut cx) {
+            return ans
+        }
+        thread::park();
+    }
+}
+
+#[test]
+fn list_and_import() -> nihao_usb::Result<()> {
+    let addr = serve(vec![open_fifo("1-4.1")?])?;
+    let list = usbip::devices_on(&addr)?;
+    assert_eq!(list.len(), 1);
+    let device = list.iter().next().expect("one device")?;
+    let info = device.device_info();
+    assert_eq!((info.vendor_id, info.product_id), (Some(0x0483), Some(0x374B)));
+    assert_eq!(info.port_path.as_deref(), Some("1-4.1"));
+    assert_eq!(info.interface_classes, [0xFF]);
+    assert_eq!(device.location()?, "1-4.1".parse()?);
+    assert_eq!(device.host(), addr);
+
+    let handle = device.open()?;
+    assert_eq!(handle.device_descriptor()?, descriptor());
+    assert_eq!(handle.config_descriptor(0)?.endpoints().count(), 3);
+    let pipes = handle.pipes()?;
+    assert_eq!(pipes.iter().map(|p| (p.endpoint_address, p.max_packet_size)).collect::<Vec<_>>(), [(0x81, 64), (0x01, 64), (0x82, 16)]);
+    assert!(matches!(handle.speed()?, Speed::High));
+    let mut raw = [0u8; 255];
+    let len = handle.get_descriptor(3, 3, 0x0409, &mut raw)?;
+    assert_eq!(nihao_usb::descriptor::parse_string(&raw[..len])?, "0672FF");
+    // a device is imported by one client at a time
+    assert!(matches!(device.open().map(drop).map_err(Error::from).unwrap_err(), Error::Busy));
+
+    let set = ControlRequest::vendor_out(Recipient::Device, 0x02, 0x1234, 0);
+    assert_eq!(handle.control_out(set, &[], TIMEOUT)?, 0);
+    let get = ControlRequest::vendor_in(Recipient::Device, 0x01, 0, 0);
+    let mut buf = [0u8; 2];
+    assert_eq!(handle.control_in(get, &mut buf, TIMEOUT)?, 2);
+    assert_eq!(u16::from_le_bytes(buf), 0x1234);
+    let unknown = ControlRequest::vendor_in(Recipient::Device, 0x7F, 0, 0);
+    assert!(matches!(handle.control_in(unknown, &mut buf, TIMEOUT).map_err(Error::from).unwrap_err(), Error::Stall));
+
+    assert_eq!(handle.write_pipe(0x01, &[0xF1, 0x80, 0x00])?, 3);
+    let mut buf = [0u8; 64];
+    assert_eq!(handle.read_pipe_timeout(0x81, &mut buf, TIMEOUT)?, 3);
+    assert_eq!(buf[..3], [0xF1, 0x80, 0x00]);
+    let written = block_on(handle.write_pipe_async(0x01, vec![1, 2]))?;
+    assert_eq!(written.1, 2);
+    let (data, len) = block_on(handle.read_pipe_async(0x81, vec![0u8; 64]))?;
+    assert_eq!(data[..len], [1, 2]);
+    Ok(())
+}
+
+#[test]
+fn time_out_and_unlink() -> nihao_usb::Result<()> {
+    let addr = serve(vec![open_fifo("2-1")?])?;
+    let handle = usbip::devices_on(&addr)?.iter().next().expect("one device")?.open()?;
+    let mut buf = [0u8; 64];
+    let err = handle.read_pipe_timeout(0x81, &mut buf, Duration::from_millis(20)).unwrap_err();
+    assert!(matches!(Error::from(err), Error::Timeout));
+    handle.set_timeout(0x81, Duration::from_millis(20))?;
+    let err = block_on(handle.read_pipe_async(0x81, vec![0u8; 64])).unwrap_err();
+    assert!(matches!(Error::from(err), Error::Timeout));
+    handle.set_timeout(0x81, Duration::from_secs(0))?;
+    // aborting the pipe unlinks transfers submitted before
+    let pending = handle.read_pipe_async(0x81, vec![0u8; 64]);
+    handle.abort_pipe(0x81)?;
+    let err = block_on(pending).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    // dropping a pending future unlinks it, so it takes no data
+    drop(handle.read_pipe_async(0x81, vec![0u8; 64]));
+    handle.write_pipe(0x01, &[7])?;
+    assert_eq!(handle.read_pipe_timeout(0x81, &mut buf, TIMEOUT)?, 1);
+    assert_eq!(buf[0], 7);
+    Ok(())
+}
+
+#[test]
+fn give_up_unanswered_unlink() -> nihao_usb::Result<()> {
+    // a host which lost track of vendor requests never answers them, nor
+    // their unlinks
+    let addr = serve_with(vec![open_fifo("2-3")?], Some(|command| match command {
+        Command::Submit { header, transfer_buffer_length, setup, .. } if setup[..2] == [0x80, 6] => {
+            let mut data = config();
+            data.truncate(transfer_buffer_length as usize);
+            let header = HeaderBasic::reply(protocol::USBIP_RET_SUBMIT, header.seqnum);
+            Some(Reply::Submit { header, status: 0, actual_length: data.len() as i32, data })
+        },
+        _ => None,
+    }))?;
+    let handle = usbip::devices_on(&addr)?.iter().next().expect("one device")?.open()?;
+    let get = ControlRequest::vendor_in(Recipient::Device, 0x01, 0, 0);
+    let err = handle.control_in(get, &mut [0u8; 2], Duration::from_millis(20)).unwrap_err();
+    assert!(matches!(Error::from(err), Error::Timeout));
+    Ok(())
+}
+
+#[test]
+fn reject_overlong_reply() -> nihao_usb::Result<()> {
+    // claims to have received more than the transfer buffer holds
+    let addr = serve_with(vec![open_fifo("2-4")?], Some(|command| match command {
+        Command::Submit { header, transfer_buffer_length, .. } => {
+            let len = transfer_buffer_length + 4;
+            let header = HeaderBasic::reply(protocol::USBIP_RET_SUBMIT, header.seqnum);
+            Some(Reply::Submit { header, status: 0, actual_length: len, data: vec![0; len as usize] })
+        },
+        Command::Unlink { .. } => None,
+    }))?;
+    let handle = usbip::devices_on(&addr)?.iter().next().expect("one device")?.open()?;
+    let get = ControlRequest::get_descriptor(1, 0, 0);
+    assert!(handle.control_in(get, &mut [0u8; 18], TIMEOUT).is_err());
+    Ok(())
+}
+
+#[test]
+fn interrupt_transfers() -> nihao_usb::Result<()> {
+    let addr = serve(vec![open_fifo("2-2")?])?;
+    let handle = usbip::devices_on(&addr)?.iter().next().expect("one device")?.open()?;
+    handle.write_pipe(0x01, &[1, 2, 3])?;
+    let mut buf = [0u8; 16];
+    // the host refuses interrupt URBs sent without the interval of the endpoint
+    assert_eq!(handle.read_pipe_timeout(0x82, &mut buf, TIMEOUT)?, 3);
+    assert_eq!(buf[..3], [1, 2, 3]);
+    Ok(())
+}
+
+#[test]
+fn report_unplugged_device() -> nihao_usb::Result<()> {
+    let addr = serve(vec![open_fifo("3-2")?])?;
+    let handle = usbip::devices_on(&addr)?.iter().next().expect("one device")?.open()?;
+    mock::clear();
+    let mut buf = [0u8; 64];
+    let err = handle.read_pipe_timeout(0x81, &mut buf, TIMEOUT).unwrap_err();
+    assert!(matches!(Error::from(err), Error::Disconnected));
+    Ok(())
+}
+
+#[test]
+fn add_and_remove_hosts() -> nihao_usb::Result<()> {
+    let addr = serve(vec![open_fifo("4-3")?])?;
+    usbip::add_host(&addr);
+    usbip::add_host("127.0.0.1");
+    assert!(usbip::hosts().contains(&"127.0.0.1:3240".to_string()));
+    assert!(usbip::remove_host("127.0.0.1:3240"));
+    let found = usbip::devices()?.iter()
+        .filter_map(|device| device.ok())
+        .any(|device| device.host() == addr && device.location().ok() == Some("4-3".parse().unwrap()));
+    assert!(found);
+    assert!(usbip::remove_host(&addr));
+    assert!(!usbip::remove_host(&addr));
+    Ok(())
+}